@@ -1,4 +1,4 @@
-use ch06_regex::do_matching;
+use ch06_regex::{do_matching, do_matching_with, match_line, Engine, Regex};
 use criterion::{criterion_group, criterion_main, Criterion};
 use std::time::Duration;
 
@@ -47,7 +47,6 @@ fn depth_first(c: &mut Criterion) {
     }
 }
 
-// TODO
 fn width_first(c: &mut Criterion) {
     let mut g = c.benchmark_group("Width First");
     g.measurement_time(Duration::from_secs(12));
@@ -59,6 +58,80 @@ fn width_first(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, depth_first);
-// criterion_group!(benches, width_first, depth_first); // TODO
+/// (計測のid、正規表現、64文字の文字列)というタプル
+const LINES_64: &[(&str, &str, &str)] = &[
+    (
+        "a*a*a*b",
+        "a*a*a*b",
+        "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+    ),
+    (
+        "(a|b)*c",
+        "(a|b)*c",
+        "abababababababababababababababababababababababababababababababab",
+    ),
+    (
+        "(ab|a)*b$",
+        "(ab|a)*b$",
+        "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaab",
+    ),
+];
+
+fn bitstate_vs_depth(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Bitstate vs Depth");
+
+    for i in LINES_64 {
+        for engine in [Engine::Depth, Engine::Bitstate] {
+            g.bench_with_input(format!("{} {:?}", i.0, engine), &(i.1, i.2), |b, args| {
+                b.iter(|| do_matching_with(args.0, args.1, engine))
+            });
+        }
+    }
+}
+
+/// 1行ごとにコンパイルする場合と、1度だけコンパイルする場合の比較
+fn compile_once(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Compile Once");
+    let expr = "(abc|de+f)*x";
+    let lines = (0..1000)
+        .map(|i| format!("line {i}: abcdeefx"))
+        .collect::<Vec<_>>();
+
+    g.bench_function("match_line", |b| {
+        b.iter(|| {
+            lines
+                .iter()
+                .filter(|line| match_line(expr, line).unwrap())
+                .count()
+        })
+    });
+    g.bench_function("Regex::is_match", |b| {
+        let regex = Regex::new(expr).unwrap();
+        b.iter(|| lines.iter().filter(|line| regex.is_match(line)).count())
+    });
+}
+
+/// マッチの数を数える場合の`find_iter`と`count_matches`の比較。
+/// マッチの探し方は同じなので、差はバイト位置の対応表と`Match`を作る分のみ。
+fn count_matches(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Count Matches");
+    let regex = Regex::new("((a|b)(c|d))+(e)?").unwrap();
+    let haystack = "acbd bde xacx ".repeat(100);
+
+    g.bench_function("find_iter().count()", |b| {
+        b.iter(|| regex.find_iter(&haystack).count())
+    });
+    g.bench_function("count_matches", |b| {
+        b.iter(|| regex.count_matches(&haystack))
+    });
+}
+
+criterion_group!(
+    benches,
+    width_first,
+    depth_first,
+    bitstate_vs_depth,
+    compile_once,
+    count_matches
+);
 criterion_main!(benches);
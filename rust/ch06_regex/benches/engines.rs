@@ -0,0 +1,142 @@
+//! すべてのエンジンを同じ入力で計測する。
+//! 計測の前に各エンジンの結果が一致することを確かめるので、等価性のテストも兼ねる。
+
+use ch06_regex::{match_line, Engine, Regex, RegexBuilder};
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion};
+use std::time::Duration;
+
+/// 計測する入力
+struct Case {
+    id: &'static str,
+    expr: String,
+    line: String,
+}
+
+impl Case {
+    fn new(id: &'static str, expr: impl Into<String>, line: impl Into<String>) -> Self {
+        Self {
+            id,
+            expr: expr.into(),
+            line: line.into(),
+        }
+    }
+}
+
+/// `unit`を`n`回繰り返した文字列
+fn repeat(unit: &str, n: usize) -> String {
+    unit.repeat(n)
+}
+
+/// `filler`を繰り返した長い行の途中に`needle`を埋め込む。`needle`が空なら埋め込まない。
+fn long_line(filler: &str, len: usize, needle: &str) -> String {
+    let half = repeat(filler, len / filler.len() / 2);
+    format!("{half}{needle}{half}")
+}
+
+fn cases() -> Vec<Case> {
+    vec![
+        Case::new(
+            "literal, no match",
+            "needle",
+            long_line("haystack ", 4096, ""),
+        ),
+        Case::new(
+            "literal, match in the middle",
+            "needle",
+            long_line("haystack ", 4096, "needle"),
+        ),
+        Case::new("(a|aa)+$ on 30 a's", "(a|aa)+$", repeat("a", 30)),
+        Case::new("a(bc|e+)*, mixed", "a(bc|e+)*", repeat("xabcbceeeabcx", 64)),
+        Case::new(
+            "a?^n a^n, n = 16",
+            repeat("a?", 16) + &repeat("a", 16),
+            repeat("a", 16),
+        ),
+    ]
+}
+
+/// 先頭に固定したパターンと固定しないパターンの比較に用いる入力
+fn anchored_cases() -> Vec<Case> {
+    let line = long_line("abcde", 1024, "xyz");
+    vec![
+        Case::new("anchored", "^abc(d|x)", line.clone()),
+        Case::new("unanchored", "xyz(d|x)?", line.clone()),
+        Case::new("anchored, no match", "^xyz", line.clone()),
+        Case::new("unanchored, no match", "xyw", line),
+    ]
+}
+
+fn build(expr: &str, engine: Engine) -> Regex {
+    RegexBuilder::new(expr)
+        .engine(engine)
+        .build()
+        .unwrap_or_else(|e| panic!("{expr}: {e}"))
+}
+
+/// すべてのエンジンの結果が一致することを確かめ、その結果を返す
+fn assert_engines_agree(case: &Case) -> bool {
+    let results = Engine::ALL
+        .iter()
+        .map(|engine| {
+            build(&case.expr, *engine)
+                .try_is_match(&case.line)
+                .unwrap_or_else(|e| panic!("{}: {engine:?}: {e}", case.id))
+        })
+        .collect::<Vec<_>>();
+    assert!(
+        results.windows(2).all(|w| w[0] == w[1]),
+        "{}: engines disagree: {:?}",
+        case.id,
+        Engine::ALL.iter().zip(&results).collect::<Vec<_>>()
+    );
+    results[0]
+}
+
+/// `case`をすべてのエンジンで計測する
+fn bench_case<M: criterion::measurement::Measurement>(g: &mut BenchmarkGroup<M>, case: &Case) {
+    let expected = assert_engines_agree(case);
+    for engine in Engine::ALL {
+        let regex = build(&case.expr, engine);
+        g.bench_function(format!("{} {engine:?}", case.id), |b| {
+            b.iter(|| assert_eq!(regex.is_match(&case.line), expected))
+        });
+    }
+}
+
+fn engines(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Engines");
+    g.measurement_time(Duration::from_secs(5));
+
+    for case in cases() {
+        bench_case(&mut g, &case);
+    }
+}
+
+fn anchored_vs_unanchored(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Anchored vs Unanchored");
+
+    for case in anchored_cases() {
+        bench_case(&mut g, &case);
+
+        // `match_line`は呼び出しごとにコンパイルする
+        let expected = assert_engines_agree(&case);
+        g.bench_function(format!("{} match_line", case.id), |b| {
+            b.iter(|| assert_eq!(match_line(&case.expr, &case.line).unwrap(), expected))
+        });
+    }
+}
+
+fn compile_only(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Compile Only");
+
+    for case in cases() {
+        for engine in Engine::ALL {
+            g.bench_function(format!("{} {engine:?}", case.id), |b| {
+                b.iter(|| build(&case.expr, engine))
+            });
+        }
+    }
+}
+
+criterion_group!(benches, engines, anchored_vs_unanchored, compile_only);
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+//! 任意のバイト列をパターンと入力に分け、コンパイルとすべてのエンジンでの評価がパニックしないことを確かめる。
+//! エラーになるのは構わない。
+//!
+//! ```text
+//! cargo +nightly fuzz run parse_eval
+//! ```
+
+#![no_main]
+
+use ch06_regex::{Engine, RegexBuilder};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // 最初の0バイトより前をパターン、後を入力とする
+    let (expr, line) = match data.iter().position(|b| *b == 0) {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => (data, &[][..]),
+    };
+    let (Ok(expr), Ok(line)) = (std::str::from_utf8(expr), std::str::from_utf8(line)) else {
+        return;
+    };
+
+    for engine in Engine::ALL {
+        // 指数的なバックトラックでタイムアウトしないよう、実行する命令数を制限する
+        let Ok(regex) = RegexBuilder::new(expr)
+            .engine(engine)
+            .size_limit(1 << 12)
+            .step_limit(Some(1 << 16))
+            .build()
+        else {
+            return;
+        };
+
+        let _ = regex.try_is_match(line);
+        let _ = regex.find(line);
+        let _ = regex.captures(line);
+        let _ = regex.find_iter(line).count();
+        let _ = regex.shortest_match(line);
+        let _ = regex.match_full(line);
+        let _ = regex.match_prefix(line);
+        let _ = regex.match_at(line, line.len() / 2);
+    }
+});
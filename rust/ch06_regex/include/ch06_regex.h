@@ -0,0 +1,33 @@
+/* ch06_regexのC FFI。所有権とエラーの扱いはsrc/ffi.rsを参照。 */
+
+#ifndef CH06_REGEX_H
+#define CH06_REGEX_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define REGEX_OK 0
+#define REGEX_MATCH 1
+#define REGEX_ERR_NULL (-1)
+#define REGEX_ERR_UTF8 (-2)
+#define REGEX_ERR_COMPILE (-3)
+#define REGEX_ERR_EVAL (-4)
+#define REGEX_ERR_PANIC (-5)
+
+typedef struct RegexHandle RegexHandle;
+
+int regex_compile(const char *pattern, RegexHandle **out);
+int regex_is_match(const RegexHandle *handle, const char *text, size_t len);
+int regex_find(const RegexHandle *handle, const char *text, size_t len, size_t *start,
+               size_t *end);
+void regex_free(RegexHandle *handle);
+const char *regex_last_error_message(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
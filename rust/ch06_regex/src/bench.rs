@@ -0,0 +1,185 @@
+//! `--bench`で、メモリに読み込んだ同じ行に対する各エンジンの評価の速さを比べる。
+//!
+//! 各候補で全ての行を`WARMUP_ITERS + iters`回評価し、最初の`WARMUP_ITERS`回を除いた時間を`Instant`で計る。
+//! マッチした行数は全ての候補で一致するはずなので、一致しなければ表の後にエラーとして書き出す。
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use ch06_regex::EngineError;
+
+/// `--bench-iters`を指定しない場合の、計測する回数
+pub const DEFAULT_ITERS: usize = 5;
+/// 計測の前に、計測せずに評価する回数
+pub const WARMUP_ITERS: usize = 1;
+
+/// 1行にマッチするかを返す評価器
+pub type Matcher<'a> = Box<dyn Fn(&str) -> Result<bool, EngineError> + 'a>;
+
+/// 比べる評価器と、表に書き出す名前
+pub struct Candidate<'a> {
+    pub name: String,
+    pub is_match: Matcher<'a>,
+}
+
+/// 1つの候補の計測結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub name: String,
+    /// 計測した回数の合計の時間
+    pub total: Duration,
+    /// 計測した回数の合計で評価した行数
+    pub lines: usize,
+    /// 1回の評価でマッチした行数
+    pub matches: usize,
+}
+
+impl Measurement {
+    fn lines_per_sec(&self) -> f64 {
+        self.lines as f64 / self.total.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// `lines`を各候補で`WARMUP_ITERS + iters`回ずつ評価し、計測結果を候補の順に返す
+pub fn measure(
+    lines: &[&str],
+    candidates: &[Candidate],
+    iters: usize,
+) -> Result<Vec<Measurement>, EngineError> {
+    candidates
+        .iter()
+        .map(|candidate| {
+            let mut matches = 0;
+            let mut total = Duration::ZERO;
+            for i in 0..WARMUP_ITERS + iters {
+                let start = Instant::now();
+                matches = 0;
+                for line in lines {
+                    matches += usize::from((candidate.is_match)(line)?);
+                }
+                if i >= WARMUP_ITERS {
+                    total += start.elapsed();
+                }
+            }
+            Ok(Measurement {
+                name: candidate.name.clone(),
+                total,
+                lines: lines.len() * iters,
+                matches,
+            })
+        })
+        .collect()
+}
+
+/// 計測結果を表として`out`に書き出す。
+/// マッチした行数が候補の間で一致しなければ、各候補の行数を`err`に書き出して`false`を返す。
+pub fn write_report(
+    results: &[Measurement],
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> io::Result<bool> {
+    writeln!(
+        out,
+        "{:<10} {:>12} {:>14} {:>10}",
+        "engine", "total", "lines/sec", "matches"
+    )?;
+    for result in results {
+        writeln!(
+            out,
+            "{:<10} {:>12} {:>14.0} {:>10}",
+            result.name,
+            format!("{:.3?}", result.total),
+            result.lines_per_sec(),
+            result.matches
+        )?;
+    }
+
+    let agreed = results
+        .windows(2)
+        .all(|pair| pair[0].matches == pair[1].matches);
+    if !agreed {
+        let counts = results
+            .iter()
+            .map(|result| format!("{}={}", result.name, result.matches))
+            .collect::<Vec<_>>();
+        writeln!(
+            err,
+            "error: DISCREPANCY: engines disagree on the number of matching lines: {}",
+            counts.join(", ")
+        )?;
+    }
+    Ok(agreed)
+}
+
+#[cfg(test)]
+mod tests {
+    use ch06_regex::Regex;
+
+    use super::*;
+
+    #[test]
+    fn test_bench() -> Result<(), EngineError> {
+        let lines = ["abc", "xbx", "", "bb", "ac"];
+        let regex = Regex::new("b+")?;
+        let candidates = [
+            Candidate {
+                name: "depth".to_string(),
+                is_match: Box::new(|line| regex.try_is_match(line)),
+            },
+            Candidate {
+                name: "contains".to_string(),
+                is_match: Box::new(|line| Ok(line.contains('b'))),
+            },
+        ];
+        let results = measure(&lines, &candidates, 1)?;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| result.matches == 3));
+        assert!(results.iter().all(|result| result.lines == lines.len()));
+
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        assert!(write_report(&results, &mut out, &mut err)?);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 3);
+        assert!(out.starts_with("engine "));
+        assert!(out.lines().nth(2).unwrap().starts_with("contains "));
+        assert!(err.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bench_discrepancy() -> Result<(), EngineError> {
+        let lines = ["abc", "xbx", "bb"];
+        let regex = Regex::new("b")?;
+        // わざと誤った結果を返す評価器
+        let candidates = [
+            Candidate {
+                name: "depth".to_string(),
+                is_match: Box::new(|line| regex.try_is_match(line)),
+            },
+            Candidate {
+                name: "broken".to_string(),
+                is_match: Box::new(|line| Ok(line.len() == 3)),
+            },
+        ];
+        let results = measure(&lines, &candidates, 2)?;
+
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        assert!(!write_report(&results, &mut out, &mut err)?);
+        assert_eq!(
+            String::from_utf8(err).unwrap(),
+            "error: DISCREPANCY: engines disagree on the number of matching lines: depth=3, broken=2\n"
+        );
+
+        // 評価中のエラーはそのまま返す
+        let failing = [Candidate {
+            name: "failing".to_string(),
+            is_match: Box::new(|_| Err(std::io::Error::other("failed").into())),
+        }];
+        assert!(measure(&lines, &failing, 1).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+//! コマンドライン引数の字句解析と、使い方の書き出し。
+//!
+//! オプションは`FLAGS`の表で宣言する。`tokenize`は引数を表に従ってオプションと位置引数に分け、
+//! 各オプションの意味は呼び出し側が`Flag::long`で判断する。使い方も同じ表から生成する。
+//!
+//! - 短いオプションは`-vin`のようにまとめて書ける。値を取る短いオプションは`-A1`か`-A 1`と書き、
+//!   まとめて書く場合は残りを値とする（`-nA1`は`-n -A 1`と同じ）。
+//! - 長いオプションの値は`--name=value`か`--name value`と書く。値を省略できるオプションは`--name=value`の形でのみ値を書ける。
+//! - `--`より後の引数と、`-`および`-`で始まらない引数は位置引数とする。
+
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io::Write,
+    slice,
+};
+
+/// オプションが値を取るか。値を取る場合は、使い方で値を表す名前を持つ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    None,
+    Required(&'static str),
+    Optional(&'static str),
+}
+
+/// 1つのオプションの宣言
+#[derive(Debug, PartialEq, Eq)]
+pub struct Flag {
+    pub short: Option<char>,
+    pub long: &'static str,
+    pub value: Value,
+    /// `--help`で書き出す説明
+    pub help: &'static str,
+}
+
+impl Flag {
+    /// エラーメッセージで用いる`--long`の形の名前
+    pub fn name(&self) -> String {
+        format!("--{}", self.long)
+    }
+
+    /// 使い方で用いる、値を含めた書き方
+    fn synopsis(&self) -> String {
+        let long = match self.value {
+            Value::None => self.name(),
+            Value::Required(meta) => format!("{}={meta}", self.name()),
+            Value::Optional(meta) => format!("{}[={meta}]", self.name()),
+        };
+        match self.short {
+            Some(short) => format!("-{short}, {long}"),
+            None => format!("    {long}"),
+        }
+    }
+}
+
+const fn flag(short: Option<char>, long: &'static str, value: Value, help: &'static str) -> Flag {
+    Flag {
+        short,
+        long,
+        value,
+        help,
+    }
+}
+
+/// このコマンドの全てのオプション。`--help`ではこの順に書き出す。
+#[rustfmt::skip]
+pub const FLAGS: &[Flag] = &[
+    flag(Some('e'), "regexp", Value::Required("PATTERN"), "use PATTERN; may be given more than once"),
+    flag(Some('f'), "file", Value::Required("FILE"), "read patterns from FILE, one per line"),
+    flag(Some('i'), "ignore-case", Value::None, "ignore case distinctions"),
+    flag(None, "normalize", Value::None, "convert lines to NFC before matching"),
+    flag(Some('v'), "invert-match", Value::None, "select non-matching lines"),
+    flag(Some('w'), "word-regexp", Value::None, "match only whole words"),
+    flag(Some('x'), "line-regexp", Value::None, "match only whole lines"),
+    flag(Some('U'), "multiline", Value::None, "match the whole file so that matches can span lines"),
+    flag(None, "multiline-dotall", Value::None, "with -U, let '.' match a newline"),
+    flag(None, "max-filesize", Value::Required("N"), "with -U, skip files larger than N bytes"),
+    flag(Some('n'), "line-number", Value::None, "prefix each line with its line number"),
+    flag(Some('b'), "byte-offset", Value::None, "prefix each line (or match with -o) with its byte offset"),
+    flag(Some('c'), "count", Value::None, "print only the number of selected lines"),
+    flag(Some('l'), "files-with-matches", Value::None, "print only names of files with selected lines"),
+    flag(Some('L'), "files-without-match", Value::None, "print only names of files without selected lines"),
+    flag(Some('Z'), "null", Value::None, "print a NUL byte after each file name"),
+    flag(Some('o'), "only-matching", Value::None, "print only the matched parts of lines"),
+    flag(None, "json", Value::None, "print selected lines as JSON objects"),
+    flag(None, "groups", Value::None, "print the capture groups of each selected line as TSV"),
+    flag(None, "groups-header", Value::None, "like --groups, with a header row of group names"),
+    flag(None, "replace", Value::Required("TEMPLATE"), "print every line with each match replaced by TEMPLATE ($1, ${name})"),
+    flag(None, "replace-only-matching-lines", Value::None, "with --replace, print only lines with a match"),
+    flag(Some('q'), "quiet", Value::None, "print nothing and stop at the first selected line"),
+    flag(None, "silent", Value::None, "same as --quiet"),
+    flag(Some('m'), "max-count", Value::Required("NUM"), "stop after NUM selected lines per file"),
+    flag(Some('A'), "after-context", Value::Required("NUM"), "print NUM lines after each selected line"),
+    flag(Some('B'), "before-context", Value::Required("NUM"), "print NUM lines before each selected line"),
+    flag(Some('C'), "context", Value::Required("NUM"), "print NUM lines around each selected line"),
+    flag(Some('r'), "recursive", Value::None, "search directories recursively"),
+    flag(None, "include", Value::Required("GLOB"), "search only files whose name matches GLOB"),
+    flag(None, "exclude", Value::Required("GLOB"), "skip files whose name matches GLOB"),
+    flag(None, "exclude-dir", Value::Required("GLOB"), "skip directories whose name matches GLOB"),
+    flag(None, "respect-gitignore", Value::None, "skip paths ignored by .gitignore files"),
+    flag(None, "progress", Value::None, "print progress of a search over many files to stderr"),
+    flag(None, "threads", Value::Required("N"), "search files with N threads"),
+    flag(None, "color", Value::Optional("WHEN"), "highlight matches: auto, always or never"),
+    flag(None, "binary-files", Value::Required("TYPE"), "binary files: binary, without-match or text"),
+    flag(None, "crlf", Value::Required("MODE"), "trailing CR of lines: strip or keep"),
+    flag(None, "mmap", Value::None, "memory-map files (requires the mmap feature)"),
+    flag(None, "engine", Value::Required("ENGINE"), "engine: depth, width, pike or bitstate"),
+    flag(None, "step-limit", Value::Required("N"), "fail if one evaluation runs more than N instructions"),
+    flag(None, "stats", Value::Optional("FORMAT"), "print statistics to stderr: text or json"),
+    flag(None, "bench", Value::None, "instead of searching, time each engine on every line of one file"),
+    flag(None, "bench-iters", Value::Required("N"), "with --bench, time N runs per engine"),
+    flag(None, "debug", Value::None, "print the AST and code of the pattern to stderr"),
+    flag(None, "trace", Value::None, "print each evaluation step to stderr"),
+    flag(None, "help", Value::None, "print this help and exit"),
+    flag(Some('V'), "version", Value::None, "print the version and exit"),
+];
+
+/// 字句解析した引数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg<'a> {
+    /// オプションと、その値。`Value::Required`のオプションは必ず値を持つ。
+    Flag(&'static Flag, Option<&'a str>),
+    Positional(&'a str),
+}
+
+/// 引数の誤り
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CliError {
+    /// 表にないオプション`flag`と、それを書いた引数`arg`
+    Unknown { flag: String, arg: String },
+    /// 値を取るオプションの値がない
+    MissingValue(&'static Flag),
+    /// 値を取らないオプションに`=value`を書いた
+    UnexpectedValue(&'static Flag),
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Unknown { flag, arg } if flag == arg => write!(f, "unknown option: {flag}"),
+            CliError::Unknown { flag, arg } => write!(f, "unknown option: {flag} (in {arg})"),
+            CliError::MissingValue(flag) => write!(f, "option {} requires a value", flag.name()),
+            CliError::UnexpectedValue(flag) => {
+                write!(f, "option {} does not take a value", flag.name())
+            }
+        }
+    }
+}
+
+impl Error for CliError {}
+
+fn find_long(long: &str) -> Option<&'static Flag> {
+    FLAGS.iter().find(|flag| flag.long == long)
+}
+
+fn find_short(short: char) -> Option<&'static Flag> {
+    FLAGS.iter().find(|flag| flag.short == Some(short))
+}
+
+/// 値を取るオプション`flag`の値として、次の引数を取り出す。次の引数が`-`で始まっても値とする。
+fn next_value<'a>(
+    args: &mut slice::Iter<'a, String>,
+    flag: &'static Flag,
+) -> Result<&'a str, CliError> {
+    args.next()
+        .map(String::as_str)
+        .ok_or(CliError::MissingValue(flag))
+}
+
+/// 引数`args`（コマンド名を含まない）を、`FLAGS`に従ってオプションと位置引数に分ける
+pub fn tokenize(args: &[String]) -> Result<Vec<Arg<'_>>, CliError> {
+    let mut tokens = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            tokens.extend(args.map(|arg| Arg::Positional(arg)));
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (long, None),
+            };
+            let flag = find_long(name).ok_or_else(|| CliError::Unknown {
+                flag: format!("--{name}"),
+                arg: arg.clone(),
+            })?;
+            let value = match (flag.value, value) {
+                (Value::None, Some(_)) => return Err(CliError::UnexpectedValue(flag)),
+                (Value::Required(_), None) => Some(next_value(&mut args, flag)?),
+                (_, value) => value,
+            };
+            tokens.push(Arg::Flag(flag, value));
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            for (i, short) in shorts.char_indices() {
+                let flag = find_short(short).ok_or_else(|| CliError::Unknown {
+                    flag: format!("-{short}"),
+                    arg: arg.clone(),
+                })?;
+                if let Value::Required(_) = flag.value {
+                    let rest = &shorts[i + short.len_utf8()..];
+                    let value = if rest.is_empty() {
+                        next_value(&mut args, flag)?
+                    } else {
+                        rest
+                    };
+                    tokens.push(Arg::Flag(flag, Some(value)));
+                    break;
+                }
+                tokens.push(Arg::Flag(flag, None));
+            }
+        } else {
+            tokens.push(Arg::Positional(arg));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// 1行の使い方と、位置引数の扱いを書き出す
+pub fn write_usage(command: &str, out: &mut impl Write) -> std::io::Result<()> {
+    let flags = FLAGS
+        .iter()
+        .map(|flag| match (flag.short, flag.value) {
+            (Some(short), Value::None) => format!("[-{short}]"),
+            (Some(short), Value::Required(meta)) => format!("[-{short} {meta}]"),
+            _ => format!("[{}]", flag.synopsis().trim_start()),
+        })
+        .collect::<Vec<_>>();
+    writeln!(out, "usage: {command} {} regex [file...]", flags.join(" "))?;
+    writeln!(
+        out,
+        "with -e or -f, every argument is a file and a line is selected if any pattern matches"
+    )?;
+    writeln!(
+        out,
+        "reads standard input if no file is given or a file is \"-\""
+    )
+}
+
+/// `--help`で、使い方と各オプションの説明を書き出す
+pub fn write_help(command: &str, out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(out, "usage: {command} [OPTION...] regex [file...]")?;
+    writeln!(out, "       {command} [OPTION...] -e PATTERN... [file...]")?;
+    writeln!(
+        out,
+        "search each file (standard input if none or \"-\") for lines that match the pattern"
+    )?;
+    writeln!(out)?;
+    writeln!(out, "options:")?;
+    let synopses = FLAGS.iter().map(Flag::synopsis).collect::<Vec<_>>();
+    let width = synopses.iter().map(String::len).max().unwrap_or(0);
+    for (flag, synopsis) in FLAGS.iter().zip(&synopses) {
+        writeln!(out, "  {synopsis:width$}  {}", flag.help)?;
+    }
+    Ok(())
+}
+
+/// `--version`で、コマンド名とバージョンを書き出す
+pub fn write_version(out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "{} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(args: &[&str]) -> Result<Vec<(String, Option<String>)>, CliError> {
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        Ok(tokenize(&args)?
+            .into_iter()
+            .map(|arg| match arg {
+                Arg::Flag(flag, value) => (flag.name(), value.map(String::from)),
+                Arg::Positional(arg) => (arg.to_string(), None),
+            })
+            .collect())
+    }
+
+    fn expected(pairs: &[(&str, Option<&str>)]) -> Vec<(String, Option<String>)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.map(String::from)))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        // 短いオプションと長いオプション、位置引数
+        assert_eq!(
+            tokens(&["-i", "--count", "abc", "-", "a.txt"]),
+            Ok(expected(&[
+                ("--ignore-case", None),
+                ("--count", None),
+                ("abc", None),
+                ("-", None),
+                ("a.txt", None),
+            ]))
+        );
+
+        // まとめて書いた短いオプション。値を取るオプションは残りを値とする
+        assert_eq!(
+            tokens(&["-vin", "-nA1", "-cm", "3"]),
+            Ok(expected(&[
+                ("--invert-match", None),
+                ("--ignore-case", None),
+                ("--line-number", None),
+                ("--line-number", None),
+                ("--after-context", Some("1")),
+                ("--count", None),
+                ("--max-count", Some("3")),
+            ]))
+        );
+
+        // 長いオプションの値は`=`の後か次の引数。値を省略できるオプションは`=`の後のみ
+        assert_eq!(
+            tokens(&[
+                "--max-count=2",
+                "--include",
+                "*.rs",
+                "--color",
+                "--stats=json"
+            ]),
+            Ok(expected(&[
+                ("--max-count", Some("2")),
+                ("--include", Some("*.rs")),
+                ("--color", None),
+                ("--stats", Some("json")),
+            ]))
+        );
+
+        // 繰り返し指定でき、次の引数は`-`で始まっても値とする
+        assert_eq!(
+            tokens(&["-e", "-x", "--regexp=a", "-eb", "-f", "p.txt"]),
+            Ok(expected(&[
+                ("--regexp", Some("-x")),
+                ("--regexp", Some("a")),
+                ("--regexp", Some("b")),
+                ("--file", Some("p.txt")),
+            ]))
+        );
+
+        // `--`より後は全て位置引数
+        assert_eq!(
+            tokens(&["-n", "--", "-v", "--help"]),
+            Ok(expected(&[
+                ("--line-number", None),
+                ("-v", None),
+                ("--help", None),
+            ]))
+        );
+        assert_eq!(tokens(&[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let err = tokens(&["-n", "-vki"]).unwrap_err();
+        assert_eq!(
+            err,
+            CliError::Unknown {
+                flag: "-k".to_string(),
+                arg: "-vki".to_string()
+            }
+        );
+        assert_eq!(err.to_string(), "unknown option: -k (in -vki)");
+        let err = tokens(&["--colour=always"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown option: --colour (in --colour=always)"
+        );
+        let err = tokens(&["--frobnicate"]).unwrap_err();
+        assert_eq!(err.to_string(), "unknown option: --frobnicate");
+
+        let err = tokens(&["abc", "-A"]).unwrap_err();
+        assert_eq!(err.to_string(), "option --after-context requires a value");
+        let err = tokens(&["--include"]).unwrap_err();
+        assert_eq!(err, CliError::MissingValue(find_long("include").unwrap()));
+
+        let err = tokens(&["--count=3"]).unwrap_err();
+        assert_eq!(err.to_string(), "option --count does not take a value");
+    }
+
+    #[test]
+    fn test_flags() {
+        // 名前は重複しない
+        for (i, flag) in FLAGS.iter().enumerate() {
+            for other in &FLAGS[i + 1..] {
+                assert_ne!(flag.long, other.long);
+                assert!(flag.short.is_none() || flag.short != other.short);
+            }
+        }
+
+        let mut help = Vec::new();
+        write_help("grep", &mut help).unwrap();
+        let help = String::from_utf8(help).unwrap();
+        assert!(help.starts_with("usage: grep [OPTION...] regex [file...]\n"));
+        assert!(help.contains("\n  -e, --regexp=PATTERN  "));
+        assert!(help.contains("\n      --stats[=FORMAT]  "));
+
+        let mut usage = Vec::new();
+        write_usage("grep", &mut usage).unwrap();
+        let usage = String::from_utf8(usage).unwrap();
+        assert!(usage.starts_with("usage: grep [-e PATTERN] [-f FILE] [-i] "));
+        assert!(usage.contains(" [--color[=WHEN]] "));
+    }
+}
@@ -0,0 +1,181 @@
+//! `-A`、`-B`、`-C`で指定した前後の行（コンテキスト）を、選んだ行と合わせて書き出す順に並べる。
+//!
+//! 各行を先頭から順に`ContextTracker::push`に渡すと、書き出す内容が決まった時点でそれを返す。
+//! 前後の行の範囲が重なったり接したりする場合は1つの範囲として、同じ行を2度書き出さない。
+//! 連続しない範囲の間には区切りを挟む。
+
+use std::collections::VecDeque;
+
+/// 書き出す内容
+#[derive(Debug, PartialEq, Eq)]
+pub enum Output<'a> {
+    /// 選んだ行（行番号、行の先頭のバイト位置と行）
+    Selected(usize, usize, &'a str),
+    /// 選んだ行の前後の行（行番号、行の先頭のバイト位置と行）
+    Context(usize, usize, &'a str),
+    /// 連続しない範囲の間の区切り
+    Separator,
+}
+
+/// 選んだ行の前後の行を書き出すかを、1行ずつ判断する状態機械
+#[derive(Debug)]
+pub struct ContextTracker {
+    /// 選んだ行の前に書き出す行数
+    before: usize,
+    /// 選んだ行の後に書き出す行数
+    after: usize,
+    /// まだ書き出していない直前の行。最大`before`行を保持する。
+    buffer: VecDeque<(usize, usize, String)>,
+    /// 後の行として、あと何行を書き出すか
+    remaining_after: usize,
+    /// 最後に書き出した行の行番号
+    last: Option<usize>,
+}
+
+impl ContextTracker {
+    pub fn new(before: usize, after: usize) -> Self {
+        ContextTracker {
+            before,
+            after,
+            buffer: VecDeque::with_capacity(before),
+            remaining_after: 0,
+            last: None,
+        }
+    }
+
+    /// 入力の先頭から`offset`バイト目に始まる`lineno`行目の`line`を受け取り、書き出す内容を順に`emit`に渡す。
+    /// `selected`は行を選んだか。行番号は1ずつ増えていなければならない。
+    pub fn push<E>(
+        &mut self,
+        lineno: usize,
+        offset: usize,
+        line: &str,
+        selected: bool,
+        mut emit: impl FnMut(Output<'_>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        if selected {
+            for (n, o, buffered) in std::mem::take(&mut self.buffer) {
+                self.emit(n, Output::Context(n, o, &buffered), &mut emit)?;
+            }
+            self.remaining_after = self.after;
+            self.emit(lineno, Output::Selected(lineno, offset, line), &mut emit)
+        } else if self.remaining_after > 0 {
+            self.remaining_after -= 1;
+            self.emit(lineno, Output::Context(lineno, offset, line), &mut emit)
+        } else {
+            if self.before > 0 {
+                if self.buffer.len() == self.before {
+                    self.buffer.pop_front();
+                }
+                self.buffer.push_back((lineno, offset, line.to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    /// `lineno`行目の内容`output`を渡す。直前に書き出した行と連続しなければ、先に区切りを渡す。
+    fn emit<E>(
+        &mut self,
+        lineno: usize,
+        output: Output<'_>,
+        emit: &mut impl FnMut(Output<'_>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        if matches!(self.last, Some(last) if lineno > last + 1) {
+            emit(Output::Separator)?;
+        }
+        self.last = Some(lineno);
+        emit(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `lines`のうち`selected`の行番号の行を選び、grepと同様の形式で書き出す内容を返す。
+    /// 各行の先頭のバイト位置は行番号の10倍とする。
+    fn run(before: usize, after: usize, lines: &[&str], selected: &[usize]) -> Vec<String> {
+        let mut tracker = ContextTracker::new(before, after);
+        let mut outputs = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            let lineno = i + 1;
+            tracker
+                .push(
+                    lineno,
+                    lineno * 10,
+                    line,
+                    selected.contains(&lineno),
+                    |output| {
+                        outputs.push(match output {
+                            Output::Selected(n, o, line) => {
+                                assert_eq!(o, n * 10);
+                                format!("{n}:{line}")
+                            }
+                            Output::Context(n, o, line) => {
+                                assert_eq!(o, n * 10);
+                                format!("{n}-{line}")
+                            }
+                            Output::Separator => "--".to_string(),
+                        });
+                        Ok::<_, ()>(())
+                    },
+                )
+                .unwrap();
+        }
+        outputs
+    }
+
+    const LINES: &[&str] = &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+
+    #[test]
+    fn test_context() {
+        // 後の行
+        assert_eq!(
+            run(0, 1, LINES, &[2, 6]),
+            ["2:b", "3-c", "--", "6:f", "7-g"]
+        );
+        // 前の行。先頭より前はない
+        assert_eq!(
+            run(2, 0, LINES, &[1, 6]),
+            ["1:a", "--", "4-d", "5-e", "6:f"]
+        );
+        // 前後の行。末尾より後はない
+        assert_eq!(
+            run(1, 1, LINES, &[3, 10]),
+            ["2-b", "3:c", "4-d", "--", "9-i", "10:j"]
+        );
+        // 前後の行がなければ、選んだ行のみ
+        assert_eq!(run(1, 1, LINES, &[]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_context_adjacent() {
+        // 範囲が接していれば区切りを挟まない
+        assert_eq!(
+            run(1, 1, LINES, &[2, 5]),
+            ["1-a", "2:b", "3-c", "4-d", "5:e", "6-f"]
+        );
+        // 選んだ行が連続する
+        assert_eq!(run(0, 0, LINES, &[2, 3, 5]), ["2:b", "3:c", "--", "5:e"]);
+        assert_eq!(run(1, 0, LINES, &[2, 3]), ["1-a", "2:b", "3:c"]);
+    }
+
+    #[test]
+    fn test_context_overlapping() {
+        // 範囲が重なっても同じ行を2度書き出さない
+        assert_eq!(
+            run(2, 2, LINES, &[3, 5]),
+            ["1-a", "2-b", "3:c", "4-d", "5:e", "6-f", "7-g"]
+        );
+        // 後の行の途中で選んだ行があれば、そこから数え直す
+        assert_eq!(
+            run(0, 3, LINES, &[1, 3]),
+            ["1:a", "2-b", "3:c", "4-d", "5-e", "6-f"]
+        );
+        // 前の行の範囲が直前の後の行と重なる
+        assert_eq!(
+            run(3, 1, LINES, &[2, 6, 10]),
+            ["1-a", "2:b", "3-c", "4-d", "5-e", "6:f", "7-g", "8-h", "9-i", "10:j"]
+        );
+    }
+}
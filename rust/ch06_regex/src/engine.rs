@@ -1,23 +1,211 @@
+use std::borrow::Cow;
+use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::ops::Index;
+use std::sync::Arc;
 
-use crate::helper::DynError;
+use self::evaluator::{eval_at, Symbol, Tracer};
 
-use self::evaluator::eval;
+pub use self::cache::{cached_match, clear_cache, set_cache_capacity};
+pub use self::codegen::CodeGenError;
+pub use self::compat::{check_compatibility, Construct, Unsupported};
+pub use self::evaluator::{EvalError, MatchMetrics, Pc};
+pub use self::normalize::Normalization;
+pub use self::parser::ParseError;
+pub use self::template::{Template, TemplateError};
 
+mod analysis;
+mod cache;
+mod casefold;
 mod codegen;
+mod compat;
 mod evaluator;
+mod grapheme;
+mod normalize;
+#[cfg(feature = "parallel")]
+mod parallel;
 mod parser;
+mod template;
 
+/// 正規表現の解析、コード生成、評価、または入出力で発生したエラー
 #[derive(Debug)]
-#[cfg_attr(test, derive(PartialEq, Eq))]
+pub enum EngineError {
+    Parse(ParseError),
+    CodeGen(CodeGenError),
+    Eval(EvalError),
+    Io(std::io::Error),
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Parse(e) => e.fmt(f),
+            EngineError::CodeGen(e) => e.fmt(f),
+            EngineError::Eval(e) => e.fmt(f),
+            EngineError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for EngineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EngineError::Parse(e) => Some(e),
+            EngineError::CodeGen(e) => Some(e),
+            EngineError::Eval(e) => Some(e),
+            EngineError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<ParseError> for EngineError {
+    fn from(e: ParseError) -> Self {
+        EngineError::Parse(e)
+    }
+}
+
+impl From<CodeGenError> for EngineError {
+    fn from(e: CodeGenError) -> Self {
+        EngineError::CodeGen(e)
+    }
+}
+
+impl From<EvalError> for EngineError {
+    fn from(e: EvalError) -> Self {
+        EngineError::Eval(e)
+    }
+}
+
+impl From<std::io::Error> for EngineError {
+    fn from(e: std::io::Error) -> Self {
+        EngineError::Io(e)
+    }
+}
+
+/// コード生成と評価の設定。`RegexBuilder`で指定する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Options {
+    /// 評価に用いるエンジン
+    engine: Engine,
+    /// 大文字小文字を区別しない
+    case_insensitive: bool,
+    /// `.`が改行にもマッチする。従来の動作に合わせ、デフォルトでは有効。
+    dot_matches_newline: bool,
+    /// 生成するプログラムの命令数の上限
+    size_limit: usize,
+    /// 1回の評価で実行する命令数の上限。`None`なら制限しない。
+    step_limit: Option<usize>,
+    /// 入力の先頭からのみマッチを試みる
+    anchored: bool,
+    /// 後で試すために積んでおける分岐の最大数
+    backtrack_limit: usize,
+    /// 幅優先の評価で、次の位置へ進めるために並べておけるスレッドの最大数。
+    /// この設定を持たない以前のシリアライズ結果も読めるよう、省略すればデフォルト値とする。
+    #[cfg_attr(feature = "serde", serde(default = "default_queue_limit"))]
+    queue_limit: usize,
+    /// 入力を書記素クラスタごとに区切って評価する
+    graphemes: bool,
+    /// 評価の前に入力を正規化する。以前のシリアライズ結果も読めるよう、省略すれば正規化しない。
+    #[cfg_attr(feature = "serde", serde(default))]
+    normalization: Normalization,
+    /// `^`と`$`が入力の先頭と末尾に加えて、行の境界（改行の直後と直前）でもマッチする
+    multiline: bool,
+    /// `multiline`であっても`$`は入力の末尾でのみマッチする。`match_full`の評価に用いる。
+    #[cfg_attr(feature = "serde", serde(skip))]
+    strict_end: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            engine: Engine::default(),
+            case_insensitive: false,
+            dot_matches_newline: true,
+            size_limit: 1 << 20,
+            step_limit: None,
+            anchored: false,
+            backtrack_limit: 1 << 20,
+            queue_limit: default_queue_limit(),
+            graphemes: false,
+            normalization: Normalization::None,
+            multiline: false,
+            strict_end: false,
+        }
+    }
+}
+
+fn default_queue_limit() -> usize {
+    1 << 20
+}
+
+impl Options {
+    /// `code`のマッチが入力の先頭からしか始まらないか。
+    /// 複数行モードの`^`は行の先頭でも成り立つので、`^`で始まっていても先頭に限らない。
+    fn is_anchored(&self, code: &[Instruction]) -> bool {
+        self.is_anchored_from(code, Pc(0))
+    }
+
+    /// `is_anchored`と同様だが、`code`の`entry`番目の命令から始まるマッチについて調べる
+    fn is_anchored_from(&self, code: &[Instruction], entry: Pc) -> bool {
+        self.anchored || (!self.multiline && analysis::is_anchored_start_from(code, entry))
+    }
+
+    /// パターンの文字`c`が入力の文字`input`にマッチするか
+    fn char_matches(&self, c: char, input: char) -> bool {
+        c == input
+            || (self.case_insensitive && casefold::simple_fold(c) == casefold::simple_fold(input))
+    }
+}
+
+/// 評価に用いるエンジン。デフォルトは`Depth`。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Engine {
+    /// 深さ優先探索（バックトラック）
+    #[default]
+    Depth,
+    /// 幅優先探索
+    Width,
+    /// 訪問済みの状態をビットマップで記録する深さ優先探索。
+    /// ビットマップが大きくなりすぎる場合は`Depth`で評価する。
+    Bitstate,
+}
+
+impl Engine {
+    /// すべてのエンジン。エンジンを追加したらここにも加える。
+    pub const ALL: [Engine; 3] = [Engine::Depth, Engine::Width, Engine::Bitstate];
+
+    /// 深さ優先であれば、状態数が小さいときに速い`Bitstate`を選ぶ
+    fn from_is_depth(is_depth: bool) -> Self {
+        if is_depth {
+            Engine::Bitstate
+        } else {
+            Engine::Width
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     Char(char),
     AnyChar,
+    /// 改行以外の任意の1文字。`dot_matches_newline`が無効な場合の`.`。
+    AnyCharExceptNewline,
     Match,
-    Jump(usize),
-    Split(usize, usize),
+    MatchId(usize),
+    Jump(Pc),
+    /// 2つの分岐のどちらかに進む。第1オペランドの分岐を優先し、
+    /// どのエンジンも優先度が最も高い経路のマッチ（leftmost-first）を返す。
+    Split(Pc, Pc),
     Head,
     MatchEnd,
+    Mark(usize),
+    /// 現在の位置をスロットに記録する。グループ`i`の開始位置はスロット`2 * i`、終了位置は`2 * i + 1`。
+    Save(usize),
 }
 
 impl Display for Instruction {
@@ -25,224 +213,3265 @@ impl Display for Instruction {
         match self {
             Instruction::Char(c) => write!(f, "char {}", c),
             Instruction::AnyChar => write!(f, "any_char"),
+            Instruction::AnyCharExceptNewline => write!(f, "any_char_except_nl"),
             Instruction::Match => write!(f, "match"),
+            Instruction::MatchId(id) => write!(f, "match_id {}", id),
             Instruction::Jump(addr) => write!(f, "jump {:>04}", addr),
             Instruction::Split(addr1, addr2) => write!(f, "split {:>04}, {:>04}", addr1, addr2),
             Instruction::Head => write!(f, "head"),
             Instruction::MatchEnd => write!(f, "match_end"),
+            Instruction::Mark(branch) => write!(f, "mark {}", branch),
+            Instruction::Save(slot) => write!(f, "save {}", slot),
         }
     }
 }
 
-#[derive(Debug)]
-#[cfg_attr(test, derive(PartialEq, Eq))]
-struct EvalResult {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EvalResult {
     matched: bool,
     should_be_head: bool,
+    /// マッチした経路が通ったトップレベルの`|`の分岐の番号
+    branch: Option<usize>,
+    /// マッチの終了位置（入力の先頭からの文字数）。マッチしなかった場合は0。
+    end: usize,
 }
 
 impl EvalResult {
-    fn matched() -> Self {
+    fn matched(end: usize) -> Self {
         Self {
             matched: true,
             should_be_head: false,
+            branch: None,
+            end,
         }
     }
     fn unmatched() -> Self {
         Self {
             matched: false,
             should_be_head: false,
+            branch: None,
+            end: 0,
         }
     }
-    fn matched_if_head() -> Self {
+    fn matched_if_head(end: usize) -> Self {
         Self {
             matched: true,
             should_be_head: true,
+            branch: None,
+            end,
         }
     }
 
+    fn with_branch(self, branch: Option<usize>) -> Self {
+        Self { branch, ..self }
+    }
+
+    /// 2つの経路の結果をまとめる。`self`が優先度の高い経路の結果。
+    ///
+    /// | self           | other          | 結果                               |
+    /// |----------------|----------------|------------------------------------|
+    /// | 不一致         | 不一致         | 不一致                             |
+    /// | 一致           | 不一致         | self                               |
+    /// | 不一致         | 一致           | other                              |
+    /// | 一致           | 一致           | selfの位置と分岐、`^`の要否はAND   |
+    ///
+    /// `should_be_head`はマッチしたすべての経路が`^`を通る場合にのみ`true`となる。
+    /// `Head`は入力の先頭でのみ成り立つため、`^`を通る経路のマッチは先頭から評価したときにしか現れず、
+    /// そのときは優先度の高い経路の位置と分岐を採用してよい。
     fn merge(&self, other: &Self) -> Self {
-        if self.matched {
-            if other.matched {
-                return Self {
-                    matched: true,
-                    should_be_head: self.should_be_head && other.should_be_head,
-                };
-            } else {
-                return Self {
-                    matched: true,
-                    should_be_head: self.should_be_head,
-                };
-            }
-        } else {
-            return Self {
-                matched: other.matched,
-                should_be_head: other.should_be_head,
-            };
+        match (self.matched, other.matched) {
+            (true, true) => Self {
+                should_be_head: self.should_be_head && other.should_be_head,
+                ..*self
+            },
+            (true, false) => *self,
+            (false, _) => *other,
         }
     }
 }
 
-pub fn print(expr: &str) -> Result<(), DynError> {
-    println!("expr: {expr}");
+/// パターンのASTと生成したコードを`out`に書き出す。
+/// `Jump`と`Split`には、`->`に続けて飛び先の命令を添える。
+pub fn print(expr: &str, out: &mut impl Write) -> Result<(), EngineError> {
+    writeln!(out, "expr: {expr}")?;
+    let (_, expr) = parser::parse_flags(expr)?;
     let ast = parser::parse(expr)?;
-    println!("AST: {:?}", ast);
+    writeln!(out, "AST:")?;
+    print_ast(&ast, 1, out)?;
 
-    println!();
-    println!("code:");
+    writeln!(out)?;
+    writeln!(out, "code:")?;
     let code = codegen::get_code(&ast)?;
+    let target = |addr: &Pc| match code.get(addr.0) {
+        Some(inst) => inst.to_string(),
+        None => "?".to_string(),
+    };
     for (n, c) in code.iter().enumerate() {
-        println!("{:>04}: {c}", n);
+        let c = c.to_string();
+        match &code[n] {
+            Instruction::Jump(addr) => writeln!(out, "{:>04}: {c:<20} -> {}", n, target(addr))?,
+            Instruction::Split(addr1, addr2) => writeln!(
+                out,
+                "{:>04}: {c:<20} -> {} | {}",
+                n,
+                target(addr1),
+                target(addr2)
+            )?,
+            _ => writeln!(out, "{:>04}: {c}", n)?,
+        }
     }
 
+    writeln!(out)?;
+    writeln!(out, "facts:")?;
+    let regex = Regex::new(expr)?;
+    writeln!(out, "    start anchored: {}", regex.is_start_anchored())?;
+    writeln!(out, "    end anchored: {}", regex.is_end_anchored())?;
+    writeln!(out, "    literal prefix: {:?}", regex.literal_prefix())?;
+    writeln!(out, "    min match length: {}", regex.min_match_len())?;
+    writeln!(out, "    capture groups: {}", regex.capture_count())?;
+
+    Ok(())
+}
+
+/// ASTを1行に1ノードずつ、深さに応じて字下げして書き出す
+fn print_ast(ast: &parser::AST, depth: usize, out: &mut impl Write) -> Result<(), EngineError> {
+    use parser::AST;
+
+    let indent = "    ".repeat(depth);
+    match ast {
+        AST::Char(c) => writeln!(out, "{indent}Char {c:?}")?,
+        AST::Caret => writeln!(out, "{indent}Caret")?,
+        AST::Dollar => writeln!(out, "{indent}Dollar")?,
+        AST::Period => writeln!(out, "{indent}Period")?,
+        AST::Plus(e) | AST::Star(e) | AST::Question(e) => {
+            let name = match ast {
+                AST::Plus(_) => "Plus",
+                AST::Star(_) => "Star",
+                _ => "Question",
+            };
+            writeln!(out, "{indent}{name}")?;
+            print_ast(e, depth + 1, out)?;
+        }
+        AST::Or(e1, e2) => {
+            writeln!(out, "{indent}Or")?;
+            print_ast(e1, depth + 1, out)?;
+            print_ast(e2, depth + 1, out)?;
+        }
+        AST::Seq(v) => {
+            writeln!(out, "{indent}Seq")?;
+            for e in v {
+                print_ast(e, depth + 1, out)?;
+            }
+        }
+        AST::Capture(index, name, e) => {
+            match name {
+                Some(name) => writeln!(out, "{indent}Capture {index} <{name}>")?,
+                None => writeln!(out, "{indent}Capture {index}")?,
+            }
+            print_ast(e, depth + 1, out)?;
+        }
+    }
     Ok(())
 }
 
-pub fn do_matching(expr: &str, line: &str, is_depth: bool) -> Result<bool, DynError> {
+/// `print`の出力先を標準出力とする
+pub fn print_stdout(expr: &str) -> Result<(), EngineError> {
+    print(expr, &mut std::io::stdout().lock())
+}
+
+pub fn do_matching(expr: &str, line: &str, is_depth: bool) -> Result<bool, EngineError> {
+    do_matching_with(expr, line, Engine::from_is_depth(is_depth))
+}
+
+/// `do_matching`と同様だが、評価に用いるエンジンを指定する
+pub fn do_matching_with(expr: &str, line: &str, engine: Engine) -> Result<bool, EngineError> {
+    let options = Options {
+        engine,
+        ..Default::default()
+    };
+    let (code, options) = compile_with_flags(expr, &options)?;
+    Ok(match_compiled_with(&code, line, &options)?)
+}
+
+/// パターンをコンパイルし、`match_compiled`などに渡せるプログラムを返す。
+/// 先頭の`(?i)`などのフラグは評価時の設定なので、ここでは受け付けない。
+pub fn compile(expr: &str) -> Result<Vec<Instruction>, EngineError> {
     let ast = parser::parse(expr)?;
-    let code = codegen::get_code(&ast)?;
+    Ok(codegen::get_code(&ast)?)
+}
+
+/// `do_matching_with`と同様だが、`compile`済みのプログラムを評価する
+pub fn match_compiled(
+    insts: &[Instruction],
+    line: &str,
+    engine: Engine,
+) -> Result<bool, EvalError> {
+    let options = Options {
+        engine,
+        ..Default::default()
+    };
+    match_compiled_with(insts, line, &options)
+}
+
+fn match_compiled_with(
+    insts: &[Instruction],
+    line: &str,
+    options: &Options,
+) -> Result<bool, EvalError> {
     let line = line.chars().collect::<Vec<_>>();
+    Ok(eval_at(insts, &line, 0, options)?.matched)
+}
 
-    Ok(evaluator::eval(&code, &line, is_depth)?.matched)
+/// `match_line`と同様だが、`compile`済みのプログラムを評価する
+pub fn match_line_compiled(
+    insts: &[Instruction],
+    line: &str,
+    engine: Engine,
+) -> Result<bool, EvalError> {
+    let options = Options {
+        engine,
+        ..Default::default()
+    };
+    let anchored = analysis::is_anchored_start(insts);
+    let code = search_code(insts, anchored).map_err(eval_error)?;
+    search(
+        &code,
+        None,
+        line,
+        anchored,
+        &options,
+        &mut SearchCounts::default(),
+    )
 }
 
-pub(crate) fn match_line(expr: &str, line: &str) -> Result<bool, DynError> {
+/// パターン先頭のフラグを読み取ってから解析し、`options`にフラグを反映した設定とともに返す
+fn parse(expr: &str, options: &Options) -> Result<(parser::AST, Options), EngineError> {
+    let (flags, expr) = parser::parse_flags(expr)?;
     let ast = parser::parse(expr)?;
-    let code = codegen::get_code(&ast)?;
+    let options = Options {
+        case_insensitive: options.case_insensitive || flags.case_insensitive,
+        multiline: options.multiline || flags.multiline,
+        ..*options
+    };
 
-    for (i, _) in line.char_indices() {
-        let partial_line = line[i..].chars().collect::<Vec<_>>();
+    Ok((ast, options))
+}
 
-        let result = eval(&code, &partial_line, true)?;
-        // let result = eval(&code, &partial_line, false)?;
-        if result.matched {
-            if !result.should_be_head || i == 0 {
-                return Ok(true);
-            } else {
-                continue;
+/// `parse`で解析してからコンパイルする
+fn compile_with_flags(
+    expr: &str,
+    options: &Options,
+) -> Result<(Vec<Instruction>, Options), EngineError> {
+    let (ast, options) = parse(expr, options)?;
+    Ok((codegen::get_code_with(&ast, &options)?, options))
+}
+
+/// `line`の先頭からマッチしたとき、トップレベルの`|`のどの分岐でマッチしたかを返す。
+/// 分岐は左から0始まりで数え、トップレベルに`|`がなければ`Some(0)`となる。
+pub fn which_branch(expr: &str, line: &str, is_depth: bool) -> Result<Option<usize>, EngineError> {
+    let options = Options {
+        engine: Engine::from_is_depth(is_depth),
+        ..Default::default()
+    };
+    let (ast, options) = parse(expr, &options)?;
+    let code = codegen::get_code_with_marks(&ast)?;
+    let line = line.chars().collect::<Vec<_>>();
+
+    Ok(eval_at(&code, &line, 0, &options)?.branch)
+}
+
+/// 複数の正規表現を1度の走査で評価し、それぞれが`line`の先頭からマッチしたかを返す。
+pub fn do_matching_set(exprs: &[&str], line: &str) -> Result<Vec<bool>, EngineError> {
+    let mut codes = Vec::new();
+    for expr in exprs {
+        let ast = parser::parse(expr)?;
+        codes.push(codegen::get_code(&ast)?);
+    }
+    let codes = codes.iter().map(|code| code.as_slice()).collect::<Vec<_>>();
+    let line = line.chars().collect::<Vec<_>>();
+
+    Ok(evaluator::eval_set(&codes, &line)?)
+}
+
+/// 評価器に渡す入力。`Options::graphemes`であれば書記素クラスタごとに区切る。
+/// `Options::normalization`であれば正規化した文字と、各文字の元の入力でのバイト位置を持つ。
+enum Input<'h> {
+    Chars(Vec<char>),
+    Graphemes(Vec<&'h str>),
+    Normalized(Vec<char>, Vec<usize>),
+}
+
+impl<'h> Input<'h> {
+    fn new(line: &'h str, options: &Options) -> Self {
+        if options.graphemes {
+            Input::Graphemes(grapheme::graphemes(line))
+        } else if options.normalization == Normalization::Nfc {
+            let (chars, offsets) = normalize::nfc(line);
+            Input::Normalized(chars, offsets)
+        } else {
+            Input::Chars(line.chars().collect())
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Input::Chars(chars) | Input::Normalized(chars, _) => chars.len(),
+            Input::Graphemes(clusters) => clusters.len(),
+        }
+    }
+
+    /// 位置からバイト位置への対応。末尾の位置も含む。
+    fn byte_offsets(&self, line: &str) -> Vec<usize> {
+        match self {
+            Input::Chars(_) => byte_offsets(line),
+            Input::Graphemes(clusters) => {
+                let mut offsets = Vec::with_capacity(clusters.len() + 1);
+                offsets.push(0);
+                for cluster in clusters {
+                    offsets.push(offsets[offsets.len() - 1] + cluster.len());
+                }
+                offsets
             }
+            Input::Normalized(_, offsets) => offsets.clone(),
+        }
+    }
+
+    /// バイト位置`pos`を位置に変換する。`pos`が区切りの境界でなければ`None`を返す。
+    fn index(&self, line: &str, pos: usize) -> Option<usize> {
+        match self {
+            Input::Chars(_) => char_index(line, pos),
+            Input::Graphemes(_) => self.byte_offsets(line).binary_search(&pos).ok(),
+            Input::Normalized(_, offsets) => offsets.binary_search(&pos).ok(),
         }
     }
-    Ok(false)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// `input`の要素の型（文字か書記素クラスタか）ごとに、要素のスライスを`line`として`body`を評価する
+macro_rules! with_input {
+    ($input:expr, $line:ident => $body:expr) => {
+        match $input {
+            Input::Chars($line) => $body,
+            Input::Graphemes($line) => $body,
+            Input::Normalized($line, _) => $body,
+        }
+    };
+}
 
-    #[test]
-    fn test_do_matching() {
-        // パースエラー
-        assert!(do_matching("+b", "bbb", true).is_err());
-        assert!(do_matching("*b", "bbb", true).is_err());
-        assert!(do_matching("|b", "bbb", true).is_err());
-        assert!(do_matching("?b", "bbb", true).is_err());
-        // assert!(do_matching(r"\\\", "bbb", true).is_err()); // TODO errになるべき?
+/// 設定を指定して`Regex`を作る。
+///
+/// ```text
+/// let regex = RegexBuilder::new("abc").case_insensitive(true).build()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct RegexBuilder {
+    expr: String,
+    options: Options,
+}
 
-        // パース成功、マッチ成功
-        assert!(do_matching("abc|def", "def", true).unwrap());
-        assert!(do_matching("(abc)*", "abcabc", true).unwrap());
-        assert!(do_matching("(ab|cd)+", "abcdcd", true).unwrap());
-        assert!(do_matching("abc?", "ab", true).unwrap());
-        assert!(do_matching("((((a*)*)*)*)", "aaaaaaaaa", true).unwrap());
-        assert!(do_matching("(a*)*b", "aaaaaaaaab", true).unwrap());
-        assert!(do_matching("(a*)*b", "b", true).unwrap());
-        assert!(do_matching("a**b", "aaaaaaaaab", true).unwrap());
-        assert!(do_matching("a**b", "b", true).unwrap());
+impl RegexBuilder {
+    pub fn new(expr: &str) -> Self {
+        Self {
+            expr: expr.to_string(),
+            options: Options::default(),
+        }
+    }
 
-        // パース成功、マッチ失敗
-        assert!(!do_matching("abc|def", "efa", true).unwrap());
-        assert!(!do_matching("(ab|cd)+", "", true).unwrap());
-        assert!(!do_matching("abc?", "acb", true).unwrap());
+    /// 大文字小文字を区別しない。パターン先頭の`(?i)`と同じ。
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.options.case_insensitive = yes;
+        self
     }
 
-    #[test]
-    fn test_match_line() -> Result<(), DynError> {
-        assert_eq!(match_line(r"\\", r"\")?, true);
-        assert_eq!(match_line(r"\.\+\(\)\|\+\*\?\^\$", r".+()|+*?^$")?, true);
-
-        assert_eq!(match_line("abc|def", "abc")?, true);
-        assert_eq!(match_line("abc|def", "def")?, true);
-        assert_eq!(match_line("abc|def", "123def")?, true);
-
-        assert_eq!(match_line("a.b", "axb")?, true);
-        assert_eq!(match_line("a.b", "aab")?, true);
-        assert_eq!(match_line("a.b", "abb")?, true);
-        assert_eq!(match_line("a.b", "aあb")?, true);
-        assert_eq!(match_line("a.b", "a\\b")?, true);
-        assert_eq!(match_line("a.b", "a b")?, true);
-        assert_eq!(match_line("a.b", "a　b")?, true);
-        assert_eq!(match_line("a.b", "a️💣b")?, false); // TODO: 1文字として扱うべき?
-        assert_eq!(match_line("a.b", "a㊙️b")?, false); // TODO: 1文字として扱うべき?
-        assert_eq!(match_line("a.b", "a\nb")?, true); // TODO: 仕様によってはfalseになる
-        assert_eq!(match_line("a.b", "ab")?, false);
-
-        assert_eq!(match_line("a..b", "axyb")?, true);
-        assert_eq!(match_line("a..b", "axb")?, false);
-
-        assert_eq!(match_line("あ.?い", "あたい")?, true);
-        assert_eq!(match_line("あ.?い", "あい")?, true);
-
-        assert_eq!(match_line("a++", "")?, false);
-        assert_eq!(match_line("a++", "a")?, true);
-        assert_eq!(match_line("a++", "aa")?, true);
-
-        assert_eq!(match_line("a**", "")?, false); // TODO: trueになるべき?
-        assert_eq!(match_line("a**", "a")?, true);
-        assert_eq!(match_line("a**", "aa")?, true);
-
-        assert_eq!(match_line("a+*", "")?, false); // TODO: trueになるべき?
-        assert_eq!(match_line("a+*", "a")?, true);
-        assert_eq!(match_line("a+*", "aa")?, true);
-
-        assert_eq!(match_line("^abc", "abc")?, true);
-        assert_eq!(match_line("^abc", "123abc")?, false);
-        assert_eq!(match_line("^abc", "abc123")?, true);
-
-        assert_eq!(match_line("^^abc", "abc")?, true);
-        assert_eq!(match_line("^^abc", "123abc")?, false);
-        assert_eq!(match_line("^^abc", "123abc")?, false);
-
-        assert_eq!(match_line("(a|^b)c", "ac")?, true);
-        assert_eq!(match_line("(a|^b)c", "bc")?, true);
-        assert_eq!(match_line("(a|^b)c", "123ac")?, true);
-        assert_eq!(match_line("(a|^b)c", "123bc")?, false);
-
-        assert_eq!(match_line("x(a|^b)c", "xac")?, true);
-        assert_eq!(match_line("x(a|^b)c", "xbc")?, false);
-        assert_eq!(match_line("x(a|^b)c", "bc")?, false);
-        assert_eq!(match_line("x(a|^b)c", "123xac")?, true);
-        assert_eq!(match_line("x(a|^b)c", "123xbc")?, false);
-
-        assert_eq!(match_line("(^ab)?c", "c")?, true);
-        assert_eq!(match_line("(^ab)?c", "abc")?, true);
-        assert_eq!(match_line("(^ab)?c", "123c")?, true);
-        assert_eq!(match_line("(^ab)?c", "123abc")?, true);
-
-        assert_eq!(match_line("abc$", "abc")?, true);
-        assert_eq!(match_line("abc$", "abc123")?, false);
-        assert_eq!(match_line("abc$", "123abc")?, true);
-
-        assert_eq!(match_line("abc$$", "abc")?, true);
-        assert_eq!(match_line("abc$$", "abc123")?, false);
-        assert_eq!(match_line("abc$$", "123abc")?, true);
-
-        assert_eq!(match_line("a(b$|c)", "ab")?, true);
-        assert_eq!(match_line("a(b$|c)", "ac")?, true);
-        assert_eq!(match_line("a(b$|c)", "ab123")?, false);
-        assert_eq!(match_line("a(b$|c)", "ac123")?, true);
-
-        assert_eq!(match_line("a(b$|c)x", "abx")?, false);
-        assert_eq!(match_line("a(b$|c)x", "acx")?, true);
-        assert_eq!(match_line("a(b$|c)x", "abx123")?, false);
-        assert_eq!(match_line("a(b$|c)x", "acx123")?, true);
-
-        assert_eq!(match_line("^abc$", "ab")?, false);
-        assert_eq!(match_line("^abc$", "bc")?, false);
-        assert_eq!(match_line("^abc$", "ac")?, false);
-        assert_eq!(match_line("^abc$", "abc")?, true);
-        assert_eq!(match_line("^abc$", "123abc")?, false);
-        assert_eq!(match_line("^abc$", "abc123")?, false);
+    /// `.`が改行にもマッチする。デフォルトでは有効。
+    pub fn dot_matches_newline(&mut self, yes: bool) -> &mut Self {
+        self.options.dot_matches_newline = yes;
+        self
+    }
+
+    /// 評価に用いるエンジン
+    pub fn engine(&mut self, engine: Engine) -> &mut Self {
+        self.options.engine = engine;
+        self
+    }
+
+    /// 生成するプログラムの命令数の上限。超えた場合は`build`がエラーを返す。
+    pub fn size_limit(&mut self, limit: usize) -> &mut Self {
+        self.options.size_limit = limit;
+        self
+    }
+
+    /// 各位置からの1回の評価で実行する命令数の上限。`None`なら制限しない。
+    pub fn step_limit(&mut self, limit: Option<usize>) -> &mut Self {
+        self.options.step_limit = limit;
+        self
+    }
+
+    /// 幅優先の評価で、1つの位置に並べておけるスレッド数の上限。
+    /// 超えた場合は`EvalError::QueueLimitExceeded`となる。
+    pub fn queue_limit(&mut self, limit: usize) -> &mut Self {
+        self.options.queue_limit = limit;
+        self
+    }
+
+    /// 入力の先頭からのみマッチを試みる。パターンの先頭に`^`を付けた場合と同じ。
+    pub fn anchored(&mut self, yes: bool) -> &mut Self {
+        self.options.anchored = yes;
+        self
+    }
+
+    /// `^`と`$`が行の境界でもマッチする。パターン先頭の`(?m)`と同じ。
+    /// `^`は入力の先頭と改行の直後で、`$`は入力の末尾と改行の直前で成り立つ。
+    pub fn multi_line(&mut self, yes: bool) -> &mut Self {
+        self.options.multiline = yes;
+        self
+    }
+
+    /// 入力を文字ではなく書記素クラスタごとに区切って評価する。
+    /// `.`は1つのクラスタに、パターンの文字は1文字からなるクラスタにのみマッチする。
+    /// そのため、結合文字を含むクラスタ（`"e\u{301}"`など）にはパターンの文字の並びではマッチしない。
+    pub fn graphemes(&mut self, yes: bool) -> &mut Self {
+        self.options.graphemes = yes;
+        self
+    }
+
+    /// 評価の前に入力の各行を正規化する。`Normalization::Nfc`であれば、
+    /// NFDの`"e\u{301}"`も合成済みの`'é'`と同じ1文字として扱う（合成できる文字は`normalize`モジュールを参照）。
+    /// マッチの位置は元の入力のバイト位置に戻して返すので、合成した文字へのマッチは元の基底の文字と結合文字を含む。
+    /// パターンは正規化しないので、合成済みの文字で書く。`graphemes`と併用した場合は正規化しない。
+    pub fn normalize(&mut self, normalization: Normalization) -> &mut Self {
+        self.options.normalization = normalization;
+        self
+    }
+
+    pub fn build(&self) -> Result<Regex, EngineError> {
+        let (ast, options) = parse(&self.expr, &self.options)?;
+        Ok(Regex::from_code(
+            self.expr.clone(),
+            codegen::get_code_with(&ast, &options)?,
+            options,
+            codegen::get_code_with_captures(&ast, &options)?,
+            parser::capture_names(&ast).into(),
+        )?)
+    }
+}
+
+/// コンパイル済みの正規表現。パターンの解析とコード生成は`Regex::new`で1度だけ行う。
+///
+/// `serde`フィーチャーを有効にすると、コンパイル済みのプログラムをシリアライズできる。
+/// `code`から求まるフィールドはシリアライズせず、デシリアライズ時に求め直す。
+///
+/// 同じパターンを同じ設定でコンパイルすれば同じプログラムが得られるので、
+/// 2つの`Regex`はパターンと設定が等しいときに等しいとする。`HashMap`のキーにも使える。
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RegexData"))]
+pub struct Regex {
+    expr: String,
+    code: Vec<Instruction>,
+    options: Options,
+    /// `try_is_match`で評価する、前置部を付けたプログラム
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    search_code: Vec<Instruction>,
+    /// マッチの1文字目になりうる文字。`None`なら任意の文字。
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    first_chars: Option<Vec<char>>,
+    /// マッチが入力の先頭からしか始まらない
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    anchored: bool,
+    /// キャプチャグループの位置を記録する`Save`を含むプログラム
+    capture_code: Vec<Instruction>,
+    /// グループの番号ごとの名前
+    capture_names: Arc<[Option<String>]>,
+    /// `match_full`で評価する、末尾に`MatchEnd`を付けたプログラム
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    full_code: Vec<Instruction>,
+    /// マッチが入力の末尾でしか終わらない
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    end_anchored: bool,
+    /// すべてのマッチが先頭に持つ文字列
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    literal_prefix: String,
+    /// マッチしうる文字列の最小の文字数
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    min_len: usize,
+    /// トップレベルの`|`の分岐ごとに探索するためのプログラム
+    #[cfg(feature = "parallel")]
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    branches: Option<parallel::Branches>,
+}
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.expr == other.expr && self.options == other.options
+    }
+}
+
+impl Eq for Regex {}
+
+impl Hash for Regex {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.expr.hash(state);
+        self.options.hash(state);
+    }
+}
+
+/// シリアライズされた`Regex`。検査してから`Regex`に変換する。
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RegexData {
+    expr: String,
+    code: Vec<Instruction>,
+    options: Options,
+    capture_code: Vec<Instruction>,
+    capture_names: Arc<[Option<String>]>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RegexData> for Regex {
+    type Error = String;
+
+    /// 改ざんされたプログラムで評価中にアドレスが範囲外となることのないよう、各命令を検査する
+    fn try_from(data: RegexData) -> Result<Self, Self::Error> {
+        if data.capture_names.is_empty() {
+            return Err("invalid capture names: missing group 0".to_string());
+        }
+        let slots = data.capture_names.len() * 2;
+        for code in [&data.code, &data.capture_code] {
+            if let Some(pc) = analysis::find_invalid(code, slots) {
+                let inst = code
+                    .get(pc)
+                    .map_or("(empty)".to_string(), |inst| inst.to_string());
+                return Err(format!("invalid instruction: {pc:>04}: {inst}"));
+            }
+        }
+
+        Regex::from_code(
+            data.expr,
+            data.code,
+            data.options,
+            data.capture_code,
+            data.capture_names,
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+impl Regex {
+    /// すべてデフォルトの設定で`RegexBuilder::build`する
+    pub fn new(expr: &str) -> Result<Regex, EngineError> {
+        RegexBuilder::new(expr).build()
+    }
+
+    /// 生成したプログラムから、評価に用いる派生したプログラムやパターンの性質を求めて`Regex`を作る
+    fn from_code(
+        expr: String,
+        code: Vec<Instruction>,
+        options: Options,
+        capture_code: Vec<Instruction>,
+        capture_names: Arc<[Option<String>]>,
+    ) -> Result<Regex, CodeGenError> {
+        let anchored = options.is_anchored(&code);
+
+        Ok(Regex {
+            expr,
+            first_chars: analysis::first_chars(&code),
+            anchored,
+            search_code: search_code(&code, anchored)?,
+            capture_code,
+            capture_names,
+            full_code: codegen::with_end_anchor(&code),
+            end_anchored: !options.multiline && analysis::is_anchored_end(&code),
+            // 大文字小文字を区別しない場合、パターンの文字はそのまま入力に現れるとは限らない
+            literal_prefix: if options.case_insensitive {
+                String::new()
+            } else {
+                analysis::literal_prefix(&code)
+            },
+            min_len: analysis::min_len(&code).unwrap_or(0),
+            #[cfg(feature = "parallel")]
+            branches: parallel::Branches::new(&code, &options),
+            code,
+            options,
+        })
+    }
+
+    /// コンパイル元のパターン
+    pub fn as_str(&self) -> &str {
+        &self.expr
+    }
+
+    /// マッチが入力の先頭からしか始まらないか。`^`で始まるパターンや、`anchored`を指定した場合。
+    pub fn is_start_anchored(&self) -> bool {
+        self.anchored
+    }
+
+    /// マッチが入力の末尾でしか終わらないか。すべての分岐が`$`で終わるパターンの場合。
+    pub fn is_end_anchored(&self) -> bool {
+        self.end_anchored
+    }
+
+    /// すべてのマッチが先頭に持つ文字列。そのような文字列がなければ`None`を返す。
+    pub fn literal_prefix(&self) -> Option<&str> {
+        if self.literal_prefix.is_empty() {
+            None
+        } else {
+            Some(&self.literal_prefix)
+        }
+    }
+
+    /// マッチしうる文字列の最小の文字数
+    pub fn min_match_len(&self) -> usize {
+        self.min_len
+    }
+
+    /// グループ0（マッチ全体）を含むキャプチャグループの数
+    pub fn capture_count(&self) -> usize {
+        self.capture_names.len()
+    }
+
+    /// キャプチャグループの名前を、グループ0から番号の順に返す。名前のないグループは`None`となる。
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> + '_ {
+        self.capture_names.iter().map(|name| name.as_deref())
+    }
+
+    /// `line`のいずれかの位置からマッチするかを返す。
+    /// 評価中のエラー（分岐数の上限を超えた場合など）はマッチしなかったものとして扱う。
+    pub fn is_match(&self, line: &str) -> bool {
+        self.try_is_match(line).unwrap_or(false)
+    }
+
+    /// `is_match`と同様だが、評価中のエラーを返す
+    pub fn try_is_match(&self, line: &str) -> Result<bool, EngineError> {
+        #[cfg(feature = "parallel")]
+        if let Some(branches) = &self.branches {
+            return Ok(branches.search(line, &self.options)?);
+        }
+
+        Ok(self.try_is_match_with_steps(line)?.0)
+    }
+
+    /// `try_is_match`と同様だが、マッチしたかとともに評価器が実行した命令数を返す。
+    /// 命令数を数えるため、`parallel`featureでも分岐を並列には評価しない。
+    pub fn try_is_match_with_steps(&self, line: &str) -> Result<(bool, usize), EngineError> {
+        let mut counts = SearchCounts::default();
+        let matched = search(
+            &self.search_code,
+            self.first_chars.as_deref(),
+            line,
+            self.anchored,
+            &self.options,
+            &mut counts,
+        )?;
+        Ok((matched, counts.steps))
+    }
+
+    /// `is_match`と同様だが、評価器が集計した指標を`metrics`に書き込む。
+    /// 評価中のエラーはマッチしなかったものとして扱う。
+    pub fn is_match_with_metrics(&self, line: &str, metrics: &mut MatchMetrics) -> bool {
+        self.try_is_match_with_metrics(line, metrics)
+            .unwrap_or(false)
+    }
+
+    /// `is_match_with_metrics`と同様だが、評価中のエラーを返す。
+    /// 指標を集計するため、`parallel`featureでも分岐を並列には評価しない。
+    pub fn try_is_match_with_metrics(
+        &self,
+        line: &str,
+        metrics: &mut MatchMetrics,
+    ) -> Result<bool, EngineError> {
+        self.try_is_match_observed(
+            line,
+            Observer {
+                metrics: Some(metrics),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// `try_is_match`と同様だが、評価の様子を`observer`に書き出す。
+    /// 書き出し先があれば、`parallel`featureでも分岐を並列には評価しない。
+    pub fn try_is_match_observed(
+        &self,
+        line: &str,
+        observer: Observer,
+    ) -> Result<bool, EngineError> {
+        if observer.trace.is_none() && observer.metrics.is_none() {
+            return self.try_is_match(line);
+        }
+
+        let mut counts = SearchCounts::from(observer);
+        Ok(search(
+            &self.search_code,
+            self.first_chars.as_deref(),
+            line,
+            self.anchored,
+            &self.options,
+            &mut counts,
+        )?)
+    }
+
+    /// `line`全体がマッチするかを返す。
+    /// 評価中のエラーはマッチしなかったものとして扱う。
+    pub fn match_full(&self, line: &str) -> bool {
+        self.try_match_full(line).unwrap_or(false)
+    }
+
+    /// `match_full`と同様だが、評価中のエラーを返す
+    pub fn try_match_full(&self, line: &str) -> Result<bool, EngineError> {
+        self.try_match_full_observed(line, Observer::default())
+    }
+
+    /// `try_match_full`と同様だが、評価の様子を`observer`に書き出す
+    pub fn try_match_full_observed(
+        &self,
+        line: &str,
+        observer: Observer,
+    ) -> Result<bool, EngineError> {
+        // 全体にマッチするには、複数行モードでも`$`は入力の末尾で成り立たなければならない
+        let options = Options {
+            strict_end: true,
+            ..self.options
+        };
+        let mut tracer = observer.tracer();
+        let input = Input::new(line, &options);
+        let result = with_input!(&input, line => {
+            evaluator::eval_with(&self.full_code, line, 0, &options, &mut tracer)?
+        });
+        Ok(result.matched)
+    }
+
+    /// バイト位置`start`から始まり、`$`が成り立つ位置で終わるマッチを探す。
+    /// `try_match_full`と異なり、`multi_line`であれば行末で終わるマッチも返す。
+    /// `^`は`try_find_at`と同様に`haystack`に対して判定する。
+    ///
+    /// `start`が文字（`graphemes`であれば書記素クラスタ）の境界でなければパニックする。
+    pub fn try_match_full_at<'h>(
+        &self,
+        haystack: &'h str,
+        start: usize,
+    ) -> Result<Option<Match<'h>>, EngineError> {
+        let input = Input::new(haystack, &self.options);
+        let from = input
+            .index(haystack, start)
+            .unwrap_or_else(|| panic!("start {start} is not a boundary in the haystack"));
+        if self.anchored && from != 0 {
+            return Ok(None);
+        }
+
+        let result =
+            with_input!(&input, line => eval_at(&self.full_code, line, from, &self.options)?);
+        if !result.matched {
+            return Ok(None);
+        }
+
+        let offsets = input.byte_offsets(haystack);
+        Ok(Some(Match {
+            haystack,
+            start: offsets[from],
+            end: offsets[result.end],
+        }))
+    }
+
+    /// `line`の先頭から始まるマッチを探し、その終了位置をバイト単位で返す。
+    /// 評価中のエラーはマッチしなかったものとして扱う。
+    pub fn match_prefix(&self, line: &str) -> Option<usize> {
+        self.try_match_prefix(line).ok()?
+    }
+
+    fn try_match_prefix(&self, line: &str) -> Result<Option<usize>, EvalError> {
+        let input = Input::new(line, &self.options);
+        let result = with_input!(&input, chars => eval_at(&self.code, chars, 0, &self.options)?);
+        if !result.matched {
+            return Ok(None);
+        }
+
+        Ok(Some(input.byte_offsets(line)[result.end]))
+    }
+
+    /// 最も左の位置から始まるマッチのうち、最も早く終わるものの終了位置をバイト単位で返す。
+    /// 繰り返しは貪欲に扱わず、マッチに到達した時点で評価を打ち切る。
+    /// 評価中のエラーはマッチしなかったものとして扱う。
+    pub fn shortest_match(&self, haystack: &str) -> Option<usize> {
+        self.try_shortest_match(haystack).ok()?
+    }
+
+    fn try_shortest_match(&self, haystack: &str) -> Result<Option<usize>, EvalError> {
+        let input = Input::new(haystack, &self.options);
+        let end = with_input!(&input, line => {
+            let Some((start, _)) = self.find_chars(line, 0)? else {
+                return Ok(None);
+            };
+            evaluator::eval_shortest(&self.code, line, start, &self.options)?
+        });
+
+        Ok(end.map(|end| input.byte_offsets(haystack)[end]))
+    }
+
+    /// `is_match`と同様だが、バイト位置`pos`以降から始まるマッチのみを探す。
+    /// `^`は`pos`ではなく`line`の先頭でのみ成り立つ。`pos`が文字の境界でなければマッチしない。
+    pub fn match_at(&self, line: &str, pos: usize) -> bool {
+        self.try_match_at(line, pos).unwrap_or(false)
+    }
+
+    fn try_match_at(&self, line: &str, pos: usize) -> Result<bool, EvalError> {
+        let input = Input::new(line, &self.options);
+        let start = match input.index(line, pos) {
+            Some(start) if !self.anchored || start == 0 => start,
+            _ => return Ok(false),
+        };
+
+        let result =
+            with_input!(&input, chars => eval_at(&self.search_code, chars, start, &self.options)?);
+        Ok(result.matched)
+    }
+
+    /// `find`と同様に最も左にあるマッチを探し、その中の各キャプチャグループの位置を返す。
+    /// 評価中のエラーはマッチしなかったものとして扱う。
+    pub fn captures<'h>(&self, haystack: &'h str) -> Option<Captures<'h>> {
+        let input = Input::new(haystack, &self.options);
+        let offsets = input.byte_offsets(haystack);
+        with_input!(&input, line => {
+            let span = self.find_chars(line, 0).ok()??;
+            self.captures_at(haystack, line, &offsets, span).ok()?
+        })
+    }
+
+    /// `line`の(開始位置, 終了位置)のマッチについて、各キャプチャグループの位置を求める。
+    /// `offsets`は`line`の位置から`haystack`のバイト位置への対応。
+    fn captures_at<'h, S: Symbol>(
+        &self,
+        haystack: &'h str,
+        line: &[S],
+        offsets: &[usize],
+        (start, end): (usize, usize),
+    ) -> Result<Option<Captures<'h>>, EvalError> {
+        let Some(slots) = evaluator::eval_captures(&self.capture_code, line, start, &self.options)?
+        else {
+            return Ok(None);
+        };
+
+        let mut locations = vec![None; self.capture_names.len() * 2];
+        locations[0] = Some(offsets[start]);
+        locations[1] = Some(offsets[end]);
+        for (location, slot) in locations.iter_mut().zip(slots).skip(2) {
+            *location = slot.map(|sp| offsets[sp]);
+        }
+
+        Ok(Some(Captures {
+            haystack,
+            locations,
+            names: Arc::clone(&self.capture_names),
+        }))
+    }
+
+    /// `haystack`中で最も左にあるマッチを返す。
+    /// 評価中のエラーはマッチしなかったものとして扱う。
+    pub fn find<'h>(&self, haystack: &'h str) -> Option<Match<'h>> {
+        self.find_at(haystack, 0)
+    }
+
+    /// `find`と同様だが、評価中のエラーを返す
+    pub fn try_find<'h>(&self, haystack: &'h str) -> Result<Option<Match<'h>>, EngineError> {
+        Ok(self.try_find_at(haystack, 0)?)
+    }
+
+    /// `find`と同様だが、バイト位置`start`以降から始まるマッチのみを探す。
+    /// `haystack`を切り出して探す場合と異なり、`^`は`start`ではなく`haystack`の先頭
+    /// （`multi_line`であれば各行の先頭）でのみ成り立ち、マッチの位置は`haystack`の先頭から数える。
+    ///
+    /// `start`が文字（`graphemes`であれば書記素クラスタ）の境界でなければパニックする。
+    pub fn find_at<'h>(&self, haystack: &'h str, start: usize) -> Option<Match<'h>> {
+        self.try_find_at(haystack, start).ok()?
+    }
+
+    fn try_find_at<'h>(
+        &self,
+        haystack: &'h str,
+        start: usize,
+    ) -> Result<Option<Match<'h>>, EvalError> {
+        let input = Input::new(haystack, &self.options);
+        let from = input
+            .index(haystack, start)
+            .unwrap_or_else(|| panic!("start {start} is not a boundary in the haystack"));
+        let Some((start, end)) = with_input!(&input, line => self.find_chars(line, from)?) else {
+            return Ok(None);
+        };
+
+        let offsets = input.byte_offsets(haystack);
+        Ok(Some(Match {
+            haystack,
+            start: offsets[start],
+            end: offsets[end],
+        }))
+    }
+
+    /// `haystack`中の重ならないマッチを左から順に返すイテレータを作る。
+    /// 各マッチの終了位置から探索を再開し、空文字列へのマッチの後は1文字進める。
+    /// 直前のマッチの終了位置と同じ位置での空文字列へのマッチは返さない。
+    pub fn find_iter<'r, 'h>(&'r self, haystack: &'h str) -> Matches<'r, 'h> {
+        let input = Input::new(haystack, &self.options);
+        Matches {
+            regex: self,
+            haystack,
+            offsets: input.byte_offsets(haystack),
+            input,
+            sp: 0,
+            last_end: None,
+            overlapping: false,
+        }
+    }
+
+    /// `find_iter().count()`と同じ数を返す。
+    /// マッチの探し方は`find_iter`と同じで、文字数からバイト位置への対応表を作らない分だけ速い。
+    /// 評価中にエラーが起きた場合は、それまでに見つかったマッチの数を返す。
+    pub fn count_matches(&self, haystack: &str) -> usize {
+        let mut count = 0;
+        // エラーは`try_count_matches`で返す。ここでは数えた分だけを返す
+        let _ = self.count_matches_into(haystack, &mut count);
+        count
+    }
+
+    /// `count_matches`と同様だが、評価中のエラーを返す
+    pub fn try_count_matches(&self, haystack: &str) -> Result<usize, EngineError> {
+        let mut count = 0;
+        self.count_matches_into(haystack, &mut count)?;
+        Ok(count)
+    }
+
+    /// `haystack`中の重ならないマッチの数を`count`に加える
+    fn count_matches_into(&self, haystack: &str, count: &mut usize) -> Result<(), EvalError> {
+        let input = Input::new(haystack, &self.options);
+        let (mut sp, mut last_end) = (0, None);
+        with_input!(&input, line => {
+            while self.next_chars(line, &mut sp, &mut last_end)?.is_some() {
+                *count += 1;
+            }
+        });
+        Ok(())
+    }
+
+    /// `haystack`を`find_iter`で見つかるマッチで区切り、間の部分文字列を順に返すイテレータを作る。
+    /// 先頭や末尾のマッチ、隣り合うマッチの間からは空文字列が得られる。
+    pub fn split<'r, 'h>(&'r self, haystack: &'h str) -> Split<'r, 'h> {
+        Split {
+            matches: self.find_iter(haystack),
+            last: 0,
+            finished: false,
+        }
+    }
+
+    /// `split`と同様だが、高々`n`個の部分文字列を返す。最後の部分文字列は残りをすべて含む。
+    pub fn splitn<'r, 'h>(&'r self, haystack: &'h str, n: usize) -> SplitN<'r, 'h> {
+        SplitN {
+            split: self.split(haystack),
+            n,
+        }
+    }
+
+    /// `rep`をこの正規表現の置換文字列のテンプレートとして解析する。
+    /// 存在しないグループを参照していればエラーを返す。
+    pub fn template(&self, rep: &str) -> Result<Template, TemplateError> {
+        Template::new(rep, &self.capture_names)
+    }
+
+    /// 最も左にあるマッチを、テンプレート`rep`を展開した文字列に置き換える。
+    /// マッチしなければ`haystack`をそのまま返す。
+    pub fn replace<'h>(&self, haystack: &'h str, rep: &str) -> Result<Cow<'h, str>, TemplateError> {
+        let template = self.template(rep)?;
+        Ok(self.replacen(haystack, 1, true, |caps, out| template.expand(caps, out)))
+    }
+
+    /// `find_iter`で見つかるすべてのマッチを、テンプレート`rep`を展開した文字列に置き換える。
+    /// マッチしなければ`haystack`をそのまま返す。
+    pub fn replace_all<'h>(
+        &self,
+        haystack: &'h str,
+        rep: &str,
+    ) -> Result<Cow<'h, str>, TemplateError> {
+        Ok(self.replace_all_template(haystack, &self.template(rep)?))
+    }
+
+    /// `replace_all`と同様だが、`template`で解析済みのテンプレートを用いる
+    pub fn replace_all_template<'h>(&self, haystack: &'h str, template: &Template) -> Cow<'h, str> {
+        self.replacen(haystack, 0, true, |caps, out| template.expand(caps, out))
+    }
+
+    /// `replace_all`と同様だが、各マッチを`f`の戻り値に置き換える
+    pub fn replace_all_with<'h, F>(&self, haystack: &'h str, mut f: F) -> Cow<'h, str>
+    where
+        F: FnMut(&Match<'h>) -> String,
+    {
+        self.replacen(haystack, 0, false, |caps, out| {
+            if let Some(m) = caps.get(0) {
+                out.push_str(&f(&m))
+            }
+        })
+    }
+
+    /// 先頭から`limit`個のマッチを置き換える。`limit`が0ならすべてのマッチを置き換える。
+    /// 置き換える文字列は`append`で`out`に追加する。`groups`が`false`であれば、
+    /// `append`に渡す`Captures`はグループ0のみを持つ。
+    /// 評価中にエラーが起きた場合は、それまでに見つかったマッチのみを置き換える。
+    fn replacen<'h, F>(
+        &self,
+        haystack: &'h str,
+        limit: usize,
+        groups: bool,
+        mut append: F,
+    ) -> Cow<'h, str>
+    where
+        F: FnMut(&Captures<'h>, &mut String),
+    {
+        let input = Input::new(haystack, &self.options);
+        let offsets = input.byte_offsets(haystack);
+        let (mut sp, mut last_end) = (0, None);
+
+        let mut out = None;
+        let mut last = 0;
+        with_input!(&input, line => {
+            let mut count = 0;
+            while limit == 0 || count < limit {
+                let Ok(Some((start, end))) = self.next_chars(line, &mut sp, &mut last_end) else {
+                    break;
+                };
+                let caps = if groups {
+                    match self.captures_at(haystack, line, &offsets, (start, end)) {
+                        Ok(Some(caps)) => caps,
+                        _ => break,
+                    }
+                } else {
+                    Captures {
+                        haystack,
+                        locations: vec![Some(offsets[start]), Some(offsets[end])],
+                        names: Arc::clone(&self.capture_names),
+                    }
+                };
+
+                let out = out.get_or_insert_with(|| String::with_capacity(haystack.len()));
+                out.push_str(&haystack[last..offsets[start]]);
+                append(&caps, out);
+                last = offsets[end];
+                count += 1;
+            }
+        });
+
+        match out {
+            Some(mut out) => {
+                out.push_str(&haystack[last..]);
+                Cow::Owned(out)
+            }
+            None => Cow::Borrowed(haystack),
+        }
+    }
+
+    /// `sp`文字目以降で、直前のマッチと重ならない次のマッチを(開始位置, 終了位置)の文字数で返し、
+    /// 次に探索を始める位置`sp`と直前のマッチの終了位置`last_end`を進める。
+    /// 直前のマッチの終了位置での空文字列へのマッチは返さない。
+    fn next_chars<S: Symbol>(
+        &self,
+        line: &[S],
+        sp: &mut usize,
+        last_end: &mut Option<usize>,
+    ) -> Result<Option<(usize, usize)>, EvalError> {
+        while *sp <= line.len() {
+            let (start, end) = match self.find_chars(line, *sp)? {
+                Some(span) => span,
+                None => break,
+            };
+
+            if start == end && *last_end == Some(end) {
+                *sp = start + 1;
+                continue;
+            }
+            *last_end = Some(end);
+            *sp = if start == end { end + 1 } else { end };
+            return Ok(Some((start, end)));
+        }
+
+        *sp = line.len() + 1;
+        Ok(None)
+    }
+
+    /// `next_chars`と同様だが、`accept`が(開始位置, 終了位置)を受け付けるマッチのみを返す。
+    /// 最も優先度の高いマッチを受け付けなければ、同じ位置から始まる他のマッチを長いものから順に試す。
+    fn next_chars_by<S: Symbol>(
+        &self,
+        line: &[S],
+        sp: &mut usize,
+        last_end: &mut Option<usize>,
+        accept: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Option<(usize, usize)>, EvalError> {
+        loop {
+            let prev_end = *last_end;
+            let Some((start, end)) = self.next_chars(line, sp, last_end)? else {
+                return Ok(None);
+            };
+            if accept(start, end) {
+                return Ok(Some((start, end)));
+            }
+
+            let ends = evaluator::eval_ends(&self.code, line, start, &self.options)?;
+            let other = ends.into_iter().rev().find(|&other| {
+                other != end && !(other == start && prev_end == Some(start)) && accept(start, other)
+            });
+            if let Some(end) = other {
+                *last_end = Some(end);
+                *sp = if start == end { end + 1 } else { end };
+                return Ok(Some((start, end)));
+            }
+
+            // 受け付けなかったマッチは、次に探す位置での空文字列へのマッチを妨げない
+            *last_end = prev_end;
+            *sp = start + 1;
+        }
+    }
+
+    /// `line`の`from`文字目以降で最も左にあるマッチを探し、(開始位置, 終了位置)を文字数で返す。
+    /// 空文字列へのマッチがありうるので、`line`の末尾の位置も試す。
+    fn find_chars<S: Symbol>(
+        &self,
+        line: &[S],
+        from: usize,
+    ) -> Result<Option<(usize, usize)>, EvalError> {
+        // 先頭からしか始まらないマッチは、先頭以外の位置では評価しない
+        let last = if self.anchored { 0 } else { line.len() };
+
+        for start in from..=last {
+            if let Some(first_chars) = &self.first_chars {
+                match line.get(start) {
+                    Some(c) if first_chars.iter().any(|f| c.matches(*f, &self.options)) => {}
+                    _ => continue,
+                }
+            }
+
+            let result = eval_at(&self.code, line, start, &self.options)?;
+            if result.matched {
+                return Ok(Some((start, result.end)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// `Regex::find_iter`で作るマッチのイテレータ。
+/// 評価中にエラーが起きた場合はそこで終了する。エラーは`try_next`で受け取れる。
+pub struct Matches<'r, 'h> {
+    regex: &'r Regex,
+    haystack: &'h str,
+    input: Input<'h>,
+    offsets: Vec<usize>,
+    /// 次に探索を始める位置（文字数）
+    sp: usize,
+    /// 直前のマッチの終了位置（文字数）
+    last_end: Option<usize>,
+    /// マッチの開始位置の次の文字から探索を再開し、重なり合うマッチも返す
+    overlapping: bool,
+}
+
+impl<'h> Matches<'_, 'h> {
+    /// `next`と同様だが、評価中のエラーを返す。エラーの後は`None`を返す。
+    pub fn try_next(&mut self) -> Result<Option<Match<'h>>, EngineError> {
+        self.try_next_by(|_, _| true)
+    }
+
+    /// `try_next`と同様だが、`accept`がバイト単位の(開始位置, 終了位置)を受け付けるマッチのみを返す。
+    /// ある位置で最も優先度の高いマッチを受け付けなければ、同じ位置から始まる優先度の低いマッチを
+    /// 長いものから順に試し、いずれも受け付けなければ次の位置から探し直す。
+    pub fn try_next_by(
+        &mut self,
+        mut accept: impl FnMut(usize, usize) -> bool,
+    ) -> Result<Option<Match<'h>>, EngineError> {
+        match self.next_span_by(&mut accept) {
+            Ok(span) => Ok(span.map(|(start, end)| Match {
+                haystack: self.haystack,
+                start,
+                end,
+            })),
+            Err(e) => {
+                self.sp = self.input.len() + 1;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// 次のマッチをバイト単位の(開始位置, 終了位置)で返す
+    fn next_span(&mut self) -> Result<Option<(usize, usize)>, EvalError> {
+        self.next_span_by(&mut |_, _| true)
+    }
+
+    /// `accept`が受け付ける次のマッチをバイト単位の(開始位置, 終了位置)で返す
+    fn next_span_by(
+        &mut self,
+        accept: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Option<(usize, usize)>, EvalError> {
+        let span = if self.overlapping {
+            loop {
+                match self.next_overlapping()? {
+                    Some((start, end)) if !accept(self.offsets[start], self.offsets[end]) => {}
+                    span => break span,
+                }
+            }
+        } else {
+            let (sp, last_end, offsets) = (&mut self.sp, &mut self.last_end, &self.offsets);
+            let accept = &mut |start: usize, end: usize| accept(offsets[start], offsets[end]);
+            with_input!(&self.input, line => self.regex.next_chars_by(line, sp, last_end, accept)?)
+        };
+        Ok(span.map(|(start, end)| (self.offsets[start], self.offsets[end])))
+    }
+
+    /// 重なり合うマッチも含めて、次のマッチを文字数で返す
+    fn next_overlapping(&mut self) -> Result<Option<(usize, usize)>, EvalError> {
+        if self.sp <= self.input.len() {
+            let found = with_input!(&self.input, line => self.regex.find_chars(line, self.sp)?);
+            if let Some((start, end)) = found {
+                // 開始位置は必ず進むので、空文字列へのマッチでも停止する
+                self.sp = start + 1;
+                return Ok(Some((start, end)));
+            }
+        }
+
+        self.sp = self.input.len() + 1;
+        Ok(None)
+    }
+}
+
+impl<'h> Iterator for Matches<'_, 'h> {
+    type Item = Match<'h>;
+
+    fn next(&mut self) -> Option<Match<'h>> {
+        self.try_next().ok()?
+    }
+}
+
+/// `Regex::split`で作る部分文字列のイテレータ
+pub struct Split<'r, 'h> {
+    matches: Matches<'r, 'h>,
+    /// 直前のマッチの終了位置（バイト単位）
+    last: usize,
+    finished: bool,
+}
+
+impl Split<'_, '_> {
+    /// まだ返していない残りの部分文字列を返して終了する
+    fn rest<'h>(&mut self, haystack: &'h str) -> Option<&'h str> {
+        if self.finished {
+            return None;
+        }
+        self.finished = true;
+        Some(&haystack[self.last..])
+    }
+}
+
+impl<'h> Iterator for Split<'_, 'h> {
+    type Item = &'h str;
+
+    fn next(&mut self) -> Option<&'h str> {
+        let haystack = self.matches.haystack;
+        if self.finished {
+            return None;
+        }
+
+        match self.matches.next() {
+            Some(m) => {
+                let piece = &haystack[self.last..m.start()];
+                self.last = m.end();
+                Some(piece)
+            }
+            None => self.rest(haystack),
+        }
+    }
+}
+
+/// `Regex::splitn`で作る部分文字列のイテレータ
+pub struct SplitN<'r, 'h> {
+    split: Split<'r, 'h>,
+    /// 残りの部分文字列の数
+    n: usize,
+}
+
+impl<'h> Iterator for SplitN<'_, 'h> {
+    type Item = &'h str;
+
+    fn next(&mut self) -> Option<&'h str> {
+        if self.n == 0 {
+            return None;
+        }
+        self.n -= 1;
+
+        if self.n == 0 {
+            let haystack = self.split.matches.haystack;
+            self.split.rest(haystack)
+        } else {
+            self.split.next()
+        }
+    }
+}
+
+/// `Regex::find`などで見つかったマッチ。位置は`haystack`のバイト単位。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'h> {
+    haystack: &'h str,
+    start: usize,
+    end: usize,
+}
+
+impl<'h> Match<'h> {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// マッチした部分文字列
+    pub fn as_str(&self) -> &'h str {
+        &self.haystack[self.start..self.end]
+    }
+}
+
+/// `Regex::captures`で見つかったマッチのキャプチャグループ。
+/// グループ0はマッチ全体で、マッチに参加しなかったグループは`None`となる。
+#[derive(Debug, Clone)]
+pub struct Captures<'h> {
+    haystack: &'h str,
+    /// グループ`i`の開始位置と終了位置を`2 * i`と`2 * i + 1`に持つ
+    locations: Vec<Option<usize>>,
+    names: Arc<[Option<String>]>,
+}
+
+impl<'h> Captures<'h> {
+    /// グループ`i`のマッチ
+    pub fn get(&self, i: usize) -> Option<Match<'h>> {
+        let start = (*self.locations.get(i * 2)?)?;
+        let end = (*self.locations.get(i * 2 + 1)?)?;
+        Some(Match {
+            haystack: self.haystack,
+            start,
+            end,
+        })
+    }
+
+    /// `(?<name>...)`で名前を付けたグループのマッチ
+    pub fn name(&self, name: &str) -> Option<Match<'h>> {
+        let i = self.names.iter().position(|n| n.as_deref() == Some(name))?;
+        self.get(i)
+    }
+
+    /// グループ0を含むグループの数
+    #[allow(clippy::len_without_is_empty)] // グループ0があるので空にはならない
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
+impl Index<usize> for Captures<'_> {
+    type Output = str;
+
+    /// グループ`i`のマッチした部分文字列。グループがないかマッチに参加しなかった場合はパニックする。
+    fn index(&self, i: usize) -> &str {
+        self.get(i)
+            .map(|m| m.as_str())
+            .unwrap_or_else(|| panic!("no group at index '{}'", i))
+    }
+}
+
+/// 複数の正規表現をまとめてコンパイルしたもの。
+/// 各パターンのプログラムを1つに連結しておき、1度の走査でどのパターンがマッチするかを求める。
+#[derive(Debug)]
+pub struct RegexSet {
+    exprs: Vec<String>,
+    /// 各パターンの前置部付きのプログラムを連結したもの。`Match`は`MatchId(パターンの番号)`になる。
+    code: Vec<Instruction>,
+    /// 各パターンのプログラムの開始アドレス
+    starts: Vec<Pc>,
+    /// パターンごとの設定
+    options: Vec<Options>,
+}
+
+impl RegexSet {
+    /// すべてのパターンをデフォルトの設定でコンパイルする
+    pub fn new(exprs: &[&str]) -> Result<RegexSet, EngineError> {
+        let regexes = exprs
+            .iter()
+            .map(|expr| Regex::new(expr))
+            .collect::<Result<Vec<_>, _>>()?;
+        let programs = regexes
+            .iter()
+            .map(|re| re.search_code.as_slice())
+            .collect::<Vec<_>>();
+        let (code, starts) = evaluator::link_programs(&programs)?;
+
+        Ok(RegexSet {
+            exprs: exprs.iter().map(|expr| expr.to_string()).collect(),
+            code,
+            starts,
+            options: regexes.iter().map(|re| re.options).collect(),
+        })
+    }
+
+    /// パターンの数
+    pub fn len(&self) -> usize {
+        self.exprs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exprs.is_empty()
+    }
+
+    /// コンパイル元のパターン
+    pub fn patterns(&self) -> &[String] {
+        &self.exprs
+    }
+
+    /// 各パターンが`line`のいずれかの位置からマッチするかを求める。
+    /// 評価中のエラーはどのパターンもマッチしなかったものとして扱う。
+    pub fn matches(&self, line: &str) -> SetMatches {
+        self.try_matches(line).unwrap_or_else(|_| SetMatches {
+            matched: vec![false; self.len()],
+        })
+    }
+
+    /// `matches`と同様だが、評価中のエラーを返す
+    pub fn try_matches(&self, line: &str) -> Result<SetMatches, EngineError> {
+        let line = line.chars().collect::<Vec<_>>();
+        let matched = evaluator::eval_linked(&self.code, &self.starts, &self.options, &line)?;
+        Ok(SetMatches { matched })
+    }
+}
+
+/// `RegexSet::matches`の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetMatches {
+    matched: Vec<bool>,
+}
+
+impl SetMatches {
+    /// `i`番目のパターンがマッチしたか。`i`がパターンの数以上ならパニックする。
+    pub fn matched(&self, i: usize) -> bool {
+        self.matched[i]
+    }
+
+    /// いずれかのパターンがマッチしたか
+    pub fn matched_any(&self) -> bool {
+        self.matched.iter().any(|m| *m)
+    }
+
+    /// マッチしたパターンの番号を昇順に返す
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.matched
+            .iter()
+            .enumerate()
+            .filter_map(|(i, m)| m.then_some(i))
+    }
+
+    /// パターンの数
+    pub fn len(&self) -> usize {
+        self.matched.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matched.is_empty()
+    }
+}
+
+/// `line`のいずれかの位置から`expr`にマッチするかを返す。
+/// `Regex::find_at`と同じく、先頭の位置から順にマッチを探す。
+pub fn match_line(expr: &str, line: &str) -> Result<bool, EngineError> {
+    Ok(Regex::new(expr)?.try_find_at(line, 0)?.is_some())
+}
+
+/// `line`全体が`expr`にマッチするかを返す
+pub fn match_full(expr: &str, line: &str) -> Result<bool, EngineError> {
+    Regex::new(expr)?.try_match_full(line)
+}
+
+/// `line`の先頭から始まるマッチの終了位置をバイト単位で返す
+pub fn match_prefix(expr: &str, line: &str) -> Result<Option<usize>, EngineError> {
+    Ok(Regex::new(expr)?.try_match_prefix(line)?)
+}
+
+/// `match_line`と同様だが、バイト位置`pos`以降から始まるマッチのみを探す
+pub fn match_at(expr: &str, line: &str, pos: usize) -> Result<bool, EngineError> {
+    Ok(Regex::new(expr)?.try_match_at(line, pos)?)
+}
+
+/// `line`のいずれかの位置からマッチするかを返す。
+/// `anchored`でなければ`code`は`codegen::with_unanchored_prefix`で前置部を付けたもので、
+/// 各位置からのマッチを評価器の1度の実行で探す。`anchored`であれば先頭の位置でのみ評価する。
+/// `first_chars`が与えられた場合は、その文字が最初に現れる位置から評価を始める。
+/// 評価器を実行した回数と実行した命令数は`counts`に加算する。
+fn search(
+    code: &[Instruction],
+    first_chars: Option<&[char]>,
+    line: &str,
+    anchored: bool,
+    options: &Options,
+    counts: &mut SearchCounts,
+) -> Result<bool, EvalError> {
+    search_from(code, Pc(0), first_chars, line, anchored, options, counts)
+}
+
+/// `search`と同様だが、`code`の`entry`番目の命令から評価を始める
+fn search_from(
+    code: &[Instruction],
+    entry: Pc,
+    first_chars: Option<&[char]>,
+    line: &str,
+    anchored: bool,
+    options: &Options,
+    counts: &mut SearchCounts,
+) -> Result<bool, EvalError> {
+    let input = Input::new(line, options);
+    with_input!(&input, line => {
+        search_symbols(code, entry, first_chars, line, anchored, options, counts)
+    })
+}
+
+/// `Regex::try_is_match_observed`などで、評価の様子を書き出す先
+#[derive(Default)]
+pub struct Observer<'a> {
+    /// 実行した命令を1つずつ書き出す先。`None`なら書き出さない。
+    pub trace: Option<&'a mut dyn Write>,
+    /// 評価器が集計した指標を書き込む先。`None`なら集計しない。
+    pub metrics: Option<&'a mut MatchMetrics>,
+}
+
+impl<'a> Observer<'a> {
+    /// 指標を初期化し、書き出し先を持つトレーサを作る
+    fn tracer(self) -> Tracer<'a> {
+        let tracer = match self.trace {
+            Some(out) => Tracer::new(out),
+            None => Tracer::disabled(),
+        };
+        let metrics = self.metrics.map(|metrics| {
+            *metrics = MatchMetrics::default();
+            metrics
+        });
+        tracer.with_metrics(metrics)
+    }
+}
+
+/// `search`で評価器を実行した回数と、実行した命令数
+#[derive(Default)]
+struct SearchCounts<'a> {
+    runs: usize,
+    steps: usize,
+    /// 実行した命令を書き出す先。`None`なら書き出さない。
+    trace: Option<&'a mut dyn Write>,
+    /// 評価器に集計させる指標。`None`なら集計しない。
+    metrics: Option<&'a mut MatchMetrics>,
+}
+
+impl<'a> From<Observer<'a>> for SearchCounts<'a> {
+    fn from(observer: Observer<'a>) -> Self {
+        let metrics = observer.metrics.map(|metrics| {
+            *metrics = MatchMetrics::default();
+            metrics
+        });
+        SearchCounts {
+            trace: observer.trace,
+            metrics,
+            ..Default::default()
+        }
+    }
+}
+
+fn search_symbols<S: Symbol>(
+    code: &[Instruction],
+    entry: Pc,
+    first_chars: Option<&[char]>,
+    line: &[S],
+    anchored: bool,
+    options: &Options,
+    counts: &mut SearchCounts,
+) -> Result<bool, EvalError> {
+    let start = match first_chars {
+        Some(first_chars) => {
+            let start = line
+                .iter()
+                .position(|c| first_chars.iter().any(|f| c.matches(*f, options)));
+            if start != Some(0) {
+                if let Some(metrics) = &mut counts.metrics {
+                    metrics.prefiltered = true;
+                }
+            }
+            match start {
+                Some(start) if !anchored || start == 0 => start,
+                _ => {
+                    if let Some(out) = &mut counts.trace {
+                        writeln!(out, "result: unmatched (no start position)")
+                            .map_err(EvalError::Trace)?;
+                    }
+                    return Ok(false);
+                }
+            }
+        }
+        None => 0,
+    };
+    counts.runs += 1;
+
+    // `Head`は`line`の先頭でのみ成り立つので、先頭以外の位置で`^`を通る経路はマッチしない
+    let tracer = match counts.trace.as_deref_mut() {
+        Some(out) => Tracer::new(out),
+        None => Tracer::disabled(),
+    };
+    let mut tracer = tracer.with_metrics(counts.metrics.as_deref_mut());
+    let result = evaluator::eval_from(code, entry, line, start, options, &mut tracer)?;
+    counts.steps += tracer.steps();
+    Ok(result.matched)
+}
+
+/// `search`に渡すプログラム。`anchored`でなければ前置部を付ける。
+fn search_code(code: &[Instruction], anchored: bool) -> Result<Vec<Instruction>, CodeGenError> {
+    if anchored {
+        Ok(code.to_vec())
+    } else {
+        codegen::with_unanchored_prefix(code)
+    }
+}
+
+/// 評価の直前に作った`search_code`の誤りを、評価時の誤りとする。
+/// 前置部を付ける際のアドレスの付け替えでのみ溢れうる。
+fn eval_error(e: CodeGenError) -> EvalError {
+    match e {
+        CodeGenError::PCOverFlow(overflow) => EvalError::PCOverFlow(overflow),
+        _ => EvalError::InvalidPC,
+    }
+}
+
+/// バイト位置`pos`を文字数に変換する。`pos`が文字の境界でなければ`None`を返す。
+fn char_index(line: &str, pos: usize) -> Option<usize> {
+    if line.is_char_boundary(pos) {
+        Some(line[..pos].chars().count())
+    } else {
+        None
+    }
+}
+
+/// 文字数からバイト位置への対応。末尾の位置も含む。
+fn byte_offsets(line: &str) -> Vec<usize> {
+    line.char_indices()
+        .map(|(i, _)| i)
+        .chain([line.len()])
+        .collect()
+}
+
+/// `line`中の重ならないマッチをすべて探し、左から順にバイト単位の(開始位置, 終了位置)で返す。
+/// 各マッチの終了位置から探索を再開する。空文字列へのマッチの後は1文字進め、
+/// 直前のマッチの終了位置と同じ位置での空文字列へのマッチは採用しない。
+pub fn find_all(expr: &str, line: &str) -> Result<Vec<(usize, usize)>, EngineError> {
+    find_spans(expr, line, false, Engine::Depth)
+}
+
+/// `find_all`と同様だが、各マッチの開始位置の次の文字から探索を再開するので、
+/// 重なり合うマッチもすべて返す。
+pub fn find_all_overlapping(expr: &str, line: &str) -> Result<Vec<(usize, usize)>, EngineError> {
+    find_spans(expr, line, true, Engine::Depth)
+}
+
+fn find_spans(
+    expr: &str,
+    line: &str,
+    overlapping: bool,
+    engine: Engine,
+) -> Result<Vec<(usize, usize)>, EngineError> {
+    let regex = RegexBuilder::new(expr).engine(engine).build()?;
+
+    let mut matches = regex.find_iter(line);
+    matches.overlapping = overlapping;
+
+    let mut spans = Vec::new();
+    while let Some(span) = matches.next_span()? {
+        spans.push(span);
+    }
+
+    Ok(spans)
+}
+
+/// `match_line`と同様にマッチを探し、実行した命令を1つずつ`out`に書き出す。
+/// `Regex::try_is_match`と同じく、前置部を付けたプログラムを評価器で1度だけ実行する。
+pub fn trace_matching(
+    expr: &str,
+    line: &str,
+    engine: Engine,
+    out: &mut dyn Write,
+) -> Result<bool, EngineError> {
+    let regex = RegexBuilder::new(expr).engine(engine).build()?;
+    regex.try_is_match_observed(
+        line,
+        Observer {
+            trace: Some(out),
+            ..Default::default()
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::DynError;
+
+    #[test]
+    fn test_merge() {
+        let head = EvalResult::matched_if_head(1).with_branch(Some(0));
+        let any = EvalResult::matched(2).with_branch(Some(1));
+        let unmatched = EvalResult::unmatched();
+
+        assert_eq!(unmatched.merge(&unmatched), unmatched);
+        assert_eq!(head.merge(&unmatched), head);
+        assert_eq!(unmatched.merge(&any), any);
+
+        assert_eq!(head.merge(&head), head);
+        assert_eq!(any.merge(&any), any);
+        // 位置と分岐は優先度の高い側、`^`はどちらの経路も必要とする場合のみ
+        assert_eq!(
+            head.merge(&any),
+            EvalResult::matched(1).with_branch(Some(0))
+        );
+        assert_eq!(any.merge(&head), any);
+    }
+
+    #[test]
+    fn test_print() -> Result<(), DynError> {
+        let mut out = Vec::new();
+        print("a(bc|e+)*", &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "\
+expr: a(bc|e+)*
+AST:
+    Seq
+        Char 'a'
+        Star
+            Capture 1
+                Or
+                    Seq
+                        Char 'b'
+                        Char 'c'
+                    Seq
+                        Plus
+                            Char 'e'
+
+code:
+0000: char a
+0001: split 0002, 0009     -> split 0003, 0006 | match
+0002: split 0003, 0006     -> char b | char e
+0003: char b
+0004: char c
+0005: jump 0008            -> jump 0001
+0006: char e
+0007: split 0006, 0008     -> char e | jump 0001
+0008: jump 0001            -> split 0002, 0009
+0009: match
+
+facts:
+    start anchored: false
+    end anchored: false
+    literal prefix: Some(\"a\")
+    min match length: 1
+    capture groups: 2
+"
+        );
+
+        assert!(print("(a", &mut Vec::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_do_matching() {
+        // パースエラー
+        assert!(do_matching("+b", "bbb", true).is_err());
+        assert!(do_matching("*b", "bbb", true).is_err());
+        assert!(do_matching("?b", "bbb", true).is_err());
+        assert!(do_matching(r"\\\", "bbb", true).is_err());
+
+        // パース成功、マッチ成功
+        assert!(do_matching("abc|def", "def", true).unwrap());
+        assert!(do_matching("(abc)*", "abcabc", true).unwrap());
+        assert!(do_matching("(ab|cd)+", "abcdcd", true).unwrap());
+        assert!(do_matching("abc?", "ab", true).unwrap());
+        assert!(do_matching("((((a*)*)*)*)", "aaaaaaaaa", true).unwrap());
+        assert!(do_matching("(a*)*b", "aaaaaaaaab", true).unwrap());
+        assert!(do_matching("(a*)*b", "b", true).unwrap());
+        assert!(do_matching("a**b", "aaaaaaaaab", true).unwrap());
+        assert!(do_matching("a**b", "b", true).unwrap());
+
+        // 空のパターン、空のグループ、空の分岐は空文字列にマッチする
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            assert!(do_matching_with("", "", engine).unwrap());
+            assert!(do_matching_with("", "abc", engine).unwrap());
+            assert!(do_matching_with("a()b", "ab", engine).unwrap());
+            assert!(do_matching_with("(|a)b", "b", engine).unwrap());
+            assert!(do_matching_with("(|a)b", "ab", engine).unwrap());
+            assert!(do_matching_with("|b", "bbb", engine).unwrap());
+            assert!(!do_matching_with("a()b", "acb", engine).unwrap());
+
+            // 空の構文の繰り返し
+            assert!(do_matching_with("()*", "", engine).unwrap());
+            assert!(do_matching_with("a()*b", "ab", engine).unwrap());
+            assert!(do_matching_with("(|a)*b", "aab", engine).unwrap());
+            assert!(do_matching_with("(|a)+b", "b", engine).unwrap());
+            assert!(do_matching_with("^(|a)+$", "aaa", engine).unwrap());
+            assert!(!do_matching_with("^(|a)*$", "aba", engine).unwrap());
+            assert!(!do_matching_with("x(|a)+y", "xaby", engine).unwrap());
+
+            // 非キャプチャグループ
+            assert!(do_matching_with("^(?:ab|c)+$", "abcab", engine).unwrap());
+            assert!(!do_matching_with("^(?:ab|c)+$", "abac", engine).unwrap());
+        }
+        let caps = Regex::new("(?:x(a))+(b)")
+            .unwrap()
+            .captures("xaxab")
+            .unwrap();
+        assert_eq!((caps.len(), &caps[1], &caps[2]), (3, "a", "b"));
+        assert!(match_line("", "anything").unwrap());
+        assert!(match_line("x|", "anything").unwrap());
+
+        // パース成功、マッチ失敗
+        assert!(!do_matching("abc|def", "efa", true).unwrap());
+        assert!(!do_matching("(ab|cd)+", "", true).unwrap());
+        assert!(!do_matching("abc?", "acb", true).unwrap());
+    }
+
+    #[test]
+    fn test_which_branch() -> Result<(), DynError> {
+        // どのエンジンでも、優先度が最も高いマッチの経路上の分岐
+        assert_eq!(which_branch("abc|abd|ab", "abd", true)?, Some(1));
+        assert_eq!(which_branch("abc|abd|ab", "abd", false)?, Some(1));
+
+        assert_eq!(which_branch("abc|abd|ab", "abc", true)?, Some(0));
+        assert_eq!(which_branch("abc|abd|ab", "abx", true)?, Some(2));
+        assert_eq!(which_branch("abc|abd|ab", "abx", false)?, Some(2));
+        assert_eq!(which_branch("abc|abd|ab", "xyz", true)?, None);
+        assert_eq!(which_branch("abc|abd|ab", "xyz", false)?, None);
+        assert_eq!(which_branch("(a|b)c|bd", "bc", true)?, Some(0));
+        assert_eq!(which_branch("abc", "abc", true)?, Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace_matching() -> Result<(), DynError> {
+        // 1文字目になりうる文字まで進めてから、前置部を付けたプログラムを1度だけ実行する
+        let mut buf = Vec::new();
+        assert!(trace_matching("a|b", "xb", Engine::Depth, &mut buf)?);
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "\
+start: sp 0001
+0000: pc 0000 | split 0003, 0001 | sp 0001 'b'
+0001: pc 0003 | split 0004, 0006 | sp 0001 'b'
+0002: pc 0004 | char a           | sp 0001 'b'
+backtrack: pc 0006, sp 0001
+0003: pc 0006 | char b           | sp 0001 'b'
+0004: pc 0007 | match            | sp 0002 EOL
+result: matched
+"
+        );
+
+        let mut buf = Vec::new();
+        assert!(trace_matching("a|b", "b", Engine::Width, &mut buf)?);
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "\
+start: sp 0000
+0000: pc 0000 | split 0003, 0001 | sp 0000 'b'
+0001: pc 0003 | split 0004, 0006 | sp 0000 'b'
+0002: pc 0004 | char a           | sp 0000 'b'
+0003: pc 0006 | char b           | sp 0000 'b'
+0004: pc 0007 | match            | sp 0001 EOL
+0005: pc 0001 | any_char         | sp 0000 'b'
+0006: pc 0002 | jump 0000        | sp 0001 EOL
+0007: pc 0000 | split 0003, 0001 | sp 0001 EOL
+0008: pc 0003 | split 0004, 0006 | sp 0001 EOL
+result: matched
+"
+        );
+
+        // 先頭以外の位置では`^`が成り立たないので、評価器を実行しない
+        let mut buf = Vec::new();
+        assert!(!trace_matching("^a", "ba", Engine::Depth, &mut buf)?);
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "result: unmatched (no start position)\n"
+        );
+
+        // 行末や空の行でのマッチ
+        let mut buf = Vec::new();
+        assert!(trace_matching("$", "abc", Engine::Depth, &mut buf)?);
+        assert!(String::from_utf8(buf)?
+            .ends_with("| match_end        | sp 0003 EOL\nresult: matched\n"));
+        for engine in Engine::ALL {
+            assert!(trace_matching("a*", "", engine, &mut Vec::new())?);
+            assert!(trace_matching("$", "abc", engine, &mut Vec::new())?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_all() -> Result<(), DynError> {
+        assert_eq!(find_all("a+", "baaca")?, vec![(1, 3), (4, 5)]);
+        assert_eq!(find_all("a+", "bbb")?, vec![]);
+        assert_eq!(find_all("abc", "abcabc")?, vec![(0, 3), (3, 6)]);
+
+        // 空文字列へのマッチの後は1文字進める
+        assert_eq!(find_all("a*", "bab")?, vec![(0, 0), (1, 2), (3, 3)]);
+        assert_eq!(find_all("a*", "")?, vec![(0, 0)]);
+        assert_eq!(find_all("a*", "aa")?, vec![(0, 2)]);
+
+        // `^`は先頭でのみ成り立つ
+        assert_eq!(find_all("^a", "aaa")?, vec![(0, 1)]);
+        assert_eq!(find_all("^a", "baa")?, vec![]);
+        assert_eq!(find_all("a|^b", "bab")?, vec![(0, 1), (1, 2)]);
+
+        // `$`は末尾でのみ成り立つ
+        assert_eq!(find_all("a$", "aaa")?, vec![(2, 3)]);
+        assert_eq!(find_all("a*$", "baa")?, vec![(1, 3)]);
+        assert_eq!(find_all("^a*$", "aaa")?, vec![(0, 3)]);
+
+        // バイト単位の位置を返す
+        assert_eq!(find_all("い+", "あいいう")?, vec![(3, 9)]);
+        assert_eq!(find_all("x*", "あ")?, vec![(0, 0), (3, 3)]);
+
+        Ok(())
+    }
+
+    /// すべてのエンジンで結果が一致することを確かめつつ`find_all`を呼ぶ
+    fn find_all_engines(expr: &str, line: &str) -> Result<Vec<(usize, usize)>, DynError> {
+        let spans = find_all(expr, line)?;
+        for engine in [Engine::Width, Engine::Bitstate] {
+            let message = format!("{engine:?}: {expr} {line}");
+            assert_eq!(find_spans(expr, line, false, engine)?, spans, "{message}");
+        }
+        Ok(spans)
+    }
+
+    #[test]
+    fn test_leftmost_first() -> Result<(), DynError> {
+        // `|`は左の分岐を優先する
+        assert_eq!(find_all_engines("a|ab", "ab")?, vec![(0, 1)]);
+        assert_eq!(find_all_engines("ab|a", "ab")?, vec![(0, 2)]);
+        assert_eq!(find_all_engines("(a|ab)c", "abc")?, vec![(0, 3)]);
+
+        // 繰り返しは貪欲
+        assert_eq!(find_all_engines("a*", "aaa")?, vec![(0, 3)]);
+        assert_eq!(find_all_engines("a+", "aaab")?, vec![(0, 3)]);
+        assert_eq!(find_all_engines("a?", "aa")?, vec![(0, 1), (1, 2)]);
+        assert_eq!(find_all_engines("(a|b)*", "abbac")?, vec![(0, 4), (5, 5)]);
+        assert_eq!(find_all_engines("(a|ab)*c", "ababc")?, vec![(0, 5)]);
+        assert_eq!(find_all_engines("a*ab", "aaab")?, vec![(0, 4)]);
+        // TODO: 非貪欲な`a*?`を実装したら、最短のマッチになることを確かめる
+
+        assert_eq!(find_all_engines("a|^b", "bab")?, vec![(0, 1), (1, 2)]);
+        assert_eq!(find_all_engines("(^a|a)b", "abab")?, vec![(0, 2), (2, 4)]);
+        assert_eq!(find_all_engines("(a|^a)b", "abab")?, vec![(0, 2), (2, 4)]);
+        assert_eq!(find_all_engines("(ab|a)(bc|c)?", "abc")?, vec![(0, 3)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_all_overlapping() -> Result<(), DynError> {
+        assert_eq!(find_all("aa", "aaaa")?, vec![(0, 2), (2, 4)]);
+        assert_eq!(
+            find_all_overlapping("aa", "aaaa")?,
+            vec![(0, 2), (1, 3), (2, 4)]
+        );
+
+        // 重ならない探索では"ab"の後の"ba"を見逃す
+        assert_eq!(find_all("ab|ba", "aba")?, vec![(0, 2)]);
+        assert_eq!(find_all_overlapping("ab|ba", "aba")?, vec![(0, 2), (1, 3)]);
+
+        // 空文字列へのマッチでも停止する
+        assert_eq!(
+            find_all_overlapping("a*", "bab")?,
+            vec![(0, 0), (1, 2), (2, 2), (3, 3)]
+        );
+        assert_eq!(find_all_overlapping("^a", "aaa")?, vec![(0, 1)]);
+        assert_eq!(find_all_overlapping("い+", "いい")?, vec![(0, 6), (3, 6)]);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_regex_serde() -> Result<(), Box<dyn Error>> {
+        let lines = ["abc", "xxabcde", "ABC", "a\nc", "ab", "", "bbb", "aaa=bb"];
+        for expr in [
+            "ab(c|d)*e",
+            "^a",
+            "b+$",
+            "(?i)abc",
+            "a.c",
+            "(?<key>a+)=(b*)",
+        ] {
+            let re = Regex::new(expr)?;
+            let json = serde_json::to_string(&re)?;
+            let de: Regex = serde_json::from_str(&json)?;
+
+            assert_eq!(de.as_str(), expr);
+            for line in lines {
+                assert_eq!(de.is_match(line), re.is_match(line), "{expr}: {line}");
+                assert_eq!(de.match_full(line), re.match_full(line), "{expr}: {line}");
+                assert_eq!(
+                    de.find(line).map(|m| (m.start(), m.end())),
+                    re.find(line).map(|m| (m.start(), m.end())),
+                    "{expr}: {line}"
+                );
+                assert_eq!(
+                    de.captures(line)
+                        .map(|c| c.get(1).map(|m| (m.start(), m.end()))),
+                    re.captures(line)
+                        .map(|c| c.get(1).map(|m| (m.start(), m.end()))),
+                    "{expr}: {line}"
+                );
+            }
+        }
+
+        let re = RegexBuilder::new("a.b")
+            .dot_matches_newline(false)
+            .build()?;
+        let de: Regex = serde_json::from_str(&serde_json::to_string(&re)?)?;
+        assert!(!de.is_match("a\nb"));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_regex_serde_invalid() -> Result<(), Box<dyn Error>> {
+        let re = Regex::new("a(b|c)*")?;
+        let mut json: serde_json::Value = serde_json::to_value(&re)?;
+
+        // ジャンプ先をプログラムの範囲外に書き換える
+        let code = json["code"].as_array_mut().unwrap();
+        let jump = code
+            .iter_mut()
+            .find(|inst| inst.get("Jump").is_some())
+            .unwrap();
+        *jump = serde_json::json!({ "Jump": 99 });
+        let err = serde_json::from_value::<Regex>(json).unwrap_err();
+        assert!(err.to_string().contains("invalid instruction"), "{err}");
+
+        // 存在しないグループのスロットに書き込む
+        let mut json = serde_json::to_value(&re)?;
+        let code = json["capture_code"].as_array_mut().unwrap();
+        code[0] = serde_json::json!({ "Save": 1000 });
+        assert!(serde_json::from_value::<Regex>(json).is_err());
+
+        Ok(())
+    }
+
+    /// スレッド間で`Arc<Regex>`を共有できる。内部に状態を持たせる場合もこれを保つこと。
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<Regex>();
+        assert_send_sync::<RegexBuilder>();
+        assert_send_sync::<RegexSet>();
+        assert_send_sync::<Instruction>();
+        assert_send_sync::<Captures>();
+        assert_send_sync::<Match>();
+        assert_send_sync::<EngineError>();
+    }
+
+    #[test]
+    fn test_regex_set() -> Result<(), DynError> {
+        let exprs = [
+            "abc|def", "^ab", "b+$", "(?i)xyz", "a.c", "^$", "x*", "(^a|c)d", "あ+い",
+        ];
+        let set = RegexSet::new(&exprs)?;
+        assert_eq!(set.len(), exprs.len());
+        assert_eq!(set.patterns()[1], "^ab");
+
+        for line in [
+            "abc",
+            "xabc",
+            "ab",
+            "abbb",
+            "XyZ",
+            "",
+            "cd",
+            "ad",
+            "bad",
+            "xad",
+            "あああい",
+            "a\nc",
+            "zzz",
+        ] {
+            let matches = set.matches(line);
+            let expected = exprs
+                .iter()
+                .map(|expr| Ok(Regex::new(expr)?.is_match(line)))
+                .collect::<Result<Vec<_>, EngineError>>()?;
+
+            for (i, expected) in expected.iter().enumerate() {
+                assert_eq!(matches.matched(i), *expected, "{}: {line}", exprs[i]);
+            }
+            assert_eq!(matches.matched_any(), expected.contains(&true), "{line}");
+            assert_eq!(
+                matches.iter().collect::<Vec<_>>(),
+                (0..exprs.len())
+                    .filter(|i| expected[*i])
+                    .collect::<Vec<_>>(),
+                "{line}"
+            );
+        }
+
+        let matches = set.matches("__def__");
+        assert_eq!(matches.iter().collect::<Vec<_>>(), vec![0, 6]);
+
+        assert!(RegexSet::new(&[])?.is_empty());
+        assert!(!RegexSet::new(&[])?.matches("abc").matched_any());
+        assert!(RegexSet::new(&["abc", "+b"]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_do_matching_set() -> Result<(), DynError> {
+        let exprs = ["abc|def", "(ab|cd)+", "a.c", "^xyz"];
+        for line in ["abc", "cdab", "axc", "def", "xyz", "abcdef"] {
+            let expected = exprs
+                .iter()
+                .map(|expr| do_matching(expr, line, true))
+                .collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(do_matching_set(&exprs, line)?, expected);
+        }
+
+        assert!(do_matching_set(&["abc", "+b"], "abc").is_err());
+
+        Ok(())
+    }
+
+    fn match_line_with(expr: &str, line: &str, engine: Engine) -> Result<bool, EngineError> {
+        RegexBuilder::new(expr)
+            .engine(engine)
+            .build()?
+            .try_is_match(line)
+    }
+
+    /// すべてのエンジンで、また前処理の有無によらず結果が一致することを確かめつつ`match_line`を呼ぶ
+    fn match_line_all(expr: &str, line: &str) -> Result<bool, DynError> {
+        let result = match_line(expr, line)?;
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            let options = Options {
+                engine,
+                ..Default::default()
+            };
+            let (code, options) = compile_with_flags(expr, &options)?;
+            let anchored = options.is_anchored(&code);
+            let code = search_code(&code, anchored)?;
+            let message = format!("{engine:?}: {expr} {line}");
+            assert_eq!(match_line_with(expr, line, engine)?, result, "{message}");
+            assert_eq!(
+                search(
+                    &code,
+                    None,
+                    line,
+                    anchored,
+                    &options,
+                    &mut SearchCounts::default()
+                )?,
+                result,
+                "{message}"
+            );
+        }
+        Ok(result)
+    }
+
+    #[test]
+    fn test_match_line() -> Result<(), DynError> {
+        assert!(match_line_all(r"\\", r"\")?);
+        assert!(match_line_all(r"\.\+\(\)\|\+\*\?\^\$", r".+()|+*?^$")?);
+        assert!(match_line_all(r"\[\]\{\}", "[]{}")?);
+        assert!(match_line_all(r"3\.14", "pi=3.14")?);
+        assert!(!match_line_all(r"3\.14", "3x14")?);
+        assert!(match_line_all(r"\$5", "costs $5")?);
+        assert!(match_line_all(r"^\^", "^a")?);
+        assert!(!match_line_all(r"^\^", "a^")?);
+
+        assert!(match_line_all("abc|def", "abc")?);
+        assert!(match_line_all("abc|def", "def")?);
+        assert!(match_line_all("abc|def", "123def")?);
+
+        assert!(match_line_all("a.b", "axb")?);
+        assert!(match_line_all("a.b", "aab")?);
+        assert!(match_line_all("a.b", "abb")?);
+        assert!(match_line_all("a.b", "aあb")?);
+        assert!(match_line_all("a.b", "a\\b")?);
+        assert!(match_line_all("a.b", "a b")?);
+        assert!(match_line_all("a.b", "a　b")?);
+        assert!(!match_line_all("a.b", "a️💣b")?); // TODO: 1文字として扱うべき?
+        assert!(!match_line_all("a.b", "a㊙️b")?); // TODO: 1文字として扱うべき?
+        assert!(match_line_all("a.b", "a\nb")?); // `dot_matches_newline(false)`ではfalse
+        assert!(!match_line_all("a.b", "ab")?);
+
+        assert!(match_line_all("a..b", "axyb")?);
+        assert!(!match_line_all("a..b", "axb")?);
+
+        assert!(match_line_all("あ.?い", "あたい")?);
+        assert!(match_line_all("あ.?い", "あい")?);
+
+        assert!(!match_line_all("a++", "")?);
+        assert!(match_line_all("a++", "a")?);
+        assert!(match_line_all("a++", "aa")?);
+
+        assert!(match_line_all("a**", "")?);
+        assert!(match_line_all("a**", "a")?);
+        assert!(match_line_all("a**", "aa")?);
+
+        assert!(match_line_all("a+*", "")?);
+        assert!(match_line_all("a+*", "a")?);
+        assert!(match_line_all("a+*", "aa")?);
+
+        assert!(match_line_all("^abc", "abc")?);
+        assert!(!match_line_all("^abc", "123abc")?);
+        assert!(match_line_all("^abc", "abc123")?);
+
+        assert!(match_line_all("^^abc", "abc")?);
+        assert!(!match_line_all("^^abc", "123abc")?);
+        assert!(!match_line_all("^^abc", "123abc")?);
+
+        assert!(match_line_all("(a|^b)c", "ac")?);
+        assert!(match_line_all("(a|^b)c", "bc")?);
+        assert!(match_line_all("(a|^b)c", "123ac")?);
+        assert!(!match_line_all("(a|^b)c", "123bc")?);
+
+        assert!(match_line_all("x(a|^b)c", "xac")?);
+        assert!(!match_line_all("x(a|^b)c", "xbc")?);
+        assert!(!match_line_all("x(a|^b)c", "bc")?);
+        assert!(match_line_all("x(a|^b)c", "123xac")?);
+        assert!(!match_line_all("x(a|^b)c", "123xbc")?);
+
+        assert!(match_line_all("(^ab)?c", "c")?);
+        assert!(match_line_all("(^ab)?c", "abc")?);
+        assert!(match_line_all("(^ab)?c", "123c")?);
+        assert!(match_line_all("(^ab)?c", "123abc")?);
+
+        assert!(match_line_all("(^a|a)b", "xab")?);
+        assert!(match_line_all("(a|^a)b", "xab")?);
+        assert!(!match_line_all("(^a|c)b", "xab")?);
+        assert!(!match_line_all("(c|^a)b", "xab")?);
+
+        assert!(match_line_all("^(a|b)", "b")?);
+        assert!(!match_line_all("^(a|b)", "xb")?);
+
+        assert!(match_line_all("abc$", "abc")?);
+        assert!(!match_line_all("abc$", "abc123")?);
+        assert!(match_line_all("abc$", "123abc")?);
+
+        assert!(match_line_all("abc$$", "abc")?);
+        assert!(!match_line_all("abc$$", "abc123")?);
+        assert!(match_line_all("abc$$", "123abc")?);
+
+        assert!(match_line_all("a(b$|c)", "ab")?);
+        assert!(match_line_all("a(b$|c)", "ac")?);
+        assert!(!match_line_all("a(b$|c)", "ab123")?);
+        assert!(match_line_all("a(b$|c)", "ac123")?);
+
+        assert!(!match_line_all("a(b$|c)x", "abx")?);
+        assert!(match_line_all("a(b$|c)x", "acx")?);
+        assert!(!match_line_all("a(b$|c)x", "abx123")?);
+        assert!(match_line_all("a(b$|c)x", "acx123")?);
+
+        assert!(!match_line_all("^abc$", "ab")?);
+        assert!(!match_line_all("^abc$", "bc")?);
+        assert!(!match_line_all("^abc$", "ac")?);
+        assert!(match_line_all("^abc$", "abc")?);
+        assert!(!match_line_all("^abc$", "123abc")?);
+        assert!(!match_line_all("^abc$", "abc123")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_insensitive() -> Result<(), DynError> {
+        assert!(match_line_all("(?i)abc", "xABCx")?);
+        assert!(match_line_all("(?i)ABC", "abc")?);
+        assert!(!match_line_all("abc", "ABC")?);
+        assert!(match_line_all("(?i)a+b|c", "xAaB")?);
+        assert!(match_line_all("(?i)^x", "X")?);
+
+        // ギリシャ文字。語末のςもσと同じ文字として扱う
+        assert!(match_line_all("(?i)σοφια", "ΣΟΦΙΑ")?);
+        assert!(match_line_all("(?i)ΣΟΦΙΑ", "σοφια")?);
+        assert!(match_line_all("(?i)οδοσ", "ΟΔΟΣ")?);
+        assert!(match_line_all("(?i)ΟΔΟΣ", "οδος")?);
+        // キリル文字
+        assert!(match_line_all("(?i)привет", "ПРИВЕТ")?);
+        assert!(!match_line_all("(?i)ПРИВЕТ", "при вет")?);
+
+        // ケルビン記号はkとして扱う
+        assert!(match_line_all("(?i)k", "\u{212A}")?);
+        assert!(match_line_all("(?i)\u{212A}", "K")?);
+
+        // 1文字を2文字に変換するフォールディングは行わないので、ßは"SS"にマッチしない
+        assert!(!match_line_all("(?i)straße", "STRASSE")?);
+        assert!(match_line_all("(?i)straße", "STRAẞE")?);
+        assert!(match_line_all("(?i)straße", "Straße")?);
+        // トルコ語のİ、ıはiと同じ文字として扱わない
+        assert!(!match_line_all("(?i)i", "İ")?);
+        assert!(!match_line_all("(?i)i", "ı")?);
+
+        assert_eq!(find_all("(?i)ab", "aBxAb")?, vec![(0, 2), (3, 5)]);
+        assert_eq!(which_branch("(?i)x|ab", "AB", true)?, Some(1));
+        assert!(do_matching("(?i)abc", "ABC", false)?);
+
+        assert!(match_line("(?x)abc", "abc").is_err());
+        assert!(match_line("(?iabc", "abc").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiline() -> Result<(), DynError> {
+        let text = "foo\nbar\nbaz";
+
+        // デフォルトでは`^`と`$`は入力の先頭と末尾でのみ成り立つ
+        assert!(!match_line_all("^bar$", text)?);
+        assert!(match_line_all("^foo", text)?);
+        assert!(match_line_all("baz$", text)?);
+        assert!(!match_line_all("foo$", text)?);
+
+        assert!(match_line_all("(?m)^bar$", text)?);
+        assert!(!match_line_all("(?m)^ar", text)?);
+        assert!(!match_line_all("(?m)ba$", text)?);
+        assert!(match_line_all("(?m)^baz$", text)?);
+        assert!(match_line_all("(?im)^BAR$", text)?);
+        assert!(match_line_all("(?m)^$", "a\n\nb")?);
+        assert!(!match_line_all("^$", "a\n\nb")?);
+
+        for engine in Engine::ALL {
+            let regex = RegexBuilder::new("^bar$")
+                .multi_line(true)
+                .engine(engine)
+                .build()?;
+            assert!(regex.is_match(text));
+            let m = regex.find(text).unwrap();
+            assert_eq!((m.start(), m.end()), (4, 7));
+
+            let regex = RegexBuilder::new("^.")
+                .multi_line(true)
+                .engine(engine)
+                .build()?;
+            let heads = regex
+                .find_iter(text)
+                .map(|m| m.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(heads, vec!["f", "b", "b"]);
+            assert!(regex.match_at(text, 4));
+            assert!(!regex.match_at(text, 9));
+
+            // 全体へのマッチでは、`$`は入力の末尾でのみ成り立つ
+            let regex = RegexBuilder::new("a$")
+                .multi_line(true)
+                .engine(engine)
+                .build()?;
+            assert!(!regex.match_full("a\nb"));
+            assert!(regex.match_full("a"));
+            let regex = RegexBuilder::new("a$|a\nb")
+                .multi_line(true)
+                .engine(engine)
+                .build()?;
+            assert!(regex.match_full("a\nb"));
+            let regex = Regex::new("(?m)a\n^b$")?;
+            assert!(regex.match_full("a\nb"));
+
+            // 位置を指定すれば、その位置から行末までのマッチを探す
+            let regex = RegexBuilder::new("bar|b")
+                .multi_line(true)
+                .engine(engine)
+                .build()?;
+            let m = regex.try_match_full_at(text, 4)?.unwrap();
+            assert_eq!(m.as_str(), "bar");
+            assert!(regex.try_match_full_at(text, 8)?.is_none());
+        }
+
+        // `^`で始まっても入力の先頭に限らない
+        assert!(Regex::new("^a")?.is_start_anchored());
+        assert!(!Regex::new("(?m)^a")?.is_start_anchored());
+        assert!(!Regex::new("(?m)a$")?.is_end_anchored());
+
+        // 書記素クラスタごとに区切る場合は`"\r\n"`も改行とする
+        let regex = RegexBuilder::new("^b$")
+            .multi_line(true)
+            .graphemes(true)
+            .build()?;
+        assert!(regex.is_match("a\r\nb\r\nc"));
+
+        let set = RegexSet::new(&["(?m)^bar$", "^bar$"])?;
+        assert_eq!(set.matches(text).iter().collect::<Vec<_>>(), vec![0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex() -> Result<(), DynError> {
+        let regex = Regex::new("(a|^b)c+")?;
+        assert_eq!(regex.as_str(), "(a|^b)c+");
+
+        let lines = (0..10_000)
+            .map(|i| match i % 4 {
+                0 => format!("{i}acc"),
+                1 => format!("bc{i}"),
+                2 => format!("{i}bc"),
+                _ => format!("{i}"),
+            })
+            .collect::<Vec<_>>();
+        for line in &lines {
+            assert_eq!(regex.is_match(line), match_line(regex.as_str(), line)?);
+        }
+        let count = lines.iter().filter(|line| regex.is_match(line)).count();
+        assert_eq!(count, 5_000);
+
+        assert!(Regex::new("+a").is_err());
+
+        // 評価中のエラーはマッチしなかったものとして扱う
+        let regex = Regex {
+            options: Options {
+                backtrack_limit: 0,
+                ..Default::default()
+            },
+            ..Regex::new("a?b")?
+        };
+        assert!(!regex.is_match("b"));
+        assert!(regex.try_is_match("b").is_err());
+        assert!(regex.find("b").is_none());
+        assert!(regex.try_find("b").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_eq_hash() -> Result<(), DynError> {
+        fn hash_of(value: &impl Hash) -> u64 {
+            let mut hasher = std::hash::DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        // 別々にコンパイルした同じパターンは等しく、ハッシュ値も等しい
+        let (a, b) = (Regex::new("(ab|c)*d")?, Regex::new("(ab|c)*d")?);
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+        assert_eq!(a.clone(), a);
+        assert_eq!(a.code, b.code);
+        assert_eq!(hash_of(&a.code), hash_of(&b.code));
+
+        // パターンか設定が異なれば等しくない
+        assert_ne!(a, Regex::new("(ab|c)*e")?);
+        let insensitive = RegexBuilder::new("(ab|c)*d")
+            .case_insensitive(true)
+            .build()?;
+        assert_ne!(a, insensitive);
+        let width = RegexBuilder::new("(ab|c)*d")
+            .engine(Engine::Width)
+            .build()?;
+        assert_ne!(a, width);
+
+        // マップのキーとして使える
+        let mut counts = std::collections::HashMap::new();
+        for regex in [a, b, insensitive, width] {
+            *counts.entry(regex).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[&Regex::new("(ab|c)*d")?], 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_compiled() -> Result<(), DynError> {
+        for (expr, line) in [
+            ("abc", "abcd"),
+            ("abc", "xabc"),
+            ("a|b+", "bb"),
+            ("^a", "ba"),
+            ("(ab)*c$", "xababc"),
+            ("a.c", "a"),
+        ] {
+            let code = compile(expr)?;
+            for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+                assert_eq!(
+                    match_compiled(&code, line, engine)?,
+                    do_matching_with(expr, line, engine)?
+                );
+                assert_eq!(
+                    match_line_compiled(&code, line, engine)?,
+                    match_line(expr, line)?
+                );
+            }
+        }
+
+        // フラグはコンパイル済みのプログラムでは扱わない
+        assert!(compile("(?i)a").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_introspection() -> Result<(), DynError> {
+        // (パターン, 先頭に固定, 末尾に固定, 先頭の文字列, 最小の文字数, グループの数)
+        for (expr, start, end, prefix, min_len, captures) in [
+            ("^abc$", true, true, Some("abc"), 3, 1),
+            ("a|ab", false, false, None, 1, 1),
+            ("(xy)+z", false, false, Some("xy"), 3, 2),
+            (".foo", false, false, None, 4, 1),
+            ("(?i)abc", false, false, None, 3, 1),
+            ("^(a|b)*c$|^d$", true, true, None, 1, 2),
+            ("(?<key>a+)=(b*)", false, false, Some("a"), 2, 3),
+        ] {
+            let re = Regex::new(expr)?;
+            assert_eq!(re.is_start_anchored(), start, "{expr}");
+            assert_eq!(re.is_end_anchored(), end, "{expr}");
+            assert_eq!(re.literal_prefix(), prefix, "{expr}");
+            assert_eq!(re.min_match_len(), min_len, "{expr}");
+            assert_eq!(re.capture_count(), captures, "{expr}");
+        }
+
+        let re = Regex::new("(?<key>a+)=(b*)")?;
+        assert_eq!(
+            re.capture_names().collect::<Vec<_>>(),
+            vec![None, Some("key"), None]
+        );
+        assert!(RegexBuilder::new("ab")
+            .anchored(true)
+            .build()?
+            .is_start_anchored());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_matches() -> Result<(), DynError> {
+        let exprs = [
+            "a", "a*", "a+", "b?", "(a|b)*", "ab|a", "^a*", "a*$", "x*|a", "(a*)*", "(ab)+c", ".",
+            "あ*",
+        ];
+        let haystacks = [
+            "",
+            "a",
+            "aaa",
+            "abab",
+            "baaab",
+            "xaxa",
+            "abcabc",
+            "あいあ",
+            "a\na",
+        ];
+        for expr in exprs {
+            let re = Regex::new(expr)?;
+            for haystack in haystacks {
+                assert_eq!(
+                    re.count_matches(haystack),
+                    re.find_iter(haystack).count(),
+                    "{expr}: {haystack}"
+                );
+            }
+        }
+
+        assert_eq!(Regex::new("a*")?.count_matches("baaab"), 3);
+        assert_eq!(Regex::new("b")?.count_matches("aaa"), 0);
+        assert_eq!(Regex::new("a*")?.try_count_matches("baaab")?, 3);
+
+        // 評価中のエラーでは、`count_matches`はそれまでの数を、`try_count_matches`はエラーを返す
+        let regex = RegexBuilder::new("(a|aa)*b")
+            .step_limit(Some(100))
+            .build()?;
+        let haystack = "b ab aaaaaaaaaaaaaaaaaaaac b";
+        assert_eq!(regex.count_matches(haystack), 2);
+        assert!(matches!(
+            regex.try_count_matches(haystack),
+            Err(EngineError::Eval(EvalError::StepLimitExceeded { .. }))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shortest_match() -> Result<(), DynError> {
+        let shortest = |expr, haystack| -> Result<Option<usize>, DynError> {
+            Ok(Regex::new(expr)?.shortest_match(haystack))
+        };
+
+        assert_eq!(shortest("a+", "aaa")?, Some(1));
+        assert_eq!(shortest("^ab.*", "abxyz")?, Some(2));
+        assert_eq!(shortest("abc", "xyz")?, None);
+        assert_eq!(shortest("^b", "ab")?, None);
+        // 最も左の位置から始まるマッチの中で最短のもの
+        assert_eq!(shortest("abcd|c", "abcd")?, Some(4));
+        assert_eq!(shortest("xa*|ab", "xaaab")?, Some(1));
+        // 分岐の優先度によらない
+        assert_eq!(shortest("ab|a", "ab")?, Some(1));
+        assert_eq!(shortest("b.*$", "aあbいう")?, Some(11));
+        assert_eq!(shortest("い+", "あいい")?, Some(6));
+
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            let re = RegexBuilder::new("(a|b)*c").engine(engine).build()?;
+            assert_eq!(re.shortest_match("xabcbc"), Some(4));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_anchoring() -> Result<(), DynError> {
+        // 行全体
+        assert!(match_full("a*", "")?);
+        assert!(match_full("a*", "aaa")?);
+        assert!(!match_full("a*", "aab")?);
+        assert!(!match_full("a*", "baa")?);
+        // 先に試す分岐が行の途中で終わっても、行全体にマッチする分岐を探す
+        assert!(match_full("a|ab", "ab")?);
+        assert!(match_full("^ab$", "ab")?);
+        assert!(match_full("a(b$|c)", "ab")?);
+
+        // 先頭から
+        assert_eq!(match_prefix("ab", "abc")?, Some(2));
+        assert_eq!(match_prefix("ab", "xabc")?, None);
+        assert_eq!(match_prefix("a*", "bbb")?, Some(0));
+        assert_eq!(match_prefix("a|ab", "abc")?, Some(1));
+        assert_eq!(match_prefix("^a+", "aab")?, Some(2));
+        assert_eq!(match_prefix("a$", "ab")?, None);
+        assert_eq!(match_prefix("あい", "あいう")?, Some(6));
+
+        // 指定した位置から
+        assert!(match_at("b+", "aabba", 3)?);
+        assert!(match_at("b+", "aabba", 0)?);
+        assert!(!match_at("b+", "aabba", 4)?);
+        assert!(match_at("a$", "aabba", 1)?);
+        assert!(!match_at("^a", "aabba", 1)?);
+        assert!(match_at("^a", "aabba", 0)?);
+        assert!(match_at("a*$", "aabba", 5)?);
+        // 文字の境界でない位置
+        assert!(!match_at("い", "あい", 1)?);
+        assert!(match_at("い", "あい", 3)?);
+        assert!(!match_at("a", "a", 2)?);
+
+        let re = Regex::new("b+")?;
+        assert!(re.match_at("aabba", 3));
+        assert!(!re.match_full("aabba"));
+        assert_eq!(re.match_prefix("bba"), Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_builder() -> Result<(), DynError> {
+        assert!(!Regex::new("abc")?.is_match("xABC"));
+        let re = RegexBuilder::new("abc").case_insensitive(true).build()?;
+        assert!(re.is_match("xABC"));
+        assert_eq!(re.find("xABC").map(|m| m.as_str()), Some("ABC"));
+
+        assert!(Regex::new("a.b")?.is_match("a\nb"));
+        let re = RegexBuilder::new("a.b")
+            .dot_matches_newline(false)
+            .build()?;
+        assert!(!re.is_match("a\nb"));
+        assert!(re.is_match("a\nazb"));
+
+        // 先頭以外の位置ではマッチを試みない
+        let re = RegexBuilder::new("bc").anchored(true).build()?;
+        assert!(!re.is_match("abc"));
+        assert!(re.is_match("bcd"));
+        assert!(re.find("abc").is_none());
+
+        // エンジンを変えても結果は同じ
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            let re = RegexBuilder::new("(a|ab)(c|bcd)").engine(engine).build()?;
+            assert_eq!(re.find("xabcd").map(|m| m.as_str()), Some("abcd"));
+        }
+
+        // 命令数の上限
+        let mut builder = RegexBuilder::new("abc");
+        assert!(builder.size_limit(4).build().is_ok());
+        assert!(matches!(
+            builder.size_limit(3).build(),
+            Err(EngineError::CodeGen(CodeGenError::SizeLimitExceeded {
+                limit: 3
+            }))
+        ));
+
+        // 実行する命令数の上限
+        let mut builder = RegexBuilder::new("(a|b)*c");
+        builder.step_limit(Some(50));
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            let re = builder.engine(engine).build()?;
+            assert!(re.try_is_match("ac")?, "{engine:?}");
+            assert!(
+                matches!(
+                    re.try_is_match(&"ab".repeat(100)),
+                    Err(EngineError::Eval(EvalError::StepLimitExceeded {
+                        limit: 50
+                    }))
+                ),
+                "{engine:?}"
+            );
+            assert!(!re.is_match(&"ab".repeat(100)), "{engine:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_error() {
+        assert!(matches!(
+            Regex::new("*a"),
+            Err(EngineError::Parse(ParseError::NoPrev(0)))
+        ));
+        assert!(matches!(
+            do_matching("a(b", "ab", true),
+            Err(EngineError::Parse(ParseError::NoRightParen))
+        ));
+
+        // メッセージは元のエラーと同じ
+        let err = match_line("a)", "a").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ParseError: invalid right parenthesis: pos = 1"
+        );
+        assert!(err.source().is_some());
+
+        let err = EngineError::from(EvalError::BacktrackLimitExceeded { limit: 2 });
+        assert_eq!(
+            err.to_string(),
+            "EvalError: backtrack limit exceeded: limit = 2"
+        );
+    }
+
+    #[test]
+    fn test_regex_captures() -> Result<(), DynError> {
+        let re = Regex::new("(a+)(b*)")?;
+        let caps = re.captures("xaabc").unwrap();
+        assert_eq!(caps.len(), 3);
+        assert_eq!(&caps[0], "aab");
+        assert_eq!(&caps[1], "aa");
+        assert_eq!(&caps[2], "b");
+        let m = caps.get(1).unwrap();
+        assert_eq!((m.start(), m.end()), (1, 3));
+        assert!(caps.get(3).is_none());
+        assert!(re.captures("bbb").is_none());
+
+        // 名前付きグループ
+        let re = Regex::new("(?<year>(0|1|2)+)-(?P<month>(0|1)+)")?;
+        let caps = re.captures("date: 2021-10").unwrap();
+        assert_eq!(caps.len(), 5);
+        assert_eq!(caps.name("year").unwrap().as_str(), "2021");
+        assert_eq!(caps.name("month").unwrap().as_str(), "10");
+        assert_eq!(&caps[3], "10");
+        assert!(caps.name("day").is_none());
+
+        // `?`の中のグループがマッチに参加しない
+        let caps = Regex::new("(a)?b")?.captures("b").unwrap();
+        assert_eq!(&caps[0], "b");
+        assert!(caps.get(1).is_none());
+
+        // 空のグループは空文字列を捕える
+        let caps = Regex::new("a()(|b)c")?.captures("xac").unwrap();
+        assert_eq!(caps.len(), 3);
+        assert_eq!(&caps[0], "ac");
+        assert_eq!(caps.get(1).map(|m| (m.start(), m.end())), Some((2, 2)));
+        assert_eq!(&caps[2], "");
+
+        // 選ばれなかった分岐の中のグループ
+        let caps = Regex::new("(a)|(b)")?.captures("b").unwrap();
+        assert!(caps.get(1).is_none());
+        assert_eq!(&caps[2], "b");
+
+        // 繰り返しの中のグループは最後の繰り返しの位置となる
+        let caps = Regex::new("((a|b))+c")?.captures("abc").unwrap();
+        assert_eq!(&caps[1], "b");
+
+        // バックトラックで取り消された位置は残らない
+        let caps = Regex::new("(a)x|ab")?.captures("ab").unwrap();
+        assert_eq!(&caps[0], "ab");
+        assert!(caps.get(1).is_none());
+
+        // マルチバイト文字を含む場合もバイト単位の位置
+        let caps = Regex::new("い(う)")?.captures("あいう").unwrap();
+        let m = caps.get(1).unwrap();
+        assert_eq!((m.start(), m.end(), m.as_str()), (6, 9, "う"));
+
+        // 名前の誤り
+        assert!(Regex::new("(?<>a)").is_err());
+        assert!(Regex::new("(?<a-b>a)").is_err());
+        assert!(Regex::new("(?x)").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "no group at index '1'")]
+    fn test_captures_index_absent() {
+        let caps = Regex::new("(a)?b").unwrap().captures("b").unwrap();
+        let _ = &caps[1];
+    }
+
+    #[test]
+    fn test_regex_find() -> Result<(), DynError> {
+        let find = |expr, haystack| -> Result<Option<(usize, usize, &str)>, DynError> {
+            let m = Regex::new(expr)?.find(haystack);
+            Ok(m.map(|m| (m.start(), m.end(), m.as_str())))
+        };
+
+        assert_eq!(find("b+", "abbc")?, Some((1, 3, "bb")));
+        assert_eq!(find("a|ab", "xab")?, Some((1, 2, "a")));
+        assert_eq!(find("x", "abc")?, None);
+
+        // 空文字列へのマッチ
+        assert_eq!(find("a*", "bbb")?, Some((0, 0, "")));
+        assert_eq!(find("a*", "")?, Some((0, 0, "")));
+        assert_eq!(find("", "abc")?, Some((0, 0, "")));
+        assert_eq!(find("", "")?, Some((0, 0, "")));
+        assert_eq!(find("()", "abc")?, Some((0, 0, "")));
+        assert_eq!(find("b*$", "aa")?, Some((2, 2, "")));
+
+        // `^`は先頭でのみ、`$`は末尾でのみ成り立つ
+        assert_eq!(find("^a", "aa")?, Some((0, 1, "a")));
+        assert_eq!(find("^b", "ab")?, None);
+        assert_eq!(find("a$", "aba")?, Some((2, 3, "a")));
+        assert_eq!(find("a$", "ab")?, None);
+
+        // マルチバイト文字を含む場合もバイト単位の位置で切り出せる
+        let haystack = "あいうabc";
+        let m = Regex::new("うa")?.find(haystack).unwrap();
+        assert_eq!((m.start(), m.end()), (6, 10));
+        assert_eq!(&haystack[m.start()..m.end()], "うa");
+        assert_eq!(m.as_str(), "うa");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_find_iter() -> Result<(), DynError> {
+        let regex = Regex::new("(0|1|2|3|4|5|6|7|8|9)+")?;
+        let numbers = regex
+            .find_iter("a12 b345 c6 d")
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec!["12", "345", "6"]);
+
+        let regex = Regex::new("a*")?;
+        let spans = regex
+            .find_iter("bab")
+            .map(|m| (m.start(), m.end()))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![(0, 0), (1, 2), (3, 3)]);
+
+        // 先頭からしか始まらないマッチは高々1つ
+        let regex = Regex::new("^a")?;
+        assert_eq!(regex.find_iter("aaa").count(), 1);
+        assert_eq!(regex.find_iter("baa").count(), 0);
+
+        // 遅延して評価するので、途中で止めてもよい
+        let regex = Regex::new("x")?;
+        let mut matches = regex.find_iter("axbxcx");
+        assert_eq!(matches.next().map(|m| m.start()), Some(1));
+        assert_eq!(matches.next().map(|m| m.start()), Some(3));
+
+        let regex = Regex::new("い+")?;
+        let found = regex.find_iter("いあいい").map(|m| m.as_str());
+        assert_eq!(found.collect::<Vec<_>>(), vec!["い", "いい"]);
+
+        // 優先されるマッチを受け付けなければ、同じ位置から始まる長いマッチから順に試す
+        for engine in Engine::ALL {
+            let regex = RegexBuilder::new("a|ab|abc").engine(engine).build()?;
+            let mut matches = regex.find_iter("abc abd");
+            let m = matches.try_next_by(|_, end| end != 1)?.unwrap();
+            assert_eq!(m.as_str(), "abc");
+            let m = matches.try_next_by(|_, end| end != 5)?.unwrap();
+            assert_eq!((m.start(), m.end()), (4, 6));
+            assert!(matches.try_next_by(|_, _| false)?.is_none());
+        }
+
+        // `try_next`は評価中のエラーを返し、その後は終了する
+        let regex = RegexBuilder::new("(a|aa)*b")
+            .step_limit(Some(100))
+            .build()?;
+        let mut matches = regex.find_iter("b aaaaaaaaaaaaaaaaaaaac");
+        assert_eq!(matches.try_next()?.map(|m| m.start()), Some(0));
+        assert!(matches.try_next().is_err());
+        assert!(matches.try_next()?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_graphemes() -> Result<(), DynError> {
+        let build = |expr: &str, graphemes: bool, engine: Engine| {
+            RegexBuilder::new(expr)
+                .graphemes(graphemes)
+                .engine(engine)
+                .build()
+        };
+        let family = "👨‍👩‍👧";
+        let acute = "e\u{301}";
+
+        for engine in Engine::ALL {
+            // ZWJで繋いだ絵文字は、文字ごとなら5つ、書記素クラスタごとなら1つ
+            let chars = build(".", false, engine)?;
+            let clusters = build(".", true, engine)?;
+            assert_eq!(chars.find_iter(family).count(), 5);
+            let spans = clusters
+                .find_iter(family)
+                .map(|m| m.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(spans, vec![family]);
+            assert_eq!(clusters.count_matches(family), 1);
+
+            // 結合文字を含むクラスタは1つの`.`にマッチする
+            assert!(!build("^.$", false, engine)?.is_match(acute));
+            assert!(build("^..$", false, engine)?.is_match(acute));
+            assert!(build("^.$", true, engine)?.is_match(acute));
+            assert!(!build("^..$", true, engine)?.is_match(acute));
+            assert!(build(".", true, engine)?.match_full(family));
+            assert_eq!(build(".", true, engine)?.match_prefix(acute), Some(3));
+
+            // パターンの文字は1文字からなるクラスタにのみマッチする
+            assert!(build("e", false, engine)?.is_match(acute));
+            assert!(!build("e", true, engine)?.is_match(acute));
+            assert!(build("e", true, engine)?.is_match("e"));
+
+            // 位置はバイト単位で返す
+            let haystack = format!("caf{acute}!");
+            let m = build("f.!", true, engine)?.find(&haystack).unwrap();
+            assert_eq!((m.start(), m.end()), (2, 7));
+            assert!(build("f.!", false, engine)?.find(&haystack).is_none());
+
+            let caps = build("(.)(.)", true, engine)?.captures("x👨‍👩‍👧y").unwrap();
+            assert_eq!(&caps[1], "x");
+            assert_eq!(&caps[2], family);
+            assert_eq!(
+                build(".+", true, engine)?.shortest_match(family),
+                Some(family.len())
+            );
+
+            // クラスタの途中からは探索しない
+            let line = format!("{acute}x");
+            assert!(build("x", false, engine)?.match_at(&line, 1));
+            assert!(!build("x", true, engine)?.match_at(&line, 1));
+            assert!(build("x", true, engine)?.match_at(&line, 3));
+        }
+
+        // `"\r\n"`は1つの改行として扱う
+        let regex = RegexBuilder::new("a.b")
+            .dot_matches_newline(false)
+            .graphemes(true)
+            .build()?;
+        assert!(!regex.is_match("a\r\nb"));
+        assert!(regex.is_match("a🇯🇵b"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() -> Result<(), DynError> {
+        let build = |expr: &str, normalization: Normalization, engine: Engine| {
+            RegexBuilder::new(expr)
+                .normalize(normalization)
+                .engine(engine)
+                .build()
+        };
+        // NFDの入力
+        let haystack = "cafe\u{301}!";
+
+        for engine in Engine::ALL {
+            // 正規化しなければ合成済みの文字のパターンにはマッチしない
+            assert!(!build("é", Normalization::None, engine)?.is_match(haystack));
+            assert!(build("e", Normalization::None, engine)?.is_match(haystack));
+
+            // 正規化すれば`'é'`の1文字にマッチし、`'e'`だけにはマッチしない
+            let regex = build("fé!", Normalization::Nfc, engine)?;
+            assert!(regex.is_match(haystack));
+            assert!(!build("e", Normalization::Nfc, engine)?.is_match(haystack));
+            assert!(build("^....!$", Normalization::Nfc, engine)?.is_match(haystack));
+
+            // 位置は元の入力のバイト位置に戻すので、マッチは基底の文字と結合文字を含む
+            let m = regex.find(haystack).unwrap();
+            assert_eq!((m.start(), m.end()), (2, 7));
+            assert_eq!(m.as_str(), "fe\u{301}!");
+            let caps = build("(.)(!)", Normalization::Nfc, engine)?
+                .captures(haystack)
+                .unwrap();
+            assert_eq!(&caps[1], "e\u{301}");
+            assert_eq!(caps.get(2).map(|m| m.start()), Some(6));
+
+            // 合成済みの入力はそのまま
+            assert!(build("fé!", Normalization::Nfc, engine)?.is_match("café!"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_find_at() -> Result<(), DynError> {
+        let span = |m: Option<Match>| m.map(|m| (m.start(), m.end()));
+
+        // 位置は`haystack`の先頭から数える
+        let regex = Regex::new("ab")?;
+        assert_eq!(span(regex.find_at("abxab", 0)), Some((0, 2)));
+        assert_eq!(span(regex.find_at("abxab", 1)), Some((3, 5)));
+        assert_eq!(span(regex.find_at("abxab", 4)), None);
+
+        // `^`は`start`ではなく先頭でのみ成り立つ
+        let regex = Regex::new("^ab")?;
+        assert_eq!(span(regex.find_at("abab", 0)), Some((0, 2)));
+        assert_eq!(span(regex.find_at("abab", 2)), None);
+        let regex = Regex::new("x|^ab")?;
+        assert_eq!(span(regex.find_at("abab x", 2)), Some((5, 6)));
+        let regex = RegexBuilder::new("^b").multi_line(true).build()?;
+        assert_eq!(span(regex.find_at("a\nb", 1)), Some((2, 3)));
+        assert_eq!(span(Regex::new("^b")?.find_at("a\nb", 1)), None);
+
+        // マルチバイト文字の境界
+        let regex = Regex::new("い|う")?;
+        assert_eq!(span(regex.find_at("あいう", 3)), Some((3, 6)));
+        assert_eq!(span(regex.find_at("あいう", 6)), Some((6, 9)));
+
+        // 末尾では空文字列へのマッチのみ
+        assert_eq!(span(Regex::new("a*")?.find_at("aa", 2)), Some((2, 2)));
+        assert_eq!(span(Regex::new("a")?.find_at("aa", 2)), None);
+        assert_eq!(span(Regex::new("a$")?.find_at("aa", 2)), None);
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "start 1 is not a boundary")]
+    fn test_regex_find_at_not_boundary() {
+        Regex::new("a").unwrap().find_at("あa", 1);
+    }
+
+    #[test]
+    fn test_regex_replace() -> Result<(), DynError> {
+        let regex = Regex::new("ab+")?;
+
+        // マッチしなければ新たに文字列を確保しない
+        let replaced = regex.replace_all("xyz", "-")?;
+        assert!(matches!(replaced, Cow::Borrowed("xyz")));
+        assert!(matches!(regex.replace("xyz", "-")?, Cow::Borrowed("xyz")));
+
+        assert_eq!(regex.replace("abxabbx", "-")?, "-xabbx");
+        assert_eq!(regex.replace_all("abxabbx", "-")?, "-x-x");
+        assert_eq!(regex.replace_all("abab", "<>")?, "<><>");
+
+        // マルチバイト文字の前後
+        assert_eq!(regex.replace_all("あabいabbう", "_")?, "あ_い_う");
+        let regex = Regex::new("い+")?;
+        assert_eq!(regex.replace_all("あいいう", "i")?, "あiう");
+
+        // 空文字列へのマッチも`find_iter`と同じ規則で置き換える
+        let regex = Regex::new("a*")?;
+        assert_eq!(regex.replace_all("bab", "-")?, "-b-b-");
+        assert_eq!(regex.replace_all("", "-")?, "-");
+
+        let regex = Regex::new("(a|d)(b|e)+")?;
+        let replaced = regex.replace_all_with("abc deef", |m| m.as_str().to_uppercase());
+        assert_eq!(replaced, "ABc DEEf");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_replace_template() -> Result<(), DynError> {
+        let regex = Regex::new("(a+)-(b+)")?;
+        assert_eq!(regex.replace_all("a-b aa-bb", "$2-$1")?, "b-a bb-aa");
+        assert_eq!(regex.replace("a-b aa-bb", "[$0]")?, "[a-b] aa-bb");
+        assert_eq!(regex.replace_all("a-b", "$$1")?, "$1");
+        // `${1}0`はグループ1の後に`0`、`$10`はグループ10
+        assert_eq!(regex.replace_all("a-b", "${1}0")?, "a0");
+        assert!(matches!(
+            regex.replace_all("a-b", "$10"),
+            Err(TemplateError::NoSuchGroup(_))
+        ));
+        // マッチしなくてもテンプレートは検証する
+        assert!(regex.replace_all("xyz", "${name}").is_err());
+
+        let regex =
+            Regex::new("(?<year>(0|1|2|3|4|5|6|7|8|9)+)年(?<month>(1|2|3|4|5|6|7|8|9)+)月")?;
+        assert_eq!(
+            regex.replace_all("2024年3月と2025年12月", "${year}/${month}")?,
+            "2024/3と2025/12"
+        );
+
+        // マルチバイト文字のグループと、マッチに参加しなかったグループ
+        let regex = Regex::new("(あ+)|(い+)")?;
+        let template = regex.template("<$1|$2>")?;
+        assert_eq!(
+            regex.replace_all_template("xあああyいz", &template),
+            "x<あああ|>y<|い>z"
+        );
+
+        // グラフェムクラスタ単位でも同じバイト位置で置き換える
+        let regex = RegexBuilder::new("(.)x").graphemes(true).build()?;
+        assert_eq!(regex.replace_all("🇯🇵xa", "[$1]")?, "[🇯🇵]a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_split() -> Result<(), DynError> {
+        let split = |expr, haystack| -> Result<Vec<String>, DynError> {
+            let regex = Regex::new(expr)?;
+            Ok(regex.split(haystack).map(|s| s.to_string()).collect())
+        };
+
+        assert_eq!(split(",", "a,b,c")?, vec!["a", "b", "c"]);
+        assert_eq!(split(", *", "a, b,c")?, vec!["a", "b", "c"]);
+        // 先頭と末尾のマッチ
+        assert_eq!(split(",", ",a,")?, vec!["", "a", ""]);
+        // 隣り合うマッチ
+        assert_eq!(split(",", "a,,b")?, vec!["a", "", "b"]);
+        // 空文字列にマッチするパターンは各文字の間で区切る
+        assert_eq!(split("x*", "abc")?, vec!["", "a", "b", "c", ""]);
+        assert_eq!(split("x*", "axxb")?, vec!["", "a", "b", ""]);
+        // マッチしなければ全体を1度だけ返す
+        assert_eq!(split("x", "abc")?, vec!["abc"]);
+        assert_eq!(split("x", "")?, vec![""]);
+        assert_eq!(split("、", "あ、い")?, vec!["あ", "い"]);
+
+        let regex = Regex::new(",")?;
+        let splitn = |n| regex.splitn("a,b,c,d", n).collect::<Vec<_>>();
+        assert_eq!(splitn(0), Vec::<&str>::new());
+        assert_eq!(splitn(1), vec!["a,b,c,d"]);
+        assert_eq!(splitn(2), vec!["a", "b,c,d"]);
+        assert_eq!(splitn(4), vec!["a", "b", "c", "d"]);
+        assert_eq!(splitn(10), vec!["a", "b", "c", "d"]);
+
+        Ok(())
+    }
+
+    /// `search`と同様に評価し、実行した命令数をトレースから数える
+    fn count_steps(code: &[Instruction], line: &str, start: usize) -> Result<usize, DynError> {
+        let chars = line.chars().collect::<Vec<_>>();
+        let mut out = Vec::new();
+        evaluator::eval_with(
+            code,
+            &chars,
+            start,
+            &Options::default(),
+            &mut Tracer::new(&mut out),
+        )?;
+        let trace = String::from_utf8(out)?;
+        Ok(trace.lines().filter(|l| l.contains("| sp ")).count())
+    }
+
+    #[test]
+    fn test_search_single_pass() -> Result<(), DynError> {
+        let code = search_code(&compile("abc")?, false)?;
+        let options = Options::default();
+
+        // マッチしない長い行でも評価器は1度だけ実行し、実行する命令数は行の長さに比例する
+        let line = "ab".repeat(500);
+        let mut counts = SearchCounts::default();
+        assert!(!search(&code, None, &line, false, &options, &mut counts)?);
+        assert_eq!(counts.runs, 1);
+        let steps = count_steps(&code, &line, 0)?;
+        assert!(steps <= 10 * line.len(), "steps = {steps}");
+        // 数えた命令数はトレースに書き出した命令数と一致する
+        assert_eq!(counts.steps, steps);
+        assert_eq!(
+            Regex::new("abc")?.try_is_match_with_steps(&line)?,
+            (false, steps)
+        );
+
+        // `^`で始まるパターンには前置部を付けない
+        let anchored = compile("^ab")?;
+        assert!(analysis::is_anchored_start(&anchored));
+        assert_eq!(search_code(&anchored, true)?, anchored);
+        assert!(!search(
+            &anchored,
+            None,
+            "xab",
+            true,
+            &options,
+            &mut SearchCounts::default()
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_metrics() -> Result<(), DynError> {
+        let metrics = |re: &Regex, line| {
+            let mut metrics = MatchMetrics::default();
+            let matched = re.is_match_with_metrics(line, &mut metrics);
+            (matched, metrics)
+        };
+
+        // 入れ子の繰り返しは、マッチしない行で分岐を指数的に試す
+        let line = "a".repeat(16);
+        let (matched, trivial) = metrics(&Regex::new("ab")?, &line);
+        assert!(!matched);
+        let (matched, pathological) = metrics(&Regex::new("(a|a)*b")?, &line);
+        assert!(!matched);
+        assert!(pathological.instructions > 100 * trivial.instructions);
+        assert!(pathological.backtracks > 100 * trivial.backtracks.max(1));
+        assert!(pathological.peak_pending > trivial.peak_pending);
+        assert!(!pathological.memoized);
+
+        // ビットマップに記録すれば同じ状態を2度評価しない
+        let bitstate = RegexBuilder::new("(a|a)*b")
+            .engine(Engine::Bitstate)
+            .build()?;
+        let (_, memoized) = metrics(&bitstate, &line);
+        assert!(memoized.memoized);
+        assert!(memoized.instructions < pathological.instructions);
+
+        // 1文字目になりうる文字で絞り込む
+        let re = Regex::new("xy")?;
+        assert!(metrics(&re, "aaxy").1.prefiltered);
+        assert!(metrics(&re, "aaa").1.prefiltered);
+        assert!(!metrics(&re, "xy").1.prefiltered);
+
+        // 指標は評価ごとに書き込み直し、命令数は`try_is_match_with_steps`と一致する
+        let mut metrics = MatchMetrics {
+            backtracks: 100,
+            ..Default::default()
+        };
+        let re = Regex::new("a|b")?;
+        assert!(re.try_is_match_with_metrics("b", &mut metrics)?);
+        assert_eq!(metrics.instructions, re.try_is_match_with_steps("b")?.1);
+        assert!(metrics.backtracks < 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_prefilter() -> Result<(), DynError> {
+        let code = compile("xy+z")?;
+        let first_chars = analysis::first_chars(&code);
+        let code = search_code(&code, false)?;
+        let line = format!("{}xyyz{}", "a".repeat(500), "b".repeat(500));
+
+        // 1文字目になりうる文字が最初に現れる位置から評価を始める
+        let mut counts = SearchCounts::default();
+        assert!(search(
+            &code,
+            first_chars.as_deref(),
+            &line,
+            false,
+            &Options::default(),
+            &mut counts
+        )?);
+        assert_eq!(counts.runs, 1);
+        assert!(count_steps(&code, &line, 500)? < count_steps(&code, &line, 0)?);
+
+        let mut counts = SearchCounts::default();
+        assert!(!search(
+            &code,
+            first_chars.as_deref(),
+            &"a".repeat(1000),
+            false,
+            &Options::default(),
+            &mut counts
+        )?);
+        assert_eq!(counts.runs, 0);
 
         Ok(())
     }
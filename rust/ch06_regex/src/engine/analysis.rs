@@ -0,0 +1,393 @@
+use std::collections::VecDeque;
+
+use super::{Instruction, Pc};
+
+/// マッチの1文字目になりうる文字の集合を求める。
+/// `pc`が0から入力を消費せずに到達できる`Char`の文字を集め、
+/// 任意の文字がありうる場合(`AnyChar`や、空文字列へのマッチに到達できる場合)は`None`を返す。
+pub(super) fn first_chars(code: &[Instruction]) -> Option<Vec<char>> {
+    first_chars_from(code, Pc(0))
+}
+
+/// `first_chars`と同様だが、`pc`が`entry`から始める
+pub(super) fn first_chars_from(code: &[Instruction], entry: Pc) -> Option<Vec<char>> {
+    let mut chars = Vec::new();
+    let mut visited = vec![false; code.len()];
+    let mut stack = vec![entry.0];
+
+    while let Some(pc) = stack.pop() {
+        if *visited.get(pc)? {
+            continue;
+        }
+        visited[pc] = true;
+
+        match &code[pc] {
+            Instruction::Char(c) => {
+                if !chars.contains(c) {
+                    chars.push(*c);
+                }
+            }
+            Instruction::AnyChar
+            | Instruction::AnyCharExceptNewline
+            | Instruction::Match
+            | Instruction::MatchId(_)
+            | Instruction::MatchEnd => return None,
+            Instruction::Head | Instruction::Mark(_) | Instruction::Save(_) => {
+                stack.push(pc.checked_add(1)?)
+            }
+            Instruction::Jump(Pc(addr)) => stack.push(*addr),
+            Instruction::Split(Pc(addr1), Pc(addr2)) => {
+                stack.push(*addr1);
+                stack.push(*addr2);
+            }
+        }
+    }
+
+    Some(chars)
+}
+
+/// `pc`が0から文字を消費するまでのすべての経路が`Head`を通る、
+/// すなわちマッチが入力の先頭からしか始まらないかを返す。
+pub(super) fn is_anchored_start(code: &[Instruction]) -> bool {
+    is_anchored_start_from(code, Pc(0))
+}
+
+/// `is_anchored_start`と同様だが、`pc`が`entry`から始める
+pub(super) fn is_anchored_start_from(code: &[Instruction], entry: Pc) -> bool {
+    let mut visited = vec![false; code.len()];
+    let mut stack = vec![entry.0];
+
+    while let Some(pc) = stack.pop() {
+        match visited.get(pc) {
+            Some(true) => continue,
+            Some(false) => visited[pc] = true,
+            None => return false,
+        }
+
+        match &code[pc] {
+            Instruction::Head => {}
+            Instruction::Char(_)
+            | Instruction::AnyChar
+            | Instruction::AnyCharExceptNewline
+            | Instruction::Match
+            | Instruction::MatchId(_)
+            | Instruction::MatchEnd => return false,
+            Instruction::Mark(_) | Instruction::Save(_) => stack.push(pc + 1),
+            Instruction::Jump(Pc(addr)) => stack.push(*addr),
+            Instruction::Split(Pc(addr1), Pc(addr2)) => {
+                stack.push(*addr1);
+                stack.push(*addr2);
+            }
+        }
+    }
+
+    true
+}
+
+/// マッチが入力の末尾でしか終わらない、すなわち`pc`が0から到達できる`Match`がなく、
+/// すべてのマッチが`MatchEnd`によるものであるかを返す。
+pub(super) fn is_anchored_end(code: &[Instruction]) -> bool {
+    let mut visited = vec![false; code.len()];
+    let mut stack = vec![0];
+
+    while let Some(pc) = stack.pop() {
+        match visited.get(pc) {
+            Some(true) => continue,
+            Some(false) => visited[pc] = true,
+            None => return false,
+        }
+
+        match &code[pc] {
+            Instruction::Match | Instruction::MatchId(_) => return false,
+            Instruction::MatchEnd => {}
+            Instruction::Char(_)
+            | Instruction::AnyChar
+            | Instruction::AnyCharExceptNewline
+            | Instruction::Head
+            | Instruction::Mark(_)
+            | Instruction::Save(_) => stack.push(pc + 1),
+            Instruction::Jump(Pc(addr)) => stack.push(*addr),
+            Instruction::Split(Pc(addr1), Pc(addr2)) => {
+                stack.push(*addr1);
+                stack.push(*addr2);
+            }
+        }
+    }
+
+    true
+}
+
+/// すべてのマッチが先頭に持つ文字列を返す。`pc`が0から分岐せずに続く`Char`の列。
+pub(super) fn literal_prefix(code: &[Instruction]) -> String {
+    let mut prefix = String::new();
+    let mut visited = vec![false; code.len()];
+    let mut pc = 0;
+
+    while let Some(false) = visited.get(pc) {
+        visited[pc] = true;
+        match &code[pc] {
+            Instruction::Char(c) => {
+                prefix.push(*c);
+                pc += 1;
+            }
+            Instruction::Head | Instruction::Mark(_) | Instruction::Save(_) => pc += 1,
+            Instruction::Jump(Pc(addr)) => pc = *addr,
+            _ => break,
+        }
+    }
+
+    prefix
+}
+
+/// マッチしうる文字列の最小の文字数を返す。マッチしえない場合は`None`を返す。
+/// 文字を消費する命令を重み1、それ以外を重み0とした、`pc`が0からマッチまでの最短経路を求める。
+pub(super) fn min_len(code: &[Instruction]) -> Option<usize> {
+    let mut dist = vec![usize::MAX; code.len()];
+    let mut queue = VecDeque::from([(0, 0)]);
+
+    while let Some((pc, d)) = queue.pop_front() {
+        match dist.get(pc) {
+            Some(&prev) if prev > d => dist[pc] = d,
+            _ => continue,
+        }
+
+        match &code[pc] {
+            Instruction::Match | Instruction::MatchId(_) | Instruction::MatchEnd => return Some(d),
+            Instruction::Char(_) | Instruction::AnyChar | Instruction::AnyCharExceptNewline => {
+                queue.push_back((pc + 1, d + 1))
+            }
+            Instruction::Head | Instruction::Mark(_) | Instruction::Save(_) => {
+                queue.push_front((pc + 1, d))
+            }
+            Instruction::Jump(Pc(addr)) => queue.push_front((*addr, d)),
+            Instruction::Split(Pc(addr1), Pc(addr2)) => {
+                queue.push_front((*addr2, d));
+                queue.push_front((*addr1, d));
+            }
+        }
+    }
+
+    None
+}
+
+/// 評価器が範囲外のアドレスやスロットに進む命令を探し、最初に見つかった命令のアドレスを返す。
+/// 外部から読み込んだプログラムのように、コード生成を経ていないプログラムを評価する前に使う。
+/// `slots`は`Save`で使えるスロットの数。
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+pub(super) fn find_invalid(code: &[Instruction], slots: usize) -> Option<usize> {
+    if code.is_empty() {
+        return Some(0);
+    }
+
+    code.iter().enumerate().find_map(|(pc, inst)| {
+        let valid = match inst {
+            Instruction::Match | Instruction::MatchId(_) | Instruction::MatchEnd => true,
+            Instruction::Char(_)
+            | Instruction::AnyChar
+            | Instruction::AnyCharExceptNewline
+            | Instruction::Head
+            | Instruction::Mark(_) => pc + 1 < code.len(),
+            Instruction::Save(slot) => pc + 1 < code.len() && *slot < slots,
+            Instruction::Jump(Pc(addr)) => *addr < code.len(),
+            Instruction::Split(Pc(addr1), Pc(addr2)) => *addr1 < code.len() && *addr2 < code.len(),
+        };
+        (!valid).then_some(pc)
+    })
+}
+
+/// プログラムの先頭がトップレベルの`|`であれば、各分岐の開始アドレスを左から順に返す。
+///
+/// `a|b|c`は右に入れ子になった`Or`なので、次のように`Split`が連なったコードになる。
+/// `|`の`Split`は、第1の分岐の末尾に合流先への前方への`Jump`があることで、
+/// `*`などの`Split`（後方への`Jump`で戻る）と区別する。
+/// 各分岐は合流先以降の命令を共有するので、どの分岐から評価を始めてもプログラム全体の意味は変わらない。
+///
+/// ```text
+/// 0000: split 0001, 0003
+/// 0001: char a
+/// 0002: jump 0007
+/// 0003: split 0004, 0006
+/// 0004: char b
+/// 0005: jump 0007
+/// 0006: char c
+/// 0007: match
+/// ```
+#[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+pub(super) fn top_level_branches(code: &[Instruction]) -> Option<Vec<usize>> {
+    let mut branches = Vec::new();
+    let mut join = None;
+    let mut pc = 0;
+
+    while let Some(Instruction::Split(Pc(addr1), Pc(addr2))) = code.get(pc) {
+        let is_or = *addr1 == pc + 1
+            && *addr2 > *addr1
+            && match code.get(*addr2 - 1) {
+                Some(Instruction::Jump(Pc(addr))) => {
+                    *addr >= *addr2 && join.is_none_or(|join| join == *addr)
+                }
+                _ => false,
+            };
+        if !is_or {
+            break;
+        }
+
+        branches.push(*addr1);
+        if let Some(Instruction::Jump(Pc(addr))) = code.get(*addr2 - 1) {
+            join = Some(*addr);
+        }
+        pc = *addr2;
+    }
+
+    if branches.is_empty() {
+        None
+    } else {
+        // 最後の分岐
+        branches.push(pc);
+        Some(branches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::codegen::get_code;
+    use crate::engine::parser::parse;
+    use crate::helper::DynError;
+
+    #[test]
+    fn test_first_chars() -> Result<(), DynError> {
+        let first_chars = |expr| -> Result<Option<Vec<char>>, DynError> {
+            let mut chars = first_chars(&get_code(&parse(expr)?)?);
+            if let Some(chars) = &mut chars {
+                chars.sort();
+            }
+            Ok(chars)
+        };
+
+        assert_eq!(first_chars("abc")?, Some(vec!['a']));
+        assert_eq!(first_chars("abc|def")?, Some(vec!['a', 'd']));
+        assert_eq!(first_chars("a*b")?, Some(vec!['a', 'b']));
+        assert_eq!(first_chars("(a|^b)c")?, Some(vec!['a', 'b']));
+        assert_eq!(first_chars("(x?y)+z")?, Some(vec!['x', 'y']));
+        assert_eq!(first_chars(r"\.")?, Some(vec!['.']));
+
+        // 任意の文字から始まりうる
+        assert_eq!(first_chars("a.b|.c")?, None);
+        assert_eq!(first_chars("a*")?, None);
+        assert_eq!(first_chars("a|b?")?, None);
+        assert_eq!(first_chars("a|$")?, None);
+
+        Ok(())
+    }
+    #[test]
+    fn test_is_anchored_start() -> Result<(), DynError> {
+        let anchored =
+            |expr| -> Result<bool, DynError> { Ok(is_anchored_start(&get_code(&parse(expr)?)?)) };
+
+        assert!(anchored("^abc")?);
+        assert!(anchored("^^a")?);
+        assert!(anchored("^a*")?);
+        assert!(anchored("^a|^b")?);
+        assert!(anchored("(^a|^b)c")?);
+
+        assert!(!anchored("abc")?);
+        assert!(!anchored("(^a|b)c")?);
+        assert!(!anchored("(^a)?b")?);
+        assert!(!anchored("(^a)*")?);
+        assert!(!anchored("a^")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_anchored_end() -> Result<(), DynError> {
+        let anchored =
+            |expr| -> Result<bool, DynError> { Ok(is_anchored_end(&get_code(&parse(expr)?)?)) };
+
+        assert!(anchored("abc$")?);
+        assert!(anchored("a$|b$")?);
+        assert!(anchored("(a$|b$)")?);
+        assert!(anchored("^$")?);
+
+        assert!(!anchored("abc")?);
+        assert!(!anchored("a$|b")?);
+        assert!(!anchored("(a$)?")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_prefix() -> Result<(), DynError> {
+        let prefix =
+            |expr| -> Result<String, DynError> { Ok(literal_prefix(&get_code(&parse(expr)?)?)) };
+
+        assert_eq!(prefix("abc")?, "abc");
+        assert_eq!(prefix("^ab$")?, "ab");
+        assert_eq!(prefix("ab(c|d)")?, "ab");
+        assert_eq!(prefix("(xy)+z")?, "xy");
+        assert_eq!(prefix("ab*")?, "a");
+        assert_eq!(prefix("a|ab")?, "");
+        assert_eq!(prefix(".a")?, "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_len() -> Result<(), DynError> {
+        let min_len =
+            |expr| -> Result<Option<usize>, DynError> { Ok(min_len(&get_code(&parse(expr)?)?)) };
+
+        assert_eq!(min_len("abc")?, Some(3));
+        assert_eq!(min_len("abc|d")?, Some(1));
+        assert_eq!(min_len("a*")?, Some(0));
+        assert_eq!(min_len("(ab)+c?")?, Some(2));
+        assert_eq!(min_len("^.a$")?, Some(2));
+        assert_eq!(min_len("(a|bc)(de|f)")?, Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_invalid() -> Result<(), DynError> {
+        let code = get_code(&parse("a(b|c)*")?)?;
+        assert_eq!(find_invalid(&code, 0), None);
+
+        use Instruction::*;
+        assert_eq!(find_invalid(&[], 0), Some(0));
+        assert_eq!(find_invalid(&[Char('a')], 0), Some(0));
+        assert_eq!(find_invalid(&[Jump(Pc(2)), Match], 0), Some(0));
+        assert_eq!(
+            find_invalid(&[Head, Split(Pc(0), Pc(3)), Match], 0),
+            Some(1)
+        );
+        assert_eq!(find_invalid(&[Save(2), Match], 2), Some(0));
+        assert_eq!(find_invalid(&[Save(1), Match], 2), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_level_branches() -> Result<(), DynError> {
+        let branches = |expr| -> Result<Option<Vec<usize>>, DynError> {
+            Ok(top_level_branches(&get_code(&parse(expr)?)?))
+        };
+
+        assert_eq!(branches("a|b|c")?, Some(vec![1, 4, 6]));
+        assert_eq!(branches("abc|de")?, Some(vec![1, 5]));
+        // 最後の分岐が`*`や`?`で始まる
+        assert_eq!(branches("a|b*")?, Some(vec![1, 3]));
+        assert_eq!(branches("a|b?|c")?, Some(vec![1, 4, 7]));
+        // 入れ子の`|`は1つの分岐として扱う
+        assert_eq!(branches("(a|b)|c")?, Some(vec![1, 6]));
+        // 合流後の命令は各分岐で共有されるので、先頭の`|`で分けても結果は変わらない
+        assert_eq!(branches("(a|b)c")?, Some(vec![1, 3]));
+
+        // トップレベルに`|`がない
+        assert_eq!(branches("abc")?, None);
+        assert_eq!(branches("a*")?, None);
+        assert_eq!(branches("a?b")?, None);
+        assert_eq!(branches("(ab)*|c")?, Some(vec![1, 6]));
+
+        Ok(())
+    }
+}
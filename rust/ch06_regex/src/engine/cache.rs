@@ -0,0 +1,168 @@
+//! パターン文字列をキーとした、コンパイル済みの`Regex`のプロセス全体で共有するキャッシュ
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard};
+
+use super::{EngineError, Regex};
+
+/// キャッシュに保持するパターン数のデフォルト
+const DEFAULT_CAPACITY: usize = 64;
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(|| Mutex::new(Cache::new(DEFAULT_CAPACITY)));
+
+/// コンパイルした回数。テストでキャッシュが使われたかを確かめるために数える。
+#[cfg(test)]
+static COMPILES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// 最も長く使われていないパターンから追い出す、容量付きのキャッシュ
+#[derive(Debug)]
+struct Cache {
+    capacity: usize,
+    /// パターンごとのコンパイル結果と、最後に使われた時刻
+    entries: HashMap<String, (Arc<Regex>, u64)>,
+    /// 使われるたびに増やす論理時刻
+    clock: u64,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, expr: &str) -> Option<Arc<Regex>> {
+        self.clock += 1;
+        let (regex, used) = self.entries.get_mut(expr)?;
+        *used = self.clock;
+        Some(Arc::clone(regex))
+    }
+
+    fn insert(&mut self, expr: &str, regex: Arc<Regex>) {
+        self.clock += 1;
+        self.entries.insert(expr.to_string(), (regex, self.clock));
+        self.evict();
+    }
+
+    /// 容量を超えた分を、最後に使われた時刻が古い順に追い出す
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(expr, _)| expr.clone());
+            match oldest {
+                Some(expr) => self.entries.remove(&expr),
+                None => break,
+            };
+        }
+    }
+}
+
+/// 評価中にパニックしたスレッドがあってもキャッシュは壊れないので、ポイズニングは無視する
+fn lock() -> MutexGuard<'static, Cache> {
+    CACHE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// `expr`のコンパイル結果をキャッシュから取り出す。なければコンパイルしてキャッシュに入れる。
+/// コンパイル中はロックを保持しないので、同じパターンが同時に複数回コンパイルされることはある。
+fn get_or_compile(expr: &str) -> Result<Arc<Regex>, EngineError> {
+    if let Some(regex) = lock().get(expr) {
+        return Ok(regex);
+    }
+
+    let regex = Arc::new(Regex::new(expr)?);
+    #[cfg(test)]
+    COMPILES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+    lock().insert(expr, Arc::clone(&regex));
+    Ok(regex)
+}
+
+/// `match_line`と同様だが、コンパイル結果をキャッシュして同じパターンの2回目以降のコンパイルを省く。
+/// パターンはデフォルトの設定でコンパイルする。
+pub fn cached_match(expr: &str, line: &str) -> Result<bool, EngineError> {
+    // 評価中はロックを保持しない
+    let regex = get_or_compile(expr)?;
+    regex.try_is_match(line)
+}
+
+/// キャッシュに保持するパターン数を設定する。超えた分はすぐに追い出す。
+pub fn set_cache_capacity(capacity: usize) {
+    let mut cache = lock();
+    cache.capacity = capacity;
+    cache.evict();
+}
+
+/// キャッシュを空にする
+pub fn clear_cache() {
+    lock().entries.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::DynError;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_cache_eviction() -> Result<(), DynError> {
+        let mut cache = Cache::new(2);
+        cache.insert("a", Arc::new(Regex::new("a")?));
+        cache.insert("b", Arc::new(Regex::new("b")?));
+        // `a`を使ったので、次に追い出されるのは`b`
+        assert!(cache.get("a").is_some());
+        cache.insert("c", Arc::new(Regex::new("c")?));
+
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+
+        cache.capacity = 1;
+        cache.evict();
+        assert_eq!(cache.entries.len(), 1);
+        assert!(cache.get("c").is_some());
+
+        Ok(())
+    }
+
+    /// プロセス全体のキャッシュを使うテストはこの1つにまとめ、並列に実行されるテストと干渉しないようにする
+    #[test]
+    fn test_cached_match() -> Result<(), DynError> {
+        let compiles = || COMPILES.load(Ordering::SeqCst);
+        clear_cache();
+
+        let before = compiles();
+        assert!(cached_match("a+b", "xaab")?);
+        assert_eq!(compiles(), before + 1);
+        // 2回目はコンパイルしない
+        assert!(!cached_match("a+b", "xaa")?);
+        assert_eq!(compiles(), before + 1);
+
+        // 容量を超えると古いパターンから追い出される
+        set_cache_capacity(2);
+        assert!(cached_match("c", "c")?);
+        assert!(cached_match("d", "d")?);
+        assert_eq!(compiles(), before + 3);
+        assert!(cached_match("a+b", "ab")?);
+        assert_eq!(compiles(), before + 4);
+
+        // 空にすると再びコンパイルする
+        clear_cache();
+        assert!(cached_match("d", "d")?);
+        assert_eq!(compiles(), before + 5);
+
+        // 不正なパターンはキャッシュしない
+        let expr = "a|*";
+        assert!(cached_match(expr, "a").is_err());
+        assert!(cached_match(expr, "a").is_err());
+
+        set_cache_capacity(DEFAULT_CAPACITY);
+        clear_cache();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+//! 大文字小文字を区別しない比較のための単純ケースフォールディング。
+//!
+//! 1文字を1文字に対応させる単純な変換のみを扱う。
+//! - `'ß'`は`"SS"`とはマッチしない（2文字への変換は扱わない）。`'ẞ'`とはマッチする。
+//! - トルコ語の`'İ'`（小文字にすると2文字になる）は変換せず、`'ı'`も`'i'`/`'I'`とはマッチしない。
+//! - ケルビン記号`'K'`は`char::to_lowercase`で`'k'`になるので、`'k'`/`'K'`とマッチする。
+
+/// `char::to_lowercase`では同じ文字に揃わない文字と、その変換先
+const SPECIAL_FOLDS: &[(char, char)] = &[
+    ('\u{017F}', 's'), // ſ: LATIN SMALL LETTER LONG S
+    ('\u{03C2}', 'σ'), // ς: GREEK SMALL LETTER FINAL SIGMA
+    ('\u{03D0}', 'β'), // ϐ: GREEK BETA SYMBOL
+    ('\u{03D1}', 'θ'), // ϑ: GREEK THETA SYMBOL
+    ('\u{03D5}', 'φ'), // ϕ: GREEK PHI SYMBOL
+    ('\u{03D6}', 'π'), // ϖ: GREEK PI SYMBOL
+    ('\u{03F0}', 'κ'), // ϰ: GREEK KAPPA SYMBOL
+    ('\u{03F1}', 'ρ'), // ϱ: GREEK RHO SYMBOL
+    ('\u{03F5}', 'ε'), // ϵ: GREEK LUNATE EPSILON SYMBOL
+];
+
+/// 大文字小文字を区別せずに比較するための代表の文字を返す
+pub(super) fn simple_fold(c: char) -> char {
+    if let Some((_, folded)) = SPECIAL_FOLDS.iter().find(|(from, _)| *from == c) {
+        return *folded;
+    }
+
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(l), None) => l,
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simple_fold() {
+        assert_eq!(simple_fold('A'), 'a');
+        assert_eq!(simple_fold('a'), 'a');
+        assert_eq!(simple_fold('1'), '1');
+        assert_eq!(simple_fold('あ'), 'あ');
+
+        // ギリシャ文字、キリル文字
+        assert_eq!(simple_fold('Σ'), 'σ');
+        assert_eq!(simple_fold('ς'), 'σ');
+        assert_eq!(simple_fold('Ж'), 'ж');
+
+        assert_eq!(simple_fold('\u{212A}'), 'k');
+        assert_eq!(simple_fold('ſ'), 's');
+        assert_eq!(simple_fold('ẞ'), 'ß');
+
+        // 2文字に変換される文字はそのまま
+        assert_eq!(simple_fold('İ'), 'İ');
+        assert_eq!(simple_fold('ı'), 'ı');
+    }
+}
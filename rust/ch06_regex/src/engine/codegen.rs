@@ -3,34 +3,65 @@ use std::{
     fmt::{Display, Formatter},
 };
 
-use super::{parser::AST, Instruction};
-use crate::helper::safe_add;
+use super::{parser::AST, Instruction, Options, Pc};
+use crate::helper::{checked, Overflow};
 
 #[derive(Debug)]
 pub enum CodeGenError {
-    PCOverFlow,
+    PCOverFlow(Overflow<usize>),
     FailStar,
     FailOr,
     FailQuestion,
+    SizeLimitExceeded { limit: usize },
 }
 
 impl Display for CodeGenError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "CodeGenError: {:?}", self)
+        match self {
+            CodeGenError::PCOverFlow(overflow) => {
+                write!(f, "CodeGenError: pc overflow: {overflow}")
+            }
+            CodeGenError::SizeLimitExceeded { limit } => {
+                write!(f, "CodeGenError: size limit exceeded: limit = {limit}")
+            }
+            _ => write!(f, "CodeGenError: {:?}", self),
+        }
     }
 }
 
 impl Error for CodeGenError {}
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct Generator {
     pc: usize,
     insts: Vec<Instruction>,
+    /// キャプチャグループの前後に`Save`を生成する
+    captures: bool,
+    /// 生成する命令数の上限
+    size_limit: usize,
+    /// `.`が改行にもマッチする
+    dot_matches_newline: bool,
 }
 
 impl Generator {
+    fn new(options: &Options) -> Self {
+        Self {
+            pc: 0,
+            insts: Vec::new(),
+            captures: false,
+            size_limit: options.size_limit,
+            dot_matches_newline: options.dot_matches_newline,
+        }
+    }
+
     fn inc_pc(&mut self) -> Result<(), CodeGenError> {
-        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)
+        checked!(self.pc += 1, CodeGenError::PCOverFlow)?;
+        if self.pc > self.size_limit {
+            return Err(CodeGenError::SizeLimitExceeded {
+                limit: self.size_limit,
+            });
+        }
+        Ok(())
     }
 
     fn gen_code(&mut self, ast: &AST) -> Result<(), CodeGenError> {
@@ -40,6 +71,21 @@ impl Generator {
         Ok(())
     }
 
+    fn gen_code_with_marks(&mut self, ast: &AST) -> Result<(), CodeGenError> {
+        self.gen_branches(ast, 0)?;
+        self.inc_pc()?;
+        self.insts.push(Instruction::Match);
+        Ok(())
+    }
+
+    /// キャプチャを生成しない場合は、キャプチャグループを取り除いた中身を返す
+    fn strip_capture<'a>(&self, ast: &'a AST) -> &'a AST {
+        match ast {
+            AST::Capture(_, _, e) if !self.captures => self.strip_capture(e),
+            _ => ast,
+        }
+    }
+
     fn gen_expr(&mut self, ast: &AST) -> Result<(), CodeGenError> {
         match ast {
             AST::Char(c) => self.gen_char(*c)?,
@@ -49,22 +95,29 @@ impl Generator {
             AST::Or(e1, e2) => self.gen_or(e1, e2)?,
             AST::Plus(e) => self.gen_plus(e)?,
             AST::Star(e) => {
-                match &**e {
+                match self.strip_capture(e) {
                     // `(a*)*`のように`Star`が二重となっている場合にスタックオーバーフローする問題を回避するため、
                     // このような`(((r*)*)*...*)*`を再帰的に処理して1つの`r*`へと変換する。
-                    AST::Star(_) => self.gen_expr(&e)?,
+                    e @ AST::Star(_) => self.gen_expr(e)?,
                     AST::Seq(e2) if e2.len() == 1 => {
-                        if let Some(e3 @ AST::Star(_)) = e2.get(0) {
+                        if let Some(e3 @ AST::Star(_)) = e2.first() {
                             self.gen_expr(e3)?
                         } else {
                             self.gen_star(e)?
                         }
                     }
-                    e => self.gen_star(&e)?,
+                    e => self.gen_star(e)?,
                 }
             }
             AST::Question(e) => self.gen_question(e)?,
             AST::Seq(v) => self.gen_seq(v)?,
+            AST::Capture(index, _, e) => {
+                if self.captures {
+                    self.gen_capture(*index, e)?
+                } else {
+                    self.gen_expr(e)?
+                }
+            }
         }
 
         Ok(())
@@ -92,7 +145,11 @@ impl Generator {
     }
 
     fn gen_period(&mut self) -> Result<(), CodeGenError> {
-        let inst = Instruction::AnyChar;
+        let inst = if self.dot_matches_newline {
+            Instruction::AnyChar
+        } else {
+            Instruction::AnyCharExceptNewline
+        };
         self.insts.push(inst);
         self.inc_pc()?;
         Ok(())
@@ -107,28 +164,53 @@ impl Generator {
     }
 
     fn gen_or(&mut self, e1: &AST, e2: &AST) -> Result<(), CodeGenError> {
+        self.gen_alternation(|g| g.gen_expr(e1), |g| g.gen_expr(e2))
+    }
+
+    /// トップレベルの`Or`の各分岐の先頭に`Mark(分岐の番号)`を挿入しつつコード生成する。
+    /// `Or`は右に入れ子になっているので、右側を辿ることで分岐を左から順に数えられる。
+    fn gen_branches(&mut self, ast: &AST, branch: usize) -> Result<(), CodeGenError> {
+        if let AST::Or(e1, e2) = ast {
+            self.gen_alternation(
+                |g| {
+                    g.gen_mark(branch)?;
+                    g.gen_expr(e1)
+                },
+                |g| g.gen_branches(e2, branch + 1),
+            )
+        } else {
+            self.gen_mark(branch)?;
+            self.gen_expr(ast)
+        }
+    }
+
+    fn gen_alternation<F1, F2>(&mut self, gen1: F1, gen2: F2) -> Result<(), CodeGenError>
+    where
+        F1: FnOnce(&mut Self) -> Result<(), CodeGenError>,
+        F2: FnOnce(&mut Self) -> Result<(), CodeGenError>,
+    {
         let split_addr = self.pc;
         self.inc_pc()?;
 
-        let split = Instruction::Split(self.pc, 0);
+        let split = Instruction::Split(Pc(self.pc), Pc(0));
         self.insts.push(split);
 
-        self.gen_expr(e1)?;
+        gen1(self)?;
 
         let jmp_addr = self.pc;
-        self.insts.push(Instruction::Jump(0));
+        self.insts.push(Instruction::Jump(Pc(0)));
 
         self.inc_pc()?;
         if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
-            *l2 = self.pc;
+            *l2 = Pc(self.pc);
         } else {
             return Err(CodeGenError::FailOr);
         }
 
-        self.gen_expr(e2)?;
+        gen2(self)?;
 
         if let Some(Instruction::Jump(l3)) = self.insts.get_mut(jmp_addr) {
-            *l3 = self.pc;
+            *l3 = Pc(self.pc);
         } else {
             return Err(CodeGenError::FailOr);
         }
@@ -136,46 +218,71 @@ impl Generator {
         Ok(())
     }
 
+    /// グループ`index`の開始位置と終了位置を、`Save(2 * index)`と`Save(2 * index + 1)`で記録する。
+    /// 溢れないか確かめるのはスロットの番号のみ。命令数は`inc_pc`で`size_limit`までに抑える。
+    fn gen_capture(&mut self, index: usize, e: &AST) -> Result<(), CodeGenError> {
+        let mut slot = index;
+        checked!(slot *= 2, CodeGenError::PCOverFlow)?;
+        self.insts.push(Instruction::Save(slot));
+        self.inc_pc()?;
+
+        self.gen_expr(e)?;
+
+        self.insts.push(Instruction::Save(slot + 1));
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_mark(&mut self, branch: usize) -> Result<(), CodeGenError> {
+        let inst = Instruction::Mark(branch);
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// 貪欲に繰り返すため、`Split`は繰り返しを続ける側を優先する
     fn gen_plus(&mut self, e: &AST) -> Result<(), CodeGenError> {
         let l1 = self.pc;
         self.gen_expr(e)?;
 
         self.inc_pc()?;
-        let split = Instruction::Split(l1, self.pc);
+        let split = Instruction::Split(Pc(l1), Pc(self.pc));
         self.insts.push(split);
 
         Ok(())
     }
 
+    /// 貪欲に繰り返すため、`Split`は繰り返しを続ける側を優先する
     fn gen_star(&mut self, e: &AST) -> Result<(), CodeGenError> {
         let l1 = self.pc;
         self.inc_pc()?;
-        let split = Instruction::Split(self.pc, 0);
+        let split = Instruction::Split(Pc(self.pc), Pc(0));
         self.insts.push(split);
 
         self.gen_expr(e)?;
 
         self.inc_pc()?;
-        self.insts.push(Instruction::Jump(l1));
+        self.insts.push(Instruction::Jump(Pc(l1)));
 
         if let Some(Instruction::Split(_, l3)) = self.insts.get_mut(l1) {
-            *l3 = self.pc;
+            *l3 = Pc(self.pc);
             Ok(())
         } else {
             Err(CodeGenError::FailStar)
         }
     }
 
+    /// 貪欲にマッチさせるため、`Split`は式を評価する側を優先する
     fn gen_question(&mut self, e: &AST) -> Result<(), CodeGenError> {
         let split_addr = self.pc;
         self.inc_pc()?;
-        let split = Instruction::Split(self.pc, 0);
+        let split = Instruction::Split(Pc(self.pc), Pc(0));
         self.insts.push(split);
 
         self.gen_expr(e)?;
 
         if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
-            *l2 = self.pc;
+            *l2 = Pc(self.pc);
             Ok(())
         } else {
             Err(CodeGenError::FailQuestion)
@@ -183,12 +290,83 @@ impl Generator {
     }
 }
 
+/// デフォルトの設定で`get_code_with`する
 pub fn get_code(ast: &AST) -> Result<Vec<Instruction>, CodeGenError> {
-    let mut generator = Generator::default();
+    get_code_with(ast, &Options::default())
+}
+
+/// `options`の`size_limit`を超えない範囲でコードを生成する
+pub fn get_code_with(ast: &AST, options: &Options) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator::new(options);
     generator.gen_code(ast)?;
     Ok(generator.insts)
 }
 
+/// `get_code_with`と同様だが、キャプチャグループの前後に`Save`を挿入する
+pub fn get_code_with_captures(
+    ast: &AST,
+    options: &Options,
+) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator {
+        captures: true,
+        ..Generator::new(options)
+    };
+    generator.gen_code(ast)?;
+    Ok(generator.insts)
+}
+
+/// 行全体を消費した場合にのみマッチするよう、`code`の末尾の`Match`の前に`MatchEnd`を挿入する。
+/// `Match`へのジャンプは挿入した`MatchEnd`を指すことになるので、アドレスの付け替えは不要。
+pub fn with_end_anchor(code: &[Instruction]) -> Vec<Instruction> {
+    let mut code = code.to_vec();
+    let pc = code.len().saturating_sub(1);
+    code.insert(pc, Instruction::MatchEnd);
+    code
+}
+
+/// マッチを入力のどの位置からでも始められるよう、`code`の前に`(.)*?`に相当する前置部を付ける。
+/// 前置部はパターンを先に試し、失敗したら1文字読み進めて戻るので、
+/// 評価器を1度実行するだけで、左の位置から始まるマッチほど優先して探せる。
+///
+/// ```text
+/// 0000: split 0003, 0001
+/// 0001: any_char
+/// 0002: jump 0000
+/// 0003: （codeの0番目の命令）
+/// ```
+pub fn with_unanchored_prefix(code: &[Instruction]) -> Result<Vec<Instruction>, CodeGenError> {
+    const PREFIX_LEN: usize = 3;
+    let relocate = |addr: &Pc| {
+        let mut addr = addr.0;
+        checked!(addr += PREFIX_LEN, CodeGenError::PCOverFlow)?;
+        Ok(Pc(addr))
+    };
+
+    let mut insts = vec![
+        Instruction::Split(Pc(PREFIX_LEN), Pc(1)),
+        Instruction::AnyChar,
+        Instruction::Jump(Pc(0)),
+    ];
+    for inst in code {
+        let inst = match inst {
+            Instruction::Jump(addr) => Instruction::Jump(relocate(addr)?),
+            Instruction::Split(addr1, addr2) => {
+                Instruction::Split(relocate(addr1)?, relocate(addr2)?)
+            }
+            inst => inst.clone(),
+        };
+        insts.push(inst);
+    }
+    Ok(insts)
+}
+
+/// `get_code`と同様だが、トップレベルの`|`の各分岐の先頭に`Mark`を挿入する
+pub fn get_code_with_marks(ast: &AST) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator::new(&Options::default());
+    generator.gen_code_with_marks(ast)?;
+    Ok(generator.insts)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::engine::parser::parse;
@@ -203,16 +381,22 @@ mod tests {
         assert_eq!(get_code(&AST::Char('a'))?, vec![Char('a'), Match]);
         assert_eq!(
             get_code(&AST::Or(Box::new(AST::Char('a')), Box::new(AST::Char('b'))))?,
-            vec![Split(1, 3), Char('a'), Jump(4), Char('b'), Match]
+            vec![
+                Split(Pc(1), Pc(3)),
+                Char('a'),
+                Jump(Pc(4)),
+                Char('b'),
+                Match
+            ]
         );
         // parse関数を使うのは望ましくないがfixtureを作るのが面倒なので仕方なく使う
         assert_eq!(
             get_code(&parse("ab|bc")?)?,
             vec![
-                Split(1, 4),
+                Split(Pc(1), Pc(4)),
                 Char('a'),
                 Char('b'),
-                Jump(6),
+                Jump(Pc(6)),
                 Char('b'),
                 Char('c'),
                 Match
@@ -227,7 +411,7 @@ mod tests {
             vec![
                 Char('a'),
                 Char('b'),
-                Split(3, 5),
+                Split(Pc(3), Pc(5)),
                 Char('d'),
                 Char('e'),
                 Match
@@ -236,15 +420,15 @@ mod tests {
         assert_eq!(
             get_code(&parse("a(bc|e+)*")?)?,
             vec![
-                Char('a'),   // 0:
-                Split(2, 9), // 1: *のsplit
-                Split(3, 6), // 2: |のsplit
-                Char('b'),   // 3:
-                Char('c'),   // 4:
-                Jump(8),     // 5: |のjump
-                Char('e'),   // 6:
-                Split(6, 8), // 7: +のsplit
-                Jump(1),     // 8: *のjump
+                Char('a'),           // 0:
+                Split(Pc(2), Pc(9)), // 1: *のsplit
+                Split(Pc(3), Pc(6)), // 2: |のsplit
+                Char('b'),           // 3:
+                Char('c'),           // 4:
+                Jump(Pc(8)),         // 5: |のjump
+                Char('e'),           // 6:
+                Split(Pc(6), Pc(8)), // 7: +のsplit
+                Jump(Pc(1)),         // 8: *のjump
                 Match
             ]
         );
@@ -256,13 +440,13 @@ mod tests {
         assert_eq!(
             get_code(&parse("(a|^b)c")?)?,
             vec![
-                Split(1, 3), // 0:
-                Char('a'),   // 1:
-                Jump(5),     // 2:
-                Head,        // 3:
-                Char('b'),   // 4:
-                Char('c'),   // 5:
-                Match,       // 6:
+                Split(Pc(1), Pc(3)), // 0:
+                Char('a'),           // 1:
+                Jump(Pc(5)),         // 2:
+                Head,                // 3:
+                Char('b'),           // 4:
+                Char('c'),           // 5:
+                Match,               // 6:
             ]
         );
         assert_eq!(get_code(&parse("a$")?)?, vec![Char('a'), MatchEnd, Match]);
@@ -273,13 +457,135 @@ mod tests {
         assert_eq!(
             get_code(&parse("a(b|c$)")?)?,
             vec![
-                Char('a'),   // 0:
-                Split(2, 4), // 1:
-                Char('b'),   // 2:
-                Jump(6),     // 3:
-                Char('c'),   // 4:
-                MatchEnd,    // 5:
-                Match,       // 6:
+                Char('a'),           // 0:
+                Split(Pc(2), Pc(4)), // 1:
+                Char('b'),           // 2:
+                Jump(Pc(6)),         // 3:
+                Char('c'),           // 4:
+                MatchEnd,            // 5:
+                Match,               // 6:
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_code_with_captures() -> Result<(), DynError> {
+        assert_eq!(
+            get_code_with_captures(&parse("(a)|b")?, &Options::default())?,
+            vec![
+                Split(Pc(1), Pc(5)), // 0:
+                Save(2),             // 1:
+                Char('a'),           // 2:
+                Save(3),             // 3:
+                Jump(Pc(6)),         // 4:
+                Char('b'),           // 5:
+                Match,               // 6:
+            ]
+        );
+        // `get_code`はグループを無視する
+        assert_eq!(get_code(&parse("(a)")?)?, vec![Char('a'), Match]);
+
+        // スロットの番号`2 * index`が溢れる場合は、折り返さずにエラーとする。
+        // パーサはグループを1から数えるのでパターンからは作れず、ASTを直接組み立てて確かめる。
+        // 確かめるのはスロットの番号のみで、命令数は`size_limit`で抑える。
+        let ast = AST::Capture(usize::MAX / 2 + 1, None, Box::new(AST::Char('a')));
+        assert!(matches!(
+            get_code_with_captures(&ast, &Options::default()),
+            Err(CodeGenError::PCOverFlow(_))
+        ));
+        let err = get_code_with_captures(&ast, &Options::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "CodeGenError: pc overflow: 9223372036854775808 * 2"
+        );
+        let options = Options {
+            size_limit: 5,
+            ..Default::default()
+        };
+        assert!(matches!(
+            get_code_with_captures(&parse("(a)(b)")?, &options),
+            Err(CodeGenError::SizeLimitExceeded { limit: 5 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_end_anchor() -> Result<(), DynError> {
+        let code = get_code(&parse("a|b")?)?;
+        assert_eq!(
+            with_end_anchor(&code),
+            vec![
+                Split(Pc(1), Pc(3)),
+                Char('a'),
+                Jump(Pc(4)),
+                Char('b'),
+                MatchEnd,
+                Match
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_unanchored_prefix() -> Result<(), DynError> {
+        assert_eq!(
+            with_unanchored_prefix(&get_code(&parse("a*")?)?)?,
+            vec![
+                Split(Pc(3), Pc(1)), // 0:
+                AnyChar,             // 1:
+                Jump(Pc(0)),         // 2:
+                Split(Pc(4), Pc(6)), // 3:
+                Char('a'),           // 4:
+                Jump(Pc(3)),         // 5:
+                Match,               // 6:
+            ]
+        );
+
+        // 付け替えたアドレスが溢れる場合は、被演算子をエラーに含める
+        let err = with_unanchored_prefix(&[Jump(Pc(usize::MAX))]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "CodeGenError: pc overflow: 18446744073709551615 + 3"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_code_with_marks() -> Result<(), DynError> {
+        assert_eq!(
+            get_code_with_marks(&parse("a")?)?,
+            vec![Mark(0), Char('a'), Match]
+        );
+        assert_eq!(
+            get_code_with_marks(&parse("a|b|c")?)?,
+            vec![
+                Split(Pc(1), Pc(4)), // 0:
+                Mark(0),             // 1:
+                Char('a'),           // 2:
+                Jump(Pc(10)),        // 3:
+                Split(Pc(5), Pc(8)), // 4:
+                Mark(1),             // 5:
+                Char('b'),           // 6:
+                Jump(Pc(10)),        // 7:
+                Mark(2),             // 8:
+                Char('c'),           // 9:
+                Match,               // 10:
+            ]
+        );
+        // 括弧の中の`|`には挿入しない
+        assert_eq!(
+            get_code_with_marks(&parse("(a|b)c")?)?,
+            vec![
+                Mark(0),
+                Split(Pc(2), Pc(4)),
+                Char('a'),
+                Jump(Pc(5)),
+                Char('b'),
+                Char('c'),
+                Match
             ]
         );
 
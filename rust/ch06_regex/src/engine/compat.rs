@@ -0,0 +1,308 @@
+//! PCREや`regex`クレート向けに書かれたパターンから、このエンジンが対応していない構文を探す。
+//!
+//! パターンを解析せずに文字を先頭から走査するだけなので、解析できないパターンにも使える。
+
+use std::fmt::{Display, Formatter};
+
+use super::parser::ESCAPABLE;
+
+/// 対応していない構文の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Construct {
+    /// `(?=...)`、`(?!...)`、`(?<=...)`、`(?<!...)`
+    Lookaround,
+    /// パターンの先頭以外の`(?i)`、`(?i:...)`、および`i`と`m`以外のフラグ
+    InlineFlags,
+    /// `{n}`、`{n,}`、`{n,m}`
+    CountedRepetition,
+    /// `[abc]`、`[^a-z]`
+    CharClass,
+    /// `\d`、`\w`、`\s`、`\p{...}`とそれらの否定
+    ClassEscape,
+    /// `\b`、`\B`、`\A`、`\z`など
+    Assertion,
+    /// `\1`、`\k<name>`
+    Backreference,
+    /// `\n`、`\x41`、`\-`など、メタ文字以外のエスケープ
+    Escape,
+    /// `*?`、`+?`、`??`
+    LazyQuantifier,
+}
+
+impl Display for Construct {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Construct::Lookaround => "lookaround",
+            Construct::InlineFlags => "inline flags",
+            Construct::CountedRepetition => "counted repetition",
+            Construct::CharClass => "character class",
+            Construct::ClassEscape => "class escape",
+            Construct::Assertion => "assertion",
+            Construct::Backreference => "backreference",
+            Construct::Escape => "escape sequence",
+            Construct::LazyQuantifier => "lazy quantifier",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Construct {
+    /// 代わりに書ける構文
+    fn suggestion(&self) -> &'static str {
+        match self {
+            Construct::Lookaround => "match the surrounding text explicitly instead",
+            Construct::InlineFlags => {
+                "only `(?i)` and `(?m)` at the start of the pattern are supported"
+            }
+            Construct::CountedRepetition => "repeat the expression, e.g. `aaa?` for `a{2,3}`",
+            Construct::CharClass => "use alternation, e.g. `(a|b|c)` for `[abc]`",
+            Construct::ClassEscape => "use alternation, e.g. `(0|1|2|3|4|5|6|7|8|9)` for `\\d`",
+            Construct::Assertion => "only `^` and `$` are supported",
+            Construct::Backreference => "repeat the referenced text literally instead",
+            Construct::Escape => {
+                "only metacharacters can be escaped; write other characters literally"
+            }
+            Construct::LazyQuantifier => "quantifiers are always greedy; `a*?` is read as `(a*)?`",
+        }
+    }
+}
+
+/// パターン中の対応していない構文
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    /// 構文の開始位置（パターンの先頭からの文字数）
+    pub pos: usize,
+    pub construct: Construct,
+    /// 代わりに書ける構文
+    pub suggestion: &'static str,
+}
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is not supported: pos = {}: {}",
+            self.construct, self.pos, self.suggestion
+        )
+    }
+}
+
+/// パターン先頭に書けるフラグ
+const FLAGS: &[char] = &['i', 'm'];
+
+/// `expr`中の、このエンジンが対応していない構文を先頭から順に返す
+pub fn check_compatibility(expr: &str) -> Vec<Unsupported> {
+    let chars = expr.chars().collect::<Vec<_>>();
+    let mut found = Vec::new();
+    let mut report = |pos, construct: Construct| {
+        found.push(Unsupported {
+            pos,
+            construct,
+            suggestion: construct.suggestion(),
+        })
+    };
+
+    let mut i = 0;
+    while i < chars.len() {
+        let next = chars.get(i + 1).copied();
+        match chars[i] {
+            '\\' => {
+                if let Some(construct) = next.and_then(classify_escape) {
+                    report(i, construct);
+                }
+                i += 2;
+                continue;
+            }
+            '[' => {
+                report(i, Construct::CharClass);
+                i = class_end(&chars, i);
+                continue;
+            }
+            '(' if next == Some('?') => {
+                if let Some(construct) = classify_group(&chars, i) {
+                    report(i, construct);
+                }
+            }
+            '{' => {
+                if let Some(end) = repetition_end(&chars, i) {
+                    report(i, Construct::CountedRepetition);
+                    i = end;
+                    continue;
+                }
+            }
+            '*' | '+' | '?' if next == Some('?') => {
+                report(i, Construct::LazyQuantifier);
+                i += 2;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    found
+}
+
+/// `\`に続く文字`c`のエスケープが対応していなければ、その種類を返す
+fn classify_escape(c: char) -> Option<Construct> {
+    match c {
+        c if ESCAPABLE.contains(&c) => None,
+        'd' | 'D' | 'w' | 'W' | 's' | 'S' | 'h' | 'H' | 'p' | 'P' => Some(Construct::ClassEscape),
+        'b' | 'B' | 'A' | 'z' | 'Z' | 'G' => Some(Construct::Assertion),
+        '1'..='9' | 'k' => Some(Construct::Backreference),
+        _ => Some(Construct::Escape),
+    }
+}
+
+/// 位置`start`の`(?`で始まるグループが対応していなければ、その種類を返す
+fn classify_group(chars: &[char], start: usize) -> Option<Construct> {
+    let rest = &chars[start + 2..];
+    match rest {
+        ['=' | '!', ..] | ['<', '=' | '!', ..] => return Some(Construct::Lookaround),
+        // 非キャプチャグループと名前付きグループ
+        [':', ..] | ['<', ..] | ['P', '<', ..] => return None,
+        _ => {}
+    }
+
+    // フラグはパターンの先頭で、`)`で閉じる場合のみ対応する
+    let len = rest.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+    let supported =
+        start == 0 && rest.get(len) == Some(&')') && rest[..len].iter().all(|c| FLAGS.contains(c));
+    (!supported).then_some(Construct::InlineFlags)
+}
+
+/// 位置`start`の`[`で始まる文字クラスの、閉じる`]`の次の位置を返す。閉じていなければ末尾を返す。
+fn class_end(chars: &[char], start: usize) -> usize {
+    let mut i = start + 1;
+    // 先頭の`^`と`]`はクラスの要素
+    if chars.get(i) == Some(&'^') {
+        i += 1;
+    }
+    if chars.get(i) == Some(&']') {
+        i += 1;
+    }
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            ']' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
+/// 位置`start`の`{`が`{n}`、`{n,}`、`{n,m}`の形であれば、閉じる`}`の次の位置を返す
+fn repetition_end(chars: &[char], start: usize) -> Option<usize> {
+    let digits = |from: usize| {
+        chars[from..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count()
+    };
+
+    let mut i = start + 1;
+    let n = digits(i);
+    if n == 0 {
+        return None;
+    }
+    i += n;
+    if chars.get(i) == Some(&',') {
+        i += 1;
+        i += digits(i);
+    }
+    (chars.get(i) == Some(&'}')).then_some(i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Construct::*;
+    use super::*;
+
+    fn constructs(expr: &str) -> Vec<(usize, Construct)> {
+        check_compatibility(expr)
+            .into_iter()
+            .map(|u| (u.pos, u.construct))
+            .collect()
+    }
+
+    #[test]
+    fn test_check_compatibility() {
+        // 実際によく見かけるパターン
+        assert_eq!(
+            constructs(r"^\d{3}-\d{4}$"),
+            vec![
+                (1, ClassEscape),
+                (3, CountedRepetition),
+                (7, ClassEscape),
+                (9, CountedRepetition)
+            ]
+        );
+        assert_eq!(constructs(r"(?:https?)://[^/\s]+"), vec![(13, CharClass)]);
+        assert_eq!(
+            constructs(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}"),
+            vec![
+                (0, CharClass),
+                (18, CharClass),
+                (34, CharClass),
+                (42, CountedRepetition)
+            ]
+        );
+        assert_eq!(
+            constructs(r"(\w+)\s+\1"),
+            vec![(1, ClassEscape), (5, ClassEscape), (8, Backreference)]
+        );
+        assert_eq!(
+            constructs(r"(?<year>\d\d)-(?P<month>\d\d)"),
+            vec![
+                (8, ClassEscape),
+                (10, ClassEscape),
+                (24, ClassEscape),
+                (26, ClassEscape)
+            ]
+        );
+        assert_eq!(constructs("foo(?=bar)"), vec![(3, Lookaround)]);
+        assert_eq!(
+            constructs("(?<!x)y(?!z)"),
+            vec![(0, Lookaround), (7, Lookaround)]
+        );
+        assert_eq!(
+            constructs(r"\bword\b"),
+            vec![(0, Assertion), (6, Assertion)]
+        );
+        assert_eq!(constructs("<.+?>"), vec![(2, LazyQuantifier)]);
+        assert_eq!(
+            constructs(r"\t\x41\-"),
+            vec![(0, Escape), (2, Escape), (6, Escape)]
+        );
+        assert_eq!(constructs(r"\[\]\{\}"), vec![]);
+
+        // フラグはパターンの先頭の`i`と`m`のみ
+        assert_eq!(constructs("(?i)foo"), vec![]);
+        assert_eq!(constructs("(?mi)^foo$"), vec![]);
+        assert_eq!(constructs("a(?i)b"), vec![(1, InlineFlags)]);
+        assert_eq!(constructs("(?x)a b"), vec![(0, InlineFlags)]);
+        assert_eq!(constructs("(?i:a)b"), vec![(0, InlineFlags)]);
+
+        // 文字クラス中の`]`やエスケープ、`{`のみの場合
+        assert_eq!(
+            constructs(r"[]a\]]x{2}"),
+            vec![(0, CharClass), (7, CountedRepetition)]
+        );
+        assert_eq!(constructs("a{x}b{"), vec![]);
+        assert_eq!(constructs("[abc"), vec![(0, CharClass)]);
+
+        // 対応している構文
+        assert_eq!(constructs(r"(a|b)*c+d?.^$\.\*\\"), vec![]);
+        assert_eq!(constructs(""), vec![]);
+        assert_eq!(constructs("\\"), vec![]);
+    }
+
+    #[test]
+    fn test_unsupported_display() {
+        let found = check_compatibility("a{2}");
+        assert_eq!(
+            found[0].to_string(),
+            "counted repetition is not supported: pos = 1: repeat the expression, e.g. `aaa?` for `a{2,3}`"
+        );
+    }
+}
@@ -1,257 +1,1297 @@
-use std::collections::VecDeque;
-use std::{error::Error, fmt::Display};
+use std::io::Write;
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+};
 
 use super::EvalResult;
-use super::Instruction;
-use crate::helper::safe_add;
+use super::{Engine, Instruction, Options};
+use crate::helper::{checked, Overflow};
 
 #[derive(Debug)]
 pub enum EvalError {
-    PCOverFlow,
-    SPOverFlow,
+    PCOverFlow(Overflow<usize>),
+    SPOverFlow(Overflow<usize>),
     InvalidPC,
-    InvalidContext,
+    Trace(std::io::Error),
+    BacktrackLimitExceeded { limit: usize },
+    StepLimitExceeded { limit: usize },
+    QueueLimitExceeded { limit: usize },
 }
 
 impl Display for EvalError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "EvalError: {:?}", self)
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::PCOverFlow(overflow) => write!(f, "EvalError: pc overflow: {overflow}"),
+            EvalError::SPOverFlow(overflow) => write!(f, "EvalError: sp overflow: {overflow}"),
+            EvalError::BacktrackLimitExceeded { limit } => {
+                write!(f, "EvalError: backtrack limit exceeded: limit = {limit}")
+            }
+            EvalError::StepLimitExceeded { limit } => {
+                write!(f, "EvalError: step limit exceeded: limit = {limit}")
+            }
+            EvalError::QueueLimitExceeded { limit } => {
+                write!(f, "EvalError: queue limit exceeded: limit = {limit}")
+            }
+            _ => write!(f, "EvalError: {:?}", self),
+        }
     }
 }
 
-impl Error for EvalError {}
+impl Error for EvalError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EvalError::Trace(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-fn eval_depth(
-    inst: &[Instruction],
-    line: &[char],
-    mut pc: usize,
-    mut sp: usize,
-) -> Result<EvalResult, EvalError> {
-    let mut should_be_head = false;
+/// 命令の位置（プログラムカウンタ）。入力の位置`Sp`と取り違えないよう、別の型とする。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct Pc(pub usize);
 
-    loop {
-        let next = if let Some(i) = inst.get(pc) {
+impl Pc {
+    /// 次の命令の位置。溢れた場合は`EvalError::PCOverFlow`を返す。
+    pub fn incr(self) -> Result<Self, EvalError> {
+        let Pc(mut pc) = self;
+        checked!(pc += 1, EvalError::PCOverFlow)?;
+        Ok(Pc(pc))
+    }
+}
+
+/// `{:>04}`のような幅の指定は中の値に渡す
+impl Display for Pc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+/// 入力の位置（入力の先頭からの文字数、または書記素クラスタの数）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Sp(pub usize);
+
+impl Sp {
+    /// 次の入力の位置。溢れた場合は`EvalError::SPOverFlow`を返す。
+    pub fn incr(self) -> Result<Self, EvalError> {
+        let Sp(mut sp) = self;
+        checked!(sp += 1, EvalError::SPOverFlow)?;
+        Ok(Sp(sp))
+    }
+}
+
+impl Display for Sp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+/// 評価器が1つずつ消費する入力の単位。文字、または書記素クラスタ。
+pub(super) trait Symbol: Copy + std::fmt::Debug {
+    /// パターンの文字`c`にマッチするか
+    fn matches(self, c: char, options: &Options) -> bool;
+
+    /// 改行か
+    fn is_newline(self) -> bool;
+}
+
+impl Symbol for char {
+    fn matches(self, c: char, options: &Options) -> bool {
+        options.char_matches(c, self)
+    }
+
+    fn is_newline(self) -> bool {
+        self == '\n'
+    }
+}
+
+/// 書記素クラスタは、1文字からなる場合にのみパターンの文字にマッチする
+impl Symbol for &str {
+    fn matches(self, c: char, options: &Options) -> bool {
+        let mut chars = self.chars();
+        match (chars.next(), chars.next()) {
+            (Some(input), None) => options.char_matches(c, input),
+            _ => false,
+        }
+    }
+
+    /// `"\r\n"`も1つの改行とする
+    fn is_newline(self) -> bool {
+        self.ends_with('\n')
+    }
+}
+
+/// 複数行モードで、`sp`が改行の直後（行の先頭）か。入力の先頭は含まない。
+fn after_newline<S: Symbol>(line: &[S], sp: Sp, options: &Options) -> bool {
+    options.multiline
+        && sp
+            .0
+            .checked_sub(1)
+            .and_then(|prev| line.get(prev))
+            .is_some_and(|s| s.is_newline())
+}
+
+/// `sp`で`$`が成り立つか。入力の末尾、または複数行モードでは改行の直前。
+fn at_end<S: Symbol>(line: &[S], sp: Sp, options: &Options) -> bool {
+    match line.get(sp.0) {
+        None => true,
+        Some(s) => options.multiline && !options.strict_end && s.is_newline(),
+    }
+}
+
+/// 評価器が集計する、1回の評価の指標。
+/// トレースと異なり何も書き出さないので、病的なパターンを見つけるために常に有効にしておける。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MatchMetrics {
+    /// 実行した命令数
+    pub instructions: usize,
+    /// 深さ優先の評価で、積んでおいた分岐に戻った回数
+    pub backtracks: usize,
+    /// 後で試す分岐の数の最大値。幅優先の評価では、次の位置へ進めるスレッドの数の最大値。
+    pub peak_pending: usize,
+    /// 訪問済みの状態をビットマップに記録して、同じ状態を2度評価しないようにしたか
+    pub memoized: bool,
+    /// 1文字目になりうる文字で絞り込み、評価を始める位置を進めたか、評価せずにマッチしないとしたか
+    pub prefiltered: bool,
+}
+
+/// 評価の各ステップを書き出すためのトレーサ。
+/// 書き出し先がない場合は、実行した命令を数えるのみで何も書き出さない。
+pub(super) struct Tracer<'a> {
+    out: Option<&'a mut dyn Write>,
+    /// 実行した命令数
+    steps: usize,
+    /// 指標を集計する先。`None`なら集計しない。
+    metrics: Option<&'a mut MatchMetrics>,
+}
+
+impl<'a> Tracer<'a> {
+    pub(super) fn new(out: &'a mut dyn Write) -> Self {
+        Self {
+            out: Some(out),
+            steps: 0,
+            metrics: None,
+        }
+    }
+
+    pub(super) fn disabled() -> Self {
+        Self {
+            out: None,
+            steps: 0,
+            metrics: None,
+        }
+    }
+
+    /// 評価しながら`metrics`に指標を集計する
+    pub(super) fn with_metrics(self, metrics: Option<&'a mut MatchMetrics>) -> Self {
+        Self { metrics, ..self }
+    }
+
+    /// これまでに実行した命令数
+    pub(super) fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// 実行する命令を1行書き出す
+    fn exec<S: Symbol>(
+        &mut self,
+        inst: &Instruction,
+        line: &[S],
+        pc: Pc,
+        sp: Sp,
+    ) -> Result<(), EvalError> {
+        if let Some(out) = &mut self.out {
+            let c = match line.get(sp.0) {
+                Some(c) => format!("{:?}", c),
+                None => "EOL".to_string(),
+            };
+            writeln!(
+                out,
+                "{:>04}: pc {:>04} | {:<16} | sp {:>04} {}",
+                self.steps,
+                pc,
+                inst.to_string(),
+                sp,
+                c
+            )
+            .map_err(EvalError::Trace)?;
+        }
+        self.steps += 1;
+        if let Some(metrics) = &mut self.metrics {
+            metrics.instructions += 1;
+        }
+        Ok(())
+    }
+
+    /// 積んでおいた分岐に戻った
+    fn backtrack(&mut self, pc: Pc, sp: Sp) -> Result<(), EvalError> {
+        if let Some(metrics) = &mut self.metrics {
+            metrics.backtracks += 1;
+        }
+        self.event("backtrack", pc, sp)
+    }
+
+    /// 後で試す分岐が`pending`個ある
+    fn pending(&mut self, pending: usize) {
+        if let Some(metrics) = &mut self.metrics {
+            metrics.peak_pending = metrics.peak_pending.max(pending);
+        }
+    }
+
+    /// 訪問済みの状態をビットマップに記録して評価する
+    fn memoized(&mut self) {
+        if let Some(metrics) = &mut self.metrics {
+            metrics.memoized = true;
+        }
+    }
+
+    fn event(&mut self, event: &str, pc: Pc, sp: Sp) -> Result<(), EvalError> {
+        if let Some(out) = &mut self.out {
+            writeln!(out, "{}: pc {:>04}, sp {:>04}", event, pc, sp).map_err(EvalError::Trace)?;
+        }
+        Ok(())
+    }
+
+    /// `sp`から評価を始める
+    fn start(&mut self, sp: usize) -> Result<(), EvalError> {
+        if let Some(out) = &mut self.out {
+            writeln!(out, "start: sp {:>04}", sp).map_err(EvalError::Trace)?;
+        }
+        Ok(())
+    }
+
+    fn result(&mut self, result: &EvalResult) -> Result<(), EvalError> {
+        if let Some(out) = &mut self.out {
+            let result = match (result.matched, result.should_be_head) {
+                (false, _) => "unmatched",
+                (true, false) => "matched",
+                (true, true) => "matched if head",
+            };
+            writeln!(out, "result: {}", result).map_err(EvalError::Trace)?;
+        }
+        Ok(())
+    }
+}
+
+/// 実行した命令数を数え、`step_limit`を超えたらエラーを返す
+fn count_step(steps: &mut usize, options: &Options) -> Result<(), EvalError> {
+    *steps = steps.saturating_add(1);
+    match options.step_limit {
+        Some(limit) if *steps > limit => Err(EvalError::StepLimitExceeded { limit }),
+        _ => Ok(()),
+    }
+}
+
+/// `Evaluator::step`で1命令実行した結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// 評価が続いている
+    Running,
+    /// マッチして評価が終了した
+    Matched(EvalResult),
+    /// マッチせずに評価が終了した
+    Failed,
+}
+
+/// バックトラック用のスタックに積む要素
+#[derive(Debug, Clone, Copy)]
+enum Frame {
+    /// まだ試していない分岐の(pc, sp, should_be_head, branch)と、分岐した時点の`Checkpoint`
+    Branch(Pc, Sp, bool, Option<usize>, Checkpoint),
+    /// 分岐に戻るときに元に戻す、`Save`の(スロット, 以前の位置)
+    Restore(usize, Option<usize>),
+}
+
+/// 1命令ずつ実行できる深さ優先（バックトラック）の評価器。
+/// 失敗したら、`Split`で積んでおいた別の分岐に戻って評価を続ける。
+pub struct Evaluator<'a, S: Symbol = char> {
+    inst: &'a [Instruction],
+    line: &'a [S],
+    pc: Pc,
+    sp: Sp,
+    should_be_head: bool,
+    branch: Option<usize>,
+    stack: Vec<Frame>,
+    /// `Save`で記録した位置
+    slots: Vec<Option<usize>>,
+    /// 優先度が最も高いマッチに到達したときの`slots`
+    captures: Option<Vec<Option<usize>>>,
+    /// これまでに見つかったマッチを`EvalResult::merge`でまとめたもの
+    result: EvalResult,
+    finished: Option<StepOutcome>,
+    /// 訪問済みの(pc, sp, should_be_head)を記録するビットマップ。`None`なら記録しない。
+    visited: Option<Vec<u64>>,
+    options: Options,
+    /// 実行した命令数
+    steps: usize,
+    /// `sp`が最後に進んでから現在の経路で実行したpc
+    progress: Progress,
+}
+
+/// pcの集合を表すビットマップ
+#[derive(Debug, Clone)]
+struct PcSet(Vec<u64>);
+
+impl PcSet {
+    fn new(len: usize) -> Self {
+        Self(vec![0; len.div_ceil(64)])
+    }
+
+    /// `pc`を加える。すでに含まれていれば`false`を返す。
+    fn insert(&mut self, Pc(pc): Pc) -> bool {
+        let (word, bit) = (pc / 64, 1 << (pc % 64));
+        let inserted = self.0[word] & bit == 0;
+        self.0[word] |= bit;
+        inserted
+    }
+
+    fn remove(&mut self, Pc(pc): Pc) {
+        self.0[pc / 64] &= !(1 << (pc % 64));
+    }
+}
+
+/// 深さ優先の評価で、`sp`が最後に進んでから現在の経路で実行したpc。
+/// 同じpcを2度実行すれば、入力を消費しないループを辿っているので、その経路は行き止まりとする。
+struct Progress {
+    pcs: PcSet,
+    /// 現在の経路で実行したpc。`None`は`sp`が進んだ位置を表す。
+    /// バックトラックで分岐した時点の集合に戻すために用いる。
+    trail: Vec<Option<Pc>>,
+    /// `trail`のうち`pcs`に含まれる部分の始まり
+    start: usize,
+}
+
+/// 分岐した時点の`Progress`
+#[derive(Debug, Clone, Copy)]
+struct Checkpoint {
+    len: usize,
+}
+
+impl Progress {
+    fn new(len: usize) -> Self {
+        Self {
+            pcs: PcSet::new(len),
+            trail: Vec::new(),
+            start: 0,
+        }
+    }
+
+    /// `pc`を実行する。`sp`が進まないまま同じpcに戻っていれば`false`を返す。
+    fn enter(&mut self, pc: Pc) -> bool {
+        if !self.pcs.insert(pc) {
+            return false;
+        }
+        self.trail.push(Some(pc));
+        true
+    }
+
+    /// `sp`が進んだので、これまでに実行したpcを忘れる
+    fn advance(&mut self) {
+        self.forget();
+        self.trail.push(None);
+        self.start = self.trail.len();
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            len: self.trail.len(),
+        }
+    }
+
+    /// 分岐した時点に戻る。その後に`sp`が進んでいれば、分岐した時点の集合を`trail`から作り直す。
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        if checkpoint.len >= self.start {
+            for pc in self.trail.drain(checkpoint.len..).flatten() {
+                self.pcs.remove(pc);
+            }
+            return;
+        }
+
+        self.forget();
+        self.trail.truncate(checkpoint.len);
+        self.start = self
+            .trail
+            .iter()
+            .rposition(Option::is_none)
+            .map_or(0, |i| i + 1);
+        for pc in self.trail[self.start..].iter().flatten() {
+            self.pcs.insert(*pc);
+        }
+    }
+
+    /// `pcs`を空にする
+    fn forget(&mut self) {
+        for pc in self.trail[self.start..].iter().flatten() {
+            self.pcs.remove(*pc);
+        }
+    }
+}
+
+/// `Engine::Bitstate`で用いるビットマップの最大ビット数
+const BITSTATE_MAX_BITS: usize = 256 * 1024;
+
+/// 状態(pc, sp)の数が`BITSTATE_MAX_BITS`以下であればビットマップのワード数を返す。
+/// `should_be_head`の違いも区別するため、ビットマップには状態数の2倍のビットを使う。
+fn bitstate_words<S>(inst: &[Instruction], line: &[S]) -> Option<usize> {
+    let states = inst.len().checked_mul(line.len().checked_add(1)?)?;
+    if states <= BITSTATE_MAX_BITS {
+        Some((states * 2).div_ceil(64))
+    } else {
+        None
+    }
+}
+
+impl<'a, S: Symbol> Evaluator<'a, S> {
+    pub fn new(inst: &'a [Instruction], line: &'a [S]) -> Self {
+        Self {
+            inst,
+            line,
+            pc: Pc(0),
+            sp: Sp(0),
+            should_be_head: false,
+            branch: None,
+            stack: Vec::new(),
+            slots: Vec::new(),
+            captures: None,
+            result: EvalResult::unmatched(),
+            finished: None,
+            visited: None,
+            options: Options::default(),
+            steps: 0,
+            progress: Progress::new(inst.len()),
+        }
+    }
+
+    pub fn with_options(self, options: &Options) -> Self {
+        Self {
+            options: *options,
+            ..self
+        }
+    }
+
+    /// `line`の`start`文字目から評価を始める。`Head`は`line`の先頭でのみ成り立つ。
+    pub fn with_start(self, start: usize) -> Self {
+        Self {
+            sp: Sp(start),
+            ..self
+        }
+    }
+
+    /// 0番目ではなく`entry`番目の命令から評価を始める
+    pub fn with_entry(self, entry: Pc) -> Self {
+        Self { pc: entry, ..self }
+    }
+
+    /// 同じ状態を2度評価しない評価器を作る。ビットマップが大きくなりすぎる場合は`None`を返す。
+    pub fn with_bitstate(inst: &'a [Instruction], line: &'a [S]) -> Option<Self> {
+        let words = bitstate_words(inst, line)?;
+        Some(Self {
+            visited: Some(vec![0; words]),
+            ..Self::new(inst, line)
+        })
+    }
+
+    pub fn pc(&self) -> Pc {
+        self.pc
+    }
+
+    pub fn sp(&self) -> Sp {
+        self.sp
+    }
+
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// バックトラック用のスタックに積む
+    fn push(&mut self, frame: Frame) -> Result<(), EvalError> {
+        if self.stack.len() >= self.options.backtrack_limit {
+            return Err(EvalError::BacktrackLimitExceeded {
+                limit: self.options.backtrack_limit,
+            });
+        }
+        self.stack.push(frame);
+        Ok(())
+    }
+
+    /// 現在の命令を1つ実行する。評価が終了した後は同じ結果を返し続ける。
+    pub fn step(&mut self) -> Result<StepOutcome, EvalError> {
+        if let Some(outcome) = self.finished {
+            return Ok(outcome);
+        }
+
+        let next = if let Some(i) = self.inst.get(self.pc.0) {
             i
         } else {
             return Err(EvalError::InvalidPC);
         };
+        count_step(&mut self.steps, &self.options)?;
+        // ビットマップを使えば同じ状態を2度評価しないので、ループしても停止する。
+        // 入力を消費せずに同じpcへ戻った経路は、それ以上辿っても新たなマッチはないので諦める。
+        if self.visited.is_none() && !self.progress.enter(self.pc) {
+            return Ok(self.backtrack());
+        }
+
+        if self.check_visited() {
+            // この状態から先はすでに評価済み
+            return Ok(self.backtrack());
+        }
 
         match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        return Ok(EvalResult::unmatched());
-                    }
-                } else {
-                    return Ok(EvalResult::unmatched());
+            Instruction::Char(c) => match self.line.get(self.sp.0) {
+                Some(input) if input.matches(*c, &self.options) => {
+                    self.pc = self.pc.incr()?;
+                    self.sp = self.sp.incr()?;
+                    self.progress.advance();
                 }
-            }
+                _ => return Ok(self.backtrack()),
+            },
             Instruction::AnyChar => {
-                if line.get(sp).is_some() {
-                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                    safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+                if self.line.get(self.sp.0).is_some() {
+                    self.pc = self.pc.incr()?;
+                    self.sp = self.sp.incr()?;
+                    self.progress.advance();
                 } else {
-                    return Ok(EvalResult::unmatched());
+                    return Ok(self.backtrack());
                 }
             }
+            Instruction::AnyCharExceptNewline => match self.line.get(self.sp.0) {
+                Some(input) if !input.is_newline() => {
+                    self.pc = self.pc.incr()?;
+                    self.sp = self.sp.incr()?;
+                    self.progress.advance();
+                }
+                _ => return Ok(self.backtrack()),
+            },
             Instruction::Head => {
-                if sp != 0 {
-                    return Ok(EvalResult::unmatched());
+                if self.sp == Sp(0) {
+                    self.should_be_head = true;
+                    self.pc = self.pc.incr()?;
+                } else if after_newline(self.line, self.sp, &self.options) {
+                    // 改行の直後は評価を始めた位置によらず行の先頭なので、先頭でのみ成り立つマッチとはしない
+                    self.pc = self.pc.incr()?;
                 } else {
-                    should_be_head = true;
-                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    return Ok(self.backtrack());
                 }
             }
-            Instruction::Match => {
-                return if should_be_head {
-                    Ok(EvalResult::matched_if_head())
-                } else {
-                    Ok(EvalResult::matched())
-                };
-            }
+            Instruction::Match | Instruction::MatchId(_) => return Ok(self.accept()),
             Instruction::MatchEnd => {
-                let is_end = line.get(sp).is_none();
-
-                if !is_end {
-                    return Ok(EvalResult::unmatched());
-                }
-
-                return if should_be_head {
-                    Ok(EvalResult::matched_if_head())
+                if at_end(self.line, self.sp, &self.options) {
+                    return Ok(self.accept());
                 } else {
-                    Ok(EvalResult::matched())
-                };
+                    return Ok(self.backtrack());
+                }
             }
             Instruction::Jump(addr) => {
-                pc = *addr;
+                self.pc = *addr;
             }
             Instruction::Split(addr1, addr2) => {
-                return Ok(
-                    eval_depth(inst, line, *addr1, sp)?.merge(&eval_depth(inst, line, *addr2, sp)?)
-                );
+                self.push(Frame::Branch(
+                    *addr2,
+                    self.sp,
+                    self.should_be_head,
+                    self.branch,
+                    self.progress.checkpoint(),
+                ))?;
+                self.pc = *addr1;
+            }
+            Instruction::Mark(b) => {
+                self.branch = Some(*b);
+                self.pc = self.pc.incr()?;
+            }
+            Instruction::Save(slot) => {
+                if self.slots.len() <= *slot {
+                    self.slots.resize(*slot + 1, None);
+                }
+                self.push(Frame::Restore(*slot, self.slots[*slot]))?;
+                self.slots[*slot] = Some(self.sp.0);
+                self.pc = self.pc.incr()?;
             }
         }
+
+        Ok(StepOutcome::Running)
+    }
+
+    /// 現在の状態が訪問済みかを返し、訪問済みとして記録する
+    fn check_visited(&mut self) -> bool {
+        if let Some(visited) = &mut self.visited {
+            let state =
+                (self.pc.0 * (self.line.len() + 1) + self.sp.0) * 2 + self.should_be_head as usize;
+            let (word, bit) = (state / 64, 1 << (state % 64));
+            if visited[word] & bit != 0 {
+                return true;
+            }
+            visited[word] |= bit;
+        }
+        false
+    }
+
+    /// マッチに到達した。先頭でのみ成り立つマッチであれば、
+    /// 先頭以外でも成り立つマッチがないか残りの分岐を探し続ける。
+    fn accept(&mut self) -> StepOutcome {
+        let result = if self.should_be_head {
+            EvalResult::matched_if_head(self.sp.0)
+        } else {
+            EvalResult::matched(self.sp.0)
+        };
+        if !self.result.matched {
+            self.captures = Some(self.slots.clone());
+        }
+        self.result = self.result.merge(&result.with_branch(self.branch));
+
+        if self.should_be_head {
+            self.backtrack()
+        } else {
+            self.finish(StepOutcome::Matched(self.result))
+        }
+    }
+
+    /// 積んでおいた分岐に戻る。戻る先がなければ評価を終了する。
+    fn backtrack(&mut self) -> StepOutcome {
+        while let Some(Frame::Restore(slot, pos)) = self.stack.last() {
+            self.slots[*slot] = *pos;
+            self.stack.pop();
+        }
+
+        if let Some(Frame::Branch(pc, sp, should_be_head, branch, checkpoint)) = self.stack.pop() {
+            self.progress.restore(checkpoint);
+            self.pc = pc;
+            self.sp = sp;
+            self.should_be_head = should_be_head;
+            self.branch = branch;
+            StepOutcome::Running
+        } else if self.result.matched {
+            self.finish(StepOutcome::Matched(self.result))
+        } else {
+            self.finish(StepOutcome::Failed)
+        }
+    }
+
+    fn finish(&mut self, outcome: StepOutcome) -> StepOutcome {
+        self.finished = Some(outcome);
+        outcome
     }
 }
 
-fn pop_ctx(
-    pc: &mut usize,
-    sp: &mut usize,
-    should_be_head: &mut bool,
-    ctx: &mut VecDeque<(usize, usize, bool)>,
-) -> Result<(), EvalError> {
-    if let Some((p, s, sh)) = ctx.pop_front() {
-        *pc = p;
-        *sp = s;
-        *should_be_head = sh;
-        Ok(())
-    } else {
-        Err(EvalError::InvalidContext)
+fn eval_depth<S: Symbol>(
+    inst: &[Instruction],
+    entry: Pc,
+    line: &[S],
+    start: usize,
+    options: &Options,
+    tracer: &mut Tracer,
+) -> Result<EvalResult, EvalError> {
+    let evaluator = Evaluator::new(inst, line)
+        .with_options(options)
+        .with_start(start)
+        .with_entry(entry);
+    run(evaluator, tracer)
+}
+
+fn eval_bitstate<S: Symbol>(
+    inst: &[Instruction],
+    entry: Pc,
+    line: &[S],
+    start: usize,
+    options: &Options,
+    tracer: &mut Tracer,
+) -> Result<EvalResult, EvalError> {
+    match Evaluator::with_bitstate(inst, line) {
+        Some(evaluator) => {
+            tracer.memoized();
+            let evaluator = evaluator.with_options(options).with_start(start);
+            run(evaluator.with_entry(entry), tracer)
+        }
+        None => eval_depth(inst, entry, line, start, options, tracer),
     }
 }
 
-fn eval_width(inst: &[Instruction], line: &[char]) -> Result<EvalResult, EvalError> {
-    let mut ctx = VecDeque::new();
-    let mut pc = 0;
-    let mut sp = 0;
-    let mut shuould_be_head = false;
+/// 評価が終了するまで`Evaluator::step`を繰り返す
+fn run<S: Symbol>(
+    mut evaluator: Evaluator<S>,
+    tracer: &mut Tracer,
+) -> Result<EvalResult, EvalError> {
+    let (inst, line) = (evaluator.inst, evaluator.line);
 
     loop {
-        let next = if let Some(i) = inst.get(pc) {
-            i
-        } else {
-            return Err(EvalError::InvalidPC);
-        };
+        if let Some(next) = inst.get(evaluator.pc().0) {
+            tracer.exec(next, line, evaluator.pc(), evaluator.sp())?;
+        }
 
-        match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        if ctx.is_empty() {
-                            return Ok(EvalResult::unmatched());
-                        } else {
-                            pop_ctx(&mut pc, &mut sp, &mut shuould_be_head, &mut ctx)?;
-                        }
-                    }
+        let depth = evaluator.stack_depth();
+        match evaluator.step()? {
+            StepOutcome::Running => {
+                if evaluator.stack_depth() < depth {
+                    tracer.backtrack(evaluator.pc(), evaluator.sp())?;
                 } else {
-                    if ctx.is_empty() {
-                        return Ok(EvalResult::unmatched());
-                    } else {
-                        pop_ctx(&mut pc, &mut sp, &mut shuould_be_head, &mut ctx)?;
-                    }
+                    tracer.pending(evaluator.stack_depth());
                 }
             }
-            Instruction::AnyChar => {
-                if line.get(sp).is_some() {
-                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                    safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                } else {
-                    if ctx.is_empty() {
-                        return Ok(EvalResult::unmatched());
-                    } else {
-                        pop_ctx(&mut pc, &mut sp, &mut shuould_be_head, &mut ctx)?;
+            StepOutcome::Matched(result) => return Ok(result),
+            StepOutcome::Failed => return Ok(EvalResult::unmatched()),
+        }
+    }
+}
+
+/// `line`の`start`文字目から深さ優先で評価し、優先度が最も高いマッチでの`Save`の各スロットの位置を返す。
+/// マッチしなければ`None`を返す。
+pub(super) fn eval_captures<S: Symbol>(
+    inst: &[Instruction],
+    line: &[S],
+    start: usize,
+    options: &Options,
+) -> Result<Option<Vec<Option<usize>>>, EvalError> {
+    let mut evaluator = Evaluator::new(inst, line)
+        .with_options(options)
+        .with_start(start);
+
+    loop {
+        match evaluator.step()? {
+            StepOutcome::Running => {}
+            StepOutcome::Matched(_) => return Ok(evaluator.captures),
+            StepOutcome::Failed => return Ok(None),
+        }
+    }
+}
+
+/// 幅優先の評価で、入力を1文字ずつ同時に進めるスレッド
+#[derive(Debug, Clone, Copy)]
+struct Thread {
+    pc: Pc,
+    should_be_head: bool,
+    branch: Option<usize>,
+    /// より優先度の高いスレッドがすでにマッチしている。
+    /// このスレッドのマッチは`should_be_head`の判定にのみ使う。
+    outranked: bool,
+}
+
+/// スレッドを優先度順に並べて進める幅優先の評価器
+struct WidthEvaluator<'a, S> {
+    inst: &'a [Instruction],
+    line: &'a [S],
+    /// 現在の位置で追加済みの(pc, should_be_head)
+    visited: Vec<bool>,
+    options: Options,
+    /// 優先度が最も高いマッチ
+    result: Option<EvalResult>,
+    /// `Head`を通らない経路でマッチしたことがあるか
+    unconditional: bool,
+    /// 現在の位置で、優先度の高いスレッドがマッチしたか
+    cut: bool,
+    /// 実行した命令数
+    steps: usize,
+    /// 最初のスレッドを始める命令
+    entry: Pc,
+    /// いずれかのスレッドがマッチした位置で評価を打ち切る
+    shortest: bool,
+    /// `Some`であれば、優先度によらずいずれかのスレッドがマッチした位置をすべて昇順に記録し、
+    /// 最も優先度の高いマッチが決まっても評価を打ち切らない
+    ends: Option<Vec<usize>>,
+}
+
+impl<'a, S: Symbol> WidthEvaluator<'a, S> {
+    fn new(inst: &'a [Instruction], line: &'a [S], options: &Options) -> Self {
+        Self {
+            inst,
+            line,
+            visited: vec![false; inst.len() * 2],
+            options: *options,
+            result: None,
+            unconditional: false,
+            cut: false,
+            steps: 0,
+            entry: Pc(0),
+            shortest: false,
+            ends: None,
+        }
+    }
+
+    /// `thread`から入力を消費せずに到達できるスレッドを、優先度の高い順に`list`に追加する
+    fn add_thread(
+        &mut self,
+        sp: Sp,
+        thread: Thread,
+        list: &mut Vec<Thread>,
+        tracer: &mut Tracer,
+    ) -> Result<(), EvalError> {
+        let mut stack = vec![thread];
+
+        while let Some(mut thread) = stack.pop() {
+            let next = self.inst.get(thread.pc.0).ok_or(EvalError::InvalidPC)?;
+            // 合流したスレッドや、入力を消費せずに戻ってきたスレッドは追加済みとして飛ばす
+            let state = thread.pc.0 * 2 + thread.should_be_head as usize;
+            if self.visited[state] {
+                continue;
+            }
+            self.visited[state] = true;
+            count_step(&mut self.steps, &self.options)?;
+            if self.cut {
+                thread.outranked = true;
+            }
+
+            // 文字を消費する命令は次の位置へ進めるときに実行する
+            if !matches!(
+                next,
+                Instruction::Char(_) | Instruction::AnyChar | Instruction::AnyCharExceptNewline
+            ) {
+                tracer.exec(next, self.line, thread.pc, sp)?;
+            }
+
+            match next {
+                Instruction::Char(_) | Instruction::AnyChar | Instruction::AnyCharExceptNewline => {
+                    if list.len() >= self.options.queue_limit {
+                        return Err(EvalError::QueueLimitExceeded {
+                            limit: self.options.queue_limit,
+                        });
                     }
+                    list.push(thread);
                 }
-            }
-            Instruction::Head => {
-                if sp != 0 {
-                    if ctx.is_empty() {
-                        return Ok(EvalResult::unmatched());
-                    } else {
-                        pop_ctx(&mut pc, &mut sp, &mut shuould_be_head, &mut ctx)?;
+                Instruction::Match | Instruction::MatchId(_) => self.accept(sp, &thread),
+                Instruction::MatchEnd => {
+                    if at_end(self.line, sp, &self.options) {
+                        self.accept(sp, &thread);
+                    }
+                }
+                Instruction::Head => {
+                    if sp == Sp(0) {
+                        thread.should_be_head = true;
+                        thread.pc = thread.pc.incr()?;
+                        stack.push(thread);
+                    } else if after_newline(self.line, sp, &self.options) {
+                        thread.pc = thread.pc.incr()?;
+                        stack.push(thread);
                     }
-                } else {
-                    shuould_be_head = true;
-                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::Mark(b) => {
+                    thread.branch = Some(*b);
+                    thread.pc = thread.pc.incr()?;
+                    stack.push(thread);
+                }
+                Instruction::Save(_) => {
+                    thread.pc = thread.pc.incr()?;
+                    stack.push(thread);
+                }
+                Instruction::Jump(addr) => {
+                    thread.pc = *addr;
+                    stack.push(thread);
+                }
+                Instruction::Split(addr1, addr2) => {
+                    // addr1を先に辿るため後に積む
+                    stack.push(Thread {
+                        pc: *addr2,
+                        ..thread
+                    });
+                    stack.push(Thread {
+                        pc: *addr1,
+                        ..thread
+                    });
                 }
             }
-            Instruction::Match => {
-                return if shuould_be_head {
-                    Ok(EvalResult::matched_if_head())
-                } else {
-                    Ok(EvalResult::matched())
-                };
+        }
+
+        Ok(())
+    }
+
+    /// マッチに到達した。優先度の高いスレッドであれば結果を置き換え、
+    /// 以降に追加するスレッドはすべて優先度が低いものとする。
+    fn accept(&mut self, sp: Sp, thread: &Thread) {
+        if let Some(ends) = &mut self.ends {
+            if ends.last() != Some(&sp.0) {
+                ends.push(sp.0);
             }
-            Instruction::MatchEnd => {
-                let is_end = line.get(sp).is_none();
+        }
+        if !thread.should_be_head {
+            self.unconditional = true;
+        }
+        if !thread.outranked {
+            let result = if thread.should_be_head {
+                EvalResult::matched_if_head(sp.0)
+            } else {
+                EvalResult::matched(sp.0)
+            };
+            self.result = Some(result.with_branch(thread.branch));
+            self.cut = true;
+        }
+    }
+}
 
-                if !is_end {
-                    if ctx.is_empty() {
-                        return Ok(EvalResult::unmatched());
-                    } else {
-                        pop_ctx(&mut pc, &mut sp, &mut shuould_be_head, &mut ctx)?;
-                    }
-                } else {
-                    return if shuould_be_head {
-                        Ok(EvalResult::matched_if_head())
-                    } else {
-                        Ok(EvalResult::matched())
-                    };
+/// Pike VMと同様に、すべてのスレッドを入力1文字ずつ同時に進める。
+/// スレッドは`Split`の第1オペランドを優先した順に並べ、最も優先度の高いマッチを返すので、
+/// 深さ優先（バックトラック）と同じマッチが得られる。
+fn eval_width<S: Symbol>(
+    inst: &[Instruction],
+    entry: Pc,
+    line: &[S],
+    start: usize,
+    options: &Options,
+    tracer: &mut Tracer,
+) -> Result<EvalResult, EvalError> {
+    let mut evaluator = WidthEvaluator {
+        entry,
+        ..WidthEvaluator::new(inst, line, options)
+    };
+    run_width(&mut evaluator, start, tracer)
+}
+
+/// `line`の`start`文字目から始まるマッチのうち、最も早く終わるものの終了位置を返す。
+/// 幅優先ではすべてのスレッドが同じ位置にあるので、いずれかのスレッドがマッチした時点で打ち切ればよい。
+/// 深さ優先では分岐の優先度の順に経路を辿るため最短のマッチを先に見つけられるとは限らず、
+/// `options.engine`によらず幅優先で評価する。
+pub(super) fn eval_shortest<S: Symbol>(
+    inst: &[Instruction],
+    line: &[S],
+    start: usize,
+    options: &Options,
+) -> Result<Option<usize>, EvalError> {
+    let mut evaluator = WidthEvaluator {
+        shortest: true,
+        ..WidthEvaluator::new(inst, line, options)
+    };
+    let result = run_width(&mut evaluator, start, &mut Tracer::disabled())?;
+    Ok(result.matched.then_some(result.end))
+}
+
+/// `line`の`start`文字目から始まるすべてのマッチの終了位置を、昇順に返す。
+/// 優先度の低い経路のマッチも含めるので、`options.engine`によらず幅優先で評価する。
+pub(super) fn eval_ends<S: Symbol>(
+    inst: &[Instruction],
+    line: &[S],
+    start: usize,
+    options: &Options,
+) -> Result<Vec<usize>, EvalError> {
+    let mut evaluator = WidthEvaluator {
+        ends: Some(Vec::new()),
+        ..WidthEvaluator::new(inst, line, options)
+    };
+    run_width(&mut evaluator, start, &mut Tracer::disabled())?;
+    Ok(evaluator.ends.unwrap_or_default())
+}
+
+fn run_width<S: Symbol>(
+    evaluator: &mut WidthEvaluator<S>,
+    start: usize,
+    tracer: &mut Tracer,
+) -> Result<EvalResult, EvalError> {
+    let (inst, line, options) = (evaluator.inst, evaluator.line, evaluator.options);
+
+    let thread = Thread {
+        pc: evaluator.entry,
+        should_be_head: false,
+        branch: None,
+        outranked: false,
+    };
+    let mut clist = Vec::new();
+    evaluator.add_thread(Sp(start), thread, &mut clist, tracer)?;
+    tracer.pending(clist.len());
+
+    let mut sp = Sp(start);
+    while !clist.is_empty() {
+        if evaluator.ends.is_none()
+            && evaluator.unconditional
+            && clist.iter().all(|thread| thread.outranked)
+        {
+            // これ以上結果は変わらない
+            break;
+        }
+        if evaluator.shortest && evaluator.result.is_some() {
+            break;
+        }
+
+        let next_sp = sp.incr()?;
+
+        let mut nlist = Vec::new();
+        evaluator.visited.fill(false);
+        evaluator.cut = false;
+        for mut thread in clist {
+            let next = &inst[thread.pc.0];
+            tracer.exec(next, line, thread.pc, sp)?;
+            count_step(&mut evaluator.steps, &options)?;
+
+            let consumed = match (next, line.get(sp.0)) {
+                (Instruction::Char(c), Some(sp_c)) => sp_c.matches(*c, &options),
+                (Instruction::AnyChar, Some(_)) => true,
+                (Instruction::AnyCharExceptNewline, Some(sp_c)) => !sp_c.is_newline(),
+                _ => false,
+            };
+            if consumed {
+                thread.pc = thread.pc.incr()?;
+                evaluator.add_thread(next_sp, thread, &mut nlist, tracer)?;
+            }
+        }
+
+        tracer.pending(nlist.len());
+        clist = nlist;
+        sp = next_sp;
+    }
+
+    Ok(match evaluator.result {
+        // 先頭でのみ成り立つのは、マッチしたすべての経路が`Head`を通る場合
+        Some(result) if evaluator.unconditional => EvalResult {
+            should_be_head: false,
+            ..result
+        },
+        Some(result) => result,
+        None => EvalResult::unmatched(),
+    })
+}
+
+/// 複数のプログラムのアドレスを付け替えて1つに連結する。
+/// 各プログラムの`Match`は`MatchId(プログラムの番号)`に置き換える。
+/// 戻り値は連結したプログラムと、各プログラムの開始アドレス。
+pub(super) fn link_programs(
+    programs: &[&[Instruction]],
+) -> Result<(Vec<Instruction>, Vec<Pc>), EvalError> {
+    let mut linked = Vec::new();
+    let mut starts = Vec::new();
+
+    for (id, program) in programs.iter().enumerate() {
+        let offset = linked.len();
+        starts.push(Pc(offset));
+
+        let relocate = |Pc(mut addr): Pc| -> Result<Pc, EvalError> {
+            checked!(addr += offset, EvalError::PCOverFlow)?;
+            Ok(Pc(addr))
+        };
+        for inst in program.iter() {
+            let inst = match inst {
+                Instruction::Char(c) => Instruction::Char(*c),
+                Instruction::AnyChar => Instruction::AnyChar,
+                Instruction::AnyCharExceptNewline => Instruction::AnyCharExceptNewline,
+                Instruction::Match | Instruction::MatchId(_) => Instruction::MatchId(id),
+                Instruction::Jump(addr) => Instruction::Jump(relocate(*addr)?),
+                Instruction::Split(addr1, addr2) => {
+                    Instruction::Split(relocate(*addr1)?, relocate(*addr2)?)
                 }
+                Instruction::Head => Instruction::Head,
+                Instruction::MatchEnd => Instruction::MatchEnd,
+                Instruction::Mark(branch) => Instruction::Mark(*branch),
+                Instruction::Save(slot) => Instruction::Save(*slot),
+            };
+            linked.push(inst);
+        }
+    }
+
+    Ok((linked, starts))
+}
+
+/// 連結したプログラムを入力1文字ずつ同時に進めるための状態
+struct SetEvaluator<'a> {
+    inst: &'a [Instruction],
+    starts: &'a [Pc],
+    /// プログラムごとの設定
+    options: &'a [Options],
+    line: &'a [char],
+    visited: Vec<bool>,
+    matched: Vec<bool>,
+}
+
+impl SetEvaluator<'_> {
+    /// `pc`から入力を消費せずに到達できる命令を`list`に追加する。
+    /// 途中で到達した`MatchId`、および入力の終端での`MatchEnd`は`matched`に記録する。
+    fn add_thread(&mut self, sp: Sp, pc: Pc, list: &mut Vec<Pc>) -> Result<(), EvalError> {
+        let mut stack = vec![pc];
+
+        while let Some(pc) = stack.pop() {
+            if *self.visited.get(pc.0).ok_or(EvalError::InvalidPC)? {
+                continue;
             }
-            Instruction::Jump(addr) => {
-                pc = *addr;
+            self.visited[pc.0] = true;
+
+            match &self.inst[pc.0] {
+                Instruction::Char(_) | Instruction::AnyChar | Instruction::AnyCharExceptNewline => {
+                    list.push(pc)
+                }
+                Instruction::Match => return Err(EvalError::InvalidPC),
+                Instruction::MatchId(id) => self.matched[*id] = true,
+                Instruction::MatchEnd => {
+                    // MatchEndはidを持たないので、属するプログラムを開始アドレスから求める
+                    let id = self.program_id(pc);
+                    if at_end(self.line, sp, &self.options[id]) {
+                        self.matched[id] = true;
+                    }
+                }
+                Instruction::Head => {
+                    if sp == Sp(0)
+                        || after_newline(self.line, sp, &self.options[self.program_id(pc)])
+                    {
+                        stack.push(pc.incr()?);
+                    }
+                }
+                Instruction::Mark(_) | Instruction::Save(_) => stack.push(pc.incr()?),
+                Instruction::Jump(addr) => stack.push(*addr),
+                Instruction::Split(addr1, addr2) => {
+                    // addr1を先に辿るため後に積む
+                    stack.push(*addr2);
+                    stack.push(*addr1);
+                }
             }
-            Instruction::Split(addr1, addr2) => {
-                pc = *addr1;
-                ctx.push_back((*addr2, sp, shuould_be_head));
-                continue;
+        }
+
+        Ok(())
+    }
+
+    /// `pc`の命令が属するプログラムの番号
+    fn program_id(&self, pc: Pc) -> usize {
+        self.starts.partition_point(|start| *start <= pc) - 1
+    }
+}
+
+/// 複数のプログラムを1つの入力に対して同時に評価し、それぞれがマッチしたかを返す。
+/// プログラムを連結し、各プログラムの先頭をスレッドとして入力を1度だけ走査する。
+pub(super) fn eval_set(programs: &[&[Instruction]], line: &[char]) -> Result<Vec<bool>, EvalError> {
+    let (inst, starts) = link_programs(programs)?;
+    let options = vec![Options::default(); programs.len()];
+    eval_linked(&inst, &starts, &options, line)
+}
+
+/// `link_programs`で連結したプログラムを評価し、それぞれがマッチしたかを返す。
+/// `options`はプログラムごとの設定で、大文字小文字の区別に用いる。
+pub(super) fn eval_linked(
+    inst: &[Instruction],
+    starts: &[Pc],
+    options: &[Options],
+    line: &[char],
+) -> Result<Vec<bool>, EvalError> {
+    let mut evaluator = SetEvaluator {
+        visited: vec![false; inst.len()],
+        matched: vec![false; starts.len()],
+        inst,
+        starts,
+        options,
+        line,
+    };
+
+    let mut clist = Vec::new();
+    for &start in starts {
+        if start.0 < inst.len() {
+            evaluator.add_thread(Sp(0), start, &mut clist)?;
+        }
+    }
+
+    let mut sp = Sp(0);
+    while !clist.is_empty() && !evaluator.matched.iter().all(|m| *m) {
+        let next_sp = sp.incr()?;
+
+        let mut nlist = Vec::new();
+        evaluator.visited.fill(false);
+        for pc in clist {
+            let consumed = match (&evaluator.inst[pc.0], line.get(sp.0)) {
+                (Instruction::Char(c), Some(sp_c)) => {
+                    evaluator.options[evaluator.program_id(pc)].char_matches(*c, *sp_c)
+                }
+                (Instruction::AnyChar, Some(_)) => true,
+                (Instruction::AnyCharExceptNewline, Some(sp_c)) => *sp_c != '\n',
+                _ => false,
+            };
+            if consumed {
+                evaluator.add_thread(next_sp, pc.incr()?, &mut nlist)?;
             }
         }
 
-        // if !ctx.is_empty() {
-        //     ctx.push_back((pc, sp, shuould_be_head));
-        //     pop_ctx(&mut pc, &mut sp, &mut shuould_be_head, &mut ctx)?;
-        // }
+        clist = nlist;
+        sp = next_sp;
     }
+
+    Ok(evaluator.matched)
 }
 
+/// `line`の先頭からデフォルトの設定でマッチを試みる
+#[cfg(test)]
 pub(super) fn eval(
     inst: &[Instruction],
     line: &[char],
-    is_depth: bool,
+    engine: Engine,
 ) -> Result<EvalResult, EvalError> {
-    if is_depth {
-        eval_depth(inst, line, 0, 0)
-    } else {
-        eval_width(inst, line)
-    }
+    let options = Options {
+        engine,
+        ..Default::default()
+    };
+    eval_at(inst, line, 0, &options)
+}
+
+/// `line`の`start`文字目からマッチを試みる。
+/// マッチの終了位置も`line`の先頭からの文字数で表す。
+/// 書記素クラスタごとに区切った入力であれば、位置はクラスタの数で表す。
+pub(super) fn eval_at<S: Symbol>(
+    inst: &[Instruction],
+    line: &[S],
+    start: usize,
+    options: &Options,
+) -> Result<EvalResult, EvalError> {
+    eval_with(inst, line, start, options, &mut Tracer::disabled())
+}
+
+pub(super) fn eval_with<S: Symbol>(
+    inst: &[Instruction],
+    line: &[S],
+    start: usize,
+    options: &Options,
+    tracer: &mut Tracer,
+) -> Result<EvalResult, EvalError> {
+    eval_from(inst, Pc(0), line, start, options, tracer)
+}
+
+/// `eval_with`と同様だが、0番目ではなく`entry`番目の命令から評価を始める。
+/// 1つのプログラムに複数の開始位置を持たせ、共有するために用いる。
+pub(super) fn eval_from<S: Symbol>(
+    inst: &[Instruction],
+    entry: Pc,
+    line: &[S],
+    start: usize,
+    options: &Options,
+    tracer: &mut Tracer,
+) -> Result<EvalResult, EvalError> {
+    tracer.start(start)?;
+    let result = match options.engine {
+        Engine::Depth => eval_depth(inst, entry, line, start, options, tracer)?,
+        Engine::Width => eval_width(inst, entry, line, start, options, tracer)?,
+        Engine::Bitstate => eval_bitstate(inst, entry, line, start, options, tracer)?,
+    };
+    tracer.result(&result)?;
+    Ok(result)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::codegen::get_code;
+    use crate::engine::parser::parse;
     use crate::engine::EvalResult;
     use crate::engine::Instruction::*;
+    use crate::helper::{DynError, Op};
 
     #[test]
     fn test_eval() -> Result<(), EvalError> {
         macro_rules! assert_eval_result {
             ($inst:expr, $line:expr, $result:expr) => {
-                assert_eq!(eval(&$inst, &$line, true)?, $result);
-                assert_eq!(eval(&$inst, &$line, false)?, $result);
+                assert_eq!(eval(&$inst, &$line, Engine::Depth)?, $result);
+                assert_eq!(eval(&$inst, &$line, Engine::Width)?, $result);
+                assert_eq!(eval(&$inst, &$line, Engine::Bitstate)?, $result);
             };
         }
 
         assert_eval_result!(
             [Char('a'), Char('b'), Char('c'), Match,],
             ['a', 'b', 'c'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
         assert_eval_result!(
             [Char('a'), Char('b'), Char('c'), Match,],
             ['a', 'b', 'c', 'd'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
-        assert_eval_result!([Match], [], EvalResult::matched());
+        assert_eval_result!([Match], [], EvalResult::matched(0));
         assert_eval_result!([Char('b')], ['a'], EvalResult::unmatched());
-        assert_eval_result!([Jump(2), Char('a'), Match], ['b'], EvalResult::matched());
+        assert_eval_result!(
+            [Jump(Pc(2)), Char('a'), Match],
+            ['b'],
+            EvalResult::matched(0)
+        );
         assert_eval_result!(
             [Char('a'), AnyChar, Char('b'), Match,],
             ['a', 'b'],
@@ -260,47 +1300,47 @@ mod tests {
         assert_eval_result!(
             [Char('a'), AnyChar, Char('b'), Match,],
             ['a', 'a', 'b'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
         assert_eval_result!(
             [Char('a'), AnyChar, Char('b'), Match,],
             ['a', 'b', 'b'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
         assert_eval_result!(
             [Char('a'), AnyChar, Char('b'), Match,],
             ['a', 'c', 'b'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
         assert_eval_result!(
             [Char('a'), AnyChar, Char('b'), Match,],
             ['a', 'あ', 'b'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
         assert_eval_result!(
             [Char('a'), AnyChar, Char('b'), Match,],
             ['a', '𐂂', 'b'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
         assert_eval_result!(
             [Char('a'), AnyChar, Char('b'), Match,],
             ['a', '💥', 'b'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
         assert_eval_result!(
-            [Char('a'), Split(2, 4), Char('b'), Char('c'), Match,],
+            [Char('a'), Split(Pc(2), Pc(4)), Char('b'), Char('c'), Match,],
             ['a', 'b', 'c'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
         assert_eval_result!(
-            [Char('a'), Split(2, 4), Char('b'), Char('c'), Match,],
+            [Char('a'), Split(Pc(2), Pc(4)), Char('b'), Char('c'), Match,],
             ['a'],
-            EvalResult::matched()
+            EvalResult::matched(1)
         );
         assert_eval_result!(
             [Head, Char('a'), Char('b'), Match],
             ['a', 'b'],
-            EvalResult::matched_if_head()
+            EvalResult::matched_if_head(2)
         );
         assert_eval_result!(
             [Char('a'), Head, Char('b'), Match],
@@ -309,59 +1349,59 @@ mod tests {
         );
         assert_eval_result!(
             [
-                Split(1, 1), // 0:
-                Head,        // 1:
-                Char('a'),   // 2:
-                Jump(6),     // 3:
-                Char('b'),   // 4:
-                Char('c'),   // 5:
-                Match,       // 6:
+                Split(Pc(1), Pc(1)), // 0:
+                Head,                // 1:
+                Char('a'),           // 2:
+                Jump(Pc(6)),         // 3:
+                Char('b'),           // 4:
+                Char('c'),           // 5:
+                Match,               // 6:
             ],
             ['a'],
-            EvalResult::matched_if_head()
+            EvalResult::matched_if_head(1)
         );
         assert_eval_result!(
             [
-                Split(1, 4), // 0:
-                Head,        // 1:
-                Char('a'),   // 2:
-                Jump(6),     // 3:
-                Char('b'),   // 4:
-                Char('c'),   // 5:
-                Match,       // 6:
+                Split(Pc(1), Pc(4)), // 0:
+                Head,                // 1:
+                Char('a'),           // 2:
+                Jump(Pc(6)),         // 3:
+                Char('b'),           // 4:
+                Char('c'),           // 5:
+                Match,               // 6:
             ],
             ['b', 'c'],
-            EvalResult::matched()
+            EvalResult::matched(2)
         );
         assert_eval_result!(
             [
-                Char('a'),   // 0:
-                Split(2, 5), // 1:
-                Head,        // 2:
-                Char('b'),   // 3:
-                Jump(6),     // 4:
-                Char('d'),   // 5:
-                Char('e'),   // 6:
-                Match,       // 7:
+                Char('a'),           // 0:
+                Split(Pc(2), Pc(5)), // 1:
+                Head,                // 2:
+                Char('b'),           // 3:
+                Jump(Pc(6)),         // 4:
+                Char('d'),           // 5:
+                Char('e'),           // 6:
+                Match,               // 7:
             ],
             ['a', 'b'],
             EvalResult::unmatched()
         );
         assert_eval_result!(
             [
-                Char('a'),   // 0:
-                Split(2, 5), // 1:
-                Head,        // 2:
-                Char('b'),   // 3:
-                Jump(7),     // 4:
-                Char('d'),   // 5:
-                Char('e'),   // 6:
-                Match,       // 7:
+                Char('a'),           // 0:
+                Split(Pc(2), Pc(5)), // 1:
+                Head,                // 2:
+                Char('b'),           // 3:
+                Jump(Pc(7)),         // 4:
+                Char('d'),           // 5:
+                Char('e'),           // 6:
+                Match,               // 7:
             ],
             ['a', 'd', 'e'],
-            EvalResult::matched()
+            EvalResult::matched(3)
         );
-        assert_eval_result!([Char('a'), MatchEnd,], ['a'], EvalResult::matched());
+        assert_eval_result!([Char('a'), MatchEnd,], ['a'], EvalResult::matched(1));
         assert_eval_result!([Char('a'), MatchEnd,], ['a', 'b'], EvalResult::unmatched());
         assert_eval_result!([Char('a'), MatchEnd,], ['c'], EvalResult::unmatched());
         assert_eval_result!(
@@ -371,43 +1411,43 @@ mod tests {
                 MatchEnd,  // 2:
             ],
             ['a'],
-            EvalResult::matched_if_head()
+            EvalResult::matched_if_head(1)
         );
         assert_eval_result!(
             [
-                Char('a'),   // 0:
-                Split(2, 4), // 1:
-                Char('b'),   // 2:
-                Jump(6),     // 3:
-                Char('c'),   // 4:
-                MatchEnd,    // 5:
-                Match,       // 6:
+                Char('a'),           // 0:
+                Split(Pc(2), Pc(4)), // 1:
+                Char('b'),           // 2:
+                Jump(Pc(6)),         // 3:
+                Char('c'),           // 4:
+                MatchEnd,            // 5:
+                Match,               // 6:
             ],
             ['a', 'b'],
-            EvalResult::matched()
+            EvalResult::matched(2)
         );
         assert_eval_result!(
             [
-                Char('a'),   // 0:
-                Split(2, 4), // 1:
-                Char('b'),   // 2:
-                Jump(6),     // 3:
-                Char('c'),   // 4:
-                MatchEnd,    // 5:
-                Match,       // 6:
+                Char('a'),           // 0:
+                Split(Pc(2), Pc(4)), // 1:
+                Char('b'),           // 2:
+                Jump(Pc(6)),         // 3:
+                Char('c'),           // 4:
+                MatchEnd,            // 5:
+                Match,               // 6:
             ],
             ['a', 'c'],
-            EvalResult::matched()
+            EvalResult::matched(2)
         );
         assert_eval_result!(
             [
-                Char('a'),   // 0:
-                Split(2, 4), // 1:
-                Char('b'),   // 2:
-                Jump(6),     // 3:
-                Char('c'),   // 4:
-                MatchEnd,    // 5:
-                Match,       // 6:
+                Char('a'),           // 0:
+                Split(Pc(2), Pc(4)), // 1:
+                Char('b'),           // 2:
+                Jump(Pc(6)),         // 3:
+                Char('c'),           // 4:
+                MatchEnd,            // 5:
+                Match,               // 6:
             ],
             ['a', 'd'],
             EvalResult::unmatched()
@@ -415,4 +1455,509 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_eval_set() -> Result<(), DynError> {
+        let exprs = ["ab+", "a.c", "^b|abc$"];
+        let programs = exprs
+            .iter()
+            .map(|expr| get_code(&parse(expr)?).map_err(|e| e.into()))
+            .collect::<Result<Vec<_>, DynError>>()?;
+        let programs = programs.iter().map(|p| p.as_slice()).collect::<Vec<_>>();
+
+        for (line, expected) in [
+            ("abc", [true, true, true]),
+            ("abbc", [true, false, false]),
+            ("axc", [false, true, false]),
+            ("b", [false, false, true]),
+            ("abcd", [true, true, false]),
+            ("", [false, false, false]),
+        ] {
+            let line = line.chars().collect::<Vec<_>>();
+            assert_eq!(eval_set(&programs, &line)?, expected);
+
+            // 1つずつ評価した結果と一致する
+            for (program, expected) in programs.iter().zip(expected) {
+                assert_eq!(eval(program, &line, Engine::Depth)?.matched, expected);
+                assert_eq!(eval(program, &line, Engine::Width)?.matched, expected);
+            }
+        }
+
+        assert_eq!(eval_set(&[], &['a'])?, Vec::<bool>::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evaluator_step() -> Result<(), EvalError> {
+        let inst = [
+            Char('a'),           // 0:
+            Split(Pc(2), Pc(4)), // 1:
+            Char('b'),           // 2:
+            Jump(Pc(5)),         // 3:
+            Char('c'),           // 4:
+            Match,               // 5:
+        ];
+        let line = ['a', 'c'];
+        let mut evaluator = Evaluator::new(&inst, &line);
+
+        // (pc, sp, stack_depth)の遷移
+        let mut states = vec![(evaluator.pc().0, evaluator.sp().0, evaluator.stack_depth())];
+        while evaluator.step()? == StepOutcome::Running {
+            states.push((evaluator.pc().0, evaluator.sp().0, evaluator.stack_depth()));
+        }
+        assert_eq!(
+            states,
+            vec![
+                (0, 0, 0), // char a
+                (1, 1, 0), // split: 4を積む
+                (2, 1, 1), // char b: 失敗して4に戻る
+                (4, 1, 0), // char c
+                (5, 2, 0), // match
+            ]
+        );
+        assert_eq!(
+            evaluator.step()?,
+            StepOutcome::Matched(EvalResult::matched(2))
+        );
+        // 終了後は同じ結果を返し続ける
+        assert_eq!(
+            evaluator.step()?,
+            StepOutcome::Matched(EvalResult::matched(2))
+        );
+
+        let line = ['a', 'd'];
+        let mut evaluator = Evaluator::new(&inst, &line);
+        let mut steps = 0;
+        let outcome = loop {
+            match evaluator.step()? {
+                StepOutcome::Running => steps += 1,
+                outcome => break outcome,
+            }
+        };
+        assert_eq!(outcome, StepOutcome::Failed);
+        assert_eq!(steps, 3);
+
+        // 先頭でのみ成り立つマッチの後も、残りの分岐を探してから終了する
+        let inst = [Split(Pc(1), Pc(3)), Head, Jump(Pc(3)), Char('a'), Match];
+        let line = ['a'];
+        let mut evaluator = Evaluator::new(&inst, &line);
+        let outcome = loop {
+            match evaluator.step()? {
+                StepOutcome::Running => {}
+                outcome => break outcome,
+            }
+        };
+        assert_eq!(outcome, StepOutcome::Matched(EvalResult::matched(1)));
+
+        // `Split`の前に通った`Head`の情報は分岐の先にも引き継がれる
+        let inst = [
+            Head,
+            Split(Pc(2), Pc(4)),
+            Char('a'),
+            Match,
+            Char('b'),
+            Match,
+        ];
+        assert_eq!(
+            eval(&inst, &['b'], Engine::Depth)?,
+            EvalResult::matched_if_head(1)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_bitstate() -> Result<(), EvalError> {
+        // 空ループを含むプログラムでも同じ状態を2度評価しないので停止する
+        let inst = [
+            Split(Pc(1), Pc(3)),
+            Split(Pc(2), Pc(0)),
+            Jump(Pc(0)),
+            Char('b'),
+            Match,
+        ];
+        assert_eq!(
+            eval(&inst, &['b'], Engine::Bitstate)?,
+            EvalResult::matched(1)
+        );
+        assert_eq!(
+            eval(&inst, &['a'], Engine::Bitstate)?,
+            EvalResult::unmatched()
+        );
+
+        // should_be_headが異なる状態は区別する
+        let inst = [
+            Split(Pc(1), Pc(4)), // 0:
+            Head,                // 1:
+            Char('a'),           // 2:
+            Jump(Pc(5)),         // 3:
+            Char('a'),           // 4:
+            Char('b'),           // 5:
+            Match,               // 6:
+        ];
+        assert_eq!(
+            eval(&inst, &['a', 'b'], Engine::Bitstate)?,
+            EvalResult::matched(2)
+        );
+
+        // ビットマップが大きすぎる場合はDepthで評価する
+        let line = vec!['a'; BITSTATE_MAX_BITS];
+        assert!(Evaluator::with_bitstate(&inst, &line).is_none());
+        assert_eq!(
+            eval(&inst, &line, Engine::Bitstate)?,
+            EvalResult::unmatched()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_epsilon_loop() -> Result<(), DynError> {
+        // 入力を消費せずに同じpcへ戻る経路は行き止まりとし、停止して残りの経路の結果を返す
+        let assert_result = |inst: &[Instruction], line: &[char], expected: EvalResult| {
+            for engine in Engine::ALL {
+                assert_eq!(eval(inst, line, engine).unwrap(), expected, "{engine:?}");
+            }
+        };
+
+        assert_result(&[Jump(Pc(1)), Jump(Pc(0))], &[], EvalResult::unmatched());
+        assert_result(&[Jump(Pc(0))], &['a'], EvalResult::unmatched());
+        assert_result(
+            &[Split(Pc(1), Pc(2)), Jump(Pc(0)), Match],
+            &['a'],
+            EvalResult::matched(0),
+        );
+        // 入力を消費した後のループ
+        assert_result(
+            &[Char('a'), Split(Pc(2), Pc(3)), Jump(Pc(1)), Match],
+            &['a', 'b'],
+            EvalResult::matched(1),
+        );
+
+        // 空文字列にマッチする式の繰り返し
+        let assert_pattern = |expr: &str, line: &str, expected: EvalResult| {
+            let inst = get_code(&parse(expr).unwrap()).unwrap();
+            assert_result(&inst, &line.chars().collect::<Vec<_>>(), expected);
+        };
+        assert_pattern("(a?)+", "b", EvalResult::matched(0));
+        assert_pattern("(a?)*b", "aab", EvalResult::matched(3));
+        assert_pattern("(a?)*b", "b", EvalResult::matched(1));
+        assert_pattern("(a*)+b", "aab", EvalResult::matched(3));
+        assert_pattern("(a|b?)*c", "abbc", EvalResult::matched(4));
+        assert_pattern("(a*b*)*c", "abbac", EvalResult::matched(5));
+        assert_pattern("()*b", "b", EvalResult::matched(1));
+        assert_pattern("(|a)*b", "aab", EvalResult::matched(3));
+        assert_pattern("(|a)*b", "aac", EvalResult::unmatched());
+        // 入力を進めた後に分岐へ戻っても、分岐した時点までに辿ったpcを覚えている
+        assert_pattern("(a?)*b", "aaaa", EvalResult::unmatched());
+        assert_pattern("(a*^)*", "a", EvalResult::matched(0));
+
+        // 別の分岐で辿ったpcに戻るのはループではない
+        let inst = [Split(Pc(1), Pc(3)), Head, Jump(Pc(3)), Char('b'), Match];
+        assert_result(&inst, &['c'], EvalResult::unmatched());
+
+        // 入力を消費するたびに数え直すので、長いマッチでも諦めない
+        let inst = get_code(&parse("(a|b)*c")?)?;
+        let mut line = ['a', 'b'].repeat(1000);
+        line.push('c');
+        assert_result(&inst, &line, EvalResult::matched(2001));
+
+        Ok(())
+    }
+
+    /// `engine`で評価するデフォルトの設定
+    fn with_engine(engine: Engine) -> Options {
+        Options {
+            engine,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_eval_at() -> Result<(), EvalError> {
+        let inst = [Char('a'), Char('b'), Match];
+        let line = ['x', 'a', 'b', 'y'];
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            // 終了位置は入力の先頭から数える
+            assert_eq!(
+                eval_at(&inst, &line, 1, &with_engine(engine))?,
+                EvalResult::matched(3)
+            );
+            assert_eq!(
+                eval_at(&inst, &line, 2, &with_engine(engine))?,
+                EvalResult::unmatched()
+            );
+        }
+
+        // 先頭以外から始めた場合は`Head`が成り立たない
+        let inst = [Split(Pc(1), Pc(3)), Head, Jump(Pc(3)), Char('a'), Match];
+        let line = ['b', 'a'];
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            assert_eq!(
+                eval_at(&inst, &line, 1, &with_engine(engine))?,
+                EvalResult::matched(2)
+            );
+        }
+        let inst = [Head, Char('a'), Match];
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            assert_eq!(
+                eval_at(&inst, &line, 1, &with_engine(engine))?,
+                EvalResult::unmatched()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_head_combinations() -> Result<(), EvalError> {
+        // 優先する分岐と他方の分岐が、それぞれ`Head`を通るか
+        let head_head = [
+            Split(Pc(1), Pc(4)),
+            Head,
+            Char('a'),
+            Jump(Pc(6)),
+            Head,
+            Char('a'),
+            Match,
+        ];
+        let head_any = [
+            Split(Pc(1), Pc(4)),
+            Head,
+            Char('a'),
+            Jump(Pc(5)),
+            Char('a'),
+            Match,
+        ];
+        let any_head = [
+            Split(Pc(1), Pc(3)),
+            Char('a'),
+            Jump(Pc(5)),
+            Head,
+            Char('a'),
+            Match,
+        ];
+        let any_any = [
+            Split(Pc(1), Pc(3)),
+            Char('a'),
+            Jump(Pc(4)),
+            Char('a'),
+            Match,
+        ];
+
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            // 先頭から評価した場合
+            let line = ['a'];
+            let results = [
+                (&head_head[..], EvalResult::matched_if_head(1)),
+                (&head_any[..], EvalResult::matched(1)),
+                (&any_head[..], EvalResult::matched(1)),
+                (&any_any[..], EvalResult::matched(1)),
+            ];
+            for (inst, expected) in results {
+                assert_eq!(eval(inst, &line, engine)?, expected, "{engine:?}");
+            }
+
+            // 先頭以外から評価した場合は`Head`を通る経路はマッチしない
+            let line = ['b', 'a'];
+            let results = [
+                (&head_head[..], EvalResult::unmatched()),
+                (&head_any[..], EvalResult::matched(2)),
+                (&any_head[..], EvalResult::matched(2)),
+                (&any_any[..], EvalResult::matched(2)),
+            ];
+            for (inst, expected) in results {
+                assert_eq!(
+                    eval_at(inst, &line, 1, &with_engine(engine))?,
+                    expected,
+                    "{engine:?}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_programs_overflow() {
+        // 2つ目のプログラムのアドレスを1つずらすと溢れる
+        let programs: [&[Instruction]; 2] = [&[Match], &[Jump(Pc(usize::MAX))]];
+        let err = link_programs(&programs).unwrap_err();
+        assert!(matches!(
+            err,
+            EvalError::PCOverFlow(Overflow {
+                lhs: usize::MAX,
+                op: Op::Add,
+                rhs: 1
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "EvalError: pc overflow: 18446744073709551615 + 1"
+        );
+    }
+
+    #[test]
+    fn test_incr_overflow() -> Result<(), EvalError> {
+        assert_eq!(Pc(1).incr()?, Pc(2));
+        assert_eq!(Sp(1).incr()?, Sp(2));
+
+        let err = Pc(usize::MAX).incr().unwrap_err();
+        assert!(matches!(
+            err,
+            EvalError::PCOverFlow(Overflow {
+                lhs: usize::MAX,
+                op: Op::Add,
+                rhs: 1
+            })
+        ));
+        let err = Sp(usize::MAX).incr().unwrap_err();
+        assert!(matches!(
+            err,
+            EvalError::SPOverFlow(Overflow {
+                lhs: usize::MAX,
+                op: Op::Add,
+                rhs: 1
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "EvalError: sp overflow: 18446744073709551615 + 1"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backtrack_limit() -> Result<(), DynError> {
+        // a?を評価するたびに分岐が1つ積まれる
+        let inst = get_code(&parse("a?a?a?a?aaaa")?)?;
+        let line = ['a', 'a', 'a', 'a'];
+        for engine in [Engine::Depth, Engine::Bitstate] {
+            let options = Options {
+                backtrack_limit: 2,
+                ..with_engine(engine)
+            };
+            let result = eval_with(&inst, &line, 0, &options, &mut Tracer::disabled());
+            let err = result.expect_err("should exceed the limit");
+            assert!(
+                matches!(err, EvalError::BacktrackLimitExceeded { limit: 2 }),
+                "{engine:?}"
+            );
+            assert_eq!(
+                err.to_string(),
+                "EvalError: backtrack limit exceeded: limit = 2"
+            );
+        }
+
+        // 通常のパターンではデフォルトの上限に達しない
+        let inst = get_code(&parse("(a|b)*c")?)?;
+        let line = ['a', 'b'].repeat(1000);
+        for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+            assert_eq!(eval(&inst, &line, engine)?, EvalResult::unmatched());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() -> Result<(), DynError> {
+        let metrics = |inst: &[Instruction], line: &str, engine| -> Result<_, EvalError> {
+            let line = line.chars().collect::<Vec<_>>();
+            let mut metrics = MatchMetrics::default();
+            let mut tracer = Tracer::disabled().with_metrics(Some(&mut metrics));
+            eval_with(inst, &line, 0, &with_engine(engine), &mut tracer)?;
+            Ok(metrics)
+        };
+
+        // 0: split 0001, 0003
+        // 1: char a
+        // 2: jump 0004
+        // 3: char b
+        // 4: match
+        let inst = [
+            Instruction::Split(Pc(1), Pc(3)),
+            Instruction::Char('a'),
+            Instruction::Jump(Pc(4)),
+            Instruction::Char('b'),
+            Instruction::Match,
+        ];
+
+        // split, char a（失敗して戻る）, char b, match
+        let expected = MatchMetrics {
+            instructions: 4,
+            backtracks: 1,
+            peak_pending: 1,
+            memoized: false,
+            prefiltered: false,
+        };
+        assert_eq!(metrics(&inst, "b", Engine::Depth)?, expected);
+        assert_eq!(
+            metrics(&inst, "b", Engine::Bitstate)?,
+            MatchMetrics {
+                memoized: true,
+                ..expected
+            }
+        );
+        // split, char a, jump, match
+        assert_eq!(
+            metrics(&inst, "a", Engine::Depth)?,
+            MatchMetrics {
+                instructions: 4,
+                backtracks: 0,
+                ..expected
+            }
+        );
+        // 先頭でsplitを実行し、char aとchar bの2つのスレッドを同時に進める
+        assert_eq!(
+            metrics(&inst, "b", Engine::Width)?,
+            MatchMetrics {
+                instructions: 4,
+                backtracks: 0,
+                peak_pending: 2,
+                ..expected
+            }
+        );
+
+        // 集計先を渡さなければ何も集計しない
+        let mut tracer = Tracer::disabled();
+        eval_with(&inst, &['b'], 0, &Options::default(), &mut tracer)?;
+        assert_eq!(tracer.steps(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_limit() -> Result<(), DynError> {
+        // 幅優先の評価では、a?の分だけ同じ位置に並ぶスレッドが増える
+        let inst = get_code(&parse("a?a?a?a?aaaa")?)?;
+        let line = ['a', 'a', 'a', 'a'];
+        let options = Options {
+            queue_limit: 2,
+            ..with_engine(Engine::Width)
+        };
+        let result = eval_with(&inst, &line, 0, &options, &mut Tracer::disabled());
+        let err = result.expect_err("should exceed the limit");
+        assert!(matches!(err, EvalError::QueueLimitExceeded { limit: 2 }));
+        assert_eq!(
+            err.to_string(),
+            "EvalError: queue limit exceeded: limit = 2"
+        );
+
+        // 分岐の上限は幅優先の評価には関係しない
+        let options = Options {
+            backtrack_limit: 0,
+            ..with_engine(Engine::Width)
+        };
+        let result = eval_with(&inst, &line, 0, &options, &mut Tracer::disabled())?;
+        assert!(result.matched);
+
+        // 長い入力でもデフォルトの上限には達しない
+        let inst = get_code(&parse("(a|b)*(a|b)*c")?)?;
+        let line = ['a', 'b'].repeat(10000);
+        assert_eq!(eval(&inst, &line, Engine::Width)?, EvalResult::unmatched());
+
+        Ok(())
+    }
 }
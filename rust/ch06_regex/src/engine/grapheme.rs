@@ -0,0 +1,114 @@
+//! 入力を書記素クラスタに区切る簡易的な分割器。
+//!
+//! 拡張書記素クラスタ（UAX #29）のうち、よく現れる次の規則のみを扱う。
+//! - `"\r\n"`は1つのクラスタ。その他の制御文字は単独のクラスタ。
+//! - 結合文字や異体字セレクタ、絵文字の肌の色の修飾子、タグ文字、ZWJは直前の文字に続ける。
+//! - ZWJの後の絵文字は直前のクラスタに続ける（`"👨‍👩‍👧"`は1つのクラスタ）。
+//! - 地域指示子（国旗）は2つずつ組にする。
+//!
+//! ハングルの字母の結合などは扱わないので、これらは1文字ずつのクラスタになる。
+
+/// 直前の文字に続ける文字の範囲
+const EXTEND: &[(char, char)] = &[
+    ('\u{0300}', '\u{036F}'),   // Combining Diacritical Marks
+    ('\u{0483}', '\u{0489}'),   // Cyrillic combining marks
+    ('\u{1AB0}', '\u{1AFF}'),   // Combining Diacritical Marks Extended
+    ('\u{1DC0}', '\u{1DFF}'),   // Combining Diacritical Marks Supplement
+    ('\u{200C}', '\u{200D}'),   // ZWNJ, ZWJ
+    ('\u{20D0}', '\u{20FF}'),   // Combining Diacritical Marks for Symbols
+    ('\u{3099}', '\u{309A}'),   // 結合用の濁点・半濁点
+    ('\u{FE00}', '\u{FE0F}'),   // Variation Selectors
+    ('\u{FE20}', '\u{FE2F}'),   // Combining Half Marks
+    ('\u{1F3FB}', '\u{1F3FF}'), // Emoji Modifiers
+    ('\u{E0020}', '\u{E007F}'), // Tags
+    ('\u{E0100}', '\u{E01EF}'), // Variation Selectors Supplement
+];
+
+/// ZWJの後に続けてよい絵文字の範囲
+const PICTOGRAPHIC: &[(char, char)] = &[
+    ('\u{2600}', '\u{27BF}'),   // Miscellaneous Symbols, Dingbats
+    ('\u{1F000}', '\u{1FAFF}'), // 絵文字の各ブロック
+];
+
+const ZWJ: char = '\u{200D}';
+
+fn in_ranges(c: char, ranges: &[(char, char)]) -> bool {
+    ranges.iter().any(|(from, to)| (*from..=*to).contains(&c))
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// `prev`までのクラスタに`c`を続けるか。`ri_count`はクラスタ末尾に連続する地域指示子の数。
+fn is_continuation(prev: char, c: char, ri_count: usize) -> bool {
+    if prev == '\r' && c == '\n' {
+        return true;
+    }
+    if prev.is_control() || c.is_control() {
+        return false;
+    }
+    if in_ranges(c, EXTEND) {
+        return true;
+    }
+    if prev == ZWJ && in_ranges(c, PICTOGRAPHIC) {
+        return true;
+    }
+    is_regional_indicator(c) && ri_count % 2 == 1
+}
+
+/// `s`を書記素クラスタに区切る。空文字列なら空の`Vec`を返す。
+pub(super) fn graphemes(s: &str) -> Vec<&str> {
+    let mut clusters = Vec::new();
+    let mut start = 0;
+    let mut prev: Option<char> = None;
+    let mut ri_count = 0;
+
+    for (i, c) in s.char_indices() {
+        if let Some(prev) = prev {
+            if !is_continuation(prev, c, ri_count) {
+                clusters.push(&s[start..i]);
+                start = i;
+                ri_count = 0;
+            }
+        }
+        if is_regional_indicator(c) {
+            ri_count += 1;
+        }
+        prev = Some(c);
+    }
+    if start < s.len() {
+        clusters.push(&s[start..]);
+    }
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graphemes() {
+        assert_eq!(graphemes(""), Vec::<&str>::new());
+        assert_eq!(graphemes("abc"), vec!["a", "b", "c"]);
+        assert_eq!(graphemes("aあ\n"), vec!["a", "あ", "\n"]);
+
+        // 結合文字
+        assert_eq!(graphemes("e\u{301}x"), vec!["e\u{301}", "x"]);
+        assert_eq!(graphemes("か\u{3099}き"), vec!["か\u{3099}", "き"]);
+        // 先頭の結合文字は単独のクラスタ
+        assert_eq!(graphemes("\u{301}a"), vec!["\u{301}", "a"]);
+
+        // ZWJで繋いだ絵文字、肌の色、異体字セレクタ
+        assert_eq!(graphemes("👨‍👩‍👧!"), vec!["👨‍👩‍👧", "!"]);
+        assert_eq!(graphemes("👍🏽👍"), vec!["👍🏽", "👍"]);
+        assert_eq!(graphemes("❤\u{FE0F}a"), vec!["❤\u{FE0F}", "a"]);
+
+        // 地域指示子は2つずつ
+        assert_eq!(graphemes("🇯🇵🇺🇸🇫"), vec!["🇯🇵", "🇺🇸", "🇫"]);
+
+        // 改行
+        assert_eq!(graphemes("a\r\nb\n\r"), vec!["a", "\r\n", "b", "\n", "\r"]);
+        assert_eq!(graphemes("\n\u{301}"), vec!["\n", "\u{301}"]);
+    }
+}
@@ -0,0 +1,127 @@
+//! 入力をNFC（正規合成）に揃える簡易的な正規化。
+//!
+//! macOSなどから来るNFDの文字列（`"e\u{301}"`）も、合成済みの文字（`'é'`）のパターンにマッチさせるため、
+//! 基底の文字とそれに続く結合文字を`COMPOSITIONS`の表に従って1文字に合成する。
+//! 表に載せているのは、ラテン文字（Latin-1 SupplementとLatin Extended-A）、ギリシャ文字、
+//! キリル文字の基本的な合成済み文字と、仮名の濁点・半濁点のみ。
+//!
+//! - 結合文字は直前の文字（合成した結果を含む）とのみ合成する。
+//!   合成できない結合文字を間に挟むと、その後の結合文字はそのまま残る。
+//! - 分解（`'Å'`（U+212B）を`'Å'`にするなど）やハングルの字母の合成は行わない。
+
+/// 正規化の方法。デフォルトは`None`。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Normalization {
+    /// 正規化しない
+    #[default]
+    None,
+    /// 基底の文字と結合文字を合成済みの文字にする
+    Nfc,
+}
+
+/// 結合文字ごとの、合成できる基底の文字と合成した文字。2つの文字列の`n`文字目どうしが対応する。
+const COMPOSITIONS: &[(char, &str, &str)] = &[
+    // COMBINING GRAVE ACCENT
+    ('\u{0300}', "AEIOUaeiouЕИеи", "ÀÈÌÒÙàèìòùЀЍѐѝ"),
+    // COMBINING ACUTE ACCENT
+    (
+        '\u{0301}',
+        "AEIOUYaeiouyCcLlNnRrSsZzΑΕΗΙΟΥΩϊαεηιϋουωГКгк",
+        "ÁÉÍÓÚÝáéíóúýĆćĹĺŃńŔŕŚśŹźΆΈΉΊΌΎΏΐάέήίΰόύώЃЌѓќ",
+    ),
+    // COMBINING CIRCUMFLEX ACCENT
+    ('\u{0302}', "AEIOUaeiouCcGgHhJjSsWwYy", "ÂÊÎÔÛâêîôûĈĉĜĝĤĥĴĵŜŝŴŵŶŷ"),
+    // COMBINING TILDE
+    ('\u{0303}', "ANOanoIiUu", "ÃÑÕãñõĨĩŨũ"),
+    // COMBINING MACRON
+    ('\u{0304}', "AaEeIiOoUu", "ĀāĒēĪīŌōŪū"),
+    // COMBINING BREVE
+    ('\u{0306}', "AaEeGgIiOoUuУИиу", "ĂăĔĕĞğĬĭŎŏŬŭЎЙйў"),
+    // COMBINING DOT ABOVE
+    ('\u{0307}', "CcEeGgIZz", "ĊċĖėĠġİŻż"),
+    // COMBINING DIAERESIS
+    ('\u{0308}', "AEIOUaeiouyYΙΥιυЕІеі", "ÄËÏÖÜäëïöüÿŸΪΫϊϋЁЇёї"),
+    // COMBINING RING ABOVE
+    ('\u{030A}', "AaUu", "ÅåŮů"),
+    // COMBINING DOUBLE ACUTE ACCENT
+    ('\u{030B}', "OoUu", "ŐőŰű"),
+    // COMBINING CARON
+    ('\u{030C}', "CcDdEeLlNnRrSsTtZz", "ČčĎďĚěĽľŇňŘřŠšŤťŽž"),
+    // COMBINING CEDILLA
+    ('\u{0327}', "CcGgKkLlNnRrSsTt", "ÇçĢģĶķĻļŅņŖŗŞşŢţ"),
+    // COMBINING OGONEK
+    ('\u{0328}', "AaEeIiUu", "ĄąĘęĮįŲų"),
+    // COMBINING KATAKANA-HIRAGANA VOICED SOUND MARK
+    (
+        '\u{3099}',
+        "かきくけこさしすせそたちつてとはひふへほうゝカキクケコサシスセソタチツテトハヒフヘホウワヰヱヲヽ",
+        "がぎぐげござじずぜぞだぢづでどばびぶべぼゔゞガギグゲゴザジズゼゾダヂヅデドバビブベボヴヷヸヹヺヾ",
+    ),
+    // COMBINING KATAKANA-HIRAGANA SEMI-VOICED SOUND MARK
+    ('\u{309A}', "はひふへほハヒフヘホ", "ぱぴぷぺぽパピプペポ"),
+];
+
+/// `base`と結合文字`mark`を合成した文字。表になければ`None`を返す。
+fn compose(base: char, mark: char) -> Option<char> {
+    let (_, bases, composed) = COMPOSITIONS.iter().find(|(m, _, _)| *m == mark)?;
+    let n = bases.chars().position(|b| b == base)?;
+    composed.chars().nth(n)
+}
+
+/// `line`をNFCに揃えた文字と、各文字が`line`のどのバイト位置から始まるか。
+/// バイト位置には末尾の位置（`line.len()`）も含むので、文字数より1つ多い。
+/// 合成した文字は、その基底の文字の位置から始まり、次の文字の位置で終わる。
+pub(super) fn nfc(line: &str) -> (Vec<char>, Vec<usize>) {
+    let mut chars: Vec<char> = Vec::with_capacity(line.len());
+    let mut offsets = Vec::with_capacity(line.len() + 1);
+    for (i, c) in line.char_indices() {
+        if let Some(last) = chars.last_mut() {
+            if let Some(composed) = compose(*last, c) {
+                *last = composed;
+                continue;
+            }
+        }
+        chars.push(c);
+        offsets.push(i);
+    }
+    offsets.push(line.len());
+    (chars, offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compositions() {
+        // 表の2つの文字列は同じ文字数
+        for (mark, bases, composed) in COMPOSITIONS {
+            assert_eq!(bases.chars().count(), composed.chars().count(), "{mark:?}");
+        }
+    }
+
+    #[test]
+    fn test_nfc() {
+        let nfc_string = |line: &str| nfc(line).0.into_iter().collect::<String>();
+
+        assert_eq!(nfc(""), (vec![], vec![0]));
+        assert_eq!(nfc("ab"), (vec!['a', 'b'], vec![0, 1, 2]));
+        assert_eq!(nfc("e\u{301}x"), (vec!['é', 'x'], vec![0, 3, 4]));
+        // すでに合成済みの文字はそのまま
+        assert_eq!(nfc("éx"), (vec!['é', 'x'], vec![0, 2, 3]));
+
+        assert_eq!(
+            nfc_string("Cafe\u{301} A\u{30A}ngstro\u{308}m"),
+            "Café Ångström"
+        );
+        assert_eq!(nfc_string("\u{3b9}\u{308}\u{301}"), "ΐ");
+        assert_eq!(nfc_string("\u{438}\u{306}"), "й");
+        assert_eq!(nfc_string("か\u{3099}ハ\u{309A}"), "がパ");
+
+        // 合成できない結合文字の後や、行頭の結合文字は合成しない
+        assert_eq!(nfc_string("q\u{301}"), "q\u{301}");
+        assert_eq!(nfc_string("a\u{323}\u{301}"), "a\u{323}\u{301}");
+        assert_eq!(nfc_string("\u{301}a"), "\u{301}a");
+    }
+}
@@ -0,0 +1,217 @@
+//! トップレベルの`|`の各分岐を並列に評価する
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rayon::prelude::*;
+
+use super::analysis::{first_chars_from, top_level_branches};
+use super::evaluator::EvalError;
+use super::{search_from, Instruction, Options, Pc, SearchCounts};
+
+/// トップレベルの`|`の分岐ごとに探索するためのプログラム。
+/// 元のプログラムの後ろに各分岐の前置部を並べ、1つのプログラムをすべての分岐で共有する。
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Branches {
+    code: Vec<Instruction>,
+    branches: Vec<Branch>,
+}
+
+/// 1つの分岐の探索を始めるアドレスと、`Regex::new`で求めておく分岐の性質
+#[derive(Debug, Clone, PartialEq)]
+struct Branch {
+    entry: Pc,
+    first_chars: Option<Vec<char>>,
+    anchored: bool,
+}
+
+impl Branches {
+    /// `code`のトップレベルの`|`の分岐ごとに、探索を始めるアドレスと前置部を用意する。
+    /// 先頭からしか始まらない分岐には前置部を付けず、分岐の先頭から評価する。
+    /// トップレベルに`|`がなければ`None`を返す。
+    pub(super) fn new(code: &[Instruction], options: &Options) -> Option<Self> {
+        let mut shared = code.to_vec();
+        let branches = top_level_branches(code)?
+            .into_iter()
+            .map(|branch| {
+                let branch = Pc(branch);
+                let anchored = options.is_anchored_from(code, branch);
+                let entry = if anchored {
+                    branch
+                } else {
+                    // `codegen::with_unanchored_prefix`と同じく、各位置で分岐を試してから1文字進める
+                    let entry = shared.len();
+                    shared.extend([
+                        Instruction::Split(branch, Pc(entry + 1)),
+                        Instruction::AnyChar,
+                        Instruction::Jump(Pc(entry)),
+                    ]);
+                    Pc(entry)
+                };
+                Branch {
+                    entry,
+                    first_chars: first_chars_from(code, branch),
+                    anchored,
+                }
+            })
+            .collect();
+
+        Some(Self {
+            code: shared,
+            branches,
+        })
+    }
+
+    /// 分岐ごとに並列に`search`を行う。
+    /// いずれかの分岐がマッチした時点で、まだ評価していない分岐は評価しない。
+    /// 評価中のエラーよりもマッチを優先し、どの分岐もマッチしなかった場合にのみ、
+    /// エラーになった分岐のうち最も前のもののエラーを返す。
+    pub(super) fn search(&self, line: &str, options: &Options) -> Result<bool, EvalError> {
+        let matched = AtomicBool::new(false);
+        let errors = self
+            .branches
+            .par_iter()
+            .filter_map(|branch| {
+                if matched.load(Ordering::Relaxed) {
+                    return None;
+                }
+                let result = search_from(
+                    &self.code,
+                    branch.entry,
+                    branch.first_chars.as_deref(),
+                    line,
+                    branch.anchored,
+                    options,
+                    &mut SearchCounts::default(),
+                );
+                match result {
+                    Ok(true) => {
+                        matched.store(true, Ordering::Relaxed);
+                        None
+                    }
+                    Ok(false) => None,
+                    Err(e) => Some(e),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if matched.into_inner() {
+            return Ok(true);
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::codegen::get_code;
+    use crate::engine::parser::parse;
+    use crate::engine::Engine;
+    use crate::engine::Instruction::*;
+    use crate::engine::{search, search_code};
+    use crate::helper::DynError;
+
+    #[test]
+    fn test_branches() -> Result<(), DynError> {
+        let code = get_code(&parse("ab|^c")?)?;
+        let branches = Branches::new(&code, &Options::default()).expect("top-level alternation");
+        // 前置部は元のプログラムの後ろに並べ、先頭からしか始まらない分岐には付けない
+        assert_eq!(
+            branches.code,
+            vec![
+                Split(Pc(1), Pc(4)),
+                Char('a'),
+                Char('b'),
+                Jump(Pc(6)),
+                Head,
+                Char('c'),
+                Match,
+                Split(Pc(1), Pc(8)),
+                AnyChar,
+                Jump(Pc(7)),
+            ]
+        );
+        assert_eq!(
+            branches.branches,
+            vec![
+                Branch {
+                    entry: Pc(7),
+                    first_chars: Some(vec!['a']),
+                    anchored: false,
+                },
+                Branch {
+                    entry: Pc(4),
+                    first_chars: Some(vec!['c']),
+                    anchored: true,
+                },
+            ]
+        );
+
+        let code = get_code(&parse("abc")?)?;
+        assert_eq!(Branches::new(&code, &Options::default()), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_parallel() -> Result<(), DynError> {
+        let rules = (0..200).map(|i| format!("r{i}x+y")).collect::<Vec<_>>();
+        let expr = rules.join("|");
+        let code = get_code(&parse(&expr)?)?;
+        let branches = Branches::new(&code, &Options::default()).expect("top-level alternation");
+        assert_eq!(branches.branches.len(), 200);
+        // 分岐ごとに元のプログラムを複製しない
+        assert_eq!(branches.code.len(), code.len() + 3 * 200);
+
+        for line in ["r0xy", "__r199xxy__", "r200xy", "r12y", "", "r1r2xy"] {
+            for engine in [Engine::Depth, Engine::Width, Engine::Bitstate] {
+                let options = Options {
+                    engine,
+                    ..Default::default()
+                };
+                let search_code = search_code(&code, false)?;
+                assert_eq!(
+                    branches.search(line, &options)?,
+                    search(
+                        &search_code,
+                        None,
+                        line,
+                        false,
+                        &options,
+                        &mut SearchCounts::default()
+                    )?,
+                    "{engine:?}: {line}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_parallel_error() -> Result<(), DynError> {
+        let code = get_code(&parse("(a|aa)*b|c")?)?;
+        let branches = Branches::new(&code, &Options::default()).expect("top-level alternation");
+        let options = Options {
+            step_limit: Some(1000),
+            ..Default::default()
+        };
+
+        // 一方の分岐が命令数の上限に達しても、他方の分岐がマッチすればマッチとする
+        let line = format!("{}c", "a".repeat(30));
+        for _ in 0..20 {
+            assert!(branches.search(&line, &options)?);
+        }
+        // どの分岐もマッチしなければエラーを返す
+        let line = format!("{}d", "a".repeat(30));
+        assert!(matches!(
+            branches.search(&line, &options),
+            Err(EvalError::StepLimitExceeded { .. })
+        ));
+
+        Ok(())
+    }
+}
@@ -1,9 +1,11 @@
-use crate::helper::DynError;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::iter::{Enumerate, Peekable};
 use std::mem;
+use std::str::Chars;
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum AST {
     Char(char),
     Plus(Box<AST>),
@@ -14,6 +16,8 @@ pub enum AST {
     Caret,
     Dollar,
     Period,
+    /// キャプチャグループ。グループの番号（左括弧の順に1から数える）と名前を持つ。
+    Capture(usize, Option<String>, Box<AST>),
 }
 
 #[derive(Debug)]
@@ -22,7 +26,12 @@ pub enum ParseError {
     InvalidRightParen(usize),
     NoPrev(usize),
     NoRightParen,
-    Empty,
+    InvalidFlag(usize, char),
+    InvalidGroupName(usize),
+    /// パターンが`\`で終わっている
+    TrailingEscape(usize),
+    /// グループや繰り返しの入れ子が深すぎる
+    TooDeep(usize),
 }
 
 impl Error for ParseError {}
@@ -31,7 +40,14 @@ impl Display for ParseError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             ParseError::InvalidEscape(pos, c) => {
-                write!(f, "ParseError: invalid escape: pos = {pos}, char = '{c}'")
+                write!(
+                    f,
+                    "ParseError: invalid escape: pos = {pos}, char = '{c}' (valid escapes:"
+                )?;
+                for e in ESCAPABLE {
+                    write!(f, " \\{e}")?;
+                }
+                write!(f, ")")
             }
             ParseError::InvalidRightParen(pos) => {
                 write!(f, "ParseError: invalid right parenthesis: pos = {pos}")
@@ -40,28 +56,110 @@ impl Display for ParseError {
             ParseError::NoRightParen => {
                 write!(f, "ParseError: no right parenthesis")
             }
-            ParseError::Empty => write!(f, "ParseError: empty expression"),
+            ParseError::InvalidFlag(pos, c) => {
+                write!(f, "ParseError: invalid flag: pos = {pos}, char = '{c}'")
+            }
+            ParseError::InvalidGroupName(pos) => {
+                write!(f, "ParseError: invalid group name: pos = {pos}")
+            }
+            ParseError::TrailingEscape(pos) => {
+                write!(f, "ParseError: trailing escape: pos = {pos}")
+            }
+            ParseError::TooDeep(pos) => {
+                write!(f, "ParseError: nesting too deep: pos = {pos}")
+            }
+        }
+    }
+}
+
+/// パターンの先頭に書くフラグ
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Flags {
+    /// `(?i)`: 大文字小文字を区別しない
+    pub case_insensitive: bool,
+    /// `(?m)`: `^`と`$`が行の境界でもマッチする
+    pub multiline: bool,
+}
+
+/// パターン先頭の`(?i)`のようなフラグ指定を読み取り、フラグと残りのパターンを返す
+pub fn parse_flags(expr: &str) -> Result<(Flags, &str), ParseError> {
+    let mut flags = Flags::default();
+    let rest = match expr.strip_prefix("(?") {
+        // `(?<name>`と`(?P<name>`は名前付きグループ、`(?:`は非キャプチャグループ
+        Some(rest) if !rest.starts_with(['<', ':']) && !rest.starts_with("P<") => rest,
+        _ => return Ok((flags, expr)),
+    };
+
+    // フラグはASCII文字なので、バイト位置と文字の位置は一致する
+    for (i, c) in rest.char_indices() {
+        match c {
+            'i' => flags.case_insensitive = true,
+            'm' => flags.multiline = true,
+            ')' => return Ok((flags, &rest[i + 1..])),
+            _ => return Err(ParseError::InvalidFlag(i + 2, c)),
         }
     }
+    Err(ParseError::NoRightParen)
 }
 
+/// `\\`の後に置いて文字そのものとして扱える文字。
+/// `[`、`]`、`{`、`}`はまだ特別な意味を持たないが、今後の構文のためにエスケープできるようにしておく。
+pub(super) const ESCAPABLE: [char; 14] = [
+    '\\', '(', ')', '|', '+', '*', '?', '^', '$', '.', '[', ']', '{', '}',
+];
+
 fn parse_escape(pos: usize, c: char) -> Result<AST, ParseError> {
-    match c {
-        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '^' | '$' | '.' => Ok(AST::Char(c)),
-        _ => {
-            let err = ParseError::InvalidEscape(pos, c);
-            Err(err)
+    if ESCAPABLE.contains(&c) {
+        Ok(AST::Char(c))
+    } else {
+        Err(ParseError::InvalidEscape(pos, c))
+    }
+}
+
+/// ASTの入れ子の深さの上限。
+/// コード生成や解析はASTを再帰的に辿るため、深すぎるとスタックオーバーフローする。
+const MAX_DEPTH: usize = 1000;
+
+/// ASTの入れ子の深さ。入れ子が深くてもスタックオーバーフローしないよう、再帰せずに求める。
+fn depth(ast: &AST) -> usize {
+    let mut max = 0;
+    let mut stack = vec![(ast, 1)];
+    while let Some((ast, d)) = stack.pop() {
+        max = max.max(d);
+        match ast {
+            AST::Plus(e) | AST::Star(e) | AST::Question(e) | AST::Capture(_, _, e) => {
+                stack.push((e, d + 1))
+            }
+            AST::Or(e1, e2) => {
+                stack.push((e1, d + 1));
+                stack.push((e2, d + 1));
+            }
+            AST::Seq(v) => v.iter().for_each(|e| stack.push((e, d + 1))),
+            AST::Char(_) | AST::Caret | AST::Dollar | AST::Period => {}
         }
     }
+    max
 }
 
+/// `ast`の入れ子が深すぎないかを確かめる。
+/// 深いASTは破棄するときにも再帰するので、上限を超える前に組み立てるたびに確かめる。
+fn check_depth(ast: AST, pos: usize) -> Result<AST, ParseError> {
+    if depth(&ast) > MAX_DEPTH {
+        // 上限をわずかに超えただけなので、破棄してもスタックオーバーフローしない
+        Err(ParseError::TooDeep(pos))
+    } else {
+        Ok(ast)
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 enum PSQ {
     Plus,
     Star,
     Question,
 }
 
-pub fn parse(expr: &str) -> Result<AST, DynError> {
+pub fn parse(expr: &str) -> Result<AST, ParseError> {
     enum ParseState {
         Char,
         Escape,
@@ -71,41 +169,52 @@ pub fn parse(expr: &str) -> Result<AST, DynError> {
     let mut seq_or = Vec::new();
     let mut stack = Vec::new();
     let mut state = ParseState::Char;
+    let mut groups = 0;
 
-    for (i, c) in expr.chars().enumerate() {
+    let mut chars = expr.chars().enumerate().peekable();
+    while let Some((i, c)) = chars.next() {
         match &state {
             ParseState::Char => match c {
                 '+' => parse_plus_question(&mut seq, PSQ::Plus, i)?,
                 '*' => parse_plus_question(&mut seq, PSQ::Star, i)?,
                 '?' => parse_plus_question(&mut seq, PSQ::Question, i)?,
                 '(' => {
+                    let group = if let Some((_, '?')) = chars.peek() {
+                        parse_group(&mut chars, i)?
+                    } else {
+                        Group::Capture(None)
+                    };
+                    // 非キャプチャグループには番号を付けない
+                    let index = match group {
+                        Group::Capture(name) => {
+                            groups += 1;
+                            Some((groups, name))
+                        }
+                        Group::NonCapturing => None,
+                    };
+
                     let prev = mem::take(&mut seq);
                     let prev_or = mem::take(&mut seq_or);
-                    stack.push((prev, prev_or));
+                    stack.push((prev, prev_or, index));
                 }
                 ')' => {
-                    if let Some((mut prev, prev_or)) = stack.pop() {
-                        if !seq.is_empty() {
-                            seq_or.push(AST::Seq(seq));
-                        }
+                    if let Some((prev, prev_or, index)) = stack.pop() {
+                        let mut branches = mem::replace(&mut seq_or, prev_or);
+                        branches.push(AST::Seq(mem::replace(&mut seq, prev)));
 
-                        if let Some(ast) = fold_or(seq_or) {
-                            prev.push(ast);
-                        }
-                        seq = prev;
-                        seq_or = prev_or;
+                        // `()`のような空のグループも、空文字列にマッチするグループとする
+                        let e = fold_or(branches, i)?;
+                        let ast = match index {
+                            Some((index, name)) => AST::Capture(index, name, Box::new(e)),
+                            None => e,
+                        };
+                        seq.push(check_depth(ast, i)?);
                     } else {
-                        return Err(Box::new(ParseError::InvalidRightParen(i)));
-                    }
-                }
-                '|' => {
-                    if seq.is_empty() {
-                        return Err(Box::new(ParseError::NoPrev(i)));
-                    } else {
-                        let prev = mem::take(&mut seq);
-                        seq_or.push(AST::Seq(prev));
+                        return Err(ParseError::InvalidRightParen(i));
                     }
                 }
+                // `(|a)`のような空の分岐は空文字列にマッチする
+                '|' => seq_or.push(AST::Seq(mem::take(&mut seq))),
                 '\\' => state = ParseState::Escape,
                 '^' => seq.push(AST::Caret),
                 '$' => seq.push(AST::Dollar),
@@ -122,19 +231,79 @@ pub fn parse(expr: &str) -> Result<AST, DynError> {
         }
     }
 
+    if let ParseState::Escape = state {
+        return Err(ParseError::TrailingEscape(expr.chars().count() - 1));
+    }
+
     if !stack.is_empty() {
-        return Err(Box::new(ParseError::NoRightParen));
+        return Err(ParseError::NoRightParen);
     }
 
-    if !seq.is_empty() {
-        seq_or.push(AST::Seq(seq));
+    // 空のパターンは空の`Seq`となり、あらゆる位置で空文字列にマッチする
+    seq_or.push(AST::Seq(seq));
+    fold_or(seq_or, expr.chars().count())
+}
+
+/// `(`で始まるグループの種類
+enum Group {
+    /// キャプチャグループ。名前付きグループであれば名前を持つ。
+    Capture(Option<String>),
+    /// `(?:...)`
+    NonCapturing,
+}
+
+/// `(`の直後の`?:`、`?<name>`、または`?P<name>`を読み、グループの種類を返す。
+/// 名前には英数字と`_`を使える。`pos`は`(`の位置。
+fn parse_group(chars: &mut Peekable<Enumerate<Chars>>, pos: usize) -> Result<Group, ParseError> {
+    let err = || ParseError::InvalidGroupName(pos);
+
+    chars.next(); // ?
+    match chars.next() {
+        Some((_, ':')) => return Ok(Group::NonCapturing),
+        Some((_, '<')) => {}
+        Some((_, 'P')) => match chars.next() {
+            Some((_, '<')) => {}
+            _ => return Err(err()),
+        },
+        _ => return Err(err()),
     }
 
-    if let Some(ast) = fold_or(seq_or) {
-        Ok(ast)
-    } else {
-        Err(Box::new(ParseError::Empty))
+    let mut name = String::new();
+    for (_, c) in chars.by_ref() {
+        match c {
+            '>' if !name.is_empty() => return Ok(Group::Capture(Some(name))),
+            c if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+            _ => return Err(err()),
+        }
+    }
+    Err(err())
+}
+
+/// 各キャプチャグループの名前を、グループの番号を添字として返す。
+/// 0番目はマッチ全体を表し、名前を持たない。
+pub fn capture_names(ast: &AST) -> Vec<Option<String>> {
+    fn walk(ast: &AST, names: &mut Vec<Option<String>>) {
+        match ast {
+            AST::Capture(index, name, e) => {
+                if names.len() <= *index {
+                    names.resize(*index + 1, None);
+                }
+                names[*index] = name.clone();
+                walk(e, names);
+            }
+            AST::Plus(e) | AST::Star(e) | AST::Question(e) => walk(e, names),
+            AST::Or(e1, e2) => {
+                walk(e1, names);
+                walk(e2, names);
+            }
+            AST::Seq(v) => v.iter().for_each(|e| walk(e, names)),
+            AST::Char(_) | AST::Caret | AST::Dollar | AST::Period => {}
+        }
     }
+
+    let mut names = vec![None];
+    walk(ast, &mut names);
+    names
 }
 
 fn parse_plus_question(seq: &mut Vec<AST>, ast_type: PSQ, pos: usize) -> Result<(), ParseError> {
@@ -144,22 +313,104 @@ fn parse_plus_question(seq: &mut Vec<AST>, ast_type: PSQ, pos: usize) -> Result<
             PSQ::Star => AST::Star(Box::new(prev)),
             PSQ::Question => AST::Question(Box::new(prev)),
         };
-        seq.push(ast);
+        seq.push(check_depth(ast, pos)?);
         Ok(())
     } else {
         Err(ParseError::NoPrev(pos))
     }
 }
 
-fn fold_or(mut seq_or: Vec<AST>) -> Option<AST> {
-    if seq_or.len() > 1 {
-        let mut ast = seq_or.pop().unwrap();
-        seq_or.reverse();
-        for s in seq_or {
-            ast = AST::Or(Box::new(s), Box::new(ast));
+/// `|`で区切られた各分岐を、右に入れ子になった`Or`にまとめる。`pos`は入れ子が深すぎる場合のエラーの位置。
+/// 分岐がなければ空の`Seq`とする。
+fn fold_or(mut seq_or: Vec<AST>, pos: usize) -> Result<AST, ParseError> {
+    let mut ast = seq_or.pop().unwrap_or(AST::Seq(Vec::new()));
+    let mut d = depth(&ast);
+    seq_or.reverse();
+    for s in seq_or {
+        // 分岐が多いと`Or`の入れ子が深くなるので、分岐ごとに深さを足していく
+        d = d.max(depth(&s)) + 1;
+        if d > MAX_DEPTH {
+            return Err(ParseError::TooDeep(pos));
         }
-        Some(ast)
-    } else {
-        seq_or.pop()
+        ast = AST::Or(Box::new(s), Box::new(ast));
+    }
+    Ok(ast)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_escape() {
+        for c in ESCAPABLE {
+            let expr = format!("\\{c}");
+            match parse(&expr) {
+                Ok(AST::Seq(seq)) => assert!(
+                    matches!(seq.as_slice(), [AST::Char(parsed)] if *parsed == c),
+                    "{expr}: {seq:?}"
+                ),
+                result => panic!("{expr}: {result:?}"),
+            }
+        }
+
+        let err = parse(r"a\d").unwrap_err();
+        assert!(matches!(err, ParseError::InvalidEscape(2, 'd')));
+        assert_eq!(
+            err.to_string(),
+            r"ParseError: invalid escape: pos = 2, char = 'd' (valid escapes: \\ \( \) \| \+ \* \? \^ \$ \. \[ \] \{ \})"
+        );
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        assert!(matches!(parse(""), Ok(AST::Seq(seq)) if seq.is_empty()));
+
+        match parse("()") {
+            Ok(AST::Seq(seq)) => assert!(
+                matches!(seq.as_slice(), [AST::Capture(1, None, e)] if matches!(e.as_ref(), AST::Seq(v) if v.is_empty())),
+                "{seq:?}"
+            ),
+            result => panic!("{result:?}"),
+        }
+
+        // 空の分岐は空の`Seq`となる
+        match parse("a|") {
+            Ok(AST::Or(e1, e2)) => {
+                assert!(matches!(e1.as_ref(), AST::Seq(v) if v.len() == 1));
+                assert!(matches!(e2.as_ref(), AST::Seq(v) if v.is_empty()));
+            }
+            result => panic!("{result:?}"),
+        }
+        assert_eq!(capture_names(&parse("(|a)()").unwrap()).len(), 3);
+
+        assert!(matches!(parse("(|"), Err(ParseError::NoRightParen)));
+        assert!(matches!(parse(")"), Err(ParseError::InvalidRightParen(0))));
+    }
+
+    #[test]
+    fn test_parse_non_capturing() {
+        // 非キャプチャグループは番号を持たず、後のグループの番号も変わらない
+        match parse("(?:a|b)*(c)") {
+            Ok(AST::Seq(seq)) => assert!(
+                matches!(seq.as_slice(), [AST::Star(e), AST::Capture(1, None, _)] if matches!(e.as_ref(), AST::Or(..))),
+                "{seq:?}"
+            ),
+            result => panic!("{result:?}"),
+        }
+        assert_eq!(
+            capture_names(&parse("(?:(?<x>a)|(b))").unwrap()),
+            [None, Some("x".to_string()), None]
+        );
+        assert!(
+            matches!(parse("(?:)"), Ok(AST::Seq(seq)) if matches!(seq.as_slice(), [AST::Seq(v)] if v.is_empty()))
+        );
+
+        assert!(matches!(parse("(?:a"), Err(ParseError::NoRightParen)));
+        assert!(matches!(
+            parse("a(?;b)"),
+            Err(ParseError::InvalidGroupName(1))
+        ));
+        assert!(matches!(parse_flags("(?:a)"), Ok((flags, "(?:a)")) if flags == Flags::default()));
     }
 }
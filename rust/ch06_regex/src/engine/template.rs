@@ -0,0 +1,183 @@
+//! `Regex::replace`などで用いる置換文字列のテンプレート。
+//!
+//! - `$N`、`${N}`: 番号`N`のキャプチャグループ。`$`の後の数字はすべて番号とみなすので、
+//!   グループ1の直後に`0`を続けるには`$10`ではなく`${1}0`と書く。
+//! - `${name}`: `(?<name>...)`で名前を付けたグループ
+//! - `$$`: `$`そのもの
+//!
+//! テンプレートは`Regex::template`で1度だけ解析し、参照するグループが存在するかもそのときに確かめる。
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use super::Captures;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    /// `$`の後にグループの参照も`$`も続かない
+    InvalidReference(usize),
+    /// `${`に対応する`}`がない
+    NoRightBrace(usize),
+    /// 正規表現にない番号または名前のグループを参照している
+    NoSuchGroup(String),
+}
+
+impl Error for TemplateError {}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::InvalidReference(pos) => write!(
+                f,
+                "TemplateError: invalid reference: pos = {pos} (use $N, ${{N}}, ${{name}} or $$)"
+            ),
+            TemplateError::NoRightBrace(pos) => {
+                write!(f, "TemplateError: no right brace: pos = {pos}")
+            }
+            TemplateError::NoSuchGroup(group) => {
+                write!(f, "TemplateError: no such group: {group}")
+            }
+        }
+    }
+}
+
+/// テンプレートを区切った各部分
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    /// 番号で解決したグループ
+    Group(usize),
+}
+
+/// 解析済みの置換文字列。作った`Regex`のマッチごとに`expand`で展開する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    /// `rep`を解析し、参照するグループを`names`（グループ0を含む各グループの名前）で番号に解決する
+    pub(super) fn new(rep: &str, names: &[Option<String>]) -> Result<Template, TemplateError> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+
+        let mut chars = rep.chars().enumerate().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c != '$' {
+                literal.push(c);
+                continue;
+            }
+
+            let reference = match chars.next() {
+                Some((_, '$')) => {
+                    literal.push('$');
+                    continue;
+                }
+                Some((_, d)) if d.is_ascii_digit() => {
+                    let mut digits = d.to_string();
+                    while let Some((_, d)) = chars.next_if(|(_, d)| d.is_ascii_digit()) {
+                        digits.push(d);
+                    }
+                    digits
+                }
+                Some((_, '{')) => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+                            Some(_) => return Err(TemplateError::InvalidReference(i)),
+                            None => return Err(TemplateError::NoRightBrace(i)),
+                        }
+                    }
+                    if name.is_empty() {
+                        return Err(TemplateError::InvalidReference(i));
+                    }
+                    name
+                }
+                _ => return Err(TemplateError::InvalidReference(i)),
+            };
+
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+            }
+            pieces.push(Piece::Group(resolve(&reference, names)?));
+        }
+
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Template { pieces })
+    }
+
+    /// `caps`の各グループでテンプレートを展開し、`out`に追加する。
+    /// マッチに参加しなかったグループは空文字列となる。
+    pub fn expand(&self, caps: &Captures, out: &mut String) {
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(s) => out.push_str(s),
+                Piece::Group(i) => {
+                    if let Some(m) = caps.get(*i) {
+                        out.push_str(m.as_str());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 数字のみからなる`reference`はグループの番号、それ以外は名前として`names`から探す
+fn resolve(reference: &str, names: &[Option<String>]) -> Result<usize, TemplateError> {
+    let index = if reference.bytes().all(|b| b.is_ascii_digit()) {
+        reference.parse().ok().filter(|i| *i < names.len())
+    } else {
+        names.iter().position(|n| n.as_deref() == Some(reference))
+    };
+    index.ok_or_else(|| TemplateError::NoSuchGroup(reference.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_new() {
+        let names = [None, None, Some("year".to_string())];
+        let new = |rep| Template::new(rep, &names).map(|t| t.pieces);
+        let literal = |s: &str| Piece::Literal(s.to_string());
+
+        assert_eq!(new(""), Ok(vec![]));
+        assert_eq!(new("a$$b"), Ok(vec![literal("a$b")]));
+        assert_eq!(
+            new("$2-$1"),
+            Ok(vec![Piece::Group(2), literal("-"), Piece::Group(1)])
+        );
+        assert_eq!(new("${year}!"), Ok(vec![Piece::Group(2), literal("!")]));
+        assert_eq!(new("${1}0"), Ok(vec![Piece::Group(1), literal("0")]));
+        assert_eq!(new("${0}${2}"), Ok(vec![Piece::Group(0), Piece::Group(2)]));
+
+        // `$`の後の数字はすべて番号とみなす
+        assert_eq!(
+            new("$10"),
+            Err(TemplateError::NoSuchGroup("10".to_string()))
+        );
+        assert_eq!(
+            new("${month}"),
+            Err(TemplateError::NoSuchGroup("month".to_string()))
+        );
+        assert_eq!(new("あ$"), Err(TemplateError::InvalidReference(1)));
+        assert_eq!(new("$x"), Err(TemplateError::InvalidReference(0)));
+        assert_eq!(new("${}"), Err(TemplateError::InvalidReference(0)));
+        assert_eq!(new("a${a-b}"), Err(TemplateError::InvalidReference(1)));
+        assert_eq!(new("${year"), Err(TemplateError::NoRightBrace(0)));
+
+        assert_eq!(
+            new("$").unwrap_err().to_string(),
+            "TemplateError: invalid reference: pos = 0 (use $N, ${N}, ${name} or $$)"
+        );
+        assert_eq!(
+            new("$9").unwrap_err().to_string(),
+            "TemplateError: no such group: 9"
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! C言語などから使うためのFFI。宣言は`include/ch06_regex.h`にある。
+//!
+//! # 所有権
+//!
+//! `regex_compile`で得たハンドルは呼び出し側が所有し、使い終わったら`regex_free`でちょうど1度だけ解放する。
+//! 解放したハンドルを使ったり、2度解放したりしてはならない（検出できず、未定義動作となる）。
+//! ハンドルは複数のスレッドから同時に使ってよい。
+//!
+//! # エラー
+//!
+//! 各関数は失敗すると負のエラーコードを返し、エラーメッセージを呼び出したスレッドに保存する。
+//! メッセージは`regex_last_error_message`で取得できる。
+//! パニックは関数の外に伝播させず、`REGEX_ERR_PANIC`を返す。
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice, str};
+
+use crate::Regex;
+
+/// マッチしなかった、または成功した
+pub const REGEX_OK: c_int = 0;
+/// マッチした
+pub const REGEX_MATCH: c_int = 1;
+/// 必須の引数がヌルポインタ
+pub const REGEX_ERR_NULL: c_int = -1;
+/// パターンまたは入力がUTF-8として不正
+pub const REGEX_ERR_UTF8: c_int = -2;
+/// パターンのコンパイルに失敗した
+pub const REGEX_ERR_COMPILE: c_int = -3;
+/// 評価中にエラーが起きた
+pub const REGEX_ERR_EVAL: c_int = -4;
+/// パニックが起きた
+pub const REGEX_ERR_PANIC: c_int = -5;
+
+/// コンパイル済みの正規表現への不透明なハンドル
+pub struct RegexHandle {
+    regex: Regex,
+}
+
+thread_local! {
+    /// このスレッドで最後に起きたエラーのメッセージ
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// エラーコードとメッセージ
+struct FfiError(c_int, String);
+
+fn set_last_error(message: String) {
+    // メッセージにNUL文字が含まれていれば、その手前までとする
+    let message = CString::new(message).unwrap_or_else(|e| {
+        let end = e.nul_position();
+        CString::new(&e.into_vec()[..end]).unwrap_or_default()
+    });
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// `f`を実行し、エラーやパニックをエラーコードに変換する
+fn call(f: impl FnOnce() -> Result<c_int, FfiError>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => code,
+        Ok(Err(FfiError(code, message))) => {
+            set_last_error(message);
+            code
+        }
+        Err(_) => {
+            set_last_error("panic in regex engine".to_string());
+            REGEX_ERR_PANIC
+        }
+    }
+}
+
+fn null_error(name: &str) -> FfiError {
+    FfiError(REGEX_ERR_NULL, format!("{name} is null"))
+}
+
+/// `text`から`len`バイトを文字列として読む。`len`が0なら`text`はヌルポインタでもよい。
+///
+/// # Safety
+///
+/// `text`は`len`バイト読み出せる領域を指していなければならない。
+unsafe fn text_arg<'a>(text: *const c_char, len: usize) -> Result<&'a str, FfiError> {
+    if len == 0 {
+        return Ok("");
+    }
+    if text.is_null() {
+        return Err(null_error("text"));
+    }
+    let bytes = slice::from_raw_parts(text.cast::<u8>(), len);
+    str::from_utf8(bytes).map_err(|e| FfiError(REGEX_ERR_UTF8, format!("invalid text: {e}")))
+}
+
+/// # Safety
+///
+/// `handle`はヌルポインタか、`regex_compile`で得て解放していないハンドルでなければならない。
+unsafe fn handle_arg<'a>(handle: *const RegexHandle) -> Result<&'a Regex, FfiError> {
+    handle
+        .as_ref()
+        .map(|handle| &handle.regex)
+        .ok_or_else(|| null_error("handle"))
+}
+
+/// NUL終端の`pattern`をコンパイルし、ハンドルを`*out`に書き込む。成功すれば`REGEX_OK`を返す。
+///
+/// # Safety
+///
+/// `pattern`はNUL終端の文字列を、`out`は書き込み可能な領域を指していなければならない。
+#[no_mangle]
+pub unsafe extern "C" fn regex_compile(
+    pattern: *const c_char,
+    out: *mut *mut RegexHandle,
+) -> c_int {
+    call(|| {
+        if pattern.is_null() {
+            return Err(null_error("pattern"));
+        }
+        if out.is_null() {
+            return Err(null_error("out"));
+        }
+        *out = ptr::null_mut();
+
+        let pattern = CStr::from_ptr(pattern)
+            .to_str()
+            .map_err(|e| FfiError(REGEX_ERR_UTF8, format!("invalid pattern: {e}")))?;
+        let regex = Regex::new(pattern).map_err(|e| FfiError(REGEX_ERR_COMPILE, e.to_string()))?;
+        *out = Box::into_raw(Box::new(RegexHandle { regex }));
+        Ok(REGEX_OK)
+    })
+}
+
+/// `text`の`len`バイトのいずれかの位置からマッチすれば`REGEX_MATCH`、しなければ`REGEX_OK`を返す。
+///
+/// # Safety
+///
+/// `handle`は`regex_compile`で得て解放していないハンドルで、`text`は`len`バイト読み出せなければならない。
+#[no_mangle]
+pub unsafe extern "C" fn regex_is_match(
+    handle: *const RegexHandle,
+    text: *const c_char,
+    len: usize,
+) -> c_int {
+    call(|| {
+        let regex = handle_arg(handle)?;
+        let text = text_arg(text, len)?;
+        match regex.try_is_match(text) {
+            Ok(true) => Ok(REGEX_MATCH),
+            Ok(false) => Ok(REGEX_OK),
+            Err(e) => Err(FfiError(REGEX_ERR_EVAL, e.to_string())),
+        }
+    })
+}
+
+/// `text`の`len`バイト中で最も左にあるマッチを探す。
+/// 見つかれば開始位置と終了位置をバイト単位で`*start`と`*end`に書き込んで`REGEX_MATCH`を返し、
+/// 見つからなければ`REGEX_OK`を返す。
+///
+/// # Safety
+///
+/// `regex_is_match`の条件に加え、`start`と`end`は書き込み可能な領域を指していなければならない。
+#[no_mangle]
+pub unsafe extern "C" fn regex_find(
+    handle: *const RegexHandle,
+    text: *const c_char,
+    len: usize,
+    start: *mut usize,
+    end: *mut usize,
+) -> c_int {
+    call(|| {
+        let regex = handle_arg(handle)?;
+        let text = text_arg(text, len)?;
+        if start.is_null() {
+            return Err(null_error("start"));
+        }
+        if end.is_null() {
+            return Err(null_error("end"));
+        }
+
+        match regex.try_find(text) {
+            Ok(Some(m)) => {
+                *start = m.start();
+                *end = m.end();
+                Ok(REGEX_MATCH)
+            }
+            Ok(None) => Ok(REGEX_OK),
+            Err(e) => Err(FfiError(REGEX_ERR_EVAL, e.to_string())),
+        }
+    })
+}
+
+/// `regex_compile`で得たハンドルを解放する。ヌルポインタなら何もしない。
+///
+/// # Safety
+///
+/// `handle`はヌルポインタか、`regex_compile`で得てまだ解放していないハンドルでなければならない。
+/// 解放した後は`handle`を使ってはならない。
+#[no_mangle]
+pub unsafe extern "C" fn regex_free(handle: *mut RegexHandle) {
+    if !handle.is_null() {
+        // 解放中のパニックは無視する。呼び出し側に返す値がないため
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(handle))));
+    }
+}
+
+/// このスレッドで最後に起きたエラーのメッセージを返す。エラーが起きていなければヌルポインタを返す。
+/// 返した文字列は、このスレッドで次にエラーが起きるまで有効。呼び出し側で解放してはならない。
+#[no_mangle]
+pub extern "C" fn regex_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RegexBuilder;
+
+    fn last_error() -> Option<String> {
+        let message = regex_last_error_message();
+        if message.is_null() {
+            None
+        } else {
+            Some(
+                unsafe { CStr::from_ptr(message) }
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_ffi() {
+        unsafe {
+            let mut handle = ptr::null_mut();
+            assert_eq!(regex_compile(c"b+c".as_ptr(), &mut handle), REGEX_OK);
+            assert!(!handle.is_null());
+
+            let text = "aあbbc";
+            let ptr = text.as_ptr().cast::<c_char>();
+            assert_eq!(regex_is_match(handle, ptr, text.len()), REGEX_MATCH);
+            assert_eq!(regex_is_match(handle, ptr, 4), REGEX_OK);
+            assert_eq!(regex_is_match(handle, ptr::null(), 0), REGEX_OK);
+
+            let (mut start, mut end) = (0, 0);
+            assert_eq!(
+                regex_find(handle, ptr, text.len(), &mut start, &mut end),
+                REGEX_MATCH
+            );
+            assert_eq!((start, end), (4, 7));
+            assert_eq!(regex_find(handle, ptr, 4, &mut start, &mut end), REGEX_OK);
+
+            regex_free(handle);
+            regex_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_ffi_errors() {
+        unsafe {
+            // 不正なパターン
+            let mut handle = ptr::null_mut();
+            assert_eq!(
+                regex_compile(c"(a".as_ptr(), &mut handle),
+                REGEX_ERR_COMPILE
+            );
+            assert!(handle.is_null());
+            assert_eq!(
+                last_error().as_deref(),
+                Some("ParseError: no right parenthesis")
+            );
+
+            // UTF-8として不正なパターンと入力
+            let invalid = [0xffu8, 0];
+            assert_eq!(
+                regex_compile(invalid.as_ptr().cast(), &mut handle),
+                REGEX_ERR_UTF8
+            );
+            assert!(last_error().unwrap().starts_with("invalid pattern"));
+
+            assert_eq!(regex_compile(c"a".as_ptr(), &mut handle), REGEX_OK);
+            assert_eq!(
+                regex_is_match(handle, invalid.as_ptr().cast(), 1),
+                REGEX_ERR_UTF8
+            );
+            // マルチバイト文字の途中までの長さ
+            let text = "あ";
+            assert_eq!(
+                regex_is_match(handle, text.as_ptr().cast(), 2),
+                REGEX_ERR_UTF8
+            );
+
+            // ヌルポインタ
+            assert_eq!(regex_compile(ptr::null(), &mut handle), REGEX_ERR_NULL);
+            assert_eq!(
+                regex_is_match(ptr::null(), c"a".as_ptr(), 1),
+                REGEX_ERR_NULL
+            );
+            assert_eq!(last_error().as_deref(), Some("handle is null"));
+            assert_eq!(regex_is_match(handle, ptr::null(), 1), REGEX_ERR_NULL);
+            let mut start = 0;
+            assert_eq!(
+                regex_find(handle, c"a".as_ptr(), 1, &mut start, ptr::null_mut()),
+                REGEX_ERR_NULL
+            );
+            regex_free(handle);
+
+            // 評価中のエラー
+            let regex = RegexBuilder::new("(a|b)*c")
+                .step_limit(Some(50))
+                .build()
+                .unwrap();
+            let handle = Box::into_raw(Box::new(RegexHandle { regex }));
+            let text = "ab".repeat(100);
+            let ptr = text.as_ptr().cast::<c_char>();
+            assert_eq!(regex_is_match(handle, ptr, text.len()), REGEX_ERR_EVAL);
+            let (mut start, mut end) = (0, 0);
+            assert_eq!(
+                regex_find(handle, ptr, text.len(), &mut start, &mut end),
+                REGEX_ERR_EVAL
+            );
+            assert!(last_error().unwrap().contains("step limit"));
+
+            regex_free(handle);
+        }
+    }
+
+    #[test]
+    fn test_ffi_panic() {
+        assert_eq!(call(|| panic!("boom")), REGEX_ERR_PANIC);
+        assert_eq!(last_error().as_deref(), Some("panic in regex engine"));
+
+        // エラーはスレッドごとに保存する
+        std::thread::spawn(|| assert_eq!(last_error(), None))
+            .join()
+            .unwrap();
+    }
+}
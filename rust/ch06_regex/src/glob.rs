@@ -0,0 +1,185 @@
+//! `--include`などで指定するファイル名のグロブ。
+//!
+//! `*`は任意の文字列に、`?`は任意の1文字に、`[...]`は括弧内のいずれかの文字にマッチする。
+//! `[...]`では`a-z`のような範囲を書け、先頭の`!`または`^`で否定する。先頭の`]`は文字として扱う。
+//! `\`は続く1文字をそのまま表す。閉じていない`[`は文字として扱う。
+//!
+//! 否定した文字の集合はこのクレートの正規表現では書けないので、グロブは正規表現に変換せずに直接照合する。
+
+/// グロブの要素
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `[...]`。範囲の両端と、否定したか
+    Class(Vec<(char, char)>, bool),
+}
+
+/// コンパイル済みのグロブ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        let chars = pattern.chars().collect::<Vec<_>>();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let token = match chars[i] {
+                '*' => Token::Star,
+                '?' => Token::Any,
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    Token::Char(chars[i])
+                }
+                '[' => match parse_class(&chars, i) {
+                    Some((token, end)) => {
+                        i = end;
+                        token
+                    }
+                    None => Token::Char('['),
+                },
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+            i += 1;
+        }
+
+        Glob { tokens }
+    }
+
+    /// `name`全体がグロブにマッチするか
+    pub fn is_match(&self, name: &str) -> bool {
+        let name = name.chars().collect::<Vec<_>>();
+        let (mut t, mut n) = (0, 0);
+        // 直前の`*`の位置と、その`*`にマッチさせた文字列の終わり
+        let mut star: Option<(usize, usize)> = None;
+
+        while n < name.len() {
+            match self.tokens.get(t) {
+                Some(Token::Star) => {
+                    star = Some((t, n));
+                    t += 1;
+                    continue;
+                }
+                Some(token) if token_matches(token, name[n]) => {
+                    t += 1;
+                    n += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            // 直前の`*`にもう1文字マッチさせてやり直す
+            match star {
+                Some((star_t, star_n)) => {
+                    star = Some((star_t, star_n + 1));
+                    t = star_t + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            }
+        }
+
+        self.tokens[t..].iter().all(|token| *token == Token::Star)
+    }
+}
+
+fn token_matches(token: &Token, c: char) -> bool {
+    match token {
+        Token::Char(expected) => *expected == c,
+        Token::Any => true,
+        Token::Star => false,
+        Token::Class(ranges, negated) => {
+            ranges.iter().any(|(from, to)| (*from..=*to).contains(&c)) != *negated
+        }
+    }
+}
+
+/// 位置`start`の`[`で始まる文字の集合と、閉じる`]`の位置を返す。閉じていなければ`None`を返す。
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(chars.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    let first = i;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ']' && i > first {
+            return Some((Token::Class(ranges, negated), i));
+        }
+        match chars.get(i + 1..i + 3) {
+            Some(['-', to]) if *to != ']' => {
+                ranges.push((c, *to));
+                i += 3;
+            }
+            _ => {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, name: &str) -> bool {
+        Glob::new(pattern).is_match(name)
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(is_match("*.rs", "main.rs"));
+        assert!(is_match("*.rs", ".rs"));
+        assert!(!is_match("*.rs", "main.rs.bak"));
+        assert!(is_match("*.min.js", "app.min.js"));
+        assert!(!is_match("*.min.js", "app.js"));
+        assert!(is_match("a*b*c", "aXbYbZc"));
+        assert!(!is_match("a*b*c", "aXbYbZ"));
+        assert!(is_match("*", ""));
+        assert!(is_match("**", "abc"));
+
+        assert!(is_match("?.txt", "a.txt"));
+        assert!(!is_match("?.txt", "ab.txt"));
+        assert!(is_match("?.txt", "あ.txt"));
+
+        // 完全に一致する必要がある
+        assert!(is_match("Makefile", "Makefile"));
+        assert!(!is_match("Makefile", "Makefile.am"));
+        assert!(!is_match("akefile", "Makefile"));
+    }
+
+    #[test]
+    fn test_glob_class() {
+        assert!(is_match("[abc].txt", "b.txt"));
+        assert!(!is_match("[abc].txt", "d.txt"));
+        assert!(is_match("file[0-9]", "file7"));
+        assert!(!is_match("file[0-9]", "filex"));
+        assert!(is_match("[a-cx-z]", "y"));
+
+        // 否定
+        assert!(is_match("[!a-c]*", "dog"));
+        assert!(!is_match("[!a-c]*", "cat"));
+        assert!(is_match("[^.]*", "visible"));
+        assert!(!is_match("[^.]*", ".hidden"));
+
+        // 先頭の`]`、末尾の`-`、閉じていない`[`
+        assert!(is_match("[]a]", "]"));
+        assert!(is_match("[a-]", "-"));
+        assert!(is_match("a[b", "a[b"));
+
+        // エスケープ
+        assert!(is_match(r"\*.txt", "*.txt"));
+        assert!(!is_match(r"\*.txt", "a.txt"));
+    }
+}
@@ -1,19 +1,332 @@
-pub type DynError = Box<dyn std::error::Error + Send + Sync + 'static>;
+use std::{
+    error::Error as StdError,
+    fmt::{Display, Formatter},
+};
 
+use crate::engine::{CodeGenError, EngineError, EvalError, ParseError, TemplateError};
+
+/// 以前の`Box<dyn Error>`に代わる`Error`の別名。既存のシグネチャをそのまま使えるよう残す。
+pub type DynError = Error;
+
+/// エラーの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// パターンや置換文字列の解析
+    Parse,
+    /// コード生成
+    CodeGen,
+    /// 評価
+    Eval,
+    /// 入出力
+    Io,
+    /// 引数や入力の誤り
+    Usage,
+}
+
+/// 種類と原因、付け加えた説明からなるエラー。
+/// `context`で説明を付け加えるたびに外側に層が1つ増え、`chain`で外側の層から順に辿れる。
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    /// この層の説明。`None`であれば`source`の説明をそのまま用いる。
+    context: Option<String>,
+    /// 内側の層、または元のエラー
+    source: Option<Box<dyn StdError + Send + Sync + 'static>>,
+}
+
+impl Error {
+    /// 元のエラー`source`を種類`kind`のエラーとする
+    pub fn new(kind: ErrorKind, source: impl StdError + Send + Sync + 'static) -> Self {
+        Error {
+            kind,
+            context: None,
+            source: Some(Box::new(source)),
+        }
+    }
+
+    /// 元のエラーのない、説明`message`のみのエラー
+    pub fn usage(message: impl Into<String>) -> Self {
+        Error {
+            kind: ErrorKind::Usage,
+            context: Some(message.into()),
+            source: None,
+        }
+    }
+
+    /// 最も内側の層の種類
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// 説明`context`を外側の層として付け加える。種類は変わらない。
+    pub fn context(self, context: impl Into<String>) -> Self {
+        Error {
+            kind: self.kind,
+            context: Some(context.into()),
+            source: Some(Box::new(self)),
+        }
+    }
+
+    /// 各層の説明を外側から順に返す。最後は元のエラーの説明となる。
+    pub fn chain(&self) -> impl Iterator<Item = &Error> {
+        std::iter::successors(Some(self), |error| error.inner())
+    }
+
+    /// 各層と元のエラー、その原因を辿り、`T`のエラーがあれば返す
+    pub fn find<T: StdError + 'static>(&self) -> Option<&T> {
+        let last = self.chain().last()?;
+        let mut source: Option<&(dyn StdError + 'static)> = Some(last.source.as_deref()?);
+        while let Some(e) = source {
+            if let Some(found) = e.downcast_ref::<T>() {
+                return Some(found);
+            }
+            source = e.source();
+        }
+        None
+    }
+
+    /// `context`で付け加えた層であれば、その内側の層
+    fn inner(&self) -> Option<&Error> {
+        self.context.as_ref()?;
+        self.source.as_deref()?.downcast_ref::<Error>()
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.context, &self.source) {
+            (Some(context), _) => write!(f, "{context}"),
+            (None, Some(source)) => write!(f, "{source}"),
+            (None, None) => write!(f, "{:?}", self.kind),
+        }
+    }
+}
+
+impl StdError for Error {
+    /// 説明を元のエラーから借りている層は、元のエラーと同じ説明を繰り返さないよう、その原因を返す
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        let source = self.source.as_deref()?;
+        if self.context.is_some() {
+            Some(source)
+        } else {
+            source.source()
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::new(ErrorKind::Parse, e)
+    }
+}
+
+impl From<CodeGenError> for Error {
+    fn from(e: CodeGenError) -> Self {
+        Error::new(ErrorKind::CodeGen, e)
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(e: EvalError) -> Self {
+        Error::new(ErrorKind::Eval, e)
+    }
+}
+
+impl From<TemplateError> for Error {
+    fn from(e: TemplateError) -> Self {
+        Error::new(ErrorKind::Parse, e)
+    }
+}
+
+impl From<&EngineError> for ErrorKind {
+    fn from(e: &EngineError) -> Self {
+        match e {
+            EngineError::Parse(_) => ErrorKind::Parse,
+            EngineError::CodeGen(_) => ErrorKind::CodeGen,
+            EngineError::Eval(_) => ErrorKind::Eval,
+            EngineError::Io(_) => ErrorKind::Io,
+        }
+    }
+}
+
+impl From<EngineError> for Error {
+    fn from(e: EngineError) -> Self {
+        Error::new(ErrorKind::from(&e), e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::new(ErrorKind::Io, e)
+    }
+}
+
+/// 文字列として読めない入力や、数として解析できない文字列は入力の誤りとする
+macro_rules! impl_from_usage_error {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Error {
+                fn from(e: $t) -> Self {
+                    Error::new(ErrorKind::Usage, e)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_usage_error!(
+    std::string::FromUtf8Error,
+    std::num::ParseIntError,
+    std::num::ParseFloatError
+);
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::usage(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::usage(message)
+    }
+}
+
+/// `Result`のエラーに説明を付け加える
+pub trait ResultExt<T> {
+    fn context(self, context: impl Into<String>) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T, Error> {
+        self.map_err(|e| e.into().context(context))
+    }
+}
+
+/// 溢れを検査する演算
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            Op::Add => '+',
+            Op::Sub => '-',
+            Op::Mul => '*',
+        };
+        write!(f, "{symbol}")
+    }
+}
+
+/// 溢れを検査する加減乗算。溢れた場合は`None`を返す。
+pub trait CheckedOps: Copy {
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+    fn checked_mul(self, rhs: Self) -> Option<Self>;
+
+    fn checked(self, op: Op, rhs: Self) -> Option<Self> {
+        match op {
+            Op::Add => CheckedOps::checked_add(self, rhs),
+            Op::Sub => CheckedOps::checked_sub(self, rhs),
+            Op::Mul => CheckedOps::checked_mul(self, rhs),
+        }
+    }
+}
+
+macro_rules! impl_checked_ops {
+    ($($t:ty),*) => {
+        $(
+            impl CheckedOps for $t {
+                fn checked_add(self, rhs: Self) -> Option<Self> {
+                    <$t>::checked_add(self, rhs)
+                }
+
+                fn checked_sub(self, rhs: Self) -> Option<Self> {
+                    <$t>::checked_sub(self, rhs)
+                }
+
+                fn checked_mul(self, rhs: Self) -> Option<Self> {
+                    <$t>::checked_mul(self, rhs)
+                }
+            }
+        )*
+    };
+}
+
+impl_checked_ops!(u8, u16, u32, u64, u128, usize);
+
+/// 溢れた演算`lhs op rhs`の被演算子と演算子
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow<T> {
+    pub lhs: T,
+    pub op: Op,
+    pub rhs: T,
+}
+
+impl<T: Display> Display for Overflow<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.lhs, self.op, self.rhs)
+    }
+}
+
+/// `*dst op src`を`dst`に書き込む。溢れた場合は`dst`を変えず、被演算子を`f`に渡して作ったエラーを返す。
+pub fn apply_checked<T, F, E>(dst: &mut T, src: T, op: Op, f: F) -> Result<(), E>
+where
+    T: CheckedOps,
+    F: FnOnce(Overflow<T>) -> E,
+{
+    match dst.checked(op, src) {
+        Some(n) => {
+            *dst = n;
+            Ok(())
+        }
+        None => Err(f(Overflow {
+            lhs: *dst,
+            op,
+            rhs: src,
+        })),
+    }
+}
+
+/// `checked!(pc += 1, EvalError::PCOverFlow)`のように、`+=`、`-=`、`*=`を`apply_checked`で行う。
+/// 溢れた場合は`Overflow`から作ったエラーを返す`Result`に評価される。
+macro_rules! checked {
+    ($($dst:ident).+ += $src:expr, $err:expr) => {
+        $crate::helper::apply_checked(&mut $($dst).+, $src, $crate::helper::Op::Add, $err)
+    };
+    ($($dst:ident).+ -= $src:expr, $err:expr) => {
+        $crate::helper::apply_checked(&mut $($dst).+, $src, $crate::helper::Op::Sub, $err)
+    };
+    ($($dst:ident).+ *= $src:expr, $err:expr) => {
+        $crate::helper::apply_checked(&mut $($dst).+, $src, $crate::helper::Op::Mul, $err)
+    };
+}
+
+pub(crate) use checked;
+
+#[deprecated(note = "use `CheckedOps` instead")]
+#[allow(dead_code)]
 pub trait SafeAdd: Sized {
     fn safe_add(&self, other: &Self) -> Option<Self>;
 }
 
-impl SafeAdd for usize {
+#[allow(deprecated)]
+impl<T: CheckedOps> SafeAdd for T {
     fn safe_add(&self, other: &Self) -> Option<Self> {
-        self.checked_add(*other)
+        CheckedOps::checked_add(*self, *other)
     }
 }
 
+#[deprecated(note = "use `checked!` or `apply_checked` instead")]
+#[allow(dead_code, deprecated)]
 pub fn safe_add<T, F, E>(dst: &mut T, src: &T, f: F) -> Result<(), E>
 where
     T: SafeAdd,
-    F: Fn() -> E,
+    F: FnOnce() -> E,
 {
     if let Some(n) = dst.safe_add(src) {
         *dst = n;
@@ -22,3 +335,153 @@ where
         Err(f())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Counter {
+        pc: usize,
+    }
+
+    macro_rules! test_checked {
+        ($($name:ident: $t:ty),*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let n: $t = 10;
+                    assert_eq!(Some(30), CheckedOps::checked_add(n, 20));
+                    assert_eq!(Some(5), CheckedOps::checked_sub(n, 5));
+                    assert_eq!(Some(20), CheckedOps::checked_mul(n, 2));
+
+                    let n: $t = !0;
+                    assert_eq!(None, CheckedOps::checked_add(n, 1));
+                    let n: $t = 0;
+                    assert_eq!(None, CheckedOps::checked_sub(n, 1));
+                    let n: $t = <$t>::MAX / 2;
+                    assert_eq!(None, CheckedOps::checked_mul(n, 3));
+
+                    // 成功すれば書き込み、溢れれば変えずにエラーを返す
+                    let mut n: $t = 10;
+                    assert_eq!(checked!(n += 20, |_| ()), Ok(()));
+                    assert_eq!(n, 30);
+                    assert_eq!(checked!(n -= 20, |_| ()), Ok(()));
+                    assert_eq!(n, 10);
+                    assert_eq!(checked!(n *= 3, |_| ()), Ok(()));
+                    assert_eq!(n, 30);
+
+                    let mut n: $t = !0;
+                    assert_eq!(
+                        checked!(n += 1, |o| o),
+                        Err(Overflow { lhs: !0, op: Op::Add, rhs: 1 })
+                    );
+                    assert_eq!(n, !0);
+                    let mut n: $t = 0;
+                    assert_eq!(
+                        checked!(n -= 1, |o| o),
+                        Err(Overflow { lhs: 0, op: Op::Sub, rhs: 1 })
+                    );
+                    assert_eq!(n, 0);
+                    let mut n: $t = <$t>::MAX / 2;
+                    assert_eq!(
+                        checked!(n *= 3, |o| o),
+                        Err(Overflow { lhs: <$t>::MAX / 2, op: Op::Mul, rhs: 3 })
+                    );
+                    assert_eq!(n, <$t>::MAX / 2);
+                }
+            )*
+        };
+    }
+
+    test_checked!(
+        test_checked_u8: u8,
+        test_checked_u16: u16,
+        test_checked_u32: u32,
+        test_checked_u64: u64,
+        test_checked_u128: u128,
+        test_checked: usize
+    );
+
+    #[test]
+    fn test_checked_macro() {
+        // フィールドにも使える
+        let mut counter = Counter { pc: usize::MAX - 1 };
+        assert_eq!(checked!(counter.pc += 1, |o| o.to_string()), Ok(()));
+        let err = checked!(counter.pc += 1, |o| o.to_string()).unwrap_err();
+        assert_eq!(err, "18446744073709551615 + 1");
+        assert_eq!(counter, Counter { pc: usize::MAX });
+
+        // エラーを作るクロージャには値をムーブできる
+        let context = String::from("counter");
+        let mut n: u8 = 0;
+        assert_eq!(
+            checked!(n -= 1, move |o| format!("{context}: {o}")),
+            Err("counter: 0 - 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_error_chain() {
+        fn parse() -> Result<(), Error> {
+            Err(ParseError::InvalidRightParen(3))?
+        }
+
+        let err = parse()
+            .context("while compiling pattern given on command line")
+            .context("-e #2")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+        let chain = err.chain().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            chain,
+            [
+                "-e #2",
+                "while compiling pattern given on command line",
+                "ParseError: invalid right parenthesis: pos = 3",
+            ]
+        );
+        assert_eq!(err.to_string(), "-e #2");
+        assert!(matches!(
+            err.find::<ParseError>(),
+            Some(ParseError::InvalidRightParen(3))
+        ));
+        assert!(err.find::<std::io::Error>().is_none());
+
+        // `source`は同じ説明を繰り返さない
+        let mut sources = Vec::new();
+        let mut source = err.source();
+        while let Some(e) = source {
+            sources.push(e.to_string());
+            source = e.source();
+        }
+        assert_eq!(sources, chain[1..]);
+
+        // 元のエラーの原因も辿れる
+        let err =
+            Error::from(EngineError::from(std::io::Error::other("disk"))).context("reading a.txt");
+        assert_eq!(err.kind(), ErrorKind::Io);
+        assert_eq!(err.find::<std::io::Error>().unwrap().to_string(), "disk");
+
+        let err = Error::from("no pattern").context("-f empty.txt");
+        assert_eq!(err.kind(), ErrorKind::Usage);
+        let chain = err.chain().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(chain, ["-f empty.txt", "no pattern"]);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_safe_add() {
+        let n: usize = 10;
+        assert_eq!(Some(30), n.safe_add(&20));
+
+        let n: usize = !0; // 2^64 - 1 (64 bits CPU)
+        assert_eq!(None, n.safe_add(&1));
+
+        let mut n: usize = 10;
+        assert!(safe_add(&mut n, &20, || ()).is_ok());
+
+        let mut n: usize = !0;
+        assert!(safe_add(&mut n, &1, || ()).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+//! `--respect-gitignore`で読む`.gitignore`の規則。
+//!
+//! 1行に1つの規則を書く。空行と`#`で始まる行は無視し、行末の空白は`\`で書いたものを除いて取り除く。
+//! 先頭の`!`は規則を否定し、一度無視したパスを再び含める。`\#`や`\!`は文字として扱う。
+//! 末尾の`/`はディレクトリにのみマッチすることを表す。
+//!
+//! `/`を含まない規則は、どの階層の名前にもマッチする。先頭や途中に`/`を含む規則は、
+//! `.gitignore`のあるディレクトリからの相対パスにマッチする。
+//! パスの各要素は`Glob`で照合するので、`*`や`?`は`/`にマッチしない。
+//! 要素全体が`**`であれば、0個以上の要素にマッチする。ただし末尾の`/**`は1個以上の要素にマッチする。
+
+use std::path::{Path, PathBuf};
+
+use crate::glob::Glob;
+
+/// 規則のパスの要素
+#[derive(Debug, Clone)]
+enum Segment {
+    Glob(Glob),
+    /// `**`
+    AnyDirs,
+}
+
+/// 1行の規則
+#[derive(Debug, Clone)]
+struct Rule {
+    segments: Vec<Segment>,
+    /// 先頭の`!`
+    negated: bool,
+    /// 末尾の`/`
+    dir_only: bool,
+}
+
+impl Rule {
+    /// 1行を規則として解析する。空行やコメントであれば`None`を返す。
+    fn parse(line: &str) -> Option<Self> {
+        let line = trim_trailing_spaces(line);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        if pattern.is_empty() {
+            return None;
+        }
+
+        // `/`を含まない規則は`**/`で始まる規則と同じ
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        let mut segments = Vec::new();
+        if !anchored {
+            segments.push(Segment::AnyDirs);
+        }
+        segments.extend(pattern.split('/').map(|segment| match segment {
+            "**" => Segment::AnyDirs,
+            _ => Segment::Glob(Glob::new(segment)),
+        }));
+
+        Some(Rule {
+            segments,
+            negated,
+            dir_only,
+        })
+    }
+
+    fn is_match(&self, components: &[&str], is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && match_segments(&self.segments, components)
+    }
+}
+
+/// 行末の空白を取り除く。`\`で書いた空白は残す。
+fn trim_trailing_spaces(line: &str) -> &str {
+    let mut end = line.trim_end_matches(' ').len();
+    // 取り除いた空白の直前が`\`であれば、その後の空白を1つ残す
+    if end < line.len() && line[..end].ends_with('\\') {
+        end += 1;
+    }
+    &line[..end]
+}
+
+fn match_segments(segments: &[Segment], components: &[&str]) -> bool {
+    match segments.split_first() {
+        None => components.is_empty(),
+        // 末尾の`**`は中身にのみマッチする
+        Some((Segment::AnyDirs, [])) => !components.is_empty(),
+        Some((Segment::AnyDirs, rest)) => {
+            (0..=components.len()).any(|i| match_segments(rest, &components[i..]))
+        }
+        Some((Segment::Glob(glob), rest)) => match components.split_first() {
+            Some((first, others)) => glob.is_match(first) && match_segments(rest, others),
+            None => false,
+        },
+    }
+}
+
+/// 1つの`.gitignore`の規則
+#[derive(Debug, Clone)]
+pub struct Gitignore {
+    /// `.gitignore`のあるディレクトリ
+    dir: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl Gitignore {
+    /// ディレクトリ`dir`にある`.gitignore`の内容`content`を解析する
+    pub fn new(dir: impl Into<PathBuf>, content: &str) -> Self {
+        Gitignore {
+            dir: dir.into(),
+            rules: content.lines().filter_map(Rule::parse).collect(),
+        }
+    }
+
+    /// `path`にマッチする最後の規則が無視するものであれば`Some(true)`、否定したものであれば`Some(false)`を返す。
+    /// どの規則にもマッチしない、または`path`が`dir`の下にない場合は`None`を返す。
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.dir).ok()?;
+        let components = relative
+            .iter()
+            .map(|component| component.to_str())
+            .collect::<Option<Vec<_>>>()?;
+        if components.is_empty() {
+            return None;
+        }
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.is_match(&components, is_dir))
+            .map(|rule| !rule.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `content`を`/repo/.gitignore`として、`/repo`からの相対パス`path`を無視するか
+    fn ignored(content: &str, path: &str, is_dir: bool) -> bool {
+        let gitignore = Gitignore::new("/repo", content);
+        gitignore.matched(&Path::new("/repo").join(path), is_dir) == Some(true)
+    }
+
+    #[test]
+    fn test_gitignore() {
+        // 空行とコメント
+        let gitignore = Gitignore::new("/repo", "\n# comment\n  \n");
+        assert!(gitignore.rules.is_empty());
+        assert!(ignored(r"\#file", "#file", false));
+        assert!(ignored("a.txt  ", "a.txt", false));
+        assert!(ignored(r"a\ ", "a ", false));
+
+        // `hello.*`は`hello.`で始まる名前のファイルとディレクトリにマッチする
+        assert!(ignored("hello.*", "hello.c", false));
+        assert!(ignored("hello.*", "src/hello.h", false));
+        assert!(ignored("hello.*", "hello.d", true));
+        assert!(!ignored("hello.*", "hello", false));
+
+        // `foo/`はディレクトリ`foo`にのみマッチし、ファイル`foo`にはマッチしない
+        assert!(ignored("foo/", "foo", true));
+        assert!(ignored("foo/", "a/foo", true));
+        assert!(!ignored("foo/", "foo", false));
+
+        // `doc/frotz`と`/doc/frotz`は同じで、`a/doc/frotz`にはマッチしない
+        for rule in ["doc/frotz", "/doc/frotz"] {
+            assert!(ignored(rule, "doc/frotz", true));
+            assert!(!ignored(rule, "a/doc/frotz", true));
+        }
+        assert!(ignored("/bar", "bar", false));
+        assert!(!ignored("/bar", "a/bar", false));
+
+        // `foo/*`は`foo/test.json`と`foo/bar`にマッチし、`foo/bar/hello.c`にはマッチしない
+        assert!(ignored("foo/*", "foo/test.json", false));
+        assert!(ignored("foo/*", "foo/bar", true));
+        assert!(!ignored("foo/*", "foo/bar/hello.c", false));
+    }
+
+    #[test]
+    fn test_gitignore_double_star() {
+        // `**/foo`はどこにある`foo`にもマッチする
+        assert!(ignored("**/foo", "foo", false));
+        assert!(ignored("**/foo", "a/b/foo", true));
+        // `**/foo/bar`は`foo`の直下の`bar`にマッチする
+        assert!(ignored("**/foo/bar", "foo/bar", false));
+        assert!(ignored("**/foo/bar", "a/foo/bar", false));
+        assert!(!ignored("**/foo/bar", "foo/a/bar", false));
+
+        // `abc/**`は`abc`の中身にのみマッチする
+        assert!(ignored("abc/**", "abc/x", false));
+        assert!(ignored("abc/**", "abc/x/y", false));
+        assert!(!ignored("abc/**", "abc", true));
+
+        // `a/**/b`は`a/b`、`a/x/b`、`a/x/y/b`にマッチする
+        for path in ["a/b", "a/x/b", "a/x/y/b"] {
+            assert!(ignored("a/**/b", path, false));
+        }
+        assert!(!ignored("a/**/b", "a/x/c", false));
+    }
+
+    #[test]
+    fn test_gitignore_negation() {
+        // 最後にマッチした規則に従う
+        let content = "*.log\n!important.log\n";
+        assert!(ignored(content, "debug.log", false));
+        assert!(!ignored(content, "important.log", false));
+        let gitignore = Gitignore::new("/repo", content);
+        assert_eq!(
+            gitignore.matched(Path::new("/repo/important.log"), false),
+            Some(false)
+        );
+        assert_eq!(gitignore.matched(Path::new("/repo/a.txt"), false), None);
+        assert!(ignored("!a\na", "a", false));
+
+        // ディレクトリ`foo/bar`以外を全て無視する
+        let content = "/*\n!/foo\n/foo/*\n!/foo/bar\n";
+        assert!(ignored(content, "a.txt", false));
+        assert!(!ignored(content, "foo", true));
+        assert!(ignored(content, "foo/baz", true));
+        assert!(!ignored(content, "foo/bar", true));
+
+        assert!(ignored(r"\!important", "!important", false));
+
+        // `dir`の下にないパス
+        let gitignore = Gitignore::new("/repo/sub", "*");
+        assert_eq!(gitignore.matched(Path::new("/repo/a"), false), None);
+        assert_eq!(gitignore.matched(Path::new("/repo/sub"), true), None);
+        assert_eq!(
+            gitignore.matched(Path::new("/repo/sub/a"), false),
+            Some(true)
+        );
+    }
+}
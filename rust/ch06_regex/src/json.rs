@@ -0,0 +1,51 @@
+//! `--json`で書き出すJSONの文字列。
+//!
+//! 書き出すのは数値と文字列、それらの配列とオブジェクトのみなので、文字列のエスケープのみを行う。
+
+use std::io::Write;
+
+/// `s`をJSONの文字列として、`"`で囲んで書き出す。
+/// `"`と`\`、制御文字をエスケープし、それ以外の文字はUTF-8のまま書き出す。
+pub fn write_str(out: &mut impl Write, s: &str) -> std::io::Result<()> {
+    write!(out, "\"")?;
+    let mut last = 0;
+    for (i, c) in s.char_indices() {
+        let escaped = match c {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            '\n' => "\\n".to_string(),
+            '\r' => "\\r".to_string(),
+            '\t' => "\\t".to_string(),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                format!("\\u{:04x}", c as u32)
+            }
+            _ => continue,
+        };
+        write!(out, "{}{escaped}", &s[last..i])?;
+        last = i + c.len_utf8();
+    }
+    write!(out, "{}\"", &s[last..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_json(s: &str) -> String {
+        let mut out = Vec::new();
+        write_str(&mut out, s).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_str() {
+        assert_eq!(to_json(""), r#""""#);
+        assert_eq!(to_json("abc"), r#""abc""#);
+        assert_eq!(to_json(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(to_json(r"C:\dir"), r#""C:\\dir""#);
+        assert_eq!(to_json("a\tb\r\n"), r#""a\tb\r\n""#);
+        assert_eq!(to_json("\u{0}\u{1b}\u{7f}"), r#""\u0000\u001b\u007f""#);
+        // ASCII以外の文字はそのまま
+        assert_eq!(to_json("日本語 é"), "\"日本語 é\"");
+    }
+}
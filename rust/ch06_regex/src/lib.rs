@@ -1,4 +1,17 @@
+//! 正規表現エンジン。パターンを`Regex::new`でコンパイルし、`is_match`や`find`などで検索する。
+
 mod engine;
+pub mod ffi;
 mod helper;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
-pub use engine::{do_matching, print};
+pub use engine::{
+    cached_match, check_compatibility, clear_cache, compile, do_matching, do_matching_set,
+    do_matching_with, find_all, find_all_overlapping, match_at, match_compiled, match_full,
+    match_line, match_line_compiled, match_prefix, print, print_stdout, set_cache_capacity,
+    trace_matching, which_branch, Captures, CodeGenError, Construct, Engine, EngineError,
+    EvalError, Instruction, Match, MatchMetrics, Matches, Normalization, Observer, ParseError, Pc,
+    Regex, RegexBuilder, RegexSet, SetMatches, Split, SplitN, Template, TemplateError, Unsupported,
+};
+pub use helper::{DynError, Error, ErrorKind, ResultExt};
@@ -0,0 +1,99 @@
+//! 検索するファイルの内容を1行ずつ取り出す読み込み元。
+//!
+//! 通常は`BufRead`から行を1つずつバッファにコピーして読む。
+//! `mmap`フィーチャーを有効にすると、`--mmap`でファイルをメモリにマップし、コピーせずにマップした内容の一部を行として渡す。
+//! `gzip`フィーチャーを有効にすると、先頭がgzipのマジックナンバーであるファイルは展開しながら読む。
+//! どの読み込み元も同じ`select_lines`で評価するので、選ぶ行や出力は変わらない。
+
+use std::io::{self, BufRead};
+
+use ch06_regex::DynError;
+
+/// 行を1つずつ取り出す読み込み元
+pub trait LineSource {
+    /// 行を読む前に、先頭の少なくとも一部を消費せずに返す。内容が空であれば空のスライスを返す。
+    fn peek(&mut self) -> io::Result<&[u8]>;
+
+    /// 各行を、末尾の改行を含めて順に`f`に渡す。`f`が`false`を返すと、それより後は読まない。
+    fn for_each_line(self, f: impl FnMut(&[u8]) -> Result<bool, DynError>) -> Result<(), DynError>;
+}
+
+impl<R: BufRead> LineSource for R {
+    fn peek(&mut self) -> io::Result<&[u8]> {
+        self.fill_buf()
+    }
+
+    fn for_each_line(
+        mut self,
+        mut f: impl FnMut(&[u8]) -> Result<bool, DynError>,
+    ) -> Result<(), DynError> {
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            if self.read_until(b'\n', &mut buf)? == 0 || !f(&buf)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// gzipで圧縮されたファイルの先頭の2バイト
+#[cfg(feature = "gzip")]
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// `reader`の先頭がgzipのマジックナンバーであるかを、先頭を消費せずに調べる。
+/// 拡張子ではなく内容で判断するので、`.gz`以外の名前に変えたファイルも展開する。
+#[cfg(feature = "gzip")]
+pub fn is_gzip(reader: &mut impl BufRead) -> io::Result<bool> {
+    Ok(reader.fill_buf()?.starts_with(&GZIP_MAGIC))
+}
+
+/// gzipで圧縮された`reader`を展開しながら読む。
+/// `cat a.gz b.gz`のように複数のメンバーを連結したファイルは、展開した内容を順に連結して読む。
+#[cfg(feature = "gzip")]
+pub fn gunzip(reader: impl BufRead) -> impl BufRead {
+    io::BufReader::new(flate2::bufread::MultiGzDecoder::new(reader))
+}
+
+/// メモリにマップしたファイル
+#[cfg(feature = "mmap")]
+pub struct Mapped(memmap2::Mmap);
+
+#[cfg(feature = "mmap")]
+impl Mapped {
+    /// `file`をメモリにマップする。通常のファイルでない場合や、
+    /// `/proc`以下のファイルのように大きさが0と報告される場合は`None`を返すので、`BufRead`から読む。
+    pub fn new(file: &std::fs::File) -> io::Result<Option<Self>> {
+        let metadata = file.metadata()?;
+        if !metadata.is_file() || metadata.len() == 0 {
+            return Ok(None);
+        }
+        // 検索中に他のプロセスがファイルを切り詰めると、範囲外を読んでシグナルで終了しうる。
+        // 読み込み専用にマップし、書き換えはしない。
+        let map = unsafe { memmap2::Mmap::map(file)? };
+        Ok(Some(Mapped(map)))
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl LineSource for Mapped {
+    fn peek(&mut self) -> io::Result<&[u8]> {
+        Ok(&self.0)
+    }
+
+    fn for_each_line(
+        self,
+        mut f: impl FnMut(&[u8]) -> Result<bool, DynError>,
+    ) -> Result<(), DynError> {
+        let mut rest = &self.0[..];
+        while !rest.is_empty() {
+            let end = memchr::memchr(b'\n', rest).map_or(rest.len(), |i| i + 1);
+            let (line, next) = rest.split_at(end);
+            if !f(line)? {
+                break;
+            }
+            rest = next;
+        }
+        Ok(())
+    }
+}
@@ -1,59 +1,3476 @@
+mod bench;
+mod cli;
+mod context;
+mod glob;
+mod ignore;
+mod json;
+mod lines;
+mod pool;
+mod progress;
+mod stats;
+mod tsv;
+mod walk;
+
 use std::{
+    borrow::Cow,
+    error::Error,
+    fmt::{self, Display, Formatter},
     fs::File,
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, IsTerminal, Write},
+    process::ExitCode,
+    str::FromStr,
+    thread,
+    time::Instant,
 };
 
-use crate::helper::DynError;
-
-mod engine;
-mod helper;
+use ch06_regex::{
+    DynError, Engine, EngineError, ErrorKind, Match, MatchMetrics, Normalization, Observer, Regex,
+    RegexBuilder, ResultExt, Template, TemplateError,
+};
+use context::{ContextTracker, Output};
+use glob::Glob;
+use lines::LineSource;
+#[cfg(feature = "mmap")]
+use lines::Mapped;
+use progress::Progress;
+use stats::{Stats, StatsFormat};
+use walk::{Filter, Walk, WalkError};
 
-fn main() -> Result<(), DynError> {
+fn main() -> ExitCode {
     let args: Vec<String> = std::env::args().collect();
+    let code = run(
+        &args,
+        std::io::stdin().lock(),
+        &mut std::io::stdout().lock(),
+        &mut std::io::stderr(),
+    );
+    ExitCode::from(code)
+}
 
-    if args.len() < 2 {
-        eprintln!("usage: {} regex file", args[0]);
-        return Err("invalid arguments".into());
-    }
+/// 選んだ行があった場合の終了コード
+const EXIT_SELECTED: u8 = 0;
+/// 選んだ行がなかった場合の終了コード
+const EXIT_NOT_SELECTED: u8 = 1;
+/// 引数の誤りや、パターンの誤り、読めないファイルがあった場合の終了コード
+const EXIT_ERROR: u8 = 2;
+
+/// `args`（先頭はコマンド名）に従って検索し、grepと同様の終了コードを返す。
+/// ファイル名`"-"`やファイル名の省略は`stdin`を表す。
+fn run(args: &[String], stdin: impl BufRead, out: &mut impl Write, err: &mut impl Write) -> u8 {
+    let (options, positional) = match parse_args(&args[1..]) {
+        Ok((options, _)) if options.help => {
+            let _ = cli::write_help(&args[0], out);
+            return EXIT_SELECTED;
+        }
+        Ok((options, _)) if options.version => {
+            let _ = cli::write_version(out);
+            return EXIT_SELECTED;
+        }
+        Ok((options, positional)) if !positional.is_empty() || !options.patterns.is_empty() => {
+            (options, positional)
+        }
+        result => {
+            if let Err(e) = result {
+                let _ = writeln!(err, "{e}");
+            }
+            let _ = cli::write_usage(&args[0], err);
+            return EXIT_ERROR;
+        }
+    };
 
-    match_file(&args[1], &args[2])?;
+    let mut expr = String::new();
+    let result = read_patterns(&options, &positional).and_then(|(combined, files)| {
+        expr = combined;
+        if options.bench {
+            run_bench(&expr, files, &options, stdin, out, err)
+        } else {
+            search(&expr, files, &options, stdin, out, err)
+        }
+    });
+    match result {
+        // `-q`では、読めないファイルがあっても選んだ行があれば成功とする
+        Ok(Outcome { selected: true, .. }) if options.quiet => EXIT_SELECTED,
+        Ok(Outcome { failed: true, .. }) => EXIT_ERROR,
+        Ok(Outcome { selected: true, .. }) => EXIT_SELECTED,
+        Ok(_) => EXIT_NOT_SELECTED,
+        Err(e) => {
+            let _ = write_error(&e, err);
+            if let Some(e) = e.find::<PatternError>() {
+                if let EngineError::Parse(_) = e.error {
+                    let _ = print_hints(&e.expr, err);
+                }
+            } else if e.kind() == ErrorKind::Parse && e.find::<TemplateError>().is_none() {
+                let _ = print_hints(&expr, err);
+            }
+            EXIT_ERROR
+        }
+    }
+}
 
+/// エラー`e`を、外側の層から1行ずつ書き出す
+fn write_error(e: &DynError, err: &mut impl Write) -> std::io::Result<()> {
+    for (i, layer) in e.chain().enumerate() {
+        if i == 0 {
+            writeln!(err, "error: {layer}")?;
+        } else {
+            writeln!(err, "  caused by: {layer}")?;
+        }
+    }
     Ok(())
 }
 
-fn match_file(expr: &str, file: &str) -> Result<(), DynError> {
-    let f = File::open(file)?;
-    let reader = BufReader::new(f);
+/// コマンドラインで指定する設定
+#[derive(Debug, Default)]
+struct Options {
+    /// `--help`: 使い方を標準出力に書き出して終わる
+    help: bool,
+    /// `-V`, `--version`: バージョンを標準出力に書き出して終わる
+    version: bool,
+    /// `--debug`: 検索の前に、パターンのASTとコード、解析結果を標準エラー出力に書き出す
+    debug: bool,
+    /// `--trace`: 各行の評価の様子を標準エラー出力に書き出す
+    trace: bool,
+    /// `-e`, `-f`: 指定した順のパターンとパターンを書いたファイル。指定すると、位置引数は全て検索するファイルとする。
+    patterns: Vec<PatternSource>,
+    /// `-i`, `--ignore-case`: 大文字小文字を区別しない
+    ignore_case: bool,
+    /// `--normalize`: 評価の前に各行をNFCに揃える。マッチの位置は元の行のバイト位置とする。
+    normalize: bool,
+    /// `-v`, `--invert-match`: マッチしない行を選ぶ
+    invert: bool,
+    /// `-w`, `--word-regexp`: 単語全体にマッチする場合のみマッチとする
+    word_regexp: bool,
+    /// `-x`, `--line-regexp`: 行全体にマッチする場合のみマッチとする。`-w`より優先する。
+    line_regexp: bool,
+    /// `-U`, `--multiline`: ファイル全体を1つの文字列として評価し、マッチと重なる行を選ぶ
+    multiline: bool,
+    /// `--multiline-dotall`: `-U`で、`.`が改行にもマッチする
+    multiline_dotall: bool,
+    /// `--max-filesize`: `-U`で読むファイルの大きさの上限（バイト）
+    max_filesize: Option<usize>,
+    /// `-n`, `--line-number`: 行番号を付ける
+    line_number: bool,
+    /// `-b`, `--byte-offset`: 入力の先頭からの行（`-o`ではマッチ）のバイト位置を付ける
+    byte_offset: bool,
+    /// `-c`, `--count`: 行の代わりに、選んだ行の数を書き出す
+    count: bool,
+    /// `-l`, `-L`: 行の代わりにファイル名を書き出す
+    list_files: Option<ListFiles>,
+    /// `-Z`, `--null`: ファイル名の後に、`:`や改行の代わりにNULを書き出す
+    null: bool,
+    /// `-o`, `--only-matching`: 行の代わりに、行中のマッチをそれぞれ1行として書き出す
+    only_matching: bool,
+    /// `--json`: 選んだ行をそれぞれ1つのJSONオブジェクトとして書き出し、最後に集計を書き出す
+    json: bool,
+    /// `--groups`: 選んだ行の代わりに、行中の最初のマッチのキャプチャグループをタブで区切って書き出す
+    groups: bool,
+    /// `--groups-header`: `--groups`で、名前付きグループがあれば最初に列の名前を書き出す
+    groups_header: bool,
+    /// `--replace`: 各行のマッチを全てこの置換文字列のテンプレートで置き換えて、全ての行を書き出す
+    replace: Option<String>,
+    /// `--replace-only-matching-lines`: `--replace`で、マッチしない行は書き出さない
+    replace_only_matching_lines: bool,
+    /// `-q`, `--quiet`: 何も書き出さず、最初に行を選んだ時点で検索をやめる
+    quiet: bool,
+    /// `-m`, `--max-count`: 1つのファイルで選ぶ行数の上限
+    max_count: Option<usize>,
+    /// `-A`, `--after-context`: 選んだ行の後に書き出す行数
+    after_context: Option<usize>,
+    /// `-B`, `--before-context`: 選んだ行の前に書き出す行数
+    before_context: Option<usize>,
+    /// `-r`, `--recursive`: ディレクトリ以下のファイルを検索し、常にファイル名を付ける
+    recursive: bool,
+    /// `--include`, `--exclude`, `--exclude-dir`: 再帰的な検索で、ファイルやディレクトリを名前で絞り込む
+    filter: Filter,
+    /// `--respect-gitignore`: 再帰的な検索で、辿ったディレクトリの`.gitignore`が無視するパスを飛ばす
+    respect_gitignore: bool,
+    /// `--progress`: 複数のファイルを検索する間、進み具合を標準エラー出力に書き出す
+    progress: bool,
+    /// `--threads`: 複数のファイルを並列に検索するスレッド数。指定しなければCPU数とする。
+    threads: Option<usize>,
+    /// `--color`: マッチした部分を色付けするか
+    color: ColorChoice,
+    /// `--binary-files`: バイナリファイルの扱い
+    binary_files: BinaryFiles,
+    /// `--crlf=keep`: 行末の`\r`を除かずに評価し、書き出す
+    keep_cr: bool,
+    /// `--mmap`: 通常のファイルをメモリにマップして検索する
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    /// `--engine`: 評価に用いるエンジン
+    engine: Engine,
+    /// `--step-limit`: 各位置からの1回の評価で実行する命令数の上限
+    step_limit: Option<usize>,
+    /// `--stats`: 検索の後に、統計を標準エラー出力に書き出す
+    stats: Option<StatsFormat>,
+    /// `--bench`: 検索せずに、1つのファイルの全ての行を各エンジンで評価した速さを書き出す
+    bench: bool,
+    /// `--bench-iters`: `--bench`で計測する回数
+    bench_iters: Option<usize>,
+}
+
+/// `-e`または`-f`で指定したパターン
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PatternSource {
+    /// `-e`, `--regexp`: パターン
+    Arg(String),
+    /// `-f`, `--file`: パターンを1行に1つずつ書いたファイル
+    File(String),
+}
+
+/// `-l`と`-L`のどちらを指定したか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListFiles {
+    /// `-l`, `--files-with-matches`: 行を選んだファイル
+    WithMatches,
+    /// `-L`, `--files-without-match`: 行を選ばなかったファイル
+    WithoutMatch,
+}
+
+impl Options {
+    /// 選んだ行そのものを書き出すか
+    fn prints_lines(&self) -> bool {
+        !self.quiet && self.list_files.is_none() && !self.count
+    }
+
+    /// 前後の行を書き出す場合は、選んだ行の前と後に書き出す行数
+    fn context(&self) -> Option<(usize, usize)> {
+        match (self.before_context, self.after_context) {
+            (None, None) => None,
+            (before, after) => Some((before.unwrap_or(0), after.unwrap_or(0))),
+        }
+    }
+
+    /// `files`を検索する場合に、各行の前にファイル名を付けるか。
+    /// `-r`では、辿ったファイルに常にファイル名を付ける。
+    /// ただし`--replace`の出力は置き換えた文章そのものとするので、ファイル名を常に付けない。
+    fn names_files(&self, files: &[&str]) -> bool {
+        if self.replace.is_some() {
+            return false;
+        }
+        files.len() > 1 || self.recursive || self.list_files.is_some() || self.json
+    }
+
+    /// 複数のファイルを検索するスレッド数
+    fn threads(&self) -> usize {
+        self.threads.unwrap_or_else(|| {
+            thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+        })
+    }
+
+    /// 1つのファイルで選ぶ行数の上限。`first_only`であれば、最初に選んだ行で読むのをやめる。
+    fn line_limit(&self, first_only: bool) -> Option<usize> {
+        match (first_only, self.max_count) {
+            (true, Some(max)) => Some(max.min(1)),
+            (true, None) => Some(1),
+            (false, max) => max,
+        }
+    }
+}
+
+/// `--binary-files`の値
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum BinaryFiles {
+    /// 行の代わりに、マッチしたことのみを書き出す
+    #[default]
+    Binary,
+    /// マッチしないものとして扱う
+    WithoutMatch,
+    /// テキストファイルと同様に検索する
+    Text,
+}
+
+impl FromStr for BinaryFiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(BinaryFiles::Binary),
+            "without-match" => Ok(BinaryFiles::WithoutMatch),
+            "text" => Ok(BinaryFiles::Text),
+            _ => Err(format!(
+                "invalid value for --binary-files: {s} (expected binary, without-match or text)"
+            )),
+        }
+    }
+}
+
+/// `--color`の値
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ColorChoice {
+    /// 標準出力が端末であれば色付けする
+    Auto,
+    Always,
+    #[default]
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => std::io::stdout().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!(
+                "invalid value for --color: {s} (expected auto, always or never)"
+            )),
+        }
+    }
+}
+
+/// 引数を設定と、それ以外の引数（パターンとファイル名）に分ける。`"-"`は標準入力を表すファイル名とする。
+/// 引数の書き方は`cli::tokenize`に従う。
+fn parse_args(args: &[String]) -> Result<(Options, Vec<&str>), String> {
+    let mut options = Options::default();
+    let mut positional = Vec::new();
+
+    for arg in cli::tokenize(args).map_err(|e| e.to_string())? {
+        let (flag, value) = match arg {
+            cli::Arg::Flag(flag, value) => (flag, value),
+            cli::Arg::Positional(arg) => {
+                positional.push(arg);
+                continue;
+            }
+        };
+        let name = flag.name();
+
+        // 値を取るオプションには、`cli::tokenize`が必ず値を付ける
+        match (flag.long, value) {
+            ("help", _) => options.help = true,
+            ("version", _) => options.version = true,
+            ("debug", _) => options.debug = true,
+            ("trace", _) => options.trace = true,
+            ("regexp", Some(expr)) => options.patterns.push(PatternSource::Arg(expr.to_string())),
+            ("file", Some(file)) => options.patterns.push(PatternSource::File(file.to_string())),
+            ("ignore-case", _) => options.ignore_case = true,
+            ("normalize", _) => options.normalize = true,
+            ("invert-match", _) => options.invert = true,
+            ("word-regexp", _) => options.word_regexp = true,
+            ("line-regexp", _) => options.line_regexp = true,
+            ("multiline", _) => options.multiline = true,
+            ("multiline-dotall", _) => options.multiline_dotall = true,
+            ("max-filesize", Some(value)) => {
+                options.max_filesize = Some(parse_number(&name, value)?)
+            }
+            ("line-number", _) => options.line_number = true,
+            ("byte-offset", _) => options.byte_offset = true,
+            ("count", _) => options.count = true,
+            ("files-with-matches", _) => options.list_files = Some(ListFiles::WithMatches),
+            ("files-without-match", _) => options.list_files = Some(ListFiles::WithoutMatch),
+            ("null", _) => options.null = true,
+            ("only-matching", _) => options.only_matching = true,
+            ("json", _) => options.json = true,
+            ("groups", _) => options.groups = true,
+            ("replace", Some(rep)) => options.replace = Some(rep.to_string()),
+            ("replace-only-matching-lines", _) => options.replace_only_matching_lines = true,
+            ("groups-header", _) => {
+                options.groups = true;
+                options.groups_header = true;
+            }
+            ("quiet" | "silent", _) => options.quiet = true,
+            ("max-count", Some(value)) => options.max_count = Some(parse_number(&name, value)?),
+            ("after-context", Some(value)) => {
+                options.after_context = Some(parse_number(&name, value)?)
+            }
+            ("before-context", Some(value)) => {
+                options.before_context = Some(parse_number(&name, value)?)
+            }
+            ("context", Some(value)) => {
+                let n = parse_number(&name, value)?;
+                options.after_context = Some(n);
+                options.before_context = Some(n);
+            }
+            ("recursive", _) => options.recursive = true,
+            ("include", Some(glob)) => options.filter.include.push(Glob::new(glob)),
+            ("exclude", Some(glob)) => options.filter.exclude.push(Glob::new(glob)),
+            ("exclude-dir", Some(glob)) => options.filter.exclude_dir.push(Glob::new(glob)),
+            ("respect-gitignore", _) => options.respect_gitignore = true,
+            ("progress", _) => options.progress = true,
+            ("threads", Some(value)) => match parse_number(&name, value)? {
+                0 => return Err("--threads must be at least 1".to_string()),
+                n => options.threads = Some(n),
+            },
+            ("color", value) => options.color = value.unwrap_or("auto").parse()?,
+            ("binary-files", Some(value)) => options.binary_files = value.parse()?,
+            ("crlf", Some(value)) => options.keep_cr = parse_crlf(value)?,
+            #[cfg(feature = "mmap")]
+            ("mmap", _) => options.mmap = true,
+            #[cfg(not(feature = "mmap"))]
+            ("mmap", _) => return Err("--mmap requires the mmap feature".to_string()),
+            ("engine", Some(value)) => options.engine = parse_engine(value)?,
+            ("step-limit", Some(value)) => options.step_limit = Some(parse_number(&name, value)?),
+            ("stats", value) => options.stats = Some(value.unwrap_or("text").parse()?),
+            ("bench", _) => options.bench = true,
+            ("bench-iters", Some(value)) => match parse_number(&name, value)? {
+                0 => return Err("--bench-iters must be at least 1".to_string()),
+                n => options.bench_iters = Some(n),
+            },
+            _ => unreachable!("option {name} is declared but not handled"),
+        }
+    }
+
+    // マッチしない行にはマッチした部分がない
+    if options.only_matching && options.invert {
+        return Err("-o cannot be used with -v".to_string());
+    }
+    // 行ごとに探したマッチしか書き出せないので、行を跨ぐマッチを書き出せない
+    if options.only_matching && options.multiline {
+        return Err("-o cannot be used with -U".to_string());
+    }
+    // JSONとして読める出力のみを書き出す
+    if options.json {
+        let conflicts = [
+            ("-o", options.only_matching),
+            ("-b", options.byte_offset),
+            // `auto`は端末でなければ色付けしないので、端末かによらず色付けしないものとして受け付ける
+            ("--color=always", options.color == ColorChoice::Always),
+            ("-c", options.count),
+            ("-l or -L", options.list_files.is_some()),
+            ("-A, -B or -C", options.context().is_some()),
+        ];
+        if let Some((flag, _)) = conflicts.iter().find(|(_, conflict)| *conflict) {
+            return Err(format!("--json cannot be used with {flag}"));
+        }
+    }
+    // 置き換えた文章のみを書き出すので、行を選ぶ条件を変えるオプションや、行以外の出力とは組み合わせない
+    if options.replace.is_some() {
+        let conflicts = [
+            ("-v", options.invert),
+            ("-o", options.only_matching),
+            ("-w", options.word_regexp),
+            ("-x", options.line_regexp),
+            ("-U", options.multiline),
+            ("--json", options.json),
+            ("--groups", options.groups),
+            ("-c", options.count),
+            ("-l or -L", options.list_files.is_some()),
+            ("-A, -B or -C", options.context().is_some()),
+        ];
+        if let Some((flag, _)) = conflicts.iter().find(|(_, conflict)| *conflict) {
+            return Err(format!("--replace cannot be used with {flag}"));
+        }
+    } else if options.replace_only_matching_lines {
+        return Err("--replace-only-matching-lines requires --replace".to_string());
+    }
+    // 1行に1つのマッチのグループを書き出すので、グループのない行や、行以外の出力とは組み合わせない
+    if options.groups {
+        let conflicts = [
+            ("-v", options.invert),
+            ("-o", options.only_matching),
+            ("-b", options.byte_offset),
+            ("--json", options.json),
+            ("-U", options.multiline),
+            ("-c", options.count),
+            ("-l or -L", options.list_files.is_some()),
+            ("-A, -B or -C", options.context().is_some()),
+        ];
+        if let Some((flag, _)) = conflicts.iter().find(|(_, conflict)| *conflict) {
+            return Err(format!("--groups cannot be used with {flag}"));
+        }
+    }
+
+    Ok((options, positional))
+}
+
+/// オプション`flag`の値`value`を0以上の整数として解析する
+fn parse_number(flag: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {flag}: {value}"))
+}
+
+/// `--crlf`の値を解析し、行末の`\r`を残すかを返す。
+fn parse_crlf(value: &str) -> Result<bool, String> {
+    match value {
+        "strip" => Ok(false),
+        "keep" => Ok(true),
+        _ => Err(format!(
+            "invalid value for --crlf: {value} (expected strip or keep)"
+        )),
+    }
+}
+
+/// `--engine`の値を解析する。`pike`は、Pike VMと同様に評価する幅優先探索を表す。
+fn parse_engine(value: &str) -> Result<Engine, String> {
+    match value {
+        "depth" => Ok(Engine::Depth),
+        "width" | "pike" => Ok(Engine::Width),
+        "bitstate" => Ok(Engine::Bitstate),
+        _ => Err(format!(
+            "invalid value for --engine: {value} (expected depth, width, pike or bitstate)"
+        )),
+    }
+}
+
+/// 検索するパターン
+#[derive(Debug, PartialEq, Eq)]
+struct Pattern {
+    expr: String,
+    /// パターンを書いた場所。`-e`では何番目の`-e`か、`-f`で指定したファイルから読んだ場合は`ファイル名:行番号`
+    origin: Option<String>,
+}
+
+/// コンパイルできなかったパターンとその原因
+#[derive(Debug)]
+struct PatternError {
+    expr: String,
+    origin: Option<String>,
+    error: EngineError,
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.origin {
+            Some(origin) => write!(f, "{origin}: {}", self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl Error for PatternError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
 
-    engine::print(expr)?;
-    println!();
+impl From<PatternError> for DynError {
+    fn from(e: PatternError) -> Self {
+        DynError::new(ErrorKind::from(&e.error), e)
+    }
+}
+
+/// 位置引数`positional`と`-e`、`-f`から、パターンと検索するファイルを決める。
+/// 複数のパターンは、いずれかにマッチする1つのパターンにまとめる。
+fn read_patterns<'a>(
+    options: &Options,
+    positional: &'a [&'a str],
+) -> Result<(String, &'a [&'a str]), DynError> {
+    if options.patterns.is_empty() {
+        return Ok((positional[0].to_string(), &positional[1..]));
+    }
 
-    for line in reader.lines() {
-        let line = line?;
-        if engine::match_line(expr, &line)? {
-            println!("{line}");
+    let mut patterns = Vec::new();
+    let mut args = 0;
+    for source in &options.patterns {
+        match source {
+            PatternSource::Arg(expr) => {
+                args += 1;
+                patterns.push(Pattern {
+                    expr: expr.clone(),
+                    origin: Some(format!("-e #{args}")),
+                });
+            }
+            PatternSource::File(file) => {
+                let content = std::fs::read_to_string(file).map_err(|e| format!("{file}: {e}"))?;
+                patterns.extend(parse_pattern_file(file, &content));
+            }
         }
     }
+    // `-f`で指定したファイルが全て空の場合
+    if patterns.is_empty() {
+        let files = options
+            .patterns
+            .iter()
+            .filter_map(|source| match source {
+                PatternSource::File(file) => Some(file.as_str()),
+                PatternSource::Arg(_) => None,
+            })
+            .collect::<Vec<_>>();
+        return Err(format!("no pattern in {}", files.join(", ")).into());
+    }
+    Ok((combine_patterns(&patterns, options)?, positional))
+}
+
+/// パターンを書いたファイル`file`の内容`content`から、空でない各行の前後の空白を除いてパターンとする
+fn parse_pattern_file(file: &str, content: &str) -> Vec<Pattern> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(lineno, line)| Pattern {
+            expr: line.to_string(),
+            origin: Some(format!("{file}:{lineno}")),
+        })
+        .collect()
+}
+
+/// 空でない`patterns`のいずれかにマッチする1つのパターンを作る。
+/// 各パターンは非キャプチャグループで囲んで`|`でつなぐので、グループの番号は最初のパターンから順に数える。
+/// まとめたパターンの誤りからは元のパターンが分からないので、先に各パターンをコンパイルして確かめる。
+fn combine_patterns(patterns: &[Pattern], options: &Options) -> Result<String, PatternError> {
+    for pattern in patterns {
+        build_regex(&pattern.expr, options).map_err(|error| PatternError {
+            expr: pattern.expr.clone(),
+            origin: pattern.origin.clone(),
+            error,
+        })?;
+    }
+
+    match patterns {
+        [pattern] => Ok(pattern.expr.clone()),
+        _ => Ok(patterns
+            .iter()
+            .map(|pattern| format!("(?:{})", pattern.expr))
+            .collect::<Vec<_>>()
+            .join("|")),
+    }
+}
+
+/// `expr`をコンパイルして`files`を検索する。`files`が空であれば標準入力を読む。
+fn search(
+    expr: &str,
+    files: &[&str],
+    options: &Options,
+    mut stdin: impl BufRead,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> Result<Outcome, DynError> {
+    let start = Instant::now();
+    // 標準出力には検索結果のみを書き出す
+    if options.debug {
+        ch06_regex::print(expr, err)?;
+        writeln!(err)?;
+    }
+
+    // パターンのコンパイルは1度だけ行う
+    let regex = build_regex(expr, options).context("while compiling the pattern")?;
+    // 置換文字列の誤りは、ファイルを読み始める前に知らせる
+    if let Some(rep) = &options.replace {
+        regex
+            .template(rep)
+            .context("while parsing the replacement")?;
+    }
+    let files = if files.is_empty() { &[STDIN] } else { files };
+    let mut stats = Stats::default();
+    if options.groups_header && !options.quiet {
+        write_groups_header(out, &regex, options.names_files(files), options.line_number)?;
+    }
+    let outcome = search_files(&regex, files, options, &mut stdin, out, err, &mut stats)?;
+    if options.json && !options.quiet {
+        write_json_summary(out, &stats)?;
+    }
+    if let Some(format) = options.stats {
+        stats.write(err, format, start.elapsed())?;
+    }
+    Ok(outcome)
+}
+
+/// `--json`で、検索の最後に集計を1つのJSONオブジェクトとして書き出す
+fn write_json_summary(out: &mut impl Write, stats: &Stats) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "{{\"summary\": {{\"files_searched\": {}, \"files_skipped\": {}, \"lines_scanned\": {}, \"lines_matched\": {}}}}}",
+        stats.files_searched, stats.files_skipped, stats.lines_scanned, stats.lines_matched
+    )
+}
+
+/// `--groups-header`で、`--groups`の各列の名前を1行として書き出す。
+/// ファイル名と行番号の列は`path`と`line`、名前のないグループはその番号とする。
+/// 名前付きグループがなければ何も書き出さない。
+fn write_groups_header(
+    out: &mut impl Write,
+    regex: &Regex,
+    named: bool,
+    line_number: bool,
+) -> std::io::Result<()> {
+    if regex.capture_names().all(|name| name.is_none()) {
+        return Ok(());
+    }
+    let numbers = (0..regex.capture_count())
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>();
+    let groups = regex
+        .capture_names()
+        .zip(&numbers)
+        .skip(1)
+        .map(|(name, number)| name.unwrap_or(number));
+    let columns = [(named, "path"), (line_number, "line")]
+        .into_iter()
+        .filter_map(|(enabled, column)| enabled.then_some(column));
+    tsv::write_row(out, columns.chain(groups))
+}
+
+/// 設定に従って`expr`をコンパイルする
+fn build_regex(expr: &str, options: &Options) -> Result<Regex, EngineError> {
+    regex_builder(expr, options).build()
+}
+
+/// 設定に従って`expr`をコンパイルする`RegexBuilder`を作る。
+/// `-U`であれば`^`と`$`は行の境界で成り立ち、`.`は`--multiline-dotall`の場合のみ改行にマッチする。
+fn regex_builder(expr: &str, options: &Options) -> RegexBuilder {
+    let mut builder = RegexBuilder::new(expr);
+    if options.multiline {
+        builder
+            .multi_line(true)
+            .dot_matches_newline(options.multiline_dotall);
+    }
+    if options.normalize {
+        builder.normalize(Normalization::Nfc);
+    }
+    builder
+        .case_insensitive(options.ignore_case)
+        .engine(options.engine)
+        .step_limit(options.step_limit);
+    builder
+}
 
+/// `--bench`で、`files`の唯一のファイル（省略すれば標準入力）を1度だけメモリに読み込み、全ての行を`Engine::ALL`の各エンジンで評価する。
+/// 速さとマッチした行数を表として`out`に書き出す。`-v`や`-w`などの行の選び方には従わない。
+/// マッチした行数が全てのエンジンで一致すれば行を選んだものとし、一致しなければ失敗とする。
+fn run_bench(
+    expr: &str,
+    files: &[&str],
+    options: &Options,
+    mut stdin: impl BufRead,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> Result<Outcome, DynError> {
+    let files = if files.is_empty() { &[STDIN] } else { files };
+    let [file] = files else {
+        return Err("--bench requires exactly one file".into());
+    };
+    let content = if *file == STDIN {
+        let mut content = Vec::new();
+        stdin.read_to_end(&mut content)?;
+        content
+    } else {
+        std::fs::read(file).map_err(|e| format!("{file}: {e}"))?
+    };
+    let text = String::from_utf8_lossy(&content);
+    let lines = text.lines().collect::<Vec<_>>();
+
+    // 各エンジンのプログラムは1度だけコンパイルし、全ての評価で使い回す
+    let regexes = Engine::ALL
+        .iter()
+        .map(|&engine| {
+            let regex = regex_builder(expr, options).engine(engine).build()?;
+            Ok((engine, regex))
+        })
+        .collect::<Result<Vec<_>, EngineError>>()?;
+    let candidates = regexes
+        .iter()
+        .map(|(engine, regex)| bench::Candidate {
+            name: format!("{engine:?}").to_lowercase(),
+            is_match: Box::new(|line| regex.try_is_match(line)),
+        })
+        .collect::<Vec<_>>();
+
+    let iters = options.bench_iters.unwrap_or(bench::DEFAULT_ITERS);
+    let results = bench::measure(&lines, &candidates, iters)?;
+    let agreed = bench::write_report(&results, out, err)?;
+    Ok(Outcome {
+        selected: agreed,
+        failed: !agreed,
+    })
+}
+
+/// 解析できなかったパターン中の、対応していない構文をヒントとして`err`に書き出す
+fn print_hints(expr: &str, err: &mut impl Write) -> std::io::Result<()> {
+    for unsupported in ch06_regex::check_compatibility(expr) {
+        writeln!(err, "hint: {unsupported}")?;
+    }
     Ok(())
 }
 
+/// 標準入力を表すファイル名
+const STDIN: &str = "-";
+
+/// 検索の結果
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Outcome {
+    /// 選んだ行があったか。`-L`では、書き出したファイルがあったか
+    selected: bool,
+    /// 開けない、または読めないファイルがあったか
+    failed: bool,
+}
+
+impl Outcome {
+    /// 1つのファイルの結果を加える。`None`はファイルを読めなかったことを表す。
+    fn record(&mut self, selected: Option<bool>) {
+        match selected {
+            Some(selected) => self.selected |= selected,
+            None => self.failed = true,
+        }
+    }
+}
+
+/// 検索する対象
+enum Target {
+    /// ファイル`path`。`name`があれば各行の前に付ける。
+    File { path: String, name: Option<String> },
+    /// 再帰的な検索で辿れなかったパス
+    Unreadable(WalkError),
+}
+
+impl Target {
+    fn path(&self) -> Cow<'_, str> {
+        match self {
+            Target::File { path, .. } => Cow::Borrowed(path),
+            Target::Unreadable(e) => e.path.to_string_lossy(),
+        }
+    }
+}
+
+/// `files`から検索する対象を順に返す。`options.recursive`であれば、ディレクトリ以下の全てのファイルを返す。
+fn targets<'a>(files: &'a [&'a str], options: &'a Options) -> impl Iterator<Item = Target> + 'a {
+    let named = options.names_files(files);
+
+    files
+        .iter()
+        .flat_map(move |file| -> Box<dyn Iterator<Item = Target>> {
+            if options.recursive && *file != STDIN {
+                // `Walk`は必要になった時点でディレクトリを読むので、途中でやめればそれ以上辿らない
+                let walk = Walk::new(file)
+                    .with_filter(options.filter.clone())
+                    .with_gitignore(options.respect_gitignore);
+                Box::new(walk.map(move |entry| match entry {
+                    Ok(path) => {
+                        let path = path.to_string_lossy().into_owned();
+                        Target::File {
+                            name: named.then(|| path.clone()),
+                            path,
+                        }
+                    }
+                    Err(e) => Target::Unreadable(e),
+                }))
+            } else {
+                Box::new(std::iter::once(Target::File {
+                    path: file.to_string(),
+                    name: named.then(|| file.to_string()),
+                }))
+            }
+        })
+}
+
+/// `files`の各ファイルから`regex`にマッチする行を`out`に書き出す。`"-"`は`stdin`を表す。
+/// 複数のファイルを検索する場合は、各行の先頭にファイル名を付ける。
+/// `options.recursive`であれば、ディレクトリ以下の全てのファイルを検索する。
+/// 開けない、または読めないファイルはエラーを`err`に書き出して飛ばす。
+/// `options.quiet`であれば、行を選んだ時点で残りのファイルを検索せずに返す。
+///
+/// 標準入力を含まない複数のファイルは、`options.threads()`個のスレッドで並列に検索する。
+/// その場合もファイルごとの出力は`files`の順に書き出す。
+/// 検索の統計は`stats`に加える。
+fn search_files(
+    regex: &Regex,
+    files: &[&str],
+    options: &Options,
+    stdin: &mut impl BufRead,
+    out: &mut impl Write,
+    err: &mut impl Write,
+    stats: &mut Stats,
+) -> Result<Outcome, DynError> {
+    let threads = options.threads();
+    // `--progress`では、ファイルごとの出力の間に進み具合を書き出せるよう、1つのスレッドでも`search_parallel`で検索する
+    if (threads > 1 || options.progress) && !options.quiet && !files.contains(&STDIN) {
+        let mut progress = options
+            .progress
+            .then(|| Progress::new(Instant::now, std::io::stderr().is_terminal()));
+        let mut found = Vec::new();
+        for target in targets(files, options) {
+            if let Some(progress) = &mut progress {
+                progress.discover(&target.path(), err)?;
+            }
+            found.push(target);
+        }
+        if found.len() > 1 || progress.is_some() {
+            return search_parallel(regex, &found, options, threads, progress, out, err, stats);
+        }
+    }
+
+    let mut outcome = Outcome::default();
+    for target in targets(files, options) {
+        match target {
+            Target::File { path, name } => {
+                let name = name.as_deref();
+                let selected = search_file(regex, &path, name, options, stdin, out, err, stats)?;
+                outcome.record(selected);
+            }
+            Target::Unreadable(e) => {
+                writeln!(err, "{e}")?;
+                stats.files_skipped += 1;
+                outcome.record(None);
+            }
+        }
+        if options.quiet && outcome.selected {
+            break;
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// `targets`を`threads`個のスレッドで並列に検索する。
+/// 各ファイルの出力はバッファに溜めておき、`targets`の順に`out`と`err`に書き出す。
+/// 評価中にエラーが起きれば、それより後のファイルは書き出さずにエラーを返す。
+/// 統計も同様に、ファイルごとに数えてから`targets`の順に`stats`に加える。
+/// `progress`があれば、各ファイルの出力を書き出すたびに進み具合を更新し、検索の終わりに消す。
+#[allow(clippy::too_many_arguments)]
+fn search_parallel(
+    regex: &Regex,
+    targets: &[Target],
+    options: &Options,
+    threads: usize,
+    mut progress: Option<Progress<impl FnMut() -> Instant>>,
+    out: &mut impl Write,
+    err: &mut impl Write,
+    stats: &mut Stats,
+) -> Result<Outcome, DynError> {
+    let search = |target: &Target| {
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let mut stats = Stats::default();
+        let result = match target {
+            Target::File { path, name } => {
+                let stdin = &mut std::io::empty();
+                search_file(
+                    regex,
+                    path,
+                    name.as_deref(),
+                    options,
+                    stdin,
+                    &mut out,
+                    &mut err,
+                    &mut stats,
+                )
+            }
+            Target::Unreadable(e) => {
+                stats.files_skipped += 1;
+                writeln!(err, "{e}").map(|_| None).map_err(Into::into)
+            }
+        };
+        (result, out, err, stats)
+    };
+
+    let mut outcome = Outcome::default();
+    let mut result = Ok(());
+    // 結果は`targets`の順に受け取る
+    let mut paths = targets.iter().map(Target::path);
+    pool::ordered_map(
+        targets,
+        threads,
+        search,
+        |(selected, buf_out, buf_err, buf_stats)| {
+            let path = paths.next().unwrap_or_default();
+            result = selected.and_then(|selected| {
+                let Some(progress) = &mut progress else {
+                    out.write_all(&buf_out)?;
+                    err.write_all(&buf_err)?;
+                    outcome.record(selected);
+                    stats.add(&buf_stats);
+                    return Ok(());
+                };
+                // 進み具合の行に検索結果が続かないよう、消してから書き出し、書き出し終えてから表示する
+                if !buf_out.is_empty() || !buf_err.is_empty() {
+                    progress.clear(err)?;
+                }
+                out.write_all(&buf_out)?;
+                out.flush()?;
+                err.write_all(&buf_err)?;
+                outcome.record(selected);
+                stats.add(&buf_stats);
+                progress.finish_file(&path, buf_stats.lines_matched, err)?;
+                Ok(())
+            });
+            result.is_ok()
+        },
+    );
+
+    if let Some(progress) = &mut progress {
+        progress.clear(err)?;
+    }
+    result.map(|_| outcome)
+}
+
+/// ファイル`file`を検索し、行を選んだか（`-L`であれば、ファイル名を書き出したか）を返す。
+/// 開けない、または読めなければエラーを`err`に書き出して`None`を返す。
+#[allow(clippy::too_many_arguments)]
+fn search_file(
+    regex: &Regex,
+    file: &str,
+    name: Option<&str>,
+    options: &Options,
+    stdin: &mut impl BufRead,
+    out: &mut impl Write,
+    err: &mut impl Write,
+    stats: &mut Stats,
+) -> Result<Option<bool>, DynError> {
+    let result = if file == STDIN {
+        match_reader(regex, stdin, file, name, out, err, options, stats)
+    } else {
+        File::open(file).map_err(DynError::from).and_then(|f| {
+            #[cfg_attr(not(feature = "gzip"), allow(unused_mut))]
+            let mut reader = BufReader::new(f);
+            // 圧縮されたファイルはマップしても検索できないので、展開しながら読む
+            #[cfg(feature = "gzip")]
+            if lines::is_gzip(&mut reader)? {
+                let reader = lines::gunzip(reader);
+                return match_reader(regex, reader, file, name, out, err, options, stats);
+            }
+            #[cfg(feature = "mmap")]
+            if options.mmap {
+                if let Some(mapped) = Mapped::new(reader.get_ref())? {
+                    return match_reader(regex, mapped, file, name, out, err, options, stats);
+                }
+            }
+            match_reader(regex, reader, file, name, out, err, options, stats)
+        })
+    };
+
+    match result {
+        Ok(count) => Ok(Some(
+            (count > 0) != (options.list_files == Some(ListFiles::WithoutMatch)),
+        )),
+        // 評価中のエラーはパターンの問題なので、他のファイルでも起きうる
+        Err(e) if e.find::<EngineError>().is_some() => Err(e),
+        Err(e) => {
+            writeln!(err, "{file}: {e}")?;
+            stats.files_skipped += 1;
+            Ok(None)
+        }
+    }
+}
+
+/// バイナリファイルとみなすかを判断するのに読む、先頭のバイト数の目安。
+/// 実際には、`BufReader`が最初に読み込んだ分（デフォルトで最大8KiB）を調べる。
+const BINARY_CHECK_LEN: usize = 8 * 1024;
+
+/// `reader`の先頭にNULがあればバイナリファイルとみなす。読み込んだ内容は消費しないので、続けて検索できる。
+fn is_binary(reader: &mut impl LineSource) -> std::io::Result<bool> {
+    let buf = reader.peek()?;
+    Ok(buf[..buf.len().min(BINARY_CHECK_LEN)].contains(&0))
+}
+
+/// ファイル`file`の内容`reader`を検索し、選んだ行の数を返す。
+/// バイナリファイルは`options.binary_files`に従って扱い、行を書き出す代わりに
+/// `binary file FILE matches`と書き出すか、マッチしないものとする。
+/// `--json`では`{"path": FILE, "binary": true}`と書き出す。
+#[allow(clippy::too_many_arguments)]
+fn match_reader(
+    regex: &Regex,
+    mut reader: impl LineSource,
+    file: &str,
+    name: Option<&str>,
+    out: &mut impl Write,
+    err: &mut impl Write,
+    options: &Options,
+    stats: &mut Stats,
+) -> Result<usize, DynError> {
+    let count = if options.binary_files == BinaryFiles::Text || !is_binary(&mut reader)? {
+        match_file(regex, reader, name, out, err, options, stats)?
+    } else {
+        match options.binary_files {
+            BinaryFiles::WithoutMatch => {
+                stats.files_skipped += 1;
+                return Ok(0);
+            }
+            _ if options.prints_lines() => {
+                let limit = options.line_limit(true);
+                let count =
+                    select_lines(regex, reader, options, limit, stats, err, |_, _, _, _| {
+                        Ok(())
+                    })?;
+                if count > 0 && options.json {
+                    write!(out, "{{\"path\": ")?;
+                    json::write_str(out, file)?;
+                    writeln!(out, ", \"binary\": true}}")?;
+                } else if count > 0 {
+                    writeln!(out, "binary file {file} matches")?;
+                }
+                count
+            }
+            // 行数やファイル名はテキストファイルと同様に書き出す
+            _ => match_file(regex, reader, name, out, err, options, stats)?,
+        }
+    };
+    stats.files_searched += 1;
+    Ok(count)
+}
+
+/// マッチした部分を囲むエスケープシーケンス（太字の赤）
+const COLOR_START: &str = "\x1b[1;31m";
+const COLOR_END: &str = "\x1b[0m";
+
+/// 選んだ行の出力形式。行の前に`ファイル名:行番号:バイト位置:`の順で付ける。
+/// `-o`であれば行の代わりに、行中の空でないマッチを1つずつ同じ形式で書き出す。
+struct OutputFormatter<'a> {
+    regex: &'a Regex,
+    /// ファイル名
+    name: Option<&'a str>,
+    /// ファイル名の後に、区切りの文字の代わりにNULを書き出すか
+    null: bool,
+    /// 行番号を付けるか
+    line_number: bool,
+    /// バイト位置を付けるか
+    byte_offset: bool,
+    /// マッチした部分を色付けするか
+    color: bool,
+    /// マッチした部分のみを書き出すか
+    only_matching: bool,
+    /// JSONとして書き出すか
+    json: bool,
+    /// キャプチャグループをTSVとして書き出すか
+    groups: bool,
+    /// 行の代わりに、行中のマッチをこのテンプレートで置き換えた行を書き出すか
+    template: Option<Template>,
+    /// 単語全体にマッチする場合のみマッチとするか
+    word_regexp: bool,
+    /// 行全体にマッチする場合のみマッチとするか
+    line_regexp: bool,
+    /// マッチしない行を選ぶか
+    invert: bool,
+}
+
+impl<'a> OutputFormatter<'a> {
+    /// `--replace`の置換文字列は`search`で確かめてあるが、誤りがあればここでもエラーとする
+    fn new(
+        regex: &'a Regex,
+        name: Option<&'a str>,
+        options: &Options,
+    ) -> Result<Self, TemplateError> {
+        let template = options
+            .replace
+            .as_deref()
+            .map(|rep| regex.template(rep))
+            .transpose()?;
+        Ok(OutputFormatter {
+            regex,
+            name,
+            null: options.null,
+            line_number: options.line_number,
+            byte_offset: options.byte_offset,
+            color: options.color.enabled(),
+            only_matching: options.only_matching,
+            json: options.json,
+            groups: options.groups,
+            template,
+            word_regexp: options.word_regexp,
+            line_regexp: options.line_regexp,
+            invert: options.invert,
+        })
+    }
+
+    /// 入力の先頭から`offset`バイト目に始まる、1から数えて`lineno`行目の`line`を書き出す
+    fn write_line(
+        &self,
+        out: &mut impl Write,
+        lineno: usize,
+        offset: usize,
+        line: &str,
+    ) -> std::io::Result<()> {
+        if self.only_matching {
+            return self.write_matches(out, lineno, offset, line);
+        }
+        if self.json {
+            return self.write_json(out, lineno, line);
+        }
+        if self.groups {
+            return self.write_groups(out, lineno, line);
+        }
+
+        self.write_prefix(out, lineno, offset, ':')?;
+        if let Some(template) = &self.template {
+            // 置き換えた行は色付けしない。マッチしない行はそのまま書き出す
+            write!(out, "{}", self.regex.replace_all_template(line, template))?;
+        } else if self.color {
+            self.write_highlighted(out, line)?;
+        } else {
+            write!(out, "{line}")?;
+        }
+        writeln!(out)
+    }
+
+    /// `offset`バイト目に始まる`lineno`行目の`line`中の空でないマッチを、それぞれ1行として書き出す。
+    /// バイト位置は行ではなくマッチの先頭の位置とする。
+    fn write_matches(
+        &self,
+        out: &mut impl Write,
+        lineno: usize,
+        offset: usize,
+        line: &str,
+    ) -> std::io::Result<()> {
+        // 空文字列へのマッチを書き出しても空の行にしかならない
+        for (start, end) in self.spans(line)? {
+            self.write_prefix(out, lineno, offset + start, ':')?;
+            if self.color {
+                writeln!(out, "{COLOR_START}{}{COLOR_END}", &line[start..end])?;
+            } else {
+                writeln!(out, "{}", &line[start..end])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `lineno`行目の`line`を、ファイル名と行中の空でないマッチのバイト単位の範囲とともに、
+    /// 1行のJSONオブジェクトとして書き出す
+    fn write_json(&self, out: &mut impl Write, lineno: usize, line: &str) -> std::io::Result<()> {
+        write!(out, "{{\"path\": ")?;
+        json::write_str(out, self.name.unwrap_or(STDIN))?;
+        write!(out, ", \"line_number\": {lineno}, \"line\": ")?;
+        json::write_str(out, line)?;
+        let spans = self
+            .spans(line)?
+            .into_iter()
+            .map(|(start, end)| format!("[{start}, {end}]"))
+            .collect::<Vec<_>>();
+        writeln!(out, ", \"spans\": [{}]}}", spans.join(", "))
+    }
+
+    /// `lineno`行目の`line`中の最初のマッチのキャプチャグループ（グループ0を除く）を、TSVの1行として書き出す。
+    /// ファイル名があれば先頭の列に、`-n`であればその次の列に行番号を書き出す。
+    /// マッチに加わらなかったグループは空文字列とする。
+    fn write_groups(&self, out: &mut impl Write, lineno: usize, line: &str) -> std::io::Result<()> {
+        let Some(captures) = self.regex.captures(line) else {
+            return Ok(());
+        };
+        let lineno = lineno.to_string();
+        let prefix = [self.name, self.line_number.then_some(lineno.as_str())];
+        let groups = (1..captures.len()).map(|i| captures.get(i).map_or("", |m| m.as_str()));
+        tsv::write_row(out, prefix.into_iter().flatten().chain(groups))
+    }
+
+    /// 選んだ行の前後の、`offset`バイト目に始まる`lineno`行目の`line`を書き出す。
+    /// 選んだ行と区別できるよう、ファイル名と行番号、バイト位置の後には`:`の代わりに`-`を付ける。
+    /// `-o`であれば何も書き出さない。
+    fn write_context_line(
+        &self,
+        out: &mut impl Write,
+        lineno: usize,
+        offset: usize,
+        line: &str,
+    ) -> std::io::Result<()> {
+        if self.only_matching {
+            return Ok(());
+        }
+        self.write_prefix(out, lineno, offset, '-')?;
+        writeln!(out, "{line}")
+    }
+
+    /// 行の前に付ける`ファイル名:行番号:バイト位置:`を、`:`を`sep`として書き出す
+    fn write_prefix(
+        &self,
+        out: &mut impl Write,
+        lineno: usize,
+        offset: usize,
+        sep: char,
+    ) -> std::io::Result<()> {
+        if let Some(name) = self.name {
+            self.write_file_name(out, name, sep)?;
+        }
+        if self.line_number {
+            write!(out, "{lineno}{sep}")?;
+        }
+        if self.byte_offset {
+            write!(out, "{offset}{sep}")?;
+        }
+        Ok(())
+    }
+
+    /// `line`中の全てのマッチを色付けして書き出す。空文字列へのマッチは色付けしない。
+    fn write_highlighted(&self, out: &mut impl Write, line: &str) -> std::io::Result<()> {
+        // マッチの位置は文字の境界なので、その位置で区切っても文字は分かれない
+        let mut last = 0;
+        for (start, end) in self.spans(line)? {
+            write!(
+                out,
+                "{}{COLOR_START}{}{COLOR_END}",
+                &line[last..start],
+                &line[start..end]
+            )?;
+            last = end;
+        }
+        write!(out, "{}", &line[last..])
+    }
+
+    /// `line`中の空でないマッチのバイト単位の(開始位置, 終了位置)。
+    /// `-x`であれば行全体を、`-w`であれば単語全体にマッチするもののみを返す。
+    /// 評価中のエラーは書き出しのエラーとして返す。
+    fn spans(&self, line: &str) -> std::io::Result<Vec<(usize, usize)>> {
+        if self.line_regexp {
+            // 行全体にマッチした行であれば、途中のマッチではなく行全体をマッチとする
+            return Ok(if self.invert || line.is_empty() {
+                Vec::new()
+            } else {
+                vec![(0, line.len())]
+            });
+        }
+
+        let mut matches = self.regex.find_iter(line);
+        let accept = |start, end| !self.word_regexp || is_word_match(line, start, end);
+        let mut spans = Vec::new();
+        while let Some(m) = matches.try_next_by(accept).map_err(std::io::Error::other)? {
+            if m.start() < m.end() {
+                spans.push((m.start(), m.end()));
+            }
+        }
+        Ok(spans)
+    }
+
+    /// ファイル名`name`と、その後に`sep`を書き出す。`-Z`であれば`sep`の代わりにNULを書き出す。
+    fn write_file_name(&self, out: &mut impl Write, name: &str, sep: char) -> std::io::Result<()> {
+        out.write_all(name.as_bytes())?;
+        if self.null {
+            out.write_all(b"\0")
+        } else {
+            write!(out, "{sep}")
+        }
+    }
+
+    /// 選んだ行の数`count`を書き出す
+    fn write_count(&self, out: &mut impl Write, count: usize) -> std::io::Result<()> {
+        if let Some(name) = self.name {
+            self.write_file_name(out, name, ':')?;
+        }
+        writeln!(out, "{count}")
+    }
+
+    /// ファイル名のみを1行として書き出す。`-Z`であれば改行の代わりにNULで終える。
+    fn write_name(&self, out: &mut impl Write) -> std::io::Result<()> {
+        self.write_file_name(out, self.name.unwrap_or(STDIN), '\n')
+    }
+}
+
+/// `reader`の各行のうち、`regex`にマッチする行を`out`に書き出し、書き出した行数を返す。
+/// `name`があれば`name:`を前に付ける。`options.invert`であればマッチしない行を書き出す。
+/// `options.count`であれば行の代わりに行数を書き出し、`options.quiet`であれば何も書き出さない。
+/// `options.list_files`であれば、条件を満たす場合にファイル名のみを書き出す。
+/// `options.replace`であれば、マッチしない行も含めた各行のマッチを置き換えて書き出し、マッチした行数を返す。
+/// `options.trace`であれば、各行の評価の様子を`err`に書き出す。
+fn match_file(
+    regex: &Regex,
+    reader: impl LineSource,
+    name: Option<&str>,
+    out: &mut impl Write,
+    err: &mut impl Write,
+    options: &Options,
+    stats: &mut Stats,
+) -> Result<usize, DynError> {
+    let formatter = OutputFormatter::new(regex, name, options)?;
+
+    if options.quiet {
+        select_lines(
+            regex,
+            reader,
+            options,
+            options.line_limit(true),
+            stats,
+            err,
+            |_, _, _, _| Ok(()),
+        )
+    } else if let Some(list_files) = options.list_files {
+        // ファイル名を書き出すかは最初に選んだ行で決まる
+        let limit = options.line_limit(true);
+        let count = select_lines(regex, reader, options, limit, stats, err, |_, _, _, _| {
+            Ok(())
+        })?;
+        if (count > 0) == (list_files == ListFiles::WithMatches) {
+            formatter.write_name(out)?;
+        }
+        Ok(count)
+    } else if options.count {
+        let limit = options.line_limit(false);
+        let count = select_lines(regex, reader, options, limit, stats, err, |_, _, _, _| {
+            Ok(())
+        })?;
+        formatter.write_count(out, count)?;
+        Ok(count)
+    } else if let Some((before, after)) = options.context() {
+        let mut tracker = ContextTracker::new(before, after);
+        let limit = options.line_limit(false);
+        select_lines(
+            regex,
+            reader,
+            options,
+            limit,
+            stats,
+            err,
+            |lineno, offset, line, selected| {
+                tracker.push(lineno, offset, line, selected, |output| match output {
+                    Output::Selected(lineno, offset, line) => {
+                        formatter.write_line(out, lineno, offset, line)
+                    }
+                    Output::Context(lineno, offset, line) => {
+                        formatter.write_context_line(out, lineno, offset, line)
+                    }
+                    Output::Separator => writeln!(out, "--"),
+                })
+            },
+        )
+    } else {
+        // `--replace`では、マッチしない行もそのまま書き出す
+        let writes_unselected = options.replace.is_some() && !options.replace_only_matching_lines;
+        let limit = options.line_limit(false);
+        select_lines(
+            regex,
+            reader,
+            options,
+            limit,
+            stats,
+            err,
+            |lineno, offset, line, selected| {
+                if selected || writes_unselected {
+                    formatter.write_line(out, lineno, offset, line)
+                } else {
+                    Ok(())
+                }
+            },
+        )
+    }
+}
+
+/// `-w`で単語を構成する文字。ASCIIの英数字と`_`に加え、他の言語の単語も区切れるよう、
+/// ASCII以外でも英数字とみなされる文字（`char::is_alphanumeric`）は単語の文字とする。
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// `line`のバイト位置`start`から`end`までのマッチが単語全体にマッチしているか、
+/// すなわち前後に単語の文字が隣接していないか
+fn is_word_match(line: &str, start: usize, end: usize) -> bool {
+    let before = line[..start].chars().next_back();
+    let after = line[end..].chars().next();
+    !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+}
+
+/// `line`が`regex`にマッチするか。`-x`であれば行全体へのマッチ、`-w`であれば単語全体へのマッチのみを数える。
+/// `--trace`であれば評価の様子を`err`に書き出し、`--stats`であれば評価器が集計した指標を`stats`に加える。
+fn is_line_match(
+    regex: &Regex,
+    line: &str,
+    options: &Options,
+    stats: &mut Stats,
+    err: &mut impl Write,
+) -> Result<bool, DynError> {
+    let mut metrics = MatchMetrics::default();
+    let observer = Observer {
+        trace: options.trace.then_some(err as &mut dyn Write),
+        metrics: options.stats.is_some().then_some(&mut metrics),
+    };
+
+    let matched = if options.line_regexp {
+        regex.try_match_full_observed(line, observer)?
+    } else if options.word_regexp {
+        // 単語全体にマッチするかは、各マッチの前後の文字で判断する。
+        // 優先されるマッチが単語の途中で終わっても、同じ位置から始まる他のマッチや、後の位置から始まるマッチを試す。
+        let mut matches = regex.find_iter(line);
+        regex.try_is_match_observed(line, observer)?
+            && matches
+                .try_next_by(|start, end| is_word_match(line, start, end))?
+                .is_some()
+    } else {
+        regex.try_is_match_observed(line, observer)?
+    };
+    if options.stats.is_some() {
+        stats.record(&metrics);
+    }
+    Ok(matched)
+}
+
+/// `reader`の各行のうち、`regex`にマッチする（`options.invert`であればマッチしない）行を選び、
+/// 各行の行番号と入力の先頭からの行のバイト位置、行、選んだかを`report`に渡す。選んだ行の数を返す。
+/// 選んだ行の数が`limit`に達すると、それより後は読まない。`limit`が0であれば何も読まない。
+/// 読んだ行数やマッチした行数は`stats`に加え、`options.stats`であれば実行した命令数も数える。
+/// `options.trace`であれば、各行の評価の様子を`err`に書き出す。
+///
+/// 行末の改行（CRLFの改行であれば`\r\n`）は除いて評価し、報告する。バイト位置は除いた改行も含めて数える。
+/// UTF-8として不正なバイト列は、ファイル全体を諦めずにU+FFFDに置き換えて評価する。
+/// 置き換えた行の中の位置は元のバイト列の位置とずれる。
+/// `options.multiline`であれば`select_multiline`で評価する。
+fn select_lines(
+    regex: &Regex,
+    reader: impl LineSource,
+    options: &Options,
+    limit: Option<usize>,
+    stats: &mut Stats,
+    err: &mut impl Write,
+    mut report: impl FnMut(usize, usize, &str, bool) -> std::io::Result<()>,
+) -> Result<usize, DynError> {
+    let mut count = 0;
+    if limit == Some(0) {
+        return Ok(0);
+    }
+    if options.multiline {
+        return select_multiline(regex, reader, options, limit, stats, report);
+    }
+
+    let (mut lineno, mut offset) = (0, 0);
+    reader.for_each_line(|bytes| {
+        lineno += 1;
+        let line_offset = offset;
+        offset += bytes.len();
+        stats.lines_scanned += 1;
+        stats.bytes_read += bytes.len();
+        // 不正なバイト列がなければ、コピーせずに`bytes`を参照する
+        let line = String::from_utf8_lossy(strip_newline(bytes, options.keep_cr));
+
+        let matched = is_line_match(regex, &line, options, stats, err)?;
+        stats.lines_matched += usize::from(matched);
+        let selected = matched != options.invert;
+        report(lineno, line_offset, &line, selected)?;
+        count += usize::from(selected);
+        Ok(!selected || Some(count) != limit)
+    })?;
+
+    Ok(count)
+}
+
+/// 行末の改行を除く。CRLFの改行では、`keep_cr`でなければ`\r`も除く。
+/// 除かなければ`$`が`\r`の後でしか成り立たない。
+fn strip_newline(bytes: &[u8], keep_cr: bool) -> &[u8] {
+    match bytes.strip_suffix(b"\n") {
+        Some(line) if !keep_cr => line.strip_suffix(b"\r").unwrap_or(line),
+        Some(line) => line,
+        None => bytes,
+    }
+}
+
+/// `-U`で、1度に読むファイルの大きさの上限のデフォルト（バイト）
+const DEFAULT_MAX_FILESIZE: usize = 64 * 1024 * 1024;
+
+/// `-U`で、`reader`の内容全体を1つの文字列として`regex`で評価し、マッチと重なる行を選ぶ。
+/// 選ぶ行や`report`に渡す値は`select_lines`と同じで、行を跨ぐマッチは、その全ての行を1度ずつ選ぶ。
+///
+/// ファイル全体をメモリに読み込むので、`options.max_filesize`（デフォルトでは`DEFAULT_MAX_FILESIZE`）
+/// より大きいファイルは評価せずにエラーとする。
+/// `-w`であれば前後に単語の文字が隣接しないマッチを、`-x`であれば行の先頭から行末までのマッチのみを数える。
+fn select_multiline(
+    regex: &Regex,
+    reader: impl LineSource,
+    options: &Options,
+    limit: Option<usize>,
+    stats: &mut Stats,
+    mut report: impl FnMut(usize, usize, &str, bool) -> std::io::Result<()>,
+) -> Result<usize, DynError> {
+    let max = options.max_filesize.unwrap_or(DEFAULT_MAX_FILESIZE);
+    let (mut content, mut len) = (Vec::new(), 0);
+    // 改行を揃える前の、各行の先頭のバイト位置
+    let mut offsets = Vec::new();
+    reader.for_each_line(|bytes| {
+        offsets.push(len);
+        len += bytes.len();
+        if len > max {
+            return Err(format!("file is larger than {max} bytes (see --max-filesize)").into());
+        }
+        stats.lines_scanned += 1;
+        stats.bytes_read += bytes.len();
+        // 改行は`\n`に揃える
+        content.extend_from_slice(strip_newline(bytes, options.keep_cr));
+        if bytes.ends_with(b"\n") {
+            content.push(b'\n');
+        }
+        Ok(true)
+    })?;
+
+    let text = String::from_utf8_lossy(&content);
+    let lines = text
+        .split_inclusive('\n')
+        .map(|line| line.strip_suffix('\n').unwrap_or(line))
+        .collect::<Vec<_>>();
+    // 各行の先頭の位置
+    let starts = lines
+        .iter()
+        .scan(0, |pos, line| {
+            let start = *pos;
+            *pos += line.len() + 1;
+            Some(start)
+        })
+        .collect::<Vec<_>>();
+    let line_of = |pos: usize| starts.partition_point(|&start| start <= pos) - 1;
+    let mut matched = vec![false; lines.len()];
+    let mut select = |m: Match| {
+        let last = m.end().saturating_sub(1).max(m.start());
+        matched[line_of(m.start())..=line_of(last)].fill(true);
+    };
+    if options.line_regexp {
+        // 各行の先頭から、その行または後の行の行末まで続くマッチを探す
+        for &start in &starts {
+            if let Some(m) = regex.try_match_full_at(&text, start)? {
+                select(m);
+            }
+        }
+    } else {
+        let mut matches = regex.find_iter(&text);
+        let accept = |start, end| !options.word_regexp || is_word_match(&text, start, end);
+        while let Some(m) = matches.try_next_by(accept)? {
+            // 最後の改行の後は行ではない
+            if lines.is_empty() || (m.start() == text.len() && text.ends_with('\n')) {
+                continue;
+            }
+            select(m);
+        }
+    }
+
+    let mut count = 0;
+    for (i, (line, matched)) in lines.iter().zip(matched).enumerate() {
+        stats.lines_matched += usize::from(matched);
+        let selected = matched != options.invert;
+        report(i + 1, offsets[i], line, selected)?;
+        if selected {
+            count += 1;
+            if Some(count) == limit {
+                break;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::helper::{safe_add, SafeAdd};
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_match_file() -> Result<(), DynError> {
+        let regex = Regex::new("error|warn")?;
+
+        // 標準入力の代わり
+        let input = Cursor::new(b"error: a\nok\nwarning\nerror: b".to_vec());
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            input,
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &Options::default(),
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, "error: a\nwarning\nerror: b\n");
+
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new(Vec::new()),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &Options::default(),
+            &mut Stats::default(),
+        )?;
+        assert!(out.is_empty());
+
+        let input = Cursor::new(b"ok\nwarn".to_vec());
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            input,
+            Some("a.log"),
+            &mut out,
+            &mut std::io::sink(),
+            &Options::default(),
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, "a.log:warn\n");
+
+        Ok(())
+    }
 
     #[test]
-    fn test_safe_add() {
-        let n: usize = 10;
-        assert_eq!(Some(30), n.safe_add(&20));
+    fn test_invalid_utf8() -> Result<(), DynError> {
+        let regex = Regex::new("a.c")?;
+        let input: &[u8] = b"abc\nx\xff\xfey\na\x80c\nzabc";
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            input,
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &Options::default(),
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, "abc\na\u{FFFD}c\nzabc\n");
 
-        let n: usize = !0; // 2^64 - 1 (64 bits CPU)
-        assert_eq!(None, n.safe_add(&1));
+        let regex = Regex::new("x.*y")?;
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            input,
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &Options::default(),
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, "x\u{FFFD}\u{FFFD}y\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invert_match() -> Result<(), DynError> {
+        let args = ["-v", "error", "-"].map(String::from);
+        let (options, positional) = parse_args(&args)?;
+        assert!(options.invert);
+        assert_eq!(positional, vec!["error", "-"]);
+
+        let regex = Regex::new(positional[0])?;
+        let input = Cursor::new(b"error: a\nok\nerror: b\nfine".to_vec());
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            input,
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, "ok\nfine\n");
+
+        let args = ["--invert-match", "x"].map(String::from);
+        assert!(parse_args(&args)?.0.invert);
+        let args = ["--unknown", "x"].map(String::from);
+        assert!(parse_args(&args).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_number() -> Result<(), DynError> {
+        let args = ["-n", "a"].map(String::from);
+        let (options, _) = parse_args(&args)?;
+        assert!(options.line_number);
+
+        let regex = Regex::new("a")?;
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new(b"abc\nxyz\ncba\n"),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(out, b"1:abc\n3:cba\n");
+
+        // ファイル名、行番号の順に付ける
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new(b"abc\nxyz\ncba\n"),
+            Some("a.txt"),
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(out, b"a.txt:1:abc\na.txt:3:cba\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_offset() -> Result<(), DynError> {
+        // 各行の先頭は0、8、17バイト目。除いた`\r\n`も数える
+        let input = "あい\r\nfoo bar\r\nxfoo\n";
+
+        let (_, out, _) = run_with(&["-b", "foo"], input);
+        assert_eq!(out, "8:foo bar\n17:xfoo\n");
+        // 行番号の後に付ける
+        let (_, out, _) = run_with(&["-n", "--byte-offset", "foo"], input);
+        assert_eq!(out, "2:8:foo bar\n3:17:xfoo\n");
+        // `-o`ではマッチの先頭の位置
+        let (_, out, _) = run_with(&["-o", "-b", "foo|い"], input);
+        assert_eq!(out, "3:い\n8:foo\n18:foo\n");
+        // 前後の行にも付ける
+        let (_, out, _) = run_with(&["-b", "-A1", "あ"], input);
+        assert_eq!(out, "0:あい\n8-foo bar\n");
+        // `-U`でも改行を揃える前の位置
+        let (_, out, _) = run_with(&["-U", "-b", "bar\nx"], input);
+        assert_eq!(out, "8:foo bar\n17:xfoo\n");
+        // `--crlf=keep`でも位置は変わらない
+        let (_, out, _) = run_with(&["--crlf=keep", "-o", "-b", "r\r"], input);
+        assert_eq!(out, "14:r\r\n");
+
+        // ファイル名、行番号、バイト位置の順に付ける
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.txt");
+        std::fs::write(&a, input)?;
+        let a = a.to_str().unwrap();
+        let (_, out, _) = run_with(&["-n", "-b", "xfoo", a, a], "");
+        assert_eq!(out, format!("{a}:3:17:xfoo\n{a}:3:17:xfoo\n"));
+
+        let (code, _, err) = run_with(&["-b", "--json", "foo"], input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("--json cannot be used with -b\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_case() -> Result<(), DynError> {
+        let args = ["-i", "error"].map(String::from);
+        let (options, positional) = parse_args(&args)?;
+        assert!(options.ignore_case);
+
+        let regex = build_regex(positional[0], &options)?;
+        let input = b"Error: a\nok\nERROR: b\nerr\nan error";
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new(input),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(out, b"Error: a\nERROR: b\nan error\n");
+
+        // 指定しなければ区別する
+        let regex = build_regex("error", &Options::default())?;
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new(input),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &Options::default(),
+            &mut Stats::default(),
+        )?;
+        assert_eq!(out, b"an error\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recursive() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("sub/deep"))?;
+        std::fs::write(root.join("a.txt"), "foo\nbar\n")?;
+        std::fs::write(root.join("sub/b.txt"), "xyz\nfoo bar\n")?;
+        std::fs::write(root.join("sub/deep/c.txt"), "baz\n")?;
+        let path = |file: &str| root.join(file).to_string_lossy().into_owned();
+
+        let args = ["-r", "-n", "foo"].map(String::from);
+        let (mut options, _) = parse_args(&args)?;
+        assert!(options.recursive);
+
+        // 1つのディレクトリでもファイル名を付ける
+        let regex = Regex::new("foo")?;
+        let root_str = root.to_str().unwrap();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let outcome = search_files(
+            &regex,
+            &[root_str],
+            &options,
+            &mut std::io::empty(),
+            &mut out,
+            &mut err,
+            &mut Stats::default(),
+        )?;
+        assert!(outcome.selected && !outcome.failed);
+        assert_eq!(
+            String::from_utf8(out)?,
+            format!("{}:1:foo\n{}:2:foo bar\n", path("a.txt"), path("sub/b.txt"))
+        );
+
+        options.line_number = false;
+        options.count = true;
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let stdin = &mut std::io::empty();
+        search_files(
+            &regex,
+            &[root_str],
+            &options,
+            stdin,
+            &mut out,
+            &mut err,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            format!(
+                "{}:1\n{}:1\n{}:0\n",
+                path("a.txt"),
+                path("sub/b.txt"),
+                path("sub/deep/c.txt")
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_color() -> Result<(), DynError> {
+        let args = ["--color=always", "-n", "b+"].map(String::from);
+        let (options, _) = parse_args(&args)?;
+        assert_eq!(options.color, ColorChoice::Always);
+
+        // 全てのマッチを色付けする。マルチバイト文字に隣接していても分かれない
+        let regex = Regex::new("b+")?;
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new("あbbいbう\nxyz"),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "1:あ\x1b[1;31mbb\x1b[0mい\x1b[1;31mb\x1b[0mう\n"
+        );
+
+        // 空文字列へのマッチは色付けしない
+        let regex = Regex::new("x*")?;
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new("axxb"),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, "1:a\x1b[1;31mxx\x1b[0mb\n");
+
+        let args = ["--color=never", "a"].map(String::from);
+        assert_eq!(parse_args(&args)?.0.color, ColorChoice::Never);
+        let args = ["--color", "a"].map(String::from);
+        assert_eq!(parse_args(&args)?.0.color, ColorChoice::Auto);
+        let args = ["--color=sometimes", "a"].map(String::from);
+        assert!(parse_args(&args).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_matching() -> Result<(), DynError> {
+        let args = ["-o", "-n", "ab+"].map(String::from);
+        let (options, _) = parse_args(&args)?;
+        assert!(options.only_matching);
+
+        // 行番号はマッチごとに付ける
+        let regex = Regex::new("ab+")?;
+        let mut out = Vec::new();
+        let input = Cursor::new("xyz\nab-abbb ab\nb");
+        match_file(
+            &regex,
+            input,
+            Some("a.txt"),
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "a.txt:2:ab\na.txt:2:abbb\na.txt:2:ab\n"
+        );
+
+        // 空文字列へのマッチは書き出さない
+        let regex = Regex::new("x*")?;
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new("axxbx\nab"),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, "1:xx\n1:x\n");
+
+        let args = ["-o", "-v", "a"].map(String::from);
+        assert!(parse_args(&args).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine() -> Result<(), DynError> {
+        let input = "abab\nbbb\naab\n\nxa\nba";
+        let mut outputs = Vec::new();
+        for engine in ["depth", "width", "pike", "bitstate"] {
+            let args = [format!("--engine={engine}"), "-n".into(), "(a|b)*ab".into()];
+            let (options, positional) = parse_args(&args)?;
+            let regex = build_regex(positional[0], &options)?;
+            let mut out = Vec::new();
+            match_file(
+                &regex,
+                Cursor::new(input),
+                None,
+                &mut out,
+                &mut std::io::sink(),
+                &options,
+                &mut Stats::default(),
+            )?;
+            outputs.push(String::from_utf8(out)?);
+        }
+        assert_eq!(outputs[0], "1:abab\n3:aab\n");
+        assert!(outputs.iter().all(|out| *out == outputs[0]));
+
+        let args = ["--engine=pike", "--step-limit=100", "a"].map(String::from);
+        let (options, _) = parse_args(&args)?;
+        assert_eq!(options.engine, Engine::Width);
+        assert_eq!(options.step_limit, Some(100));
+
+        for arg in [
+            "--engine=dfa",
+            "--engine",
+            "--step-limit=-1",
+            "--step-limit",
+        ] {
+            assert!(parse_args(&[arg.to_string(), "a".into()]).is_err(), "{arg}");
+        }
+
+        Ok(())
+    }
+
+    /// 読み出したバイト数を数える入力
+    struct CountingReader {
+        inner: Cursor<Vec<u8>>,
+        consumed: usize,
+    }
+
+    impl CountingReader {
+        fn new(input: impl Into<Vec<u8>>) -> Self {
+            CountingReader {
+                inner: Cursor::new(input.into()),
+                consumed: 0,
+            }
+        }
+    }
+
+    impl std::io::Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.consumed += n;
+            Ok(n)
+        }
+    }
+
+    impl BufRead for CountingReader {
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            self.inner.fill_buf()
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.consumed += amt;
+            self.inner.consume(amt);
+        }
+    }
+
+    #[test]
+    fn test_quiet() -> Result<(), DynError> {
+        let args = ["-q", "b"].map(String::from);
+        let (mut options, _) = parse_args(&args)?;
+        assert!(options.quiet);
+
+        // 最初にマッチした行までしか読まず、何も書き出さない
+        let regex = Regex::new("b")?;
+        let mut reader = CountingReader::new("xyz\nabc\nbbb\nccc\n");
+        let mut out = Vec::new();
+        assert_eq!(
+            match_file(
+                &regex,
+                &mut reader,
+                None,
+                &mut out,
+                &mut std::io::sink(),
+                &options,
+                &mut Stats::default()
+            )?,
+            1
+        );
+        assert_eq!(reader.consumed, "xyz\nabc\n".len());
+        assert!(out.is_empty());
+
+        // `-v`では最初にマッチしなかった行まで
+        options.invert = true;
+        let mut reader = CountingReader::new("b\nbb\nc\nd\n");
+        assert_eq!(
+            match_file(
+                &regex,
+                &mut reader,
+                None,
+                &mut out,
+                &mut std::io::sink(),
+                &options,
+                &mut Stats::default()
+            )?,
+            1
+        );
+        assert_eq!(reader.consumed, "b\nbb\nc\n".len());
+
+        // 選んだ行があれば残りのファイルは開かない
+        options.invert = false;
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.txt");
+        std::fs::write(&a, "abc\n")?;
+        let missing = dir.path().join("missing.txt");
+        let (a, missing) = (a.to_str().unwrap(), missing.to_str().unwrap());
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let stdin = &mut std::io::empty();
+        let outcome = search_files(
+            &regex,
+            &[a, missing],
+            &options,
+            stdin,
+            &mut out,
+            &mut err,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(
+            outcome,
+            Outcome {
+                selected: true,
+                failed: false
+            }
+        );
+        assert!(out.is_empty() && err.is_empty());
+
+        // 再帰的な検索でも、最初に選んだファイルでやめる
+        options.recursive = true;
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("sub"))?;
+        std::fs::write(dir.path().join("a.txt"), "b\n")?;
+        std::fs::write(dir.path().join("sub/b.txt"), "b\n")?;
+        let root = dir.path().to_str().unwrap();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        search_files(
+            &regex,
+            &[root],
+            &options,
+            stdin,
+            &mut out,
+            &mut err,
+            &mut Stats::default(),
+        )?;
+        assert!(out.is_empty() && err.is_empty());
+
+        let (code, _, _) = run_with(&["-q", "b", a, missing], "");
+        assert_eq!(code, EXIT_SELECTED);
+        let (code, _, _) = run_with(&["-q", "q"], "abc\n");
+        assert_eq!(code, EXIT_NOT_SELECTED);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_files() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        std::fs::write(root.join("a.txt"), "foo\nfoo\n")?;
+        std::fs::write(root.join("b.txt"), "bar\n")?;
+        std::fs::write(root.join("c.txt"), "")?;
+        let path = |file: &str| root.join(file).to_string_lossy().into_owned();
+        let (a, b, c) = (path("a.txt"), path("b.txt"), path("c.txt"));
+        let files = [a.as_str(), b.as_str(), c.as_str()];
+
+        let (code, out, _) = run_with(&[&["-l", "foo"][..], &files].concat(), "");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, format!("{a}\n"));
+
+        let (code, out, _) = run_with(&[&["-L", "foo"][..], &files].concat(), "");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, format!("{b}\n{c}\n"));
+
+        // 1つのファイルでもファイル名を書き出す
+        let (code, out, _) = run_with(&["-l", "foo", &a], "");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, format!("{a}\n"));
+        let (code, out, _) = run_with(&["-L", "foo", &a], "");
+        assert_eq!(code, EXIT_NOT_SELECTED);
+        assert!(out.is_empty());
+
+        // 再帰的な検索
+        let root_str = root.to_str().unwrap();
+        let (_, out, _) = run_with(&["-r", "-l", "ba|fo", root_str], "");
+        assert_eq!(out, format!("{a}\n{b}\n"));
+
+        // 最初にマッチした行より後は読まない
+        let (options, _) = parse_args(&["-l".to_string(), "b".to_string()])?;
+        let regex = Regex::new("b")?;
+        let mut reader = CountingReader::new("abc\nbbb\n");
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            &mut reader,
+            Some("x"),
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(reader.consumed, "abc\n".len());
+        assert_eq!(out, b"x\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_null() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("my file.txt");
+        std::fs::write(&a, "foo\nbar\n")?;
+        let b = dir.path().join("b.txt");
+        std::fs::write(&b, "baz\n")?;
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        // `-l`と`-L`では改行の代わりにNULで終える
+        let (_, out, _) = run_with(&["-Z", "-l", "ba", a, b], "");
+        assert_eq!(out.as_bytes(), format!("{a}\0{b}\0").as_bytes());
+        let (_, out, _) = run_with(&["--null", "-L", "foo", a, b], "");
+        assert_eq!(out.as_bytes(), format!("{b}\0").as_bytes());
+
+        // 行の前では`:`や`-`の代わりにNULを書き出す。行番号の後は変えない
+        let (_, out, _) = run_with(&["-Z", "-n", "foo", a, b], "");
+        assert_eq!(out.as_bytes(), format!("{a}\x001:foo\n").as_bytes());
+        let (_, out, _) = run_with(&["-Z", "-A", "1", "foo", a, b], "");
+        assert_eq!(out.as_bytes(), format!("{a}\0foo\n{a}\0bar\n").as_bytes());
+        let (_, out, _) = run_with(&["-Z", "-c", "ba", a, b], "");
+        assert_eq!(out.as_bytes(), format!("{a}\x001\n{b}\x001\n").as_bytes());
+
+        // ファイル名を付けなければ変わらない
+        let (_, out, _) = run_with(&["-Z", "foo", a], "");
+        assert_eq!(out, "foo\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_files() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let bin = dir.path().join("a.bin");
+        std::fs::write(&bin, b"\x7fELF\0\x01\nfoo bar\n\0\0")?;
+        let txt = dir.path().join("b.txt");
+        std::fs::write(&txt, "foo\n")?;
+        let (bin, txt) = (bin.to_str().unwrap(), txt.to_str().unwrap());
+
+        // 行の代わりにマッチしたことのみを書き出す
+        let (code, out, _) = run_with(&["foo", bin, txt], "");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, format!("binary file {bin} matches\n{txt}:foo\n"));
+        let (code, out, _) = run_with(&["xyz", bin], "");
+        assert_eq!(code, EXIT_NOT_SELECTED);
+        assert!(out.is_empty());
+
+        let (code, out, _) = run_with(&["--binary-files=without-match", "foo", bin, txt], "");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, format!("{txt}:foo\n"));
+
+        // 判定に読んだ内容も検索する
+        let (_, out, _) = run_with(&["--binary-files=text", "ELF|foo", bin], "");
+        assert_eq!(out, "\x7fELF\0\x01\nfoo bar\n");
+
+        // 行数やファイル名はテキストファイルと同様
+        let (_, out, _) = run_with(&["-c", "o", bin], "");
+        assert_eq!(out, "1\n");
+        let (_, out, _) = run_with(&["-l", "o", bin, txt], "");
+        assert_eq!(out, format!("{bin}\n{txt}\n"));
+
+        // 標準入力
+        let (_, out, _) = run_with(&["foo"], "\0foo\n");
+        assert_eq!(out, "binary file - matches\n");
+
+        assert!(parse_args(&["--binary-files=yes".to_string()]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_count() -> Result<(), DynError> {
+        let input = "match\n".repeat(100);
+        let regex = Regex::new("a")?;
+
+        let (options, _) = parse_args(&["-m", "3", "a"].map(String::from))?;
+        assert_eq!(options.max_count, Some(3));
+        let mut reader = CountingReader::new(input.as_str());
+        let mut out = Vec::new();
+        assert_eq!(
+            match_file(
+                &regex,
+                &mut reader,
+                None,
+                &mut out,
+                &mut std::io::sink(),
+                &options,
+                &mut Stats::default()
+            )?,
+            3
+        );
+        assert_eq!(out, b"match\nmatch\nmatch\n");
+        assert_eq!(reader.consumed, "match\n".len() * 3);
+
+        // 行数は上限まで。上限はファイルごと
+        let (code, out, _) = run_with(&["-c", "--max-count=3", "a"], &input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "3\n");
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.txt");
+        std::fs::write(&a, "a1\na2\nb\na3\n")?;
+        let a = a.to_str().unwrap();
+        let (_, out, _) = run_with(&["-m", "2", "a", a, a], "");
+        assert_eq!(out, format!("{a}:a1\n{a}:a2\n{a}:a1\n{a}:a2\n"));
+        let (_, out, _) = run_with(&["-v", "-m", "1", "a", a], "");
+        assert_eq!(out, "b\n");
+
+        // 0であれば検索しない
+        let (code, out, _) = run_with(&["-m", "0", "a"], &input);
+        assert_eq!(code, EXIT_NOT_SELECTED);
+        assert!(out.is_empty());
+
+        assert!(parse_args(&["a".to_string(), "-m".to_string()]).is_err());
+        assert!(parse_args(&["-m", "x", "a"].map(String::from)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_context() -> Result<(), DynError> {
+        let input = "a\nfoo\nb\nc\nd\ne\nfoo\nf\n";
+
+        let (_, out, _) = run_with(&["-A", "1", "foo"], input);
+        assert_eq!(out, "foo\nb\n--\nfoo\nf\n");
+        let (_, out, _) = run_with(&["-n", "-B1", "foo"], input);
+        assert_eq!(out, "1-a\n2:foo\n--\n6-e\n7:foo\n");
+        let (_, out, _) = run_with(&["-n", "--context=2", "foo"], input);
+        assert_eq!(out, "1-a\n2:foo\n3-b\n4-c\n5-d\n6-e\n7:foo\n8-f\n");
+
+        // ファイル名にも`-`を付ける
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.txt");
+        std::fs::write(&a, input)?;
+        let a = a.to_str().unwrap();
+        let (_, out, _) = run_with(&["-n", "-C", "0", "foo", a, a], "");
+        assert_eq!(
+            out,
+            format!("{a}:2:foo\n--\n{a}:7:foo\n{a}:2:foo\n--\n{a}:7:foo\n")
+        );
+
+        assert!(parse_args(&["-B".to_string()]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_threads() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir(root.join("sub"))?;
+        let mut files = Vec::new();
+        for i in 0..16 {
+            let file = root
+                .join(if i % 2 == 0 { "sub" } else { "" })
+                .join(format!("{i:02}.txt"));
+            let content = (0..50)
+                .map(|j| {
+                    if (i + j) % 7 == 0 {
+                        format!("foo {j}\n")
+                    } else {
+                        format!("bar {j}\n")
+                    }
+                })
+                .collect::<String>();
+            std::fs::write(&file, content)?;
+            files.push(file.to_string_lossy().into_owned());
+        }
+        files.insert(5, root.join("missing.txt").to_string_lossy().into_owned());
+        let root = root.to_str().unwrap();
+
+        // 逐次に検索した場合と、バイト単位で同じ結果になる
+        let cases = [
+            [
+                &["-n", "foo"][..],
+                &files.iter().map(String::as_str).collect::<Vec<_>>(),
+            ]
+            .concat(),
+            vec!["-r", "-c", "foo", root],
+            vec!["-r", "-l", "foo 4", root],
+            vec!["-r", "-C1", "foo 1", root],
+        ];
+        for case in cases {
+            let sequential = run_with(&[&["--threads=1"][..], &case].concat(), "");
+            let parallel = run_with(&[&["--threads=4"][..], &case].concat(), "");
+            assert_eq!(sequential, parallel, "{case:?}");
+
+            // `--progress`は標準出力に影響しない
+            let (code, out, _) =
+                run_with(&[&["--threads=1", "--progress"][..], &case].concat(), "");
+            assert_eq!((code, out), (sequential.0, sequential.1), "{case:?}");
+        }
+
+        let (code, out, err) = run_with(
+            &[
+                &["--threads=4", "foo 0"][..],
+                &files.iter().map(String::as_str).collect::<Vec<_>>(),
+            ]
+            .concat(),
+            "",
+        );
+        assert_eq!(code, EXIT_ERROR);
+        assert_eq!(out.lines().count(), 3);
+        assert!(err.contains("missing.txt: "));
+
+        assert!(parse_args(&["--threads=0".to_string()]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_exclude() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("src"))?;
+        std::fs::create_dir_all(root.join("node_modules/lib"))?;
+        for file in [
+            "main.rs",
+            "app.js",
+            "app.min.js",
+            "src/lib.rs",
+            "node_modules/lib/index.js",
+        ] {
+            std::fs::write(root.join(file), "todo\n")?;
+        }
+        let path = |file: &str| root.join(file).to_string_lossy().into_owned();
+        let root_str = root.to_str().unwrap();
+
+        let (_, out, _) = run_with(
+            &[
+                "-r",
+                "-l",
+                "--include=*.rs",
+                "--include",
+                "*.js",
+                "--exclude=*.min.js",
+                "--exclude-dir=node_modules",
+                "todo",
+                root_str,
+            ],
+            "",
+        );
+        assert_eq!(
+            out,
+            format!(
+                "{}\n{}\n{}\n",
+                path("app.js"),
+                path("main.rs"),
+                path("src/lib.rs")
+            )
+        );
+
+        let (_, out, _) = run_with(&["-r", "-l", "--exclude=*.js", "todo", root_str], "");
+        assert_eq!(
+            out,
+            format!("{}\n{}\n", path("main.rs"), path("src/lib.rs"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_respect_gitignore() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("target/debug"))?;
+        std::fs::create_dir_all(root.join("src/gen"))?;
+        std::fs::write(root.join(".gitignore"), "target/\n*.log\n")?;
+        std::fs::write(root.join("src/.gitignore"), "gen\n!keep.log\n")?;
+        for file in [
+            "main.rs",
+            "debug.log",
+            "target/debug/out.rs",
+            "src/lib.rs",
+            "src/keep.log",
+            "src/gen/code.rs",
+        ] {
+            std::fs::write(root.join(file), "todo\n")?;
+        }
+        let path = |file: &str| root.join(file).to_string_lossy().into_owned();
+        let root_str = root.to_str().unwrap();
+
+        // 深いディレクトリの`.gitignore`の規則が優先する
+        let (_, out, _) = run_with(&["-r", "-l", "--respect-gitignore", "todo", root_str], "");
+        assert_eq!(
+            out,
+            format!(
+                "{}\n{}\n{}\n",
+                path("main.rs"),
+                path("src/keep.log"),
+                path("src/lib.rs")
+            )
+        );
+
+        // 指定しなければ`.gitignore`を読まない
+        let (_, out, _) = run_with(&["-r", "-c", "todo", root_str], "");
+        assert_eq!(out.lines().count(), 8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regexp() -> Result<(), DynError> {
+        let input = "foo\nbar\nbaaar\nbaz\n-x\n";
+
+        // いずれかのパターンにマッチする行を選ぶ
+        let (code, out, _) = run_with(&["-e", "foo", "-e", "ba+r"], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "foo\nbar\nbaaar\n");
+        let (_, out, _) = run_with(&["-v", "-e", "foo", "--regexp=ba+r"], input);
+        assert_eq!(out, "baz\n-x\n");
+        let (_, out, _) = run_with(&["-c", "-efoo", "-e", "ba+r"], input);
+        assert_eq!(out, "3\n");
+        let (_, out, _) = run_with(&["-c", "-v", "-e", "foo", "-e", "ba+r"], input);
+        assert_eq!(out, "2\n");
+        let (_, out, _) = run_with(&["-i", "-e", "FOO", "-e", "BAZ"], input);
+        assert_eq!(out, "foo\nbaz\n");
+
+        // `-`で始まるパターン
+        let (_, out, _) = run_with(&["-e", "-x"], input);
+        assert_eq!(out, "-x\n");
+
+        // 位置引数は全て検索するファイル
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("input.txt");
+        std::fs::write(&file, input)?;
+        let file = file.to_str().unwrap();
+        let (_, out, _) = run_with(&["-e", "foo", file], "");
+        assert_eq!(out, "foo\n");
+
+        // `-f`とも合わせられる
+        let patterns = dir.path().join("patterns.txt");
+        std::fs::write(&patterns, "baz\n")?;
+        let (_, out, _) = run_with(&["-e", "foo", "-f", patterns.to_str().unwrap()], input);
+        assert_eq!(out, "foo\nbaz\n");
+
+        // 誤ったパターンは何番目の`-e`かを示す
+        let (code, _, err) = run_with(&["-e", "foo", "-e", "(bar"], input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("error: -e #2: "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pattern_file() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let patterns = dir.path().join("patterns.txt");
+        std::fs::write(&patterns, "^foo\n\n  ba+r  \nbaz$\n")?;
+        let patterns = patterns.to_str().unwrap();
+        let input = "foo\nxfoo\nbaaar\nbazz\nabaz\nqux\n";
+
+        // いずれかのパターンにマッチする行を選ぶ。空行は無視し、前後の空白は除く
+        let (code, out, _) = run_with(&["-f", patterns], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "foo\nbaaar\nabaz\n");
+        let (_, out, _) = run_with(&["-n", &format!("--file={patterns}")], input);
+        assert_eq!(out, "1:foo\n3:baaar\n5:abaz\n");
+        let (_, out, _) = run_with(&["-v", &format!("-f{patterns}")], input);
+        assert_eq!(out, "xfoo\nbazz\nqux\n");
+        let (_, out, _) = run_with(&["-o", "-i", "-f", patterns], "FOO BAR BAZ\n");
+        assert_eq!(out, "FOO\nBAR\nBAZ\n");
+
+        // 位置引数は全て検索するファイル
+        let file = dir.path().join("input.txt");
+        std::fs::write(&file, input)?;
+        let file = file.to_str().unwrap();
+        let (_, out, _) = run_with(&["-c", "-f", patterns, file, file], "");
+        assert_eq!(out, format!("{file}:3\n{file}:3\n"));
+
+        // 誤ったパターンはファイル名と行番号を示す
+        let invalid = dir.path().join("invalid.txt");
+        std::fs::write(&invalid, "foo\n(bar\n")?;
+        let invalid = invalid.to_str().unwrap();
+        let (code, _, err) = run_with(&["-f", patterns, "-f", invalid], input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with(&format!("error: {invalid}:2: ")));
+
+        // 読めないファイルやパターンのないファイル
+        let (code, _, err) = run_with(&["-f", "missing.txt"], input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("error: missing.txt: "));
+        let empty = dir.path().join("empty.txt");
+        std::fs::write(&empty, "\n")?;
+        let (code, _, err) = run_with(&["-f", empty.to_str().unwrap()], input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("error: no pattern in "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace() {
+        // 評価の様子を書き出しても、選ぶ行は変わらない
+        let input = "abc\n\nFOO\nxfoo\nconcat\ncat dog\n";
+        for args in [
+            &["$"][..],
+            &["a*"],
+            &["-i", "foo"],
+            &["-x", "foo"],
+            &["-x", "-i", "foo"],
+            &["-w", "cat"],
+        ] {
+            let traced = [&["--trace"], args].concat();
+            let (code, out, err) = run_with(&traced, input);
+            let (expected_code, expected_out, _) = run_with(args, input);
+            assert_eq!((code, out), (expected_code, expected_out), "{args:?}");
+            // 評価の様子は標準エラー出力に書き出す
+            assert!(err.contains("result: "), "{args:?}: {err}");
+        }
+
+        let (code, out, err) = run_with(&["--trace", "b"], "abc\nxyz\n");
+        assert_eq!((code, out.as_str()), (EXIT_SELECTED, "abc\n"));
+        assert_eq!(
+            err,
+            "start: sp 0001\n\
+             0000: pc 0000 | split 0003, 0001 | sp 0001 'b'\n\
+             0001: pc 0003 | char b           | sp 0001 'b'\n\
+             0002: pc 0004 | match            | sp 0002 'c'\n\
+             result: matched\n\
+             result: unmatched (no start position)\n"
+        );
+        let (code, out, err) = run_with(&["--trace", "-c", "a*"], "\n\n");
+        assert_eq!((code, out.as_str()), (EXIT_SELECTED, "2\n"));
+        assert_eq!(err.matches("result: matched").count(), 2);
+
+        // 並列に検索しても、各ファイルの評価の様子はファイルの順に書き出す
+        let dir = tempfile::tempdir().unwrap();
+        let files = ["a.txt", "b.txt"].map(|name| dir.path().join(name));
+        std::fs::write(&files[0], "a\n").unwrap();
+        std::fs::write(&files[1], "xb\n").unwrap();
+        let files = files.each_ref().map(|file| file.to_str().unwrap());
+        let (_, _, sequential) =
+            run_with(&["--threads=1", "--trace", "a|b", files[0], files[1]], "");
+        let a = sequential.find("sp 0000 'a'").unwrap();
+        let b = sequential.find("sp 0001 'b'").unwrap();
+        assert!(a < b, "{sequential}");
+        for _ in 0..10 {
+            let args = ["--threads=2", "--trace", "a|b", files[0], files[1]];
+            assert_eq!(run_with(&args, "").2, sequential);
+        }
+        // `-i`は評価するパターンに反映される
+        assert_eq!(run_with(&["--trace", "-i", "FOO"], "foo\n").1, "foo\n");
+        assert_eq!(
+            run_with(&["--trace", "-x", "foo"], "xfoo\nfoo\n").1,
+            "foo\n"
+        );
+        assert_eq!(
+            run_with(&["--trace", "-w", "cat"], "concat\ncat dog\n").1,
+            "cat dog\n"
+        );
+    }
+
+    #[test]
+    fn test_stats() -> Result<(), DynError> {
+        /// JSONの統計から`key`の値を取り出す
+        fn field(json: &str, key: &str) -> String {
+            let start = json.find(&format!("\"{key}\":")).unwrap() + key.len() + 3;
+            let len = json[start..].find([',', '}']).unwrap();
+            json[start..start + len].to_string()
+        }
+
+        let input = "abc\nxyz\nbb\n";
+        let (code, out, err) = run_with(&["--stats=json", "b+"], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "abc\nbb\n");
+        assert_eq!(err.lines().count(), 1);
+        assert_eq!(field(&err, "lines_scanned"), "3");
+        assert_eq!(field(&err, "lines_matched"), "2");
+        assert_eq!(field(&err, "files_searched"), "1");
+        assert_eq!(field(&err, "files_skipped"), "0");
+        assert_eq!(field(&err, "bytes_read"), input.len().to_string());
+        assert!(field(&err, "steps").parse::<usize>()? > 0);
+        assert!(field(&err, "elapsed_secs").parse::<f64>()? >= 0.0);
+
+        // `-v`でもマッチした行を数える。`-m`で読むのをやめた行は数えない
+        let (_, _, err) = run_with(&["--stats=json", "-v", "b+"], input);
+        assert_eq!(field(&err, "lines_matched"), "2");
+        let (_, _, err) = run_with(&["--stats=json", "-m", "1", "b+"], input);
+        assert_eq!(field(&err, "lines_scanned"), "1");
+
+        // 評価器の指標。`b+`は1文字目の`b`で絞り込み、`xyz`は評価せずにマッチしないとする
+        assert_eq!(field(&err, "lines_prefiltered"), "1");
+        let (_, _, err) = run_with(&["--stats=json", "b+"], input);
+        assert_eq!(field(&err, "lines_prefiltered"), "2");
+        assert_eq!(field(&err, "lines_memoized"), "0");
+        let (_, _, err) = run_with(&["--stats=json", "--engine=bitstate", "(a|b)*c"], input);
+        assert_eq!(field(&err, "lines_memoized"), "2");
+        assert_eq!(field(&err, "lines_prefiltered"), "1");
+        assert!(field(&err, "backtracks").parse::<usize>()? > 0);
+        assert!(field(&err, "peak_pending").parse::<usize>()? > 0);
+
+        // `-x`や`-w`、`--trace`でも評価器の指標を集計する
+        for flag in ["-x", "-w", "--trace"] {
+            let (_, _, err) = run_with(&["--stats=json", flag, "b+"], input);
+            assert!(field(&err, "steps").parse::<usize>()? > 0, "{flag}");
+        }
+
+        // 読めないファイルとバイナリファイルは飛ばしたものとする
+        let dir = tempfile::tempdir()?;
+        let text = dir.path().join("a.txt");
+        std::fs::write(&text, input)?;
+        let binary = dir.path().join("b.bin");
+        std::fs::write(&binary, b"b\0\n")?;
+        let missing = dir.path().join("missing.txt");
+        let files = [&text, &binary, &missing].map(|path| path.to_str().unwrap());
+        for threads in ["--threads=1", "--threads=4"] {
+            let args = [
+                &[
+                    "--stats=json",
+                    "--binary-files=without-match",
+                    threads,
+                    "b+",
+                ],
+                &files[..],
+            ]
+            .concat();
+            let (code, _, err) = run_with(&args, "");
+            assert_eq!(code, EXIT_ERROR);
+            let json = err.lines().last().unwrap();
+            assert_eq!(field(json, "lines_scanned"), "3");
+            assert_eq!(field(json, "files_searched"), "1");
+            assert_eq!(field(json, "files_skipped"), "2");
+        }
+
+        // 人が読む形式
+        let (_, _, err) = run_with(&["--stats", "b+"], input);
+        assert!(err.starts_with("lines scanned: 3\nlines matched: 2\n"));
+        assert!(err.contains("\nelapsed: "));
+
+        let (code, _, err) = run_with(&["--stats=xml", "b+"], input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("invalid value for --stats"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json() -> Result<(), DynError> {
+        let input = "say \"hello\" hello\nnothing\n日本語 hello\ttab\n";
+        let (code, out, _) = run_with(&["--json", "hel+o"], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(
+            out,
+            concat!(
+                r#"{"path": "-", "line_number": 1, "line": "say \"hello\" hello", "spans": [[5, 10], [12, 17]]}"#,
+                "\n",
+                r#"{"path": "-", "line_number": 3, "line": "日本語 hello\ttab", "spans": [[10, 15]]}"#,
+                "\n",
+                r#"{"summary": {"files_searched": 1, "files_skipped": 0, "lines_scanned": 3, "lines_matched": 2}}"#,
+                "\n",
+            )
+        );
+
+        // 再帰的な検索や複数のファイル
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("sub"))?;
+        std::fs::write(dir.path().join("a.txt"), "x\nab\n")?;
+        std::fs::write(dir.path().join("sub/b.txt"), "b\n")?;
+        std::fs::write(dir.path().join("c.bin"), "b\0\n")?;
+        let path = |file: &str| dir.path().join(file).to_string_lossy().into_owned();
+        let (_, out, _) = run_with(&["--json", "-r", "b", dir.path().to_str().unwrap()], "");
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            format!(
+                r#"{{"path": "{}", "line_number": 2, "line": "ab", "spans": [[1, 2]]}}"#,
+                path("a.txt")
+            )
+        );
+        assert_eq!(
+            lines[1],
+            format!(r#"{{"path": "{}", "binary": true}}"#, path("c.bin"))
+        );
+        assert!(lines[2].starts_with(&format!(
+            r#"{{"path": "{}", "line_number": 1,"#,
+            path("sub/b.txt")
+        )));
+        assert!(lines[3].starts_with(r#"{"summary": {"files_searched": 3,"#));
+
+        // `-v`ではマッチした部分がない
+        let (_, out, _) = run_with(&["--json", "-v", "hel+o"], input);
+        assert!(
+            out.starts_with(r#"{"path": "-", "line_number": 2, "line": "nothing", "spans": []}"#)
+        );
+
+        // JSONでない出力とは合わせられない
+        for flag in ["-o", "--color=always", "-c", "-l", "-C1"] {
+            let (code, _, err) = run_with(&["--json", flag, "a"], "");
+            assert_eq!(code, EXIT_ERROR, "{flag}");
+            assert!(err.starts_with("--json cannot be used with "), "{flag}");
+        }
+        for flag in ["--color=never", "--color=auto"] {
+            let (code, out, _) = run_with(&["--json", flag, "a"], "a\n");
+            assert_eq!(code, EXIT_SELECTED, "{flag}");
+            assert!(!out.contains('\x1b'), "{flag}");
+        }
+        let (_, _, err) = run_with(&["--json", "--color=always", "a"], "");
+        assert!(err.starts_with("--json cannot be used with --color=always\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_groups() -> Result<(), DynError> {
+        let input = "a=1\nnothing\n=\tx\nb=2\\3\n";
+        let (code, out, _) = run_with(&["--groups", "(a|b)?=(.*)"], input);
+        assert_eq!(code, EXIT_SELECTED);
+        // マッチに加わらなかったグループは空文字列とし、タブと`\`はエスケープする
+        assert_eq!(out, "a\t1\n\t\\tx\nb\t2\\\\3\n");
+
+        let (_, out, _) = run_with(&["--groups-header", "-n", "(?<key>a|b)?=(.*)"], input);
+        assert_eq!(out, "line\tkey\t2\n1\ta\t1\n3\t\t\\tx\n4\tb\t2\\\\3\n");
+        // 名前付きグループがなければ列の名前を書き出さない
+        let (_, out, _) = run_with(&["--groups-header", "(a|b)?=(.*)"], "a=\n");
+        assert_eq!(out, "a\t\n");
+        // 複数のパターンのグループは、最初のパターンから順に番号を付ける
+        let (_, out, _) = run_with(&["--groups", "-e", "(a)=(.*)", "-e", "(b)=2"], input);
+        assert_eq!(out, "a\t1\t\n\t\tb\n");
+
+        // 複数のファイルではファイル名の列を付ける
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a.txt"), "x=1\nb=2\n")?;
+        std::fs::write(dir.path().join("b.txt"), "a=3\r\n")?;
+        let path = |file: &str| dir.path().join(file).to_string_lossy().into_owned();
+        let (_, out, _) = run_with(
+            &[
+                "--groups-header",
+                "-n",
+                "--crlf=keep",
+                "(?<key>a|b)=(?<value>.*)",
+                &path("a.txt"),
+                &path("b.txt"),
+            ],
+            "",
+        );
+        assert_eq!(
+            out,
+            format!(
+                "path\tline\tkey\tvalue\n{}\t2\tb\t2\n{}\t1\ta\t3\\r\n",
+                path("a.txt"),
+                path("b.txt")
+            )
+        );
+
+        // 1行に1つのマッチのグループを書き出す出力以外とは合わせられない
+        for flag in ["-v", "-o", "--json", "-U", "-c", "-L", "-A1"] {
+            let (code, _, err) = run_with(&["--groups", flag, "a"], "");
+            assert_eq!(code, EXIT_ERROR, "{flag}");
+            assert!(err.starts_with("--groups cannot be used with "), "{flag}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace() -> Result<(), DynError> {
+        // マッチが0個、1個、複数の行。マッチしない行はそのまま書き出す
+        let input = "none\nfoo12\nfoo1 foo22 foo\n";
+        let expr = "foo((1|2)+)";
+        let (code, out, _) = run_with(&["--replace", "bar$1", expr], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "none\nbar12\nbar1 bar22 foo\n");
+        let (_, out, _) = run_with(
+            &[
+                "--replace",
+                "[${1}]$$",
+                "--replace-only-matching-lines",
+                expr,
+            ],
+            input,
+        );
+        assert_eq!(out, "[12]$\n[1]$ [22]$ foo\n");
+        let (_, out, _) = run_with(&["--replace", "<$0>", "-n", "(?<d>1|2)"], input);
+        assert_eq!(out, "1:none\n2:foo<1><2>\n3:foo<1> foo<2><2> foo\n");
+        let (_, out, _) = run_with(&["--replace", "${d}${d}", "(?<d>1|2)"], input);
+        assert_eq!(out, "none\nfoo1122\nfoo11 foo2222 foo\n");
+        let (_, out, _) = run_with(&["--replace", "bar$1", "-e", expr, "-e", "none"], input);
+        assert_eq!(out, "bar\nbar12\nbar1 bar22 foo\n");
+
+        // マッチしなければ、行をそのまま書き出して失敗とする
+        let (code, out, _) = run_with(&["--replace", "x", "z"], input);
+        assert_eq!((code, out.as_str()), (EXIT_NOT_SELECTED, input));
+
+        // 複数のファイルでもファイル名を付けない
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a.txt"), "ab\nb\n")?;
+        std::fs::write(dir.path().join("b.txt"), "cab\n")?;
+        let path = |file: &str| dir.path().join(file).to_string_lossy().into_owned();
+        let (_, out, _) = run_with(
+            &["--replace", "X", "ab", &path("a.txt"), &path("b.txt")],
+            "",
+        );
+        assert_eq!(out, "X\nb\ncX\n");
+        let root = dir.path().to_string_lossy();
+        let (_, out, _) = run_with(&["-r", "--threads", "1", "--replace", "X", "ab", &root], "");
+        assert_eq!(out, "X\nb\ncX\n");
+
+        // 置換文字列の誤りは検索の前に知らせ、パターンの書き方は案内しない
+        let (code, out, err) = run_with(&["--replace", "$2", "(a)"], input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(out.is_empty());
+        assert_eq!(
+            err,
+            "error: while parsing the replacement\n  caused by: TemplateError: no such group: 2\n"
+        );
+
+        for flag in ["-v", "-o", "-w", "-x", "-U", "--json", "-c", "-l", "-A1"] {
+            let (code, _, err) = run_with(&["--replace", "x", flag, "a"], "");
+            assert_eq!(code, EXIT_ERROR, "{flag}");
+            assert!(err.starts_with("--replace cannot be used with "), "{flag}");
+        }
+        let (code, _, err) = run_with(&["--replace-only-matching-lines", "a"], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("--replace-only-matching-lines requires --replace\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_word_regexp() {
+        let input = "the cat sat\nconcatenate\ncat5\n_cat\ncat\ncat-like, cats\n";
+        let (code, out, _) = run_with(&["-w", "cat"], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "the cat sat\ncat\ncat-like, cats\n");
+        let (_, out, _) = run_with(&["-w", "-v", "cat"], input);
+        assert_eq!(out, "concatenate\ncat5\n_cat\n");
+        let (code, _, _) = run_with(&["-w", "cat"], "concatenate\n");
+        assert_eq!(code, EXIT_NOT_SELECTED);
+
+        // 単語全体にマッチする部分のみを書き出し、色付けする
+        let (_, out, _) = run_with(&["-w", "-o", "-n", "cats?"], input);
+        assert_eq!(out, "1:cat\n5:cat\n6:cat\n6:cats\n");
+        let (_, out, _) = run_with(&["-w", "--color=always", "cat"], "cat concat cat\n");
+        assert_eq!(
+            out,
+            format!("{COLOR_START}cat{COLOR_END} concat {COLOR_START}cat{COLOR_END}\n")
+        );
+
+        // ASCII以外の英数字も単語の文字とする
+        let (_, out, _) = run_with(&["-w", "cat"], "猫cat\nねこ cat\n");
+        assert_eq!(out, "ねこ cat\n");
+
+        // 優先されるマッチが単語の途中で終わっても、同じ位置から始まる他のマッチを試す
+        let (code, out, _) = run_with(&["-w", "foo|foobar"], "foobar\n");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "foobar\n");
+        let (_, out, _) = run_with(&["-w", "-o", "foo|foobar"], "foo foobar\n");
+        assert_eq!(out, "foo\nfoobar\n");
+        let (_, out, _) = run_with(&["-w", "-U", "-c", "foo|foobar"], "x\nfoobar\n");
+        assert_eq!(out, "1\n");
+        // 単語の途中から始まるマッチの後に始まるマッチも試す
+        let (_, out, _) = run_with(&["-w", "-o", "foo"], "xfoo foo\n");
+        assert_eq!(out, "foo\n");
+
+        // 評価中のエラーはマッチしなかったものとせず知らせる
+        let input = format!("{}c\n", "a".repeat(30));
+        let (code, _, err) = run_with(&["-w", "--step-limit=2000", "(a|aa)*b"], &input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.contains("step limit exceeded"), "{err}");
+    }
+
+    #[test]
+    fn test_line_regexp() {
+        let input = "abc\nabcd\nxabc\n\n";
+        let (code, out, _) = run_with(&["-x", "abc"], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "abc\n");
+        let (_, out, _) = run_with(&["-x", "-v", "abc"], input);
+        assert_eq!(out, "abcd\nxabc\n\n");
+        let (_, out, _) = run_with(&["-x", "-c", "abc|xabc"], input);
+        assert_eq!(out, "2\n");
+
+        // `^`や`$`を含むパターン、行全体にしかマッチしない候補がある選択
+        let (_, out, _) = run_with(&["--line-regexp", "^abc$"], input);
+        assert_eq!(out, "abc\n");
+        let (_, out, _) = run_with(&["-x", "-i", "ABC|ABCD"], input);
+        assert_eq!(out, "abc\nabcd\n");
+        // 空の行には空文字列にマッチするパターンのみが行全体にマッチする
+        let (_, out, _) = run_with(&["-x", "-n", "a*"], input);
+        assert_eq!(out, "4:\n");
+
+        // 途中のマッチではなく行全体を書き出し、色付けする
+        let (_, out, _) = run_with(&["-x", "-o", "ab|abcd"], input);
+        assert_eq!(out, "abcd\n");
+        let (_, out, _) = run_with(&["-x", "--color=always", "ab|abcd"], input);
+        assert_eq!(out, format!("{COLOR_START}abcd{COLOR_END}\n"));
+        let (_, out, _) = run_with(&["-x", "-v", "--color=always", "abc"], "abcd\n");
+        assert_eq!(out, "abcd\n");
+    }
+
+    #[test]
+    fn test_empty_pattern() {
+        // 空のパターンは空の行を含むすべての行を選ぶ
+        let input = "abc\n\nxyz\n";
+        let (code, out, _) = run_with(&["-n", ""], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "1:abc\n2:\n3:xyz\n");
+        let (code, out, _) = run_with(&["-v", ""], input);
+        assert_eq!((code, out.as_str()), (EXIT_NOT_SELECTED, ""));
+
+        // 空文字列へのマッチは`-o`では書き出さない
+        let (_, out, _) = run_with(&["-o", "()"], input);
+        assert!(out.is_empty());
+        let (_, out, _) = run_with(&["-c", "a()b|(|x)y"], input);
+        assert_eq!(out, "2\n");
+    }
+
+    #[test]
+    fn test_normalize() {
+        // NFDの行は、正規化しなければ合成済みの文字のパターンにマッチしない
+        let input = "cafe\u{301}\ncafé\n";
+        let (code, out, _) = run_with(&["-n", "é"], input);
+        assert_eq!((code, out.as_str()), (EXIT_SELECTED, "2:café\n"));
+
+        // 正規化すればどちらの行も選ぶが、書き出すのは元の行
+        let (_, out, _) = run_with(&["-n", "--normalize", "é"], input);
+        assert_eq!(out, "1:cafe\u{301}\n2:café\n");
+
+        // マッチの位置は元の行のバイト位置
+        let (_, out, _) = run_with(&["-ob", "--normalize", "fé"], input);
+        assert_eq!(out, "2:fe\u{301}\n9:fé\n");
+    }
+
+    #[test]
+    fn test_crlf() -> Result<(), DynError> {
+        let regex = Regex::new("abc$")?;
+        let input: &[u8] = b"abc\r\nxabc\nabc\r\r\nabc\r";
+
+        // 改行の前の`\r`を1つだけ除く。改行のない最後の行の`\r`は除かない
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            input,
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &Options::default(),
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, "abc\nxabc\n");
+
+        let (_, out, _) = run_with(&["-x", "-n", "abc"], "abc\r\nabc\n");
+        assert_eq!(out, "1:abc\n2:abc\n");
+
+        // `--crlf=keep`では`\r`も行の一部
+        let (_, out, _) = run_with(&["--crlf=keep", "abc$"], "abc\r\nxabc\n");
+        assert_eq!(out, "xabc\n");
+        let (_, out, _) = run_with(&["--crlf=keep", "-c", "c\r"], "abc\r\nxabc\n");
+        assert_eq!(out, "1\n");
+
+        let (code, _, err) = run_with(&["--crlf=drop", "a"], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("invalid value for --crlf"));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap() -> Result<(), DynError> {
+        // CRLFの行や不正なバイト列、改行のない最後の行を含む数MBのファイル
+        let mut content = Vec::new();
+        for i in 0..100_000 {
+            match i % 5 {
+                0 => content.extend_from_slice(format!("error {i}\n").as_bytes()),
+                1 => content.extend_from_slice(format!("warn {i}\r\n").as_bytes()),
+                2 => content.extend_from_slice(b"bad \xff error\n"),
+                3 => content.extend_from_slice("あいう 0123456789\n".as_bytes()),
+                _ => content.push(b'\n'),
+            }
+        }
+        content.extend_from_slice(b"error at end");
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("large.log");
+        std::fs::write(&file, &content)?;
+        let file = file.to_str().unwrap();
+
+        for args in [
+            &["-n", "error|9$"][..],
+            &["-c", "-v", "error"],
+            &["-o", "-n", "(1|2)+"],
+            &["-C", "1", "-m", "100", "warn"],
+            &["--json", "-x", "warn .*1"],
+            &["-l", "at end$"],
+        ] {
+            let streaming = run_with(&[args, &[file]].concat(), "");
+            let mapped = run_with(&[&["--mmap"], args, &[file]].concat(), "");
+            assert_eq!(mapped, streaming, "{args:?}");
+            assert_eq!(streaming.0, EXIT_SELECTED, "{args:?}");
+        }
+
+        // 通常のファイルでなければ`BufRead`から読む
+        let (_, out, _) = run_with(&["--mmap", "-c", "b"], "abc\nxyz\n");
+        assert_eq!(out, "1\n");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() -> Result<(), DynError> {
+        use flate2::{write::GzEncoder, Compression};
+
+        let gzip = |content: &[u8]| -> std::io::Result<Vec<u8>> {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content)?;
+            encoder.finish()
+        };
+
+        let content = "error 1\nok\r\nbad \u{FFFD} error\n\nあいう error\nwarn".repeat(1000);
+        let dir = tempfile::tempdir()?;
+        let plain = dir.path().join("app.log");
+        std::fs::write(&plain, &content)?;
+        let compressed = dir.path().join("app.log.gz");
+        std::fs::write(&compressed, gzip(content.as_bytes())?)?;
+        let (plain, compressed) = (plain.to_str().unwrap(), compressed.to_str().unwrap());
+
+        for args in [
+            &["-n", "error|n$"][..],
+            &["-c", "-v", "error"],
+            &["-o", "-b", "(r|o)+"],
+            &["-C", "1", "-m", "100", "warn"],
+            &["--json", "-x", "ok"],
+        ] {
+            let expected = run_with(&[args, &[plain]].concat(), "");
+            let (code, out, err) = run_with(&[args, &[compressed]].concat(), "");
+            // `--json`ではパスを書き出す
+            let actual = (code, out.replace(compressed, plain), err);
+            assert_eq!(actual, expected, "{args:?}");
+            assert_eq!(code, EXIT_SELECTED, "{args:?}");
+        }
+
+        // 連結したメンバーは順に展開し、ファイル名ではなく内容で判断する
+        let concatenated = dir.path().join("rotated");
+        std::fs::write(&concatenated, [gzip(b"a\n")?, gzip(b"b\n")?].concat())?;
+        let concatenated = concatenated.to_str().unwrap();
+        let (_, out, _) = run_with(&["-n", "a|b", concatenated], "");
+        assert_eq!(out, "1:a\n2:b\n");
+
+        // 展開に失敗したファイルはパスとともに報告し、他のファイルの検索を続ける
+        let broken = dir.path().join("broken.gz");
+        let mut data = gzip(b"error\n".repeat(100).as_slice())?;
+        data.truncate(data.len() / 2);
+        std::fs::write(&broken, data)?;
+        let broken = broken.to_str().unwrap();
+        let (code, out, err) = run_with(&["-c", "error", broken, plain], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert!(out.ends_with(&format!("{plain}:3000\n")), "{out}");
+        assert!(err.starts_with(&format!("{broken}: ")), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiline() -> Result<(), DynError> {
+        let input = "fn foo(a,\n  b) {\n}\r\nfn bar() {\n";
+
+        // 行を跨ぐマッチは、その全ての行を1度ずつ選ぶ
+        let (code, out, _) = run_with(&["-U", "-n", "foo\\(a,\n *b"], input);
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "1:fn foo(a,\n2:  b) {\n");
+        let (_, out, _) = run_with(&["-U", "-c", "(a|b)(,|\\))"], input);
+        assert_eq!(out, "2\n");
+
+        // `^`と`$`は行の境界で成り立ち、`.`は`--multiline-dotall`の場合のみ改行にマッチする
+        let (_, out, _) = run_with(&["-U", "-n", "^}$"], input);
+        assert_eq!(out, "3:}\n");
+        let (code, _, _) = run_with(&["-U", "foo.*{"], input);
+        assert_eq!(code, EXIT_NOT_SELECTED);
+        let (_, out, _) = run_with(&["-U", "--multiline-dotall", "-c", "foo.*{"], input);
+        assert_eq!(out, "4\n");
+        let (_, out, _) = run_with(&["--multiline-dotall", "-c", "foo.*{"], input);
+        assert_eq!(out, "0\n");
+
+        let (_, out, _) = run_with(&["-U", "-v", "-n", "a,\n"], input);
+        assert_eq!(out, "2:  b) {\n3:}\n4:fn bar() {\n");
+        let (_, out, _) = run_with(&["-U", "-n", "-A", "1", "}\n"], input);
+        assert_eq!(out, "3:}\n4-fn bar() {\n");
+        let (_, out, _) = run_with(&["-U", "-x", "-c", "}\nfn"], input);
+        assert_eq!(out, "0\n");
+        let (_, out, _) = run_with(&["-U", "-x", "-c", "}\nfn bar\\(\\) {"], input);
+        assert_eq!(out, "2\n");
+        // 行の先頭で優先されるマッチが行末で終わらなくても、行末まで続くマッチを探す
+        let (code, out, _) = run_with(&["-U", "-x", "foo|foobar"], "foobar\n");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "foobar\n");
+
+        // 評価中のエラーは、行ごとの評価と同様に知らせる
+        let pathological = format!("{}c\nzzb\n", "a".repeat(30));
+        for flag in ["-n", "-U"] {
+            let (code, _, err) = run_with(&[flag, "--step-limit=2000", "(a|aa)*b"], &pathological);
+            assert_eq!(code, EXIT_ERROR, "{flag}");
+            assert!(err.contains("step limit exceeded"), "{flag}: {err}");
+        }
+
+        // 上限より大きいファイルは評価しない
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("a.rs");
+        std::fs::write(&file, input)?;
+        let file = file.to_str().unwrap();
+        let (code, _, err) = run_with(&["-U", "--max-filesize=10", "fn", file], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.contains("file is larger than 10 bytes"));
+        let (code, _, _) = run_with(&["-U", "--max-filesize=100", "fn", file], "");
+        assert_eq!(code, EXIT_SELECTED);
+
+        let (code, _, err) = run_with(&["-U", "-o", "a"], input);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("-o cannot be used with -U"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_count() -> Result<(), DynError> {
+        let regex = Regex::new("a")?;
+        let input = b"abc\nxyz\ncba\nb\n";
+
+        let args = ["-c", "a"].map(String::from);
+        let (mut options, _) = parse_args(&args)?;
+        assert!(options.count);
+
+        // 行は書き出さない
+        let mut out = Vec::new();
+        assert_eq!(
+            match_file(
+                &regex,
+                Cursor::new(input),
+                None,
+                &mut out,
+                &mut std::io::sink(),
+                &options,
+                &mut Stats::default()
+            )?,
+            2
+        );
+        assert_eq!(out, b"2\n");
+
+        options.invert = true;
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new(input),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(out, b"2\n");
+
+        let mut out = Vec::new();
+        match_file(
+            &regex,
+            Cursor::new(b"xyz"),
+            None,
+            &mut out,
+            &mut std::io::sink(),
+            &options,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(out, b"1\n");
+
+        // 複数のファイル
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        std::fs::write(&a, input)?;
+        std::fs::write(&b, "xyz\n")?;
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        options.invert = false;
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let stdin = &mut std::io::empty();
+        search_files(
+            &regex,
+            &[a, b],
+            &options,
+            stdin,
+            &mut out,
+            &mut err,
+            &mut Stats::default(),
+        )?;
+        assert_eq!(String::from_utf8(out)?, format!("{a}:2\n{b}:0\n"));
+        assert!(err.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_files() -> Result<(), DynError> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        std::fs::write(&a, "foo\nbar\n")?;
+        std::fs::write(&b, "baz\nqux\n")?;
+        let missing = dir.path().join("missing.txt");
+        let (a, b, missing) = (
+            a.to_str().unwrap(),
+            b.to_str().unwrap(),
+            missing.to_str().unwrap(),
+        );
+        let regex = Regex::new("ba")?;
+
+        // 1つのファイルであれば名前を付けない
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let outcome = search_files(
+            &regex,
+            &[a],
+            &Options::default(),
+            &mut std::io::empty(),
+            &mut out,
+            &mut err,
+            &mut Stats::default(),
+        )?;
+        assert!(outcome.selected && !outcome.failed);
+        assert_eq!(String::from_utf8(out)?, "bar\n");
+
+        // 開けないファイルは飛ばして残りのファイルを検索する
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let outcome = search_files(
+            &regex,
+            &[a, missing, b],
+            &Options::default(),
+            &mut std::io::empty(),
+            &mut out,
+            &mut err,
+            &mut Stats::default(),
+        )?;
+        assert!(outcome.failed);
+        assert_eq!(String::from_utf8(out)?, format!("{a}:bar\n{b}:baz\n"));
+        assert!(String::from_utf8(err)?.starts_with(&format!("{missing}: ")));
+
+        Ok(())
+    }
+
+    /// `run`を`args`と標準入力`stdin`で実行し、終了コードと標準出力、標準エラー出力を返す
+    fn run_with(args: &[&str], stdin: &str) -> (u8, String, String) {
+        let args = std::iter::once("ch06_regex")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&args, Cursor::new(stdin), &mut out, &mut err);
+        (
+            code,
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(err).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_debug() {
+        // 標準出力にはマッチした行のみを書き出す
+        let (code, out, err) = run_with(&["b+"], "abc\nxyz\nbb\n");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "abc\nbb\n");
+        assert!(err.is_empty());
+
+        let (code, out, err) = run_with(&["--debug", "b+"], "abc\nxyz\nbb\n");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "abc\nbb\n");
+        assert!(err.starts_with("expr: b+\nAST:\n"));
+        assert!(err.contains("    min match length: 1\n"));
+    }
+
+    #[test]
+    fn test_exit_code() -> Result<(), DynError> {
+        // マッチした行があれば0、なければ1でエラーメッセージは出さない
+        let (code, out, _) = run_with(&["b+"], "abc\nxyz\n");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "abc\n");
+        let (code, out, err) = run_with(&["q"], "abc\nxyz\n");
+        assert_eq!(code, EXIT_NOT_SELECTED);
+        assert!(out.is_empty() && err.is_empty());
+        let (code, _, _) = run_with(&["-v", "a|x"], "abc\nxyz\n");
+        assert_eq!(code, EXIT_NOT_SELECTED);
+
+        // 引数やパターンの誤りは2
+        let (code, _, err) = run_with(&[], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("usage: "));
+        let (code, _, err) = run_with(&["--engine=dfa", "a"], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("invalid value for --engine"));
+        let (code, _, err) = run_with(&["(a"], "a\n");
+        assert_eq!(code, EXIT_ERROR);
+        // 付け加えた説明から順に、1行ずつ書き出す
+        assert_eq!(
+            err,
+            "error: while compiling the pattern\n  caused by: ParseError: no right parenthesis\n"
+        );
+
+        // 読めないファイルがあれば、他のファイルでマッチしても2
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.txt");
+        std::fs::write(&a, "abc\n")?;
+        let missing = dir.path().join("missing.txt");
+        let (code, out, _) = run_with(&["a", a.to_str().unwrap(), missing.to_str().unwrap()], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert_eq!(out, format!("{}:abc\n", a.display()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bench() {
+        // 全てのエンジンの行を書き出し、マッチした行数が一致すれば0で終わる
+        let (code, out, err) = run_with(
+            &["--bench", "--bench-iters=1", "a(b|c)+"],
+            "abc\nxyz\nacb\n",
+        );
+        assert_eq!(code, EXIT_SELECTED, "{err}");
+        let rows = out.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 1 + Engine::ALL.len());
+        for (row, name) in rows[1..].iter().zip(["depth", "width", "bitstate"]) {
+            assert!(row.starts_with(name), "{row}");
+            assert!(row.ends_with(" 2"), "{row}");
+        }
+
+        let (code, _, err) = run_with(&["--bench", "a", "x.txt", "y.txt"], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("error: --bench requires exactly one file"));
+        let (code, _, _) = run_with(&["--bench", "--bench-iters=0", "a"], "");
+        assert_eq!(code, EXIT_ERROR);
+    }
+
+    #[test]
+    fn test_help_version() {
+        // 使い方とバージョンは標準出力に書き出し、検索せずに0で終わる
+        let (code, out, err) = run_with(&["--help"], "abc\n");
+        assert_eq!(code, EXIT_SELECTED);
+        assert!(out.starts_with("usage: ch06_regex [OPTION...] regex [file...]\n"));
+        assert!(out.contains("  -i, --ignore-case  "));
+        assert!(err.is_empty());
+        let (code, out, _) = run_with(&["-V"], "");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, format!("ch06_regex {}\n", env!("CARGO_PKG_VERSION")));
 
-        let mut n: usize = 10;
-        assert!(safe_add(&mut n, &20, || ()).is_ok());
+        // パターンとしての`--help`
+        let (code, out, _) = run_with(&["-e", "--help"], "a --help\n");
+        assert_eq!(code, EXIT_SELECTED);
+        assert_eq!(out, "a --help\n");
+        let (_, out, _) = run_with(&["-c", "--", "--help"], "--help\n");
+        assert_eq!(out, "1\n");
 
-        let mut n: usize = !0;
-        assert!(safe_add(&mut n, &1, || ()).is_err());
+        let (code, _, err) = run_with(&["-nk", "a"], "");
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("unknown option: -k (in -nk)\nusage: "));
     }
 }
@@ -0,0 +1,126 @@
+//! 複数のファイルを並列に検索するためのスレッドプール。
+//!
+//! 各要素の処理は並列に行うが、結果は元の順に受け取るので、出力は逐次に処理した場合と変わらない。
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+/// `items`の各要素に`f`を最大`threads`個のスレッドで並列に適用し、結果を`items`の順に`consume`に渡す。
+/// `consume`が`false`を返すと、まだ始めていない要素は処理せずに終える。
+///
+/// 先の要素の処理が終わるまで、後の要素の結果は保持しておく。
+pub fn ordered_map<T, R>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(&T) -> R + Sync,
+    mut consume: impl FnMut(R) -> bool,
+) where
+    T: Sync,
+    R: Send,
+{
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, items.len().max(1)) {
+            let tx = tx.clone();
+            let (next, stop, f) = (&next, &stop, &f);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    // 受け取る側が終えていれば、それ以上処理しない
+                    if tx.send((i, f(item))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let mut pending = HashMap::new();
+        let mut expected = 0;
+        for (i, result) in rx {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&expected) {
+                expected += 1;
+                if !consume(result) {
+                    stop.store(true, Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ordered_map() {
+        // 後の要素ほど早く終わっても、結果は元の順に受け取る
+        let items = (0..20).collect::<Vec<u64>>();
+        let mut results = Vec::new();
+        ordered_map(
+            &items,
+            4,
+            |n| {
+                thread::sleep(Duration::from_millis(20 - n));
+                n * n
+            },
+            |n| {
+                results.push(n);
+                true
+            },
+        );
+        assert_eq!(results, items.iter().map(|n| n * n).collect::<Vec<_>>());
+
+        // 空の入力や1つのスレッド
+        ordered_map(&[] as &[u64], 4, |n| *n, |_| panic!("no items"));
+        let mut results = Vec::new();
+        ordered_map(
+            &items,
+            1,
+            |n| *n,
+            |n| {
+                results.push(n);
+                true
+            },
+        );
+        assert_eq!(results, items);
+    }
+
+    #[test]
+    fn test_ordered_map_stop() {
+        let items = (0..1000).collect::<Vec<usize>>();
+        let processed = AtomicUsize::new(0);
+        let mut results = Vec::new();
+        ordered_map(
+            &items,
+            4,
+            |n| {
+                processed.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(1));
+                *n
+            },
+            |n| {
+                results.push(n);
+                n < 10
+            },
+        );
+        assert_eq!(results, (0..=10).collect::<Vec<_>>());
+        // 途中でやめたので、全ての要素は処理しない
+        assert!(processed.load(Ordering::Relaxed) < items.len());
+    }
+}
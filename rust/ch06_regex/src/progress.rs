@@ -0,0 +1,173 @@
+//! `--progress`で標準エラー出力に書き出す、再帰的な検索の進み具合。
+//!
+//! 検索するファイルを見つけるたびに`discover`を、1つのファイルを検索し終えるたびに`finish_file`を呼ぶ。
+//! 前回の表示から`REPORT_EVERY`個のファイルを検索したか、`REPORT_INTERVAL`以上経つと、
+//! 検索したファイル数と見つけたファイル数、マッチした行数、最後に扱ったファイルを1行で書き出す。
+//!
+//! 端末には`\r`で同じ行を書き換え、検索結果を書き出す前と検索の終わりに`clear`で消す。
+//! 端末でなければ、表示ごとに1行を書き出し、消さない。
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+/// 表示の間に検索するファイル数の上限
+pub const REPORT_EVERY: usize = 100;
+/// 表示の間隔の上限
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 行を消すエスケープシーケンス。行頭に戻り、行末までを消す。
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+/// 検索の進み具合。`clock`で現在時刻を得る。
+pub struct Progress<C> {
+    clock: C,
+    /// `\r`で行を書き換えるか
+    terminal: bool,
+    discovered: usize,
+    searched: usize,
+    matches: usize,
+    /// 前回表示した時刻と、その後に検索したファイル数
+    last: Instant,
+    pending: usize,
+    /// 端末に行を表示しているか
+    shown: bool,
+}
+
+impl<C: FnMut() -> Instant> Progress<C> {
+    pub fn new(mut clock: C, terminal: bool) -> Self {
+        let last = clock();
+        Progress {
+            clock,
+            terminal,
+            discovered: 0,
+            searched: 0,
+            matches: 0,
+            last,
+            pending: 0,
+            shown: false,
+        }
+    }
+
+    /// 検索するファイル`path`を見つけたことを記録する。前回の表示から時間が経っていれば表示する。
+    pub fn discover(&mut self, path: &str, err: &mut impl Write) -> io::Result<()> {
+        self.discovered += 1;
+        self.report_if_due(path, err)
+    }
+
+    /// ファイル`path`を検索し終え、`matches`行がマッチしたことを記録する。
+    /// 前回の表示から十分な数のファイルを検索したか、時間が経っていれば表示する。
+    pub fn finish_file(
+        &mut self,
+        path: &str,
+        matches: usize,
+        err: &mut impl Write,
+    ) -> io::Result<()> {
+        self.searched += 1;
+        self.matches += matches;
+        self.pending += 1;
+        self.report_if_due(path, err)
+    }
+
+    fn report_if_due(&mut self, path: &str, err: &mut impl Write) -> io::Result<()> {
+        let now = (self.clock)();
+        if self.pending < REPORT_EVERY && now.duration_since(self.last) < REPORT_INTERVAL {
+            return Ok(());
+        }
+        self.last = now;
+        self.pending = 0;
+
+        let line = format!(
+            "{}/{} files, {} matches: {path}",
+            self.searched, self.discovered, self.matches
+        );
+        if self.terminal {
+            write!(err, "{CLEAR_LINE}{line}")?;
+            self.shown = true;
+        } else {
+            writeln!(err, "{line}")?;
+        }
+        err.flush()
+    }
+
+    /// 端末に表示している行を消す。検索結果を書き出す前と、検索の終わりに呼ぶ。
+    pub fn clear(&mut self, err: &mut impl Write) -> io::Result<()> {
+        if self.shown {
+            self.shown = false;
+            write!(err, "{CLEAR_LINE}")?;
+            err.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    /// 手動で進める時計
+    fn clock() -> (Rc<Cell<Instant>>, impl FnMut() -> Instant) {
+        let now = Rc::new(Cell::new(Instant::now()));
+        let clock = {
+            let now = Rc::clone(&now);
+            move || now.get()
+        };
+        (now, clock)
+    }
+
+    #[test]
+    fn test_progress_terminal() -> io::Result<()> {
+        let (now, clock) = clock();
+        let mut progress = Progress::new(clock, true);
+        let mut err = Vec::new();
+
+        // 時間が経つまで表示しない
+        progress.discover("a", &mut err)?;
+        progress.discover("b", &mut err)?;
+        progress.finish_file("a", 2, &mut err)?;
+        assert!(err.is_empty());
+        now.set(now.get() + REPORT_INTERVAL);
+        progress.finish_file("b", 1, &mut err)?;
+        assert_eq!(err, b"\r\x1b[K2/2 files, 3 matches: b");
+
+        // 表示した行のみを消す
+        err.clear();
+        progress.clear(&mut err)?;
+        progress.clear(&mut err)?;
+        assert_eq!(err, b"\r\x1b[K");
+
+        // 一定数のファイルを検索すると、時間が経っていなくても表示する
+        err.clear();
+        for i in 0..REPORT_EVERY {
+            progress.finish_file(&i.to_string(), 0, &mut err)?;
+        }
+        assert_eq!(
+            String::from_utf8(err).unwrap(),
+            format!("\r\x1b[K{}/2 files, 3 matches: 99", REPORT_EVERY + 2)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_not_terminal() -> io::Result<()> {
+        let (now, clock) = clock();
+        let mut progress = Progress::new(clock, false);
+        let mut err = Vec::new();
+
+        now.set(now.get() + REPORT_INTERVAL);
+        progress.discover("dir/a.txt", &mut err)?;
+        now.set(now.get() + REPORT_INTERVAL * 2);
+        progress.finish_file("dir/a.txt", 5, &mut err)?;
+        progress.clear(&mut err)?;
+        assert_eq!(
+            String::from_utf8(err).unwrap(),
+            "0/1 files, 0 matches: dir/a.txt\n1/1 files, 5 matches: dir/a.txt\n"
+        );
+
+        Ok(())
+    }
+}
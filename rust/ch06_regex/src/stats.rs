@@ -0,0 +1,180 @@
+//! `--stats`で標準エラー出力に書き出す、検索の統計。
+//!
+//! 各ファイルの検索で数えた値を`Stats::add`で合計し、検索の終わりに`Stats::write`で書き出す。
+
+use std::{io::Write, str::FromStr, time::Duration};
+
+use ch06_regex::MatchMetrics;
+
+/// `--stats`の値
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// 1行に1項目ずつ、人が読む形式
+    #[default]
+    Text,
+    /// 1行のJSONオブジェクト
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(StatsFormat::Text),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(format!(
+                "invalid value for --stats: {s} (expected text or json)"
+            )),
+        }
+    }
+}
+
+/// 検索の統計
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// 読んだ行数
+    pub lines_scanned: usize,
+    /// パターンにマッチした行数。`-v`でも、選んだ行ではなくマッチした行を数える。
+    pub lines_matched: usize,
+    /// 検索したファイル数
+    pub files_searched: usize,
+    /// 読めなかったファイルと、マッチしないものとしたバイナリファイルの数
+    pub files_skipped: usize,
+    /// 読んだバイト数
+    pub bytes_read: usize,
+    /// 評価器が実行した命令数
+    pub steps: usize,
+    /// 深さ優先の評価で、積んでおいた分岐に戻った回数
+    pub backtracks: usize,
+    /// 1行の評価で、後で試すために積んでおいた分岐やスレッドの数の最大値
+    pub peak_pending: usize,
+    /// 訪問済みの状態をビットマップに記録して評価した行数
+    pub lines_memoized: usize,
+    /// 1文字目になりうる文字で絞り込んだ行数
+    pub lines_prefiltered: usize,
+}
+
+impl Stats {
+    /// 別に数えた`other`を加える
+    pub fn add(&mut self, other: &Stats) {
+        self.lines_scanned += other.lines_scanned;
+        self.lines_matched += other.lines_matched;
+        self.files_searched += other.files_searched;
+        self.files_skipped += other.files_skipped;
+        self.bytes_read += other.bytes_read;
+        self.steps += other.steps;
+        self.backtracks += other.backtracks;
+        self.peak_pending = self.peak_pending.max(other.peak_pending);
+        self.lines_memoized += other.lines_memoized;
+        self.lines_prefiltered += other.lines_prefiltered;
+    }
+
+    /// 1行の評価で集計した`metrics`を加える
+    pub fn record(&mut self, metrics: &MatchMetrics) {
+        self.steps += metrics.instructions;
+        self.backtracks += metrics.backtracks;
+        self.peak_pending = self.peak_pending.max(metrics.peak_pending);
+        self.lines_memoized += usize::from(metrics.memoized);
+        self.lines_prefiltered += usize::from(metrics.prefiltered);
+    }
+
+    /// 検索にかかった時間`elapsed`とともに、`format`の形式で`out`に書き出す
+    pub fn write(
+        &self,
+        out: &mut impl Write,
+        format: StatsFormat,
+        elapsed: Duration,
+    ) -> std::io::Result<()> {
+        match format {
+            StatsFormat::Text => {
+                writeln!(out, "lines scanned: {}", self.lines_scanned)?;
+                writeln!(out, "lines matched: {}", self.lines_matched)?;
+                writeln!(out, "files searched: {}", self.files_searched)?;
+                writeln!(out, "files skipped: {}", self.files_skipped)?;
+                writeln!(out, "bytes read: {}", self.bytes_read)?;
+                writeln!(out, "elapsed: {:.6}s", elapsed.as_secs_f64())?;
+                writeln!(out, "instructions executed: {}", self.steps)?;
+                writeln!(out, "backtracks: {}", self.backtracks)?;
+                writeln!(out, "peak pending: {}", self.peak_pending)?;
+                writeln!(out, "lines memoized: {}", self.lines_memoized)?;
+                writeln!(out, "lines prefiltered: {}", self.lines_prefiltered)
+            }
+            StatsFormat::Json => writeln!(
+                out,
+                "{{\"lines_scanned\":{},\"lines_matched\":{},\"files_searched\":{},\
+                 \"files_skipped\":{},\"bytes_read\":{},\"elapsed_secs\":{:.6},\"steps\":{},\
+                 \"backtracks\":{},\"peak_pending\":{},\"lines_memoized\":{},\
+                 \"lines_prefiltered\":{}}}",
+                self.lines_scanned,
+                self.lines_matched,
+                self.files_searched,
+                self.files_skipped,
+                self.bytes_read,
+                elapsed.as_secs_f64(),
+                self.steps,
+                self.backtracks,
+                self.peak_pending,
+                self.lines_memoized,
+                self.lines_prefiltered
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() -> std::io::Result<()> {
+        let mut stats = Stats {
+            lines_scanned: 3,
+            lines_matched: 1,
+            files_searched: 1,
+            files_skipped: 0,
+            bytes_read: 12,
+            steps: 40,
+            backtracks: 5,
+            peak_pending: 3,
+            lines_memoized: 0,
+            lines_prefiltered: 1,
+        };
+        let mut other = Stats {
+            lines_scanned: 2,
+            lines_matched: 2,
+            files_skipped: 1,
+            bytes_read: 8,
+            ..Stats::default()
+        };
+        other.record(&MatchMetrics {
+            instructions: 2,
+            backtracks: 1,
+            peak_pending: 2,
+            memoized: true,
+            prefiltered: false,
+        });
+        stats.add(&other);
+        let elapsed = Duration::from_millis(1500);
+
+        let mut out = Vec::new();
+        stats.write(&mut out, StatsFormat::Text, elapsed)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "lines scanned: 5\nlines matched: 3\nfiles searched: 1\nfiles skipped: 1\n\
+             bytes read: 20\nelapsed: 1.500000s\ninstructions executed: 42\nbacktracks: 6\n\
+             peak pending: 3\nlines memoized: 1\nlines prefiltered: 1\n"
+        );
+
+        let mut out = Vec::new();
+        stats.write(&mut out, StatsFormat::Json, elapsed)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"lines_scanned\":5,\"lines_matched\":3,\"files_searched\":1,\"files_skipped\":1,\
+             \"bytes_read\":20,\"elapsed_secs\":1.500000,\"steps\":42,\"backtracks\":6,\
+             \"peak_pending\":3,\"lines_memoized\":1,\"lines_prefiltered\":1}\n"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+//! `--groups`で書き出すTSVの行。
+//!
+//! 各フィールドはタブで区切り、行は改行で終える。
+//! フィールド中の`\`、タブ、改行、`\r`はそれぞれ`\\`、`\t`、`\n`、`\r`の2文字に置き換えるので、
+//! フィールドの区切りと行の終わりは常にタブと改行のみとなる。
+
+use std::io::Write;
+
+/// `fields`をタブで区切った1行として書き出す
+pub fn write_row<'a>(
+    out: &mut impl Write,
+    fields: impl IntoIterator<Item = &'a str>,
+) -> std::io::Result<()> {
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            write!(out, "\t")?;
+        }
+        write_field(out, field)?;
+    }
+    writeln!(out)
+}
+
+/// `s`を1つのフィールドとして、`\`とタブ、改行、`\r`をエスケープして書き出す
+fn write_field(out: &mut impl Write, s: &str) -> std::io::Result<()> {
+    let mut last = 0;
+    for (i, c) in s.char_indices() {
+        let escaped = match c {
+            '\\' => "\\\\",
+            '\t' => "\\t",
+            '\n' => "\\n",
+            '\r' => "\\r",
+            _ => continue,
+        };
+        write!(out, "{}{escaped}", &s[last..i])?;
+        last = i + c.len_utf8();
+    }
+    write!(out, "{}", &s[last..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_tsv(fields: &[&str]) -> String {
+        let mut out = Vec::new();
+        write_row(&mut out, fields.iter().copied()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_row() {
+        assert_eq!(to_tsv(&[]), "\n");
+        assert_eq!(to_tsv(&["a", "", "bc"]), "a\t\tbc\n");
+        assert_eq!(to_tsv(&["a\tb", "c\r\nd"]), "a\\tb\tc\\r\\nd\n");
+        assert_eq!(to_tsv(&[r"C:\dir"]), "C:\\\\dir\n");
+        // ASCII以外の文字はそのまま
+        assert_eq!(to_tsv(&["日本語", "é"]), "日本語\té\n");
+    }
+}
@@ -0,0 +1,352 @@
+//! `-r`で指定したディレクトリを辿り、検索する通常のファイルを列挙する。
+//!
+//! ディレクトリは深さ優先で、各ディレクトリの中は名前順に辿る。
+//! シンボリックリンクは辿るが、既に辿ったディレクトリには再び入らないので、リンクが循環していても終わる。
+//! `Filter`を指定すると、辿る途中で見つけたファイルとディレクトリを名前で絞り込む。
+//! `.gitignore`を考慮する場合は、辿ったディレクトリにある`.gitignore`の規則で無視するパスを飛ばす。
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{glob::Glob, ignore::Gitignore};
+
+/// 辿れなかったパスとその原因
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl Display for WalkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+impl Error for WalkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// 名前による絞り込み。`root`自身には適用しない。
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    /// 空でなければ、いずれかにマッチする名前のファイルのみを返す
+    pub include: Vec<Glob>,
+    /// いずれかにマッチする名前のファイルは返さない。`include`より優先する。
+    pub exclude: Vec<Glob>,
+    /// いずれかにマッチする名前のディレクトリには入らない
+    pub exclude_dir: Vec<Glob>,
+}
+
+impl Filter {
+    fn accepts_file(&self, name: &str) -> bool {
+        !self.exclude.iter().any(|glob| glob.is_match(name))
+            && (self.include.is_empty() || self.include.iter().any(|glob| glob.is_match(name)))
+    }
+
+    fn accepts_dir(&self, name: &str) -> bool {
+        !self.exclude_dir.iter().any(|glob| glob.is_match(name))
+    }
+}
+
+/// パスの最後の要素の名前。`..`などで終わる場合は空文字列とする。
+fn file_name(path: &Path) -> std::borrow::Cow<'_, str> {
+    path.file_name().unwrap_or_default().to_string_lossy()
+}
+
+/// 辿る途中のパスと、そのパスに適用する`.gitignore`。`.gitignore`は浅いディレクトリのものから並べる。
+struct Entry {
+    path: PathBuf,
+    ignores: Rc<[Gitignore]>,
+}
+
+/// `ignores`のうち、`path`にマッチする規則を持つ最も深いディレクトリの`.gitignore`に従って、無視するかを返す
+fn is_ignored(ignores: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    ignores
+        .iter()
+        .rev()
+        .find_map(|gitignore| gitignore.matched(path, is_dir))
+        .unwrap_or(false)
+}
+
+/// `root`以下の通常のファイルを順に返すイテレータ。`root`が通常のファイルなら`root`のみを返す。
+/// 辿れなかったパスは`Err`として返し、残りを辿り続ける。
+pub struct Walk {
+    root: PathBuf,
+    /// これから辿るパス。末尾から取り出す。
+    stack: Vec<Entry>,
+    /// 辿ったディレクトリの正規化したパス
+    visited: HashSet<PathBuf>,
+    filter: Filter,
+    /// 辿ったディレクトリの`.gitignore`を読むか
+    respect_gitignore: bool,
+}
+
+impl Walk {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        Walk {
+            stack: vec![Entry {
+                path: root.clone(),
+                ignores: Rc::new([]),
+            }],
+            root,
+            visited: HashSet::new(),
+            filter: Filter::default(),
+            respect_gitignore: false,
+        }
+    }
+
+    /// 辿る途中で見つけたファイルとディレクトリを`filter`で絞り込む
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// `respect`であれば、辿ったディレクトリの`.gitignore`の規則にマッチするファイルとディレクトリを飛ばす。
+    /// `root`より上のディレクトリの`.gitignore`は読まず、`root`自身は飛ばさない。
+    pub fn with_gitignore(mut self, respect: bool) -> Self {
+        self.respect_gitignore = respect;
+        self
+    }
+
+    /// ディレクトリ`dir`の中身を、名前順に取り出されるよう`stack`に積む。
+    /// `dir`に`.gitignore`があれば、中身には`ignores`に加えてその規則を適用する。
+    fn push_children(&mut self, dir: &Path, ignores: Rc<[Gitignore]>) -> io::Result<()> {
+        let ignores = match self.read_gitignore(dir)? {
+            Some(gitignore) => ignores.iter().cloned().chain([gitignore]).collect(),
+            None => ignores,
+        };
+        let mut children = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        children.sort();
+        self.stack
+            .extend(children.into_iter().rev().map(|path| Entry {
+                path,
+                ignores: Rc::clone(&ignores),
+            }));
+        Ok(())
+    }
+
+    /// `.gitignore`を考慮する場合に、`dir`にある`.gitignore`を読む。なければ`None`を返す。
+    fn read_gitignore(&self, dir: &Path) -> io::Result<Option<Gitignore>> {
+        if !self.respect_gitignore {
+            return Ok(None);
+        }
+        match fs::read(dir.join(".gitignore")) {
+            Ok(content) => Ok(Some(Gitignore::new(
+                dir,
+                &String::from_utf8_lossy(&content),
+            ))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<PathBuf, WalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Entry { path, ignores }) = self.stack.pop() {
+            // シンボリックリンクはリンク先の種類で判断する
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(error) => return Some(Err(WalkError { path, error })),
+            };
+
+            let is_root = path == self.root;
+            if !is_root && is_ignored(&ignores, &path, metadata.is_dir()) {
+                continue;
+            }
+            if metadata.is_dir() {
+                if !is_root && !self.filter.accepts_dir(&file_name(&path)) {
+                    continue;
+                }
+                let result = fs::canonicalize(&path).and_then(|real| {
+                    if self.visited.insert(real) {
+                        self.push_children(&path, ignores)
+                    } else {
+                        Ok(())
+                    }
+                });
+                if let Err(error) = result {
+                    return Some(Err(WalkError { path, error }));
+                }
+            } else if metadata.is_file() && (is_root || self.filter.accepts_file(&file_name(&path)))
+            {
+                return Some(Ok(path));
+            }
+            // デバイスファイルなどは検索しない
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("b/d"))?;
+        fs::create_dir(root.join("c"))?;
+        for file in ["a.txt", "b/d/x.txt", "b/y.txt", "z.txt"] {
+            fs::write(root.join(file), "")?;
+        }
+
+        // 深さ優先で、名前順に辿る。空のディレクトリは何も返さない
+        let files = Walk::new(root)
+            .map(|entry| entry.unwrap().strip_prefix(root).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            ["a.txt", "b/d/x.txt", "b/y.txt", "z.txt"].map(PathBuf::from)
+        );
+
+        // ファイルはそのファイルのみ
+        let file = root.join("a.txt");
+        assert_eq!(
+            Walk::new(&file).map(Result::unwrap).collect::<Vec<_>>(),
+            [file]
+        );
+
+        // 存在しないパス
+        let missing = root.join("missing");
+        let errors = Walk::new(&missing).collect::<Vec<_>>();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].as_ref().unwrap_err().path, missing);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_filter() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/target"))?;
+        fs::create_dir_all(root.join("target"))?;
+        for file in [
+            "a.rs",
+            "b.js",
+            "b.min.js",
+            "src/c.rs",
+            "src/target/d.rs",
+            "target/e.rs",
+        ] {
+            fs::write(root.join(file), "")?;
+        }
+        let walk = |filter: Filter| {
+            Walk::new(root)
+                .with_filter(filter)
+                .map(|entry| entry.unwrap().strip_prefix(root).unwrap().to_path_buf())
+                .collect::<Vec<_>>()
+        };
+
+        let filter = Filter {
+            include: vec![Glob::new("*.rs"), Glob::new("*.js")],
+            exclude: vec![Glob::new("*.min.js")],
+            exclude_dir: vec![Glob::new("targ?t")],
+        };
+        assert_eq!(
+            walk(filter),
+            ["a.rs", "b.js", "src/c.rs"].map(PathBuf::from)
+        );
+
+        // `exclude`は`include`より優先する
+        let filter = Filter {
+            include: vec![Glob::new("*.rs")],
+            exclude: vec![Glob::new("[a-c].rs")],
+            ..Filter::default()
+        };
+        assert_eq!(
+            walk(filter),
+            ["src/target/d.rs", "target/e.rs"].map(PathBuf::from)
+        );
+
+        // 指定したパス自身は絞り込まない
+        let filter = Filter {
+            include: vec![Glob::new("*.txt")],
+            ..Filter::default()
+        };
+        let file = root.join("a.rs");
+        assert_eq!(
+            Walk::new(&file)
+                .with_filter(filter)
+                .map(Result::unwrap)
+                .collect::<Vec<_>>(),
+            [file]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_gitignore() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("a/build"))?;
+        fs::create_dir_all(root.join("build"))?;
+        fs::write(root.join(".gitignore"), "/build/\n*.o\n")?;
+        fs::write(root.join("a/.gitignore"), "!x.o\n")?;
+        for file in ["a/build/y.c", "a/x.o", "a/z.o", "build/w.c", "v.c"] {
+            fs::write(root.join(file), "")?;
+        }
+        let walk = |respect: bool| {
+            Walk::new(root)
+                .with_gitignore(respect)
+                .map(|entry| entry.unwrap().strip_prefix(root).unwrap().to_path_buf())
+                .collect::<Vec<_>>()
+        };
+
+        // `/build/`は`a/build`にはマッチしない。`a/.gitignore`の否定が優先する
+        assert_eq!(
+            walk(true),
+            [".gitignore", "a/.gitignore", "a/build/y.c", "a/x.o", "v.c"].map(PathBuf::from)
+        );
+        assert_eq!(walk(false).len(), 7);
+
+        // 指定したパス自身は飛ばさない
+        let build = root.join("build");
+        assert_eq!(Walk::new(&build).with_gitignore(true).count(), 1);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_symlink() -> io::Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        fs::create_dir(root.join("a"))?;
+        fs::write(root.join("a/x.txt"), "")?;
+        // 親ディレクトリへのリンクと、壊れたリンク
+        symlink(root, root.join("a/loop"))?;
+        symlink(root.join("missing"), root.join("broken"))?;
+
+        let (files, errors): (Vec<_>, Vec<_>) = Walk::new(root).partition(Result::is_ok);
+        let files = files
+            .into_iter()
+            .map(|entry| entry.unwrap().strip_prefix(root).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        assert_eq!(files, [PathBuf::from("a/x.txt")]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].as_ref().unwrap_err().path, root.join("broken"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+//! ブラウザから使うためのwasm-bindgenによるバインディング。`wasm`フィーチャーで有効になる。
+//!
+//! JavaScriptの文字列はUTF-16なので、マッチの位置はバイト単位ではなくUTF-16のコード単位で返す。
+
+use wasm_bindgen::prelude::*;
+
+use crate::{EngineError, Regex};
+
+impl From<EngineError> for JsValue {
+    fn from(e: EngineError) -> Self {
+        JsValue::from_str(&e.to_string())
+    }
+}
+
+/// コンパイル済みの正規表現
+#[wasm_bindgen(js_name = Regex)]
+pub struct JsRegex {
+    regex: Regex,
+}
+
+/// マッチの位置。`text.slice(start, end)`でマッチした部分文字列が得られる。
+#[wasm_bindgen(js_name = Match)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsMatch {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// `pattern`をコンパイルする。不正なパターンはエラーメッセージの文字列を投げる。
+#[wasm_bindgen]
+pub fn compile(pattern: &str) -> Result<JsRegex, JsValue> {
+    Ok(JsRegex {
+        regex: Regex::new(pattern)?,
+    })
+}
+
+/// `pattern`のASTとコード生成したプログラムを、`print`と同じ形式の文字列で返す
+#[wasm_bindgen]
+pub fn disassemble(pattern: &str) -> Result<String, JsValue> {
+    let mut out = Vec::new();
+    crate::print(pattern, &mut out)?;
+    String::from_utf8(out).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+#[wasm_bindgen(js_class = Regex)]
+impl JsRegex {
+    /// コンパイル元のパターン
+    #[wasm_bindgen(getter)]
+    pub fn pattern(&self) -> String {
+        self.regex.as_str().to_string()
+    }
+
+    /// `text`のいずれかの位置からマッチするか
+    #[wasm_bindgen(js_name = isMatch)]
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+
+    /// `text`中の重ならないマッチをすべて返す
+    #[wasm_bindgen(js_name = findAll)]
+    pub fn find_all(&self, text: &str) -> Vec<JsMatch> {
+        self.regex
+            .find_iter(text)
+            .map(|m| JsMatch {
+                start: utf16_offset(text, m.start()),
+                end: utf16_offset(text, m.end()),
+            })
+            .collect()
+    }
+}
+
+/// バイト位置をUTF-16のコード単位での位置に変換する
+fn utf16_offset(text: &str, byte: usize) -> usize {
+    text[..byte].encode_utf16().count()
+}
+
+// `JsValue`を作る処理はWebAssembly以外では実行できないので、ここではマッチした場合のみを確かめる。
+// エラーの場合は`tests/wasm.rs`で確かめる。
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_all() {
+        let Ok(regex) = compile("a+") else {
+            panic!("should compile");
+        };
+        assert_eq!(regex.pattern(), "a+");
+        assert!(regex.is_match("baa"));
+        assert_eq!(
+            regex.find_all("aあa😀aa"),
+            vec![
+                JsMatch { start: 0, end: 1 },
+                JsMatch { start: 2, end: 3 },
+                JsMatch { start: 5, end: 7 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let Ok(listing) = disassemble("ab") else {
+            panic!("should disassemble");
+        };
+        assert!(listing.contains("0000: char a\n0001: char b\n0002: match\n"));
+    }
+}
@@ -0,0 +1,296 @@
+//! `regex`クレートを正解として、両者が対応する構文（文字、`|`、`*`、`+`、`?`、`.`、`^`、`$`、グループ）の
+//! ランダムなパターンで`match_line`の結果を比べる。
+//!
+//! 意図して異なる意味は、比較の前に次のように揃える。
+//!
+//! - `.`: このエンジンはデフォルトで改行にもマッチするので、`regex`には`(?s:.)`として渡す。
+//! - `$`: このエンジンの`$`は入力の末尾に到達した時点でマッチを終えるので、`$`の後に続くパターンは無視される。
+//!   `regex`と意味が一致するよう、`$`はパターンの末尾（に続く位置）にのみ生成する。
+//! - `^`: どちらも入力の先頭でのみ成り立つので、変換しない。
+
+use ch06_regex::match_line;
+
+/// 生成するパターンの構文木
+#[derive(Debug, Clone)]
+enum Pat {
+    Char(char),
+    Any,
+    Caret,
+    Dollar,
+    Seq(Vec<Pat>),
+    Or(Box<Pat>, Box<Pat>),
+    Group(Box<Pat>),
+    Star(Box<Pat>),
+    Plus(Box<Pat>),
+    Question(Box<Pat>),
+}
+
+impl Pat {
+    /// パターンの文字列。`oracle`なら`regex`に渡す文字列にする。
+    fn render(&self, oracle: bool) -> String {
+        match self {
+            Pat::Char(c) => c.to_string(),
+            Pat::Any if oracle => "(?s:.)".to_string(),
+            Pat::Any => ".".to_string(),
+            Pat::Caret => "^".to_string(),
+            Pat::Dollar => "$".to_string(),
+            Pat::Seq(v) => v.iter().map(|p| p.render_atom(oracle)).collect(),
+            Pat::Or(l, r) => format!("{}|{}", l.render(oracle), r.render(oracle)),
+            Pat::Group(p) => format!("({})", p.render(oracle)),
+            Pat::Star(p) => format!("{}*", p.render_operand(oracle)),
+            Pat::Plus(p) => format!("{}+", p.render_operand(oracle)),
+            Pat::Question(p) => format!("{}?", p.render_operand(oracle)),
+        }
+    }
+
+    /// 連接の要素として書けるよう、`|`や連接は括弧で囲む
+    fn render_atom(&self, oracle: bool) -> String {
+        match self {
+            Pat::Or(..) | Pat::Seq(_) => format!("({})", self.render(oracle)),
+            _ => self.render(oracle),
+        }
+    }
+
+    /// 繰り返しの対象として書けるよう、1文字以外は括弧で囲む
+    fn render_operand(&self, oracle: bool) -> String {
+        match self {
+            Pat::Char(_) | Pat::Group(_) => self.render(oracle),
+            Pat::Any if !oracle => self.render(oracle),
+            _ => format!("({})", self.render(oracle)),
+        }
+    }
+
+    /// 比較できるパターンか。`$`がパターンの末尾に続く位置にのみある。
+    /// `tail`はこのパターンの後に何も続かないか。
+    fn is_valid(&self, tail: bool) -> bool {
+        match self {
+            Pat::Char(_) | Pat::Any | Pat::Caret => true,
+            Pat::Dollar => tail,
+            Pat::Seq(v) => {
+                !v.is_empty()
+                    && v.iter()
+                        .enumerate()
+                        .all(|(i, p)| p.is_valid(tail && i == v.len() - 1))
+            }
+            Pat::Or(l, r) => l.is_valid(tail) && r.is_valid(tail),
+            Pat::Group(p) => p.is_valid(tail),
+            // 繰り返しの後には繰り返し自身が続きうる
+            Pat::Star(p) | Pat::Plus(p) | Pat::Question(p) => p.is_valid(false),
+        }
+    }
+
+    /// 1段階小さくしたパターンの候補
+    fn shrink(&self) -> Vec<Pat> {
+        let mut candidates = Vec::new();
+        match self {
+            Pat::Char('a') | Pat::Caret | Pat::Dollar => {}
+            Pat::Char(_) | Pat::Any => candidates.push(Pat::Char('a')),
+            Pat::Seq(v) => {
+                for i in 0..v.len() {
+                    if v.len() > 1 {
+                        let mut v2 = v.clone();
+                        v2.remove(i);
+                        candidates.push(Pat::Seq(v2));
+                    }
+                    for p in v[i].shrink() {
+                        let mut v2 = v.clone();
+                        v2[i] = p;
+                        candidates.push(Pat::Seq(v2));
+                    }
+                }
+            }
+            Pat::Or(l, r) => {
+                candidates.push((**l).clone());
+                candidates.push((**r).clone());
+                candidates.extend(
+                    l.shrink()
+                        .into_iter()
+                        .map(|l| Pat::Or(Box::new(l), r.clone())),
+                );
+                candidates.extend(
+                    r.shrink()
+                        .into_iter()
+                        .map(|r| Pat::Or(l.clone(), Box::new(r))),
+                );
+            }
+            Pat::Group(p) | Pat::Star(p) | Pat::Plus(p) | Pat::Question(p) => {
+                candidates.push((**p).clone());
+                let wrap = |p: Pat| match self {
+                    Pat::Group(_) => Pat::Group(Box::new(p)),
+                    Pat::Star(_) => Pat::Star(Box::new(p)),
+                    Pat::Plus(_) => Pat::Plus(Box::new(p)),
+                    _ => Pat::Question(Box::new(p)),
+                };
+                candidates.extend(p.shrink().into_iter().map(wrap));
+            }
+        }
+        candidates
+    }
+}
+
+/// 再現できるよう、シードを固定した疑似乱数（xorshift）
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// パターンを生成する。`depth`は残りの入れ子の深さ、`tail`はこのパターンの後に何も続かないか。
+fn gen_pat(rng: &mut Rng, depth: usize, tail: bool) -> Pat {
+    if depth > 0 && rng.below(4) == 0 {
+        Pat::Or(
+            Box::new(gen_seq(rng, depth - 1, tail)),
+            Box::new(gen_seq(rng, depth - 1, tail)),
+        )
+    } else {
+        gen_seq(rng, depth, tail)
+    }
+}
+
+fn gen_seq(rng: &mut Rng, depth: usize, tail: bool) -> Pat {
+    let len = 1 + rng.below(4);
+    let v = (0..len)
+        .map(|i| gen_atom(rng, depth, tail && i == len - 1))
+        .collect();
+    Pat::Seq(v)
+}
+
+fn gen_atom(rng: &mut Rng, depth: usize, tail: bool) -> Pat {
+    // 繰り返しの後には繰り返し自身が続きうるので、繰り返しの対象には`$`を含めない
+    let quantifier = rng.below(8);
+    let tail = tail && quantifier > 2;
+
+    let atom = match rng.below(10) {
+        0..=4 => Pat::Char(['a', 'b', 'c'][rng.below(3)]),
+        5 => Pat::Any,
+        6 => Pat::Caret,
+        7 if tail => Pat::Dollar,
+        8 | 9 if depth > 0 => Pat::Group(Box::new(gen_pat(rng, depth - 1, tail))),
+        _ => Pat::Char('a'),
+    };
+    // アンカーそのものは繰り返さない
+    if matches!(atom, Pat::Caret | Pat::Dollar) {
+        return atom;
+    }
+
+    match quantifier {
+        0 => Pat::Star(Box::new(atom)),
+        1 => Pat::Plus(Box::new(atom)),
+        2 => Pat::Question(Box::new(atom)),
+        _ => atom,
+    }
+}
+
+fn gen_line(rng: &mut Rng) -> String {
+    let len = rng.below(8);
+    (0..len)
+        .map(|_| ['a', 'b', 'c', '\n'][rng.below(4)])
+        .collect()
+}
+
+/// 2つのエンジンの結果が異なれば、その説明を返す
+fn compare(expr: &str, oracle_expr: &str, line: &str) -> Option<String> {
+    let oracle = regex::Regex::new(oracle_expr)
+        .unwrap_or_else(|e| panic!("invalid oracle pattern {oracle_expr:?}: {e}"));
+    let expected = oracle.is_match(line);
+
+    match match_line(expr, line) {
+        Ok(actual) if actual == expected => None,
+        actual => Some(format!(
+            "{expr:?} on {line:?}: expected {expected}, got {actual:?} (oracle: {oracle_expr:?})"
+        )),
+    }
+}
+
+/// 結果が異なるパターンと入力を、異なったまま小さくできなくなるまで小さくする
+fn shrink(mut pat: Pat, mut line: String) -> String {
+    let fails = |pat: &Pat, line: &str| compare(&pat.render(false), &pat.render(true), line);
+
+    'outer: loop {
+        for candidate in pat.shrink() {
+            if candidate.is_valid(true) && fails(&candidate, &line).is_some() {
+                pat = candidate;
+                continue 'outer;
+            }
+        }
+        for i in 0..line.len() {
+            let mut shorter = line.clone();
+            shorter.remove(i);
+            if fails(&pat, &shorter).is_some() {
+                line = shorter;
+                continue 'outer;
+            }
+        }
+        return fails(&pat, &line).unwrap();
+    }
+}
+
+/// 既存のテストのパターン。変換が不要な構文のみを使う。
+const SEEDS: &[&str] = &[
+    "abc|def",
+    "(ab|cd)+",
+    "a.c",
+    "^xyz",
+    "ab(c|d)*e",
+    "(a|aa)+$",
+    "a(bc|e+)*",
+    "(a|b)*c",
+    "(ab|a)*b$",
+    "a*a*a*b",
+    "^abc$",
+    "a|ab",
+    "(xy)+z",
+    "b+$",
+    "^ab",
+    "a+b",
+    // 空文字列にマッチしうる繰り返し
+    "(a?)*b",
+    "(a|b?)*c",
+    "()*b",
+    "(|a)*b",
+];
+
+#[test]
+fn test_seeds() {
+    let mut rng = Rng(0x5eed);
+    for seed in SEEDS {
+        let oracle = seed.replace('.', "(?s:.)");
+        let lines = [
+            "abc", "xyz", "aab", "abcbce", "aaaa", "ab\nc", "zxyzz", "de",
+        ]
+        .into_iter()
+        .map(String::from)
+        .chain((0..32).map(|_| gen_line(&mut rng)));
+        for line in lines {
+            if let Some(diff) = compare(seed, &oracle, &line) {
+                panic!("{diff}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_random_patterns() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..2000 {
+        let pat = gen_pat(&mut rng, 3, true);
+        assert!(pat.is_valid(true), "{pat:?}");
+        let (expr, oracle) = (pat.render(false), pat.render(true));
+
+        for _ in 0..8 {
+            let line = gen_line(&mut rng);
+            if compare(&expr, &oracle, &line).is_some() {
+                panic!("{}", shrink(pat, line));
+            }
+        }
+    }
+}
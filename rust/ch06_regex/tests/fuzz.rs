@@ -0,0 +1,69 @@
+//! 開発中のファジングで見つかった、パニックやスタックオーバーフローを起こした入力の回帰テスト。
+//! `fuzz/fuzz_targets/parse_eval.rs`と同じ手順で評価する。
+
+use ch06_regex::{Engine, EngineError, ParseError, RegexBuilder};
+
+/// パターンのコンパイルとすべてのエンジンでの評価がパニックしないことを確かめ、コンパイルの結果を返す
+fn replay(expr: &str, line: &str) -> Result<(), EngineError> {
+    for engine in Engine::ALL {
+        let regex = RegexBuilder::new(expr)
+            .engine(engine)
+            .size_limit(1 << 12)
+            .step_limit(Some(1 << 16))
+            .build()?;
+
+        let _ = regex.try_is_match(line);
+        let _ = regex.find(line);
+        let _ = regex.captures(line);
+        let _ = regex.find_iter(line).count();
+        let _ = regex.shortest_match(line);
+        let _ = regex.match_full(line);
+        let _ = regex.match_prefix(line);
+        let _ = regex.match_at(line, line.len() / 2);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_deep_nesting() {
+    // 入れ子が深いと、コード生成やASTの破棄でスタックオーバーフローしていた
+    let n = 100_000;
+    for expr in [
+        format!("{}a{}", "(".repeat(n), ")".repeat(n)),
+        format!("a{}", "*".repeat(n)),
+        format!("a{}", "?".repeat(n)),
+        vec!["a"; n].join("|"),
+        format!("{}a{}", "(a|".repeat(n), ")*".repeat(n)),
+    ] {
+        assert!(matches!(
+            replay(&expr, "aaa"),
+            Err(EngineError::Parse(ParseError::TooDeep(_)))
+        ));
+    }
+
+    // 上限より浅ければコンパイルできる
+    let n = 100;
+    assert!(replay(&format!("{}a{}", "(".repeat(n), ")".repeat(n)), "a").is_ok());
+    assert!(replay(&vec!["a"; n].join("|"), "a").is_ok());
+}
+
+#[test]
+fn test_trailing_escape() {
+    for expr in ["\\", "a\\", "\\\\\\", "(a|b\\"] {
+        assert!(matches!(
+            replay(expr, "a"),
+            Err(EngineError::Parse(ParseError::TrailingEscape(_)))
+        ));
+    }
+
+    let err = replay("a\\", "a").unwrap_err();
+    assert_eq!(err.to_string(), "ParseError: trailing escape: pos = 1");
+}
+
+#[test]
+fn test_step_limit() {
+    // 命令数を制限しないと指数的なバックトラックでタイムアウトしていた
+    let expr = format!("{}{}", "(a|aa)+".repeat(4), "$");
+    let line = format!("{}b", "a".repeat(40));
+    assert!(replay(&expr, &line).is_ok());
+}
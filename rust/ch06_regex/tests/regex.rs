@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::thread;
+
+use ch06_regex::{
+    do_matching, find_all, match_line, DynError, Engine, EngineError, ParseError, Regex,
+};
+
+#[test]
+fn test_regex() -> Result<(), DynError> {
+    let re = Regex::new("ab(c|d)*e")?;
+    assert_eq!(re.as_str(), "ab(c|d)*e");
+    assert!(re.is_match("xxabcdcex"));
+    assert!(!re.is_match("abcd"));
+
+    let m = re.find("xxabcdcex").unwrap();
+    assert_eq!((m.start(), m.end(), m.as_str()), (2, 8, "abcdce"));
+
+    let caps = Regex::new("(?<key>a+)=(b*)")?.captures("x aa=bb").unwrap();
+    assert_eq!(caps.name("key").unwrap().as_str(), "aa");
+    assert_eq!(&caps[2], "bb");
+
+    let words = Regex::new(" +")?.split("a  b c").collect::<Vec<_>>();
+    assert_eq!(words, vec!["a", "b", "c"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_functions() -> Result<(), DynError> {
+    assert!(do_matching("a+b", "aab", true)?);
+    assert!(!do_matching("a+b", "xaab", false)?);
+    assert!(match_line("a+b", "xaab")?);
+    assert_eq!(find_all("a+", "aaxa")?, vec![(0, 2), (3, 4)]);
+    assert!(ch06_regex::do_matching_with("a|b", "b", Engine::Bitstate)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_errors() {
+    // 不正なパターンのリテラルをそのまま渡すとclippyの`invalid_regex`に検出されるため、変数を介す
+    let exprs = ["a|*", "(a"];
+
+    let err = Regex::new(exprs[0]).unwrap_err();
+    assert!(matches!(err, EngineError::Parse(ParseError::NoPrev(2))));
+
+    let err = Regex::new(exprs[1]).unwrap_err();
+    assert_eq!(err.to_string(), "ParseError: no right parenthesis");
+}
+
+#[test]
+fn test_shared_across_threads() -> Result<(), DynError> {
+    let re = Arc::new(Regex::new("err(or)?: ")?);
+    let lines = (0..800)
+        .map(|i| match i % 4 {
+            0 => format!("error: {i}"),
+            1 => format!("warn: {i}"),
+            2 => format!("[{i}] err: {i}"),
+            _ => format!("error {i}"),
+        })
+        .collect::<Vec<_>>();
+    let expected = lines.iter().filter(|line| re.is_match(line)).count();
+    assert_eq!(expected, 400);
+
+    // 8つのスレッドで重ならない範囲の行を数える
+    let handles = lines
+        .chunks(lines.len() / 8)
+        .map(|chunk| {
+            let re = Arc::clone(&re);
+            let chunk = chunk.to_vec();
+            thread::spawn(move || chunk.iter().filter(|line| re.is_match(line)).count())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(handles.len(), 8);
+
+    let count = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .sum::<usize>();
+    assert_eq!(count, expected);
+
+    Ok(())
+}
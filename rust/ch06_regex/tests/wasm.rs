@@ -0,0 +1,45 @@
+//! `wasm`フィーチャーのバインディングのテスト。
+//!
+//! ```text
+//! wasm-pack test --node --features wasm
+//! ```
+
+#![cfg(all(target_arch = "wasm32", feature = "wasm"))]
+
+use ch06_regex::wasm::{compile, disassemble, JsMatch};
+use wasm_bindgen_test::wasm_bindgen_test;
+
+#[wasm_bindgen_test]
+fn test_compile() {
+    let regex = compile("ab(c|d)*e").unwrap();
+    assert_eq!(regex.pattern(), "ab(c|d)*e");
+    assert!(regex.is_match("xxabcdcex"));
+    assert!(!regex.is_match("abcd"));
+
+    let err = compile("(a").err().unwrap();
+    assert_eq!(
+        err.as_string().as_deref(),
+        Some("ParseError: no right parenthesis")
+    );
+}
+
+#[wasm_bindgen_test]
+fn test_find_all() {
+    let regex = compile("a+").unwrap();
+    assert_eq!(
+        regex.find_all("aあa😀aa"),
+        vec![
+            JsMatch { start: 0, end: 1 },
+            JsMatch { start: 2, end: 3 },
+            JsMatch { start: 5, end: 7 },
+        ]
+    );
+}
+
+#[wasm_bindgen_test]
+fn test_disassemble() {
+    let listing = disassemble("a|b").unwrap();
+    assert!(listing.starts_with("expr: a|b\nAST:\n"));
+    assert!(listing.contains("0000: split 0001, 0003"));
+    assert!(disassemble("+a").is_err());
+}
@@ -1,24 +1,270 @@
-fn main() -> std::io::Result<()> {
-    hello(&mut std::io::stdout())?;
-    Ok(())
+use std::{
+    io::{self, BufRead, BufWriter, Write},
+    process::ExitCode,
+    str::FromStr,
+};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let code = run(
+        &args,
+        io::stdin().lock(),
+        &mut io::stdout().lock(),
+        &mut io::stderr(),
+    );
+    ExitCode::from(code)
+}
+
+/// 引数の誤りがあった場合の終了コード
+const EXIT_USAGE: u8 = 2;
+
+/// `args`（先頭はコマンド名）に従って挨拶を書き出し、終了コードを返す。
+/// `--stdin`では、`stdin`から読んだ名前ごとに挨拶し、挨拶した数を`err`に書き出す。
+fn run(args: &[String], stdin: impl BufRead, out: &mut impl Write, err: &mut impl Write) -> u8 {
+    let (greeter, from_stdin) = match parse_args(&args[1..]) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let _ = writeln!(err, "{e}");
+            let _ = writeln!(err, "usage: {} [--lang=en|ja|fr] [NAME | --stdin]", args[0]);
+            return EXIT_USAGE;
+        }
+    };
+    let result = if from_stdin {
+        greet_all(stdin, BufWriter::new(out), greeter.lang)
+            .and_then(|count| writeln!(err, "{count} greetings"))
+    } else {
+        greeter.write_greeting(out)
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            let _ = writeln!(err, "error: {e}");
+            1
+        }
+    }
+}
+
+/// 引数から`Greeter`と、`--stdin`を指定したかを返す。
+/// `--lang=LANG`と、省略できる名前を1つ、または名前の代わりに`--stdin`を受け付ける。
+fn parse_args(args: &[String]) -> Result<(Greeter, bool), String> {
+    let mut greeter = Greeter::default();
+    let mut from_stdin = false;
+    for arg in args {
+        if let Some(lang) = arg.strip_prefix("--lang=") {
+            greeter.lang = lang.parse()?;
+        } else if arg == "--stdin" {
+            from_stdin = true;
+        } else if arg.starts_with("--") {
+            return Err(format!("unknown option: {arg}"));
+        } else if greeter.name.is_none() {
+            greeter.name = Some(arg.clone());
+        } else {
+            return Err(format!("unexpected argument: {arg}"));
+        }
+    }
+    if from_stdin && greeter.name.is_some() {
+        return Err("--stdin cannot be used with NAME".to_string());
+    }
+    Ok((greeter, from_stdin))
+}
+
+/// `reader`から1行に1つずつ名前を読み、それぞれへの挨拶を`lang`で`writer`に書き出して、挨拶した数を返す。
+/// 名前の前後の空白は除き、空の行は飛ばす。UTF-8として不正なバイト列はU+FFFDに置き換える。
+/// 最後に`writer`をフラッシュする。
+fn greet_all(mut reader: impl BufRead, mut writer: impl Write, lang: Lang) -> io::Result<usize> {
+    let mut count = 0;
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        let name = String::from_utf8_lossy(&buf);
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        let greeter = Greeter {
+            name: Some(name.to_string()),
+            lang,
+        };
+        greeter.write_greeting(&mut writer)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// 挨拶の言語
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Lang {
+    #[default]
+    En,
+    Ja,
+    Fr,
 }
 
-fn hello(writer: &mut impl std::io::Write) -> std::io::Result<()> {
-    writeln!(writer, "Hello, world!")?;
-    writeln!(writer, "This is ykyki.")
+impl FromStr for Lang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "en" => Ok(Lang::En),
+            "ja" => Ok(Lang::Ja),
+            "fr" => Ok(Lang::Fr),
+            _ => Err(format!(
+                "invalid value for --lang: {s} (expected en, ja or fr)"
+            )),
+        }
+    }
+}
+
+/// 名前を指定すればその人に、しなければ世界に挨拶する
+#[derive(Debug, Default)]
+struct Greeter {
+    name: Option<String>,
+    lang: Lang,
+}
+
+impl Greeter {
+    /// 挨拶を1行として書き出す
+    fn write_greeting(&self, w: &mut impl Write) -> io::Result<()> {
+        // フランス語では`!`の前に空白を置く
+        match (self.lang, &self.name) {
+            (Lang::En, None) => writeln!(w, "Hello, world!"),
+            (Lang::En, Some(name)) => writeln!(w, "Hello, {name}!"),
+            (Lang::Ja, None) => writeln!(w, "こんにちは、世界！"),
+            (Lang::Ja, Some(name)) => writeln!(w, "こんにちは、{name}さん！"),
+            (Lang::Fr, None) => writeln!(w, "Bonjour le monde !"),
+            (Lang::Fr, Some(name)) => writeln!(w, "Bonjour, {name} !"),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::Cursor;
+
     use super::*;
 
-    #[test]
-    fn test_hello() -> std::io::Result<()> {
+    fn greet(name: Option<&str>, lang: Lang) -> io::Result<Vec<u8>> {
+        let greeter = Greeter {
+            name: name.map(str::to_string),
+            lang,
+        };
         let mut buf = Vec::new();
+        greeter.write_greeting(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_greeting() -> io::Result<()> {
+        assert_eq!(greet(None, Lang::En)?, b"Hello, world!\n");
+        assert_eq!(greet(Some("ykyki"), Lang::En)?, b"Hello, ykyki!\n");
+        assert_eq!(greet(None, Lang::Ja)?, "こんにちは、世界！\n".as_bytes());
+        assert_eq!(
+            greet(Some("ykyki"), Lang::Ja)?,
+            "こんにちは、ykykiさん！\n".as_bytes()
+        );
+        assert_eq!(greet(None, Lang::Fr)?, b"Bonjour le monde !\n");
+        assert_eq!(greet(Some("ykyki"), Lang::Fr)?, b"Bonjour, ykyki !\n");
+
+        // 複数バイトの文字を含む名前
+        assert_eq!(
+            greet(Some("太郎"), Lang::Ja)?,
+            "こんにちは、太郎さん！\n".as_bytes()
+        );
+        assert_eq!(greet(Some("Zoé"), Lang::En)?, "Hello, Zoé!\n".as_bytes());
+        assert_eq!(greet(Some("Zoé"), Lang::Fr)?, "Bonjour, Zoé !\n".as_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_greet_all() -> io::Result<()> {
+        let greet_all_with = |input: &[u8], lang| -> io::Result<(usize, String)> {
+            let mut out = Cursor::new(Vec::new());
+            let count = greet_all(Cursor::new(input), &mut out, lang)?;
+            Ok((count, String::from_utf8(out.into_inner()).unwrap()))
+        };
+
+        // 前後の空白を除き、空の行は飛ばす
+        let (count, out) = greet_all_with(b"ykyki\n\n  Zo\xc3\xa9 \n\t\n", Lang::En)?;
+        assert_eq!(count, 2);
+        assert_eq!(out, "Hello, ykyki!\nHello, Zoé!\n");
 
-        hello(&mut buf)?;
+        // 最後の行に改行がなくても同じ
+        let (count, out) = greet_all_with(b"a\nb", Lang::Fr)?;
+        assert_eq!(count, 2);
+        assert_eq!(out, "Bonjour, a !\nBonjour, b !\n");
+        let (count, out) = greet_all_with(b"a\nb\n", Lang::Fr)?;
+        assert_eq!(count, 2);
+        assert_eq!(out, "Bonjour, a !\nBonjour, b !\n");
+
+        // 不正なバイト列で諦めずに置き換える
+        let (count, out) = greet_all_with(b"\xff\xfeb\nc\n", Lang::Ja)?;
+        assert_eq!(count, 2);
+        assert_eq!(
+            out,
+            "こんにちは、\u{FFFD}\u{FFFD}bさん！\nこんにちは、cさん！\n"
+        );
+
+        assert_eq!(greet_all_with(b"", Lang::En)?, (0, String::new()));
 
-        assert_eq!(buf, b"Hello, world!\nThis is ykyki.\n");
         Ok(())
     }
+
+    fn run_with(args: &[&str]) -> (u8, String, String) {
+        run_with_stdin(args, "")
+    }
+
+    fn run_with_stdin(args: &[&str], stdin: &str) -> (u8, String, String) {
+        let args = std::iter::once("hello")
+            .chain(args.iter().copied())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&args, stdin.as_bytes(), &mut out, &mut err);
+        (
+            code,
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(err).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(
+            run_with(&[]),
+            (0, "Hello, world!\n".to_string(), String::new())
+        );
+        let (code, out, _) = run_with(&["--lang=ja", "太郎"]);
+        assert_eq!((code, out.as_str()), (0, "こんにちは、太郎さん！\n"));
+        let (code, out, _) = run_with(&["ykyki", "--lang=fr"]);
+        assert_eq!((code, out.as_str()), (0, "Bonjour, ykyki !\n"));
+
+        // 未知の言語や余分な引数は使い方の誤り
+        let (code, out, err) = run_with(&["--lang=de"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(out.is_empty());
+        assert_eq!(
+            err,
+            "invalid value for --lang: de (expected en, ja or fr)\nusage: hello [--lang=en|ja|fr] [NAME | --stdin]\n"
+        );
+        let (code, _, err) = run_with(&["a", "b"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("unexpected argument: b\n"));
+        let (code, _, err) = run_with(&["--name=a"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("unknown option: --name=a\n"));
+
+        // `--stdin`では名前ごとに挨拶し、挨拶した数を標準エラー出力に書き出す
+        let (code, out, err) = run_with_stdin(&["--stdin", "--lang=ja"], "太郎\n\n花子\n");
+        assert_eq!(code, 0);
+        assert_eq!(out, "こんにちは、太郎さん！\nこんにちは、花子さん！\n");
+        assert_eq!(err, "2 greetings\n");
+        let (code, _, err) = run_with(&["--stdin", "ykyki"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("--stdin cannot be used with NAME\n"));
+    }
 }